@@ -0,0 +1,444 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::http_server::{read_request, write_response, Request};
+use crate::magnet::MagnetLink;
+use crate::pieces::Priority;
+use crate::rate_limit::deserialize_rate;
+use crate::session::{info_hash_from_hex, Session, TorrentHandle, TorrentStatus};
+use crate::transmission_rpc::{TransmissionRpc, SESSION_ID_HEADER};
+use crate::utils::decode_base64;
+use crate::utils::torrents::Torrent;
+
+/// The biggest request body we take, the torrent files of huge torrents are a few megabytes.
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+/// An HTTP JSON API to control a session remotely, e.g. when it runs headless on a server.
+///
+/// ```text
+/// GET    /api/session                           the stats of the session
+/// GET    /api/torrents                          the status of every torrent
+/// POST   /api/torrents                          add the torrent file or magnet link in the body
+/// GET    /api/torrents/<info hash>              the status of a torrent
+/// POST   /api/torrents/<info hash>/pause
+/// POST   /api/torrents/<info hash>/resume
+/// PUT    /api/torrents/<info hash>/priorities   ["high", "skip", "normal"]
+/// PUT    /api/torrents/<info hash>/limits       {"download_limit": "1M", "upload_limit": null}
+/// DELETE /api/torrents/<info hash>              add ?delete_data=true to delete what was downloaded
+/// ```
+///
+/// Every request needs the token of the server as `Authorization: Bearer <token>`, or as the
+/// password of basic auth for clients that only know that. Changes are answered with the new
+/// status of the torrent, errors with {"error": "..."}.
+///
+/// The server can answer the RPC of Transmission at /transmission/rpc as well, see
+/// with_transmission_rpc, so that its remote UIs and scripts work with the session too.
+///
+/// There's no TLS, so the token is only safe on localhost or behind a proxy that adds it.
+pub struct ApiServer {
+    listener: TcpListener,
+    api: Arc<Api>,
+}
+
+/// What the connections of a server share.
+struct Api {
+    session: Arc<Session>,
+    token: String,
+    transmission_rpc: Option<TransmissionRpc>,
+}
+
+impl ApiServer {
+    pub async fn bind(addr: &str, session: Arc<Session>, token: &str) -> io::Result<ApiServer> {
+        if token.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The API needs a token"));
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+
+        let api = Api { session, token: token.to_string(), transmission_rpc: None };
+
+        Ok(ApiServer { listener, api: Arc::new(api) })
+    }
+
+    /// Also answer the Transmission RPC at /transmission/rpc.
+    pub fn with_transmission_rpc(mut self) -> ApiServer {
+        if let Some(api) = Arc::get_mut(&mut self.api) {
+            api.transmission_rpc = Some(TransmissionRpc::new());
+        }
+
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the listener fails, every connection gets its own task.
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let api = self.api.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, api).await {
+                    warn!(error = %e, "API connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// The answer to a request, a status, a JSON body and the headers besides the usual ones.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Value,
+}
+
+impl Response {
+    fn ok<T: Serialize>(value: T) -> Response {
+        Response { status: "200 OK", headers: vec![], body: serde_json::to_value(value).unwrap_or(Value::Null) }
+    }
+
+    fn error<E: Display>(status: &'static str, error: E) -> Response {
+        Response { status, headers: vec![], body: json!({ "error": error.to_string() }) }
+    }
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Response {
+        let status = match e {
+            Error::UnknownTorrent => "404 Not Found",
+            Error::DuplicateTorrent(_) => "409 Conflict",
+            Error::Metainfo(_) | Error::Config(_) => "400 Bad Request",
+            Error::Metadata(_) => "502 Bad Gateway",
+            _ => "500 Internal Server Error",
+        };
+
+        Response::error(status, e)
+    }
+}
+
+impl From<Result<TorrentStatus>> for Response {
+    fn from(result: Result<TorrentStatus>) -> Response {
+        match result {
+            Ok(status) => Response::ok(status),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// New limits for a torrent, a missing or null limit lifts it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Limits {
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    download_limit: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    upload_limit: Option<u64>,
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, api: Arc<Api>) -> io::Result<()> {
+    let response = match read_request(&mut stream, MAX_BODY_LEN).await? {
+        Some(request) if !is_authorized(&request, &api.token) => Response {
+            headers: vec![("WWW-Authenticate", "Bearer, Basic realm=\"torrenter\"".to_string())],
+            ..Response::error("401 Unauthorized", "Missing or wrong API token")
+        },
+        Some(request) => handle_request(&api, &request).await,
+        None => Response::error("400 Bad Request", "Malformed or too long request"),
+    };
+
+    let body = response.body.to_string();
+    let mut headers = vec![("Content-Type", "application/json".to_string()), ("Content-Length", body.len().to_string())];
+    headers.extend(response.headers);
+
+    write_response(&mut stream, response.status, &headers, body.as_bytes()).await
+}
+
+/// Check the token of a request, given as a bearer token or as the password of basic auth.
+///
+/// The comparison takes constant time so that the token can't be guessed byte by byte.
+fn is_authorized(request: &Request, token: &str) -> bool {
+    let authorization = request.header("authorization").unwrap_or("");
+
+    let given = if let Some(bearer) = authorization.strip_prefix("Bearer ") {
+        bearer.to_string()
+    } else if let Some(basic) = authorization.strip_prefix("Basic ") {
+        let credentials = decode_base64(basic).map(|credentials| String::from_utf8_lossy(&credentials).to_string()).unwrap_or_default();
+        credentials.split_once(':').map(|(_, password)| password.to_string()).unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn handle_request(api: &Api, request: &Request) -> Response {
+    let session = &*api.session;
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "session"]) => Response::ok(session.stats()),
+        ("GET", ["api", "torrents"]) => Response::ok(session.torrents()),
+        ("POST", ["api", "torrents"]) => add_torrent(session, &request.body).await,
+        (_, ["transmission", "rpc"]) if api.transmission_rpc.is_some() => transmission_request(api.transmission_rpc.as_ref().unwrap(), session, request).await,
+        (method, ["api", "torrents", info_hash, action @ ..]) => match info_hash_from_hex(info_hash).and_then(|info_hash| session.torrent(&info_hash)) {
+            Some(handle) => torrent_request(&handle, method, action, query, &request.body).await,
+            None => Error::UnknownTorrent.into(),
+        },
+        (method, _) => Response::error("404 Not Found", format!("No such endpoint: {} {}", method, path)),
+    }
+}
+
+/// Answer the Transmission RPC. Clients first get a 409 with the session id, which they send
+/// back with every request, a page of another site can't read it to post from the browser.
+async fn transmission_request(rpc: &TransmissionRpc, session: &Session, request: &Request) -> Response {
+    let headers = vec![(SESSION_ID_HEADER, rpc.session_id().to_string())];
+
+    if request.header(SESSION_ID_HEADER) != Some(rpc.session_id()) {
+        return Response { headers, ..Response::error("409 Conflict", format!("Missing or wrong {}", SESSION_ID_HEADER)) };
+    }
+    if request.method != "POST" {
+        return Response { headers, ..Response::error("405 Method Not Allowed", "The RPC only takes POST requests") };
+    }
+
+    Response { headers, ..Response::ok(rpc.handle(session, &request.body).await) }
+}
+
+/// Add a torrent from its torrent file, into the download folder of the session.
+async fn add_torrent(session: &Session, body: &[u8]) -> Response {
+    let torrent = if body.starts_with(b"magnet:") {
+        let magnet = match MagnetLink::parse(String::from_utf8_lossy(body).trim()) {
+            Ok(magnet) => magnet,
+            Err(e) => return Response::error("400 Bad Request", e),
+        };
+
+        match session.fetch_metadata(&magnet, &[]).await {
+            Ok(torrent) => torrent,
+            Err(e) => return e.into(),
+        }
+    } else {
+        match Torrent::from_bytes(body) {
+            Ok(torrent) => torrent,
+            Err(e) => return Error::from(e).into(),
+        }
+    };
+
+    match session.add_torrent(torrent, session.settings().download_options()) {
+        Ok(handle) => Response { status: "201 Created", ..handle.status().into() },
+        Err(e) => e.into(),
+    }
+}
+
+async fn torrent_request(handle: &TorrentHandle, method: &str, action: &[&str], query: &str, body: &[u8]) -> Response {
+    let result = match (method, action) {
+        ("GET", []) => Ok(()),
+        ("POST", ["pause"]) => handle.pause().await,
+        ("POST", ["resume"]) => handle.resume(),
+        ("PUT", ["priorities"]) => match parse_priorities(body) {
+            Ok(priorities) => handle.set_file_priorities(priorities).await,
+            Err(e) => return Response::error("400 Bad Request", e),
+        },
+        ("PUT", ["limits"]) => match serde_json::from_slice::<Limits>(body) {
+            Ok(limits) => handle.set_limits(limits.download_limit, limits.upload_limit),
+            Err(e) => return Response::error("400 Bad Request", e),
+        },
+        ("DELETE", []) => {
+            let delete_data = query.split('&').any(|pair| pair == "delete_data=true" || pair == "delete_data=1");
+
+            return match handle.remove(delete_data).await {
+                Ok(()) => Response::ok(json!({ "removed": true })),
+                Err(e) => e.into(),
+            };
+        }
+        _ => return Response::error("404 Not Found", format!("No such endpoint: {} {}", method, action.join("/"))),
+    };
+
+    match result {
+        Ok(()) => handle.status().into(),
+        Err(e) => e.into(),
+    }
+}
+
+/// Read the priorities of the files of a torrent, a JSON list like ["high", "skip"].
+fn parse_priorities(body: &[u8]) -> std::result::Result<Vec<Priority>, String> {
+    let priorities: Vec<String> = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+
+    priorities.iter().map(|priority| priority.parse()).collect()
+}
+
+#[tokio::test]
+async fn test_api_server() {
+    use std::fs;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::settings::Settings;
+    use crate::utils::gen_peer_id;
+
+    let folder = Path::new("test-files/test12");
+    fs::create_dir_all(folder.join("source")).unwrap();
+    fs::write(folder.join("source").join("data.txt"), vec![7; 100]).unwrap();
+    let torrent_file = create_torrent(&folder.join("source").join("data.txt"), &CreateOptions::default()).unwrap();
+    let info_hash = Torrent::from_bytes(&torrent_file).unwrap().info_hash_hex();
+
+    let settings = Settings { download_dir: folder.join("downloads"), ..Default::default() };
+    let session = Arc::new(Session::new(gen_peer_id(), settings).unwrap());
+    let api = Arc::new(Api { session, token: "secret".to_string(), transmission_rpc: None });
+
+    let request = |method: &'static str, path: String, bearer: &'static str, body: Vec<u8>| {
+        let api = api.clone();
+
+        async move {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(handle_connection(server, api));
+
+            let head = format!("{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n", method, path, bearer, body.len());
+            client.write_all(head.as_bytes()).await.unwrap();
+            client.write_all(&body).await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+
+            let head_len = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            let status = String::from_utf8_lossy(&response[9..12]).parse::<u16>().unwrap();
+            (status, serde_json::from_slice::<Value>(&response[head_len..]).unwrap())
+        }
+    };
+    let torrent_path = |action: &str| format!("/api/torrents/{}{}", info_hash, action);
+
+    assert_eq!(request("GET", "/api/torrents".to_string(), "wrong", vec![]).await.0, 401);
+
+    let (status, body) = request("POST", "/api/torrents".to_string(), "secret", torrent_file.clone()).await;
+    assert_eq!(status, 201);
+    assert_eq!(body["name"], "data.txt");
+    assert_eq!(request("POST", "/api/torrents".to_string(), "secret", torrent_file).await.0, 409);
+    assert_eq!(request("POST", "/api/torrents".to_string(), "secret", b"not a torrent".to_vec()).await.0, 400);
+    assert_eq!(request("POST", "/api/torrents".to_string(), "secret", format!("magnet:?xt=urn:btih:{}", info_hash).into_bytes()).await.0, 502);
+
+    let (status, body) = request("GET", "/api/torrents".to_string(), "secret", vec![]).await;
+    assert_eq!((status, body[0]["info_hash"].as_str()), (200, Some(info_hash.as_str())));
+
+    let (_, body) = request("POST", torrent_path("/pause"), "secret", vec![]).await;
+    assert_eq!(body["state"], "paused");
+    let (_, body) = request("POST", torrent_path("/resume"), "secret", vec![]).await;
+    assert_eq!(body["state"], "downloading");
+
+    assert_eq!(request("PUT", torrent_path("/limits"), "secret", br#"{"download_limit": "1M"}"#.to_vec()).await.0, 200);
+    assert_eq!(request("PUT", torrent_path("/limits"), "secret", br#"{"download_limit": "fast"}"#.to_vec()).await.0, 400);
+    assert_eq!(request("PUT", torrent_path("/priorities"), "secret", br#"["high"]"#.to_vec()).await.0, 200);
+    assert_eq!(request("PUT", torrent_path("/priorities"), "secret", br#"["urgent"]"#.to_vec()).await.0, 400);
+
+    let (_, body) = request("GET", "/api/session".to_string(), "secret", vec![]).await;
+    assert_eq!((body["torrents"].as_u64(), body["downloading"].as_u64()), (Some(1), Some(1)));
+
+    assert_eq!(request("DELETE", torrent_path("?delete_data=true"), "secret", vec![]).await.0, 200);
+    assert_eq!(request("GET", torrent_path(""), "secret", vec![]).await.0, 404);
+    assert!(!folder.join("downloads").join("data.txt").exists());
+    assert_eq!(request("GET", "/api/nothing".to_string(), "secret", vec![]).await.0, 404);
+    assert_eq!(request("POST", "/transmission/rpc".to_string(), "secret", vec![]).await.0, 404);
+
+    let _ = fs::remove_dir_all(folder);
+}
+
+#[tokio::test]
+async fn test_transmission_rpc() {
+    use std::fs;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::settings::Settings;
+    use crate::utils::gen_peer_id;
+
+    let folder = Path::new("test-files/test13");
+    fs::create_dir_all(folder.join("source")).unwrap();
+    fs::write(folder.join("source").join("data.txt"), vec![7; 100]).unwrap();
+    let torrent_file = create_torrent(&folder.join("source").join("data.txt"), &CreateOptions::default()).unwrap();
+    let info_hash = Torrent::from_bytes(&torrent_file).unwrap().info_hash_hex();
+    fs::write(folder.join("data.torrent"), &torrent_file).unwrap();
+    let filename = folder.join("data.torrent").display().to_string();
+
+    let settings = Settings { download_dir: folder.join("downloads"), ..Default::default() };
+    let session = Arc::new(Session::new(gen_peer_id(), settings).unwrap());
+    let api = Arc::new(Api { session: session.clone(), token: "secret".to_string(), transmission_rpc: Some(TransmissionRpc::new()) });
+
+    // Basic auth with any user and the token as password, "user:secret".
+    let request = |session_id: String, body: Value| {
+        let api = api.clone();
+
+        async move {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(handle_connection(server, api));
+
+            let body = body.to_string();
+            let head = format!(
+                "POST /transmission/rpc HTTP/1.1\r\nAuthorization: Basic dXNlcjpzZWNyZXQ=\r\nX-Transmission-Session-Id: {}\r\nContent-Length: {}\r\n\r\n",
+                session_id,
+                body.len()
+            );
+            client.write_all(head.as_bytes()).await.unwrap();
+            client.write_all(body.as_bytes()).await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+
+            let head_len = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            let head = String::from_utf8_lossy(&response[..head_len]).to_string();
+            let status = head[9..12].parse::<u16>().unwrap();
+            let session_id = head.lines().find_map(|line| line.strip_prefix("X-Transmission-Session-Id: ")).unwrap_or("").to_string();
+            (status, session_id, serde_json::from_slice::<Value>(&response[head_len..]).unwrap())
+        }
+    };
+
+    let (status, session_id, _) = request("".to_string(), json!({ "method": "session-get" })).await;
+    assert_eq!(status, 409);
+    assert_eq!(session_id.len(), 48);
+    assert_eq!(request("wrong".to_string(), json!({ "method": "session-get" })).await.0, 409);
+    let rpc = |body: Value| {
+        let request = request(session_id.clone(), body);
+        async move { request.await.2 }
+    };
+
+    let body = rpc(json!({ "method": "torrent-add", "arguments": { "filename": filename, "paused": true }, "tag": 5 })).await;
+    assert_eq!((body["result"].as_str(), body["tag"].as_u64()), (Some("success"), Some(5)));
+    assert_eq!(body["arguments"]["torrent-added"], json!({ "id": 1, "name": "data.txt", "hashString": info_hash }));
+    let body = rpc(json!({ "method": "torrent-add", "arguments": { "filename": filename } })).await;
+    assert_eq!(body["arguments"]["torrent-duplicate"]["id"], 1);
+    let body = rpc(json!({ "method": "torrent-add", "arguments": { "metainfo": "not base64!" } })).await;
+    assert_ne!(body["result"], "success");
+    let body = rpc(json!({ "method": "torrent-add", "arguments": { "filename": format!("magnet:?xt=urn:btih:{}", info_hash) } })).await;
+    assert_ne!(body["result"], "success");
+
+    let get = json!({ "method": "torrent-get", "arguments": { "ids": [1], "fields": ["id", "status", "totalSize"] } });
+    assert_eq!(rpc(get.clone()).await["arguments"]["torrents"], json!([{ "id": 1, "status": 0, "totalSize": 100 }]));
+    rpc(json!({ "method": "torrent-start", "arguments": { "ids": info_hash } })).await;
+    assert_eq!(rpc(get.clone()).await["arguments"]["torrents"][0]["status"], 4);
+    rpc(json!({ "method": "torrent-stop" })).await;
+    assert_eq!(rpc(get.clone()).await["arguments"]["torrents"][0]["status"], 0);
+    assert_eq!(rpc(json!({ "method": "torrent-get", "arguments": { "ids": [2] } })).await["arguments"]["torrents"], json!([]));
+
+    let set = json!({ "method": "session-set", "arguments": { "speed-limit-down": 100, "speed-limit-down-enabled": true, "download-queue-size": 2, "download-queue-enabled": true, "alt-speed-enabled": true } });
+    assert_eq!(rpc(set).await["result"], "success");
+    assert_eq!(session.settings().download_limit, Some(100_000));
+    assert_eq!(session.settings().max_active_downloads, Some(2));
+    let body = rpc(json!({ "method": "session-get", "arguments": { "fields": ["speed-limit-down", "speed-limit-down-enabled", "alt-speed-enabled"] } })).await;
+    assert_eq!(body["arguments"], json!({ "speed-limit-down": 100, "speed-limit-down-enabled": true, "alt-speed-enabled": true }));
+
+    assert_eq!(rpc(json!({ "method": "torrent-remove", "arguments": { "ids": [1], "delete-local-data": true } })).await["result"], "success");
+    assert!(session.torrents().is_empty());
+    assert!(!folder.join("downloads").join("data.txt").exists());
+    assert_ne!(rpc(json!({ "method": "torrent-verify" })).await["result"], "success");
+
+    let _ = fs::remove_dir_all(folder);
+}
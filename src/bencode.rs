@@ -0,0 +1,135 @@
+use anyhow::{anyhow, bail, Result};
+
+/// How deep lists and dictionaries may nest. Real torrents need a handful of levels, the cap
+/// keeps a hostile file from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Find the byte span of the value stored under `key` in a top level bencoded dictionary.
+///
+/// The info hash must be computed from the exact bytes of the `info` dictionary
+/// as they appear in the metainfo file, so rather than re-serializing a decoded struct
+/// we walk the raw bencode and return where the value starts and ends.
+///
+/// ```text
+/// d8:announce...4:infod...ee
+///                 ^      ^
+///               start   end
+/// ```
+pub fn find_dict_value(buf: &[u8], key: &[u8]) -> Result<Option<(usize, usize)>> {
+    if buf.first() != Some(&b'd') {
+        bail!("Expected a bencoded dictionary");
+    }
+
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let (key_start, key_end) = parse_string(buf, pos)?;
+        let value_start = key_end;
+        let value_end = skip_value(buf, value_start)?;
+
+        if &buf[key_start..key_end] == key {
+            return Ok(Some((value_start, value_end)));
+        }
+
+        pos = value_end;
+    }
+
+    Ok(None)
+}
+
+
+/// Skip over a single bencoded value starting at `pos` and return the position right after it.
+///
+/// Values nested deeper than MAX_DEPTH are rejected.
+pub fn skip_value(buf: &[u8], pos: usize) -> Result<usize> {
+    skip_nested_value(buf, pos, 0)
+}
+
+
+fn skip_nested_value(buf: &[u8], pos: usize, depth: usize) -> Result<usize> {
+    match buf.get(pos) {
+        // Integer: i<digits>e
+        Some(b'i') => {
+            let end = find_byte(buf, pos + 1, b'e')?;
+            Ok(end + 1)
+        }
+        // List or dictionary: l<values>e / d<key><value>e
+        Some(b'l') | Some(b'd') => {
+            if depth >= MAX_DEPTH {
+                bail!("Lists and dictionaries nested deeper than {} levels at offset {}", MAX_DEPTH, pos);
+            }
+
+            let mut pos = pos + 1;
+            while buf.get(pos) != Some(&b'e') {
+                if buf.get(pos).is_none() {
+                    bail!("Unterminated bencoded list or dictionary");
+                }
+                pos = skip_nested_value(buf, pos, depth + 1)?;
+            }
+            Ok(pos + 1)
+        }
+        // Byte string: <length>:<bytes>
+        Some(b'0'..=b'9') => {
+            let (_, end) = parse_string(buf, pos)?;
+            Ok(end)
+        }
+        Some(b) => Err(anyhow!("Unexpected byte {:?} at offset {}", *b as char, pos)),
+        None => Err(anyhow!("Unexpected end of bencoded data")),
+    }
+}
+
+
+/// Parse a bencoded byte string header and return the span of its contents.
+fn parse_string(buf: &[u8], pos: usize) -> Result<(usize, usize)> {
+    let colon = find_byte(buf, pos, b':')?;
+
+    let len = std::str::from_utf8(&buf[pos..colon])?
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid string length at offset {}", pos))?;
+
+    let start = colon + 1;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= buf.len())
+        .ok_or_else(|| anyhow!("String at offset {} runs past the end of the data", pos))?;
+
+    Ok((start, end))
+}
+
+
+fn find_byte(buf: &[u8], from: usize, byte: u8) -> Result<usize> {
+    buf.iter()
+        .skip(from)
+        .position(|b| *b == byte)
+        .map(|i| from + i)
+        .ok_or_else(|| anyhow!("Expected {:?} after offset {}", byte as char, from))
+}
+
+#[test]
+fn test_find_dict_value() {
+    let buf = b"d3:cow3:moo4:infod4:name4:spam6:sourcei1ee4:spaml1:a1:bee";
+    let (start, end) = find_dict_value(buf, b"info").unwrap().unwrap();
+    assert_eq!(&buf[start..end], b"d4:name4:spam6:sourcei1ee");
+
+    let (start, end) = find_dict_value(buf, b"spam").unwrap().unwrap();
+    assert_eq!(&buf[start..end], b"l1:a1:be");
+
+    assert_eq!(find_dict_value(buf, b"missing").unwrap(), None);
+}
+
+#[test]
+fn test_find_dict_value_invalid() {
+    assert!(find_dict_value(b"l4:infoe", b"info").is_err());
+    assert!(find_dict_value(b"d4:infod4:name4:spam", b"info").is_err());
+    assert!(find_dict_value(b"d4:info99:short", b"info").is_err());
+}
+
+#[test]
+fn test_skip_value_depth() {
+    let nested = |depth: usize| format!("{}i1e{}", "l".repeat(depth), "e".repeat(depth)).into_bytes();
+
+    assert_eq!(skip_value(&nested(MAX_DEPTH), 0).unwrap(), MAX_DEPTH * 2 + 3);
+    assert!(skip_value(&nested(MAX_DEPTH + 1), 0).is_err());
+    // Deep enough to overflow the stack without the cap.
+    assert!(skip_value(&nested(1_000_000), 0).is_err());
+    assert!(find_dict_value(&[b"d4:info".to_vec(), nested(1_000_000), b"e".to_vec()].concat(), b"info").is_err());
+}
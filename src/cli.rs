@@ -0,0 +1,640 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use serde_derive::Serialize;
+
+use torrenter::api_server::ApiServer;
+use torrenter::create::{create_torrent, CreateOptions};
+use torrenter::logging;
+use torrenter::logging::DEFAULT_LOG_FILTER;
+use torrenter::magnet::MagnetLink;
+use torrenter::rate_limit::Rate;
+use torrenter::session::info_hash_from_hex;
+use torrenter::stream_server::StreamServer;
+use torrenter::tracker::scrape_tracker;
+use torrenter::{
+    gen_peer_id, AllocationMode, DownloadOptions, FsStorage, PickMode, Priority, SeedGoals, Session, Settings, Storage, Torrent,
+    TorrentState, TorrentSummary,
+};
+
+use crate::tui;
+
+/// How often the progress of a download is printed.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A BitTorrent client.
+#[derive(Debug, Parser)]
+#[command(name = "torrenter", version)]
+pub struct Cli {
+    /// Don't print the progress of downloads, only results and errors.
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Print JSON instead of text, one object per line.
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// What to log, a level like debug or levels per module like warn,torrenter::download=trace.
+    #[arg(long, global = true, env = "RUST_LOG", value_name = "FILTER", default_value = DEFAULT_LOG_FILTER)]
+    pub log_level: String,
+
+    /// Append the logs to a file instead of writing them to stderr.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// A TOML settings file, the options given on the command line take precedence over it.
+    #[arg(short, long, global = true, env = "TORRENTER_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download torrent files or magnet links.
+    Download(DownloadArgs),
+    /// Show what's in a torrent file.
+    Info {
+        torrent: PathBuf,
+    },
+    /// Create a torrent file from a file or a folder.
+    Create(CreateArgs),
+    /// Check downloaded data against the piece hashes of a torrent.
+    Verify {
+        torrent: PathBuf,
+        /// The folder the torrent was downloaded to.
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Ask the tracker of a torrent how many seeders and leechers it has.
+    Scrape {
+        torrent: PathBuf,
+    },
+    /// Run without a terminal and take commands over an HTTP JSON API.
+    Daemon(DaemonArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct DownloadArgs {
+    #[arg(value_name = "TORRENT|MAGNET", required = true)]
+    pub torrents: Vec<String>,
+
+    /// The folder to download to, the current folder unless the settings say otherwise.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// The port other peers connect to.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// The most peers a torrent is connected to at once.
+    #[arg(long)]
+    pub max_peers: Option<usize>,
+
+    /// How many blocks to ask a peer for at once.
+    #[arg(long)]
+    pub pipeline_depth: Option<usize>,
+
+    /// The most torrents downloading at once, the others wait in the queue in the order they were given.
+    #[arg(long)]
+    pub max_active: Option<usize>,
+
+    /// The most bytes per second to download across all torrents, e.g. 500K or 2M.
+    #[arg(long, value_name = "RATE")]
+    pub download_limit: Option<Rate>,
+
+    /// The most bytes per second to upload across all torrents.
+    #[arg(long, value_name = "RATE")]
+    pub upload_limit: Option<Rate>,
+
+    /// The download limit while the alternative speed limits are on.
+    #[arg(long, value_name = "RATE")]
+    pub alt_download_limit: Option<Rate>,
+
+    /// The upload limit while the alternative speed limits are on.
+    #[arg(long, value_name = "RATE")]
+    pub alt_upload_limit: Option<Rate>,
+
+    /// Turn the alternative speed limits on or off until their schedule switches them, or follow the schedule with auto.
+    #[arg(long, value_enum, default_value_t = AltSpeed::Auto)]
+    pub alt_speed: AltSpeed,
+
+    /// How files are created on disk: sparse, full or zero.
+    #[arg(long, default_value = "sparse")]
+    pub allocation: AllocationMode,
+
+    /// The order pieces are downloaded in: rarest-first, sequential or deadline.
+    #[arg(long, default_value = "rarest-first")]
+    pub pick_mode: PickMode,
+
+    /// The priority of each file in order, e.g. high,skip,normal. Files that aren't listed are normal.
+    #[arg(long, value_delimiter = ',')]
+    pub priorities: Vec<Priority>,
+
+    /// A peer to connect to instead of asking the tracker, can be given more than once. Magnet
+    /// links get their metadata from them too.
+    #[arg(long = "peer", value_name = "ADDRESS")]
+    pub peers: Vec<SocketAddr>,
+
+    /// Serve the files over HTTP while they download, e.g. 127.0.0.1:8888
+    #[arg(long, value_name = "ADDRESS")]
+    pub stream: Option<String>,
+
+    /// Show a full screen dashboard instead of printing the progress.
+    #[arg(long)]
+    pub tui: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AltSpeed {
+    On,
+    Off,
+    Auto,
+}
+
+impl DownloadArgs {
+    /// Override the settings with the options given on the command line.
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(output) = &self.output {
+            settings.download_dir = output.clone();
+        }
+        if let Some(port) = self.port {
+            settings.listen_port = port;
+        }
+        if let Some(max_peers) = self.max_peers {
+            settings.max_peers = max_peers;
+        }
+        if let Some(pipeline_depth) = self.pipeline_depth {
+            settings.pipeline_depth = pipeline_depth;
+        }
+        if let Some(max_active) = self.max_active {
+            settings.max_active_downloads = Some(max_active);
+        }
+        if let Some(Rate(rate)) = self.download_limit {
+            settings.download_limit = Some(rate);
+        }
+        if let Some(Rate(rate)) = self.upload_limit {
+            settings.upload_limit = Some(rate);
+        }
+        if let Some(Rate(rate)) = self.alt_download_limit {
+            settings.alt_download_limit = Some(rate);
+        }
+        if let Some(Rate(rate)) = self.alt_upload_limit {
+            settings.alt_upload_limit = Some(rate);
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct DaemonArgs {
+    /// Where the API listens. There's no TLS, so keep it on localhost or behind a proxy.
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:6683")]
+    pub api: String,
+
+    /// The token API clients send as `Authorization: Bearer <token>`, or as the password of basic auth.
+    #[arg(long, env = "TORRENTER_API_TOKEN", hide_env_values = true)]
+    pub token: String,
+
+    /// Also serve the Transmission RPC at /transmission/rpc, for its remote UIs and scripts.
+    #[arg(long)]
+    pub transmission_rpc: bool,
+
+    /// The folder to download to, the current folder unless the settings say otherwise.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// The port other peers connect to.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Args)]
+pub struct CreateArgs {
+    /// The file or folder to share.
+    pub path: PathBuf,
+
+    /// Where to write the torrent file, <name>.torrent by default.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// A tracker url, can be given more than once.
+    #[arg(short, long = "tracker", value_name = "URL")]
+    pub trackers: Vec<String>,
+
+    /// The length of a piece in bytes, picked from the size of the data by default.
+    #[arg(long)]
+    pub piece_length: Option<u64>,
+
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Only share the torrent with peers from its trackers.
+    #[arg(long)]
+    pub private: bool,
+
+    /// The url of a server hosting the files, can be given more than once.
+    #[arg(long = "web-seed", value_name = "URL")]
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Created {
+    path: String,
+    info_hash: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Verified {
+    pieces: u64,
+    verified: u64,
+    /// The pieces that are missing or don't match their hash.
+    failed: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Scraped {
+    tracker: String,
+    seeders: i32,
+    completed: i32,
+    leechers: i32,
+}
+
+impl Cli {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        match &self.command {
+            Command::Download(args) => self.download(args).await,
+            Command::Info { torrent } => self.info(torrent),
+            Command::Create(args) => self.create(args),
+            Command::Verify { torrent, output } => self.verify(torrent, output),
+            Command::Scrape { torrent } => self.scrape(torrent),
+            Command::Daemon(args) => self.daemon(args).await,
+        }
+    }
+
+    /// Set up logging from the command line.
+    ///
+    /// The dashboard takes over the terminal, so with it logs are only written to a log file.
+    pub fn init_logging(&self) -> anyhow::Result<()> {
+        let tui = matches!(&self.command, Command::Download(args) if args.tui);
+
+        match &self.log_file {
+            Some(path) => logging::init(&self.log_level, Some(path))?,
+            None if tui => {}
+            None => logging::init(&self.log_level, None)?,
+        }
+
+        Ok(())
+    }
+
+    /// The settings from the settings file, or the defaults without one.
+    fn settings(&self) -> anyhow::Result<Settings> {
+        match &self.config {
+            Some(path) => Ok(Settings::from_path(path)?),
+            None => Ok(Settings::default()),
+        }
+    }
+
+    /// Print an error to stderr, as an object with an error key in JSON mode.
+    pub fn print_error(&self, e: &anyhow::Error) {
+        if self.json {
+            eprintln!("{}", serde_json::json!({ "error": format!("{:#}", e) }));
+        } else {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+
+    /// Print a result, either as JSON or as the given text.
+    fn print<T: Serialize>(&self, value: &T, text: &str) {
+        if self.json {
+            println!("{}", serde_json::to_string(value).unwrap_or_default());
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    async fn download(&self, args: &DownloadArgs) -> anyhow::Result<()> {
+        let mut settings = self.settings()?;
+        args.apply(&mut settings);
+
+        let mut session = Session::new(gen_peer_id(), settings)?;
+        session.set_alt_speed(match args.alt_speed {
+            AltSpeed::On => Some(true),
+            AltSpeed::Off => Some(false),
+            AltSpeed::Auto => None,
+        });
+        session.listen()?;
+        // Subscribe before adding the torrents, so the dashboard sees them being added.
+        let events = session.subscribe();
+
+        let mut torrents = Vec::with_capacity(args.torrents.len());
+        for torrent in &args.torrents {
+            if torrent.starts_with("magnet:") {
+                let magnet = MagnetLink::parse(torrent)?;
+                if !self.quiet && !self.json && !args.tui {
+                    println!("Fetching the metadata of {} from peers", magnet.name.clone().unwrap_or_else(|| hex(&magnet.info_hash)));
+                }
+
+                torrents.push(session.fetch_metadata(&magnet, &args.peers).await?);
+            } else {
+                torrents.push(Torrent::from_path(torrent)?);
+            }
+        }
+
+        if let Some(stream_address) = &args.stream {
+            let server = StreamServer::bind(stream_address, session.stream_torrents()).await?;
+            if !self.quiet && !self.json && !args.tui {
+                for torrent in &torrents {
+                    println!("Streaming {} at http://{}/{}", torrent.name(), server.local_addr()?, torrent.info_hash_hex());
+                }
+            }
+            tokio::spawn(server.run());
+        }
+
+        let mut handles = Vec::with_capacity(torrents.len());
+        for torrent in torrents {
+            let options = DownloadOptions {
+                allocation: args.allocation,
+                file_priorities: args.priorities.clone(),
+                pick_mode: args.pick_mode,
+                peers: args.peers.clone(),
+                ..session.settings().download_options()
+            };
+            handles.push(session.add_torrent(torrent, options)?);
+        }
+
+        if args.tui {
+            return tui::run(&session, events).await;
+        }
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(PROGRESS_INTERVAL) => {}
+                _ = tokio::signal::ctrl_c() => {
+                    for handle in &handles {
+                        handle.pause().await?;
+                    }
+                    bail!("Interrupted");
+                }
+            }
+
+            // Without seed goals there's no end to seeding, so the download ends once it starts.
+            let seed = session.settings().seed_goals != SeedGoals::default();
+            // Torrents that were removed by their seed goals are done too.
+            handles.retain(|handle| handle.status().is_ok());
+            let mut finished = true;
+            for handle in &handles {
+                let status = handle.status()?;
+                let done = status.state == TorrentState::Finished || (status.state == TorrentState::Seeding && !seed);
+                finished &= done;
+
+                if done || !self.quiet {
+                    self.print(&status, &format!("{}: {:.1}% {:?}", status.name, status.progress, status.state));
+                }
+            }
+
+            if finished {
+                // Pausing saves the totals of the torrents with their data.
+                for handle in &handles {
+                    handle.pause().await?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn info(&self, torrent: &Path) -> anyhow::Result<()> {
+        let summary = Torrent::from_path(torrent)?.summary();
+        self.print(&summary, &format_summary(&summary));
+
+        Ok(())
+    }
+
+    fn create(&self, args: &CreateArgs) -> anyhow::Result<()> {
+        let options = CreateOptions {
+            trackers: args.trackers.clone(),
+            piece_length: args.piece_length,
+            comment: args.comment.clone(),
+            private: args.private,
+            web_seeds: args.web_seeds.clone(),
+        };
+
+        let metainfo = create_torrent(&args.path, &options)?;
+        let torrent = Torrent::from_bytes(&metainfo)?;
+
+        let output = args.output.clone().unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.name())));
+
+        // Never overwrite a torrent file that's already there.
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&output)
+            .and_then(|mut file| file.write_all(&metainfo))
+            .with_context(|| format!("Unable to write {}", output.display()))?;
+
+        let created = Created { path: output.display().to_string(), info_hash: torrent.info_hash_hex() };
+        self.print(&created, &format!("Created {} with info hash {}", created.path, created.info_hash));
+
+        Ok(())
+    }
+
+    fn verify(&self, torrent: &Path, output: &Path) -> anyhow::Result<()> {
+        let torrent = Arc::new(Torrent::from_path(torrent)?);
+
+        if !output.join(torrent.name()).exists() {
+            bail!("{} hasn't been downloaded to {}", torrent.name(), output.display());
+        }
+
+        let mut storage = FsStorage::new(output, torrent.clone())?;
+        // The data of skipped files is in the partfile.
+        storage.skip_missing_files();
+
+        // A piece that can't be read, e.g. because its file is missing, is just as incomplete.
+        let failed: Vec<u64> = (0..torrent.num_pieces())
+            .filter(|piece_index| !storage.verify_piece(*piece_index).unwrap_or(false))
+            .collect();
+
+        let verified = Verified { pieces: torrent.num_pieces(), verified: torrent.num_pieces() - failed.len() as u64, failed };
+        self.print(&verified, &format!("{} of {} pieces are complete", verified.verified, verified.pieces));
+
+        if !verified.failed.is_empty() {
+            bail!("{} pieces are missing or corrupt", verified.failed.len());
+        }
+
+        Ok(())
+    }
+
+    fn scrape(&self, torrent: &Path) -> anyhow::Result<()> {
+        let torrent = Torrent::from_path(torrent)?;
+        let scrape_resp = scrape_tracker(&torrent, self.settings()?.tracker_timeout)?;
+
+        let scraped = Scraped {
+            tracker: torrent.announce.clone().unwrap_or_default(),
+            seeders: scrape_resp.seeders,
+            completed: scrape_resp.completed,
+            leechers: scrape_resp.leechers,
+        };
+        let text = format!("{}: {} seeders, {} leechers, downloaded {} times", scraped.tracker, scraped.seeders, scraped.leechers, scraped.completed);
+        self.print(&scraped, &text);
+
+        Ok(())
+    }
+
+    async fn daemon(&self, args: &DaemonArgs) -> anyhow::Result<()> {
+        let mut settings = self.settings()?;
+        if let Some(output) = &args.output {
+            settings.download_dir = output.clone();
+        }
+        if let Some(port) = args.port {
+            settings.listen_port = port;
+        }
+
+        let mut session = Session::new(gen_peer_id(), settings)?;
+        session.listen()?;
+        let session = Arc::new(session);
+
+        let mut server = ApiServer::bind(&args.api, session.clone(), &args.token)
+            .await
+            .with_context(|| format!("Unable to serve the API at {}", args.api))?;
+        if args.transmission_rpc {
+            server = server.with_transmission_rpc();
+        }
+        let url = format!("http://{}/api", server.local_addr()?);
+        if !self.quiet {
+            self.print(&serde_json::json!({ "api": url }), &format!("Serving the API at {}", url));
+        }
+
+        tokio::select! {
+            result = server.run() => result?,
+            _ = tokio::signal::ctrl_c() => {}
+        }
+
+        // Flush what was downloaded before going down.
+        for status in session.torrents() {
+            if let Some(handle) = info_hash_from_hex(&status.info_hash).and_then(|info_hash| session.torrent(&info_hash)) {
+                handle.pause().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Lay out the summary of a torrent as label and value lines.
+fn format_summary(summary: &TorrentSummary) -> String {
+    let mut lines = vec![
+        format!("name:\t\t{}", summary.name),
+        format!("info hash:\t{}", summary.info_hash),
+        format!("size:\t\t{} bytes", summary.size),
+        format!("piece length:\t{}", summary.piece_length),
+        format!("pieces:\t\t{}", summary.pieces),
+        format!("private:\t{}", summary.private),
+    ];
+
+    let optional = [
+        ("announce:\t", summary.announce.clone()),
+        ("comment:\t", summary.comment.clone()),
+        ("created by:\t", summary.created_by.clone()),
+        ("creation date:\t", summary.creation_date.map(|date| date.to_string())),
+    ];
+    lines.extend(optional.iter().filter_map(|(label, value)| value.as_ref().map(|value| format!("{}{}", label, value))));
+
+    lines.extend(summary.announce_list.iter().flatten().map(|tracker| format!("announce list:\t{}", tracker)));
+    lines.extend(summary.web_seeds.iter().map(|url| format!("url-list:\t{}", url)));
+    lines.extend(summary.http_seeds.iter().map(|url| format!("httpseeds:\t{}", url)));
+    lines.extend(summary.files.iter().map(|file| format!("file:\t\t{} ({} bytes)", file.path, file.length)));
+
+    lines.join("\n")
+}
+
+#[test]
+fn test_parse_download_args() {
+    let cli = Cli::try_parse_from(vec![
+        "torrenter", "download", "test-tor.torrent", "other.torrent", "-o", "downloads", "--max-peers", "5",
+        "--download-limit", "1M", "--priorities", "high,skip", "--peer", "127.0.0.1:6881", "--json",
+    ]).unwrap();
+
+    assert!(cli.json);
+    match cli.command {
+        Command::Download(args) => {
+            assert_eq!(args.torrents, vec!["test-tor.torrent", "other.torrent"]);
+            assert_eq!(args.output, Some(PathBuf::from("downloads")));
+            assert_eq!(args.port, None);
+            assert_eq!(args.max_peers, Some(5));
+            assert_eq!(args.download_limit, Some(Rate(1024 * 1024)));
+            assert_eq!(args.priorities, vec![Priority::High, Priority::Skip]);
+            assert_eq!(args.pick_mode, PickMode::RarestFirst);
+            assert_eq!(args.peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+            assert!(!args.tui);
+            assert_eq!(args.alt_speed, AltSpeed::Auto);
+        }
+        command => panic!("Expected the download command, got {:?}", command),
+    }
+
+    assert!(Cli::try_parse_from(vec!["torrenter", "download", "a.torrent", "--pick-mode", "random"]).is_err());
+    assert!(Cli::try_parse_from(vec!["torrenter", "download"]).is_err());
+}
+
+#[test]
+fn test_download_settings() {
+    let cli = Cli::try_parse_from(vec![
+        "torrenter", "download", "a.torrent", "--port", "7000", "--pipeline-depth", "8", "--alt-download-limit", "100K", "--alt-speed", "on", "--max-active", "3",
+    ]).unwrap();
+    let mut settings = Settings::from_toml("listen_port = 6000\nmax_peers = 10\ndownload_limit = \"1M\"").unwrap();
+
+    match cli.command {
+        Command::Download(args) => {
+            assert_eq!(args.alt_speed, AltSpeed::On);
+            args.apply(&mut settings);
+        }
+        command => panic!("Expected the download command, got {:?}", command),
+    }
+
+    // What's on the command line wins, the rest comes from the settings file.
+    assert_eq!(settings.listen_port, 7000);
+    assert_eq!(settings.pipeline_depth, 8);
+    assert_eq!(settings.max_peers, 10);
+    assert_eq!(settings.download_limit, Some(1024 * 1024));
+    assert_eq!(settings.download_dir, PathBuf::from("."));
+    assert_eq!(settings.alt_download_limit, Some(100 * 1024));
+    assert_eq!(settings.max_active_downloads, Some(3));
+}
+
+#[test]
+fn test_parse_daemon_args() {
+    let cli = Cli::try_parse_from(vec!["torrenter", "daemon", "--token", "secret", "-o", "downloads"]).unwrap();
+
+    match cli.command {
+        Command::Daemon(args) => {
+            assert_eq!(args.api, "127.0.0.1:6683");
+            assert_eq!(args.token, "secret");
+            assert_eq!(args.output, Some(PathBuf::from("downloads")));
+            assert!(!args.transmission_rpc);
+        }
+        command => panic!("Expected the daemon command, got {:?}", command),
+    }
+
+    let cli = Cli::try_parse_from(vec!["torrenter", "daemon", "--token", "secret", "--transmission-rpc"]).unwrap();
+    assert!(matches!(cli.command, Command::Daemon(DaemonArgs { transmission_rpc: true, .. })));
+}
+
+#[test]
+fn test_format_summary() {
+    let summary = Torrent::from_path("test-tor.torrent").unwrap().summary();
+    let text = format_summary(&summary);
+
+    assert!(text.starts_with(&format!("name:\t\t{}\n", summary.name)));
+    assert!(text.contains("info hash:\t06cb061240b24f730fbef7ead1b348d8865244af"));
+    assert_eq!(text.lines().filter(|line| line.starts_with("file:")).count(), 2);
+}
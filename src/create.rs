@@ -0,0 +1,251 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use serde_bytes::ByteBuf;
+use serde_derive::Serialize;
+
+/// The smallest and largest piece length picked when none is given.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// The number of pieces aimed for when picking the piece length.
+const TARGET_PIECES: u64 = 1500;
+
+/// Options for creating a torrent file.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Tracker urls, the first one becomes the announce url.
+    pub trackers: Vec<String>,
+    /// Picked from the size of the data when it isn't set.
+    pub piece_length: Option<u64>,
+    pub comment: Option<String>,
+    pub private: bool,
+    /// BEP 19 web seeds hosting the files.
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: String,
+    #[serde(rename = "creation date")]
+    creation_date: i64,
+    info: MetaInfoDict,
+    #[serde(rename = "url-list", skip_serializing_if = "Vec::is_empty")]
+    url_list: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetaInfoDict {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<MetaInfoFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    pieces: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetaInfoFile {
+    length: u64,
+    path: Vec<String>,
+}
+
+/// Create the bencoded metainfo of a torrent holding a file, or every file within a folder.
+///
+/// The files of a folder are added in the order of their paths, so creating a torrent
+/// from the same data twice gives the same info hash.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> anyhow::Result<Vec<u8>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Unable to name the torrent after {}", path.display()))?
+        .to_string();
+
+    let metadata = fs::metadata(path)?;
+    let files = if metadata.is_dir() { list_files(path)? } else { vec![(path.to_path_buf(), Vec::new(), metadata.len())] };
+
+    if files.is_empty() {
+        bail!("There are no files in {}", path.display());
+    }
+
+    let size: u64 = files.iter().map(|(_, _, length)| length).sum();
+    let piece_length = options.piece_length.unwrap_or_else(|| pick_piece_length(size));
+
+    if piece_length == 0 {
+        bail!("The piece length must be greater than zero");
+    }
+
+    let pieces = hash_pieces(files.iter().map(|(file_path, _, _)| file_path.as_path()), piece_length)?;
+
+    let (files, length) = if metadata.is_dir() {
+        let files = files.into_iter().map(|(_, path, length)| MetaInfoFile { length, path }).collect();
+        (Some(files), None)
+    } else {
+        (None, Some(size))
+    };
+
+    let creation_date = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default();
+
+    let metainfo = MetaInfo {
+        announce: options.trackers.first().cloned(),
+        announce_list: if options.trackers.len() > 1 { Some(options.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()) } else { None },
+        comment: options.comment.clone(),
+        created_by: format!("torrenter {}", env!("CARGO_PKG_VERSION")),
+        creation_date,
+        info: MetaInfoDict {
+            files,
+            length,
+            name,
+            piece_length,
+            pieces: ByteBuf::from(pieces),
+            private: if options.private { Some(1) } else { None },
+        },
+        url_list: options.web_seeds.clone(),
+    };
+
+    Ok(serde_bencode::to_bytes(&metainfo)?)
+}
+
+/// Pick a power of two piece length that gives around TARGET_PIECES pieces.
+fn pick_piece_length(size: u64) -> u64 {
+    (size / TARGET_PIECES).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Every file within a folder with its path components relative to the folder and its length, sorted by path.
+fn list_files(folder: &Path) -> io::Result<Vec<(PathBuf, Vec<String>, u64)>> {
+    let mut files = Vec::new();
+    let mut folders = vec![(folder.to_path_buf(), Vec::new())];
+
+    while let Some((folder, components)) = folders.pop() {
+        for entry in fs::read_dir(&folder)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let file_name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("File name isn't valid UTF-8: {:?}", name))
+            })?;
+
+            let mut path = components.clone();
+            path.push(file_name);
+
+            if file_type.is_dir() {
+                folders.push((entry.path(), path));
+            } else if file_type.is_file() {
+                files.push((entry.path(), path, entry.metadata()?.len()));
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+
+    Ok(files)
+}
+
+/// Hash the files as one contiguous stream of data, split into pieces.
+fn hash_pieces<'a, I: Iterator<Item = &'a Path>>(files: I, piece_length: u64) -> io::Result<Vec<u8>> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length as usize);
+
+    let hash = |piece: &[u8], pieces: &mut Vec<u8>| {
+        let mut hasher = Sha1::new();
+        let mut hash = [0; 20];
+
+        hasher.input(piece);
+        hasher.result(&mut hash);
+        pieces.extend_from_slice(&hash);
+    };
+
+    for file_path in files {
+        let mut file = File::open(file_path)?;
+
+        loop {
+            let wanted = piece_length - piece.len() as u64;
+            let read = (&mut file).take(wanted).read_to_end(&mut piece)?;
+
+            if piece.len() as u64 == piece_length {
+                hash(&piece, &mut pieces);
+                piece.clear();
+            }
+            if read == 0 {
+                break;
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        hash(&piece, &mut pieces);
+    }
+
+    Ok(pieces)
+}
+
+#[test]
+fn test_create_torrent() {
+    use crate::storage::verify_piece_data;
+    use crate::utils::torrents::Torrent;
+
+    let folder = Path::new("test-files/test10/spam");
+    let _ = fs::remove_dir_all(folder);
+    fs::create_dir_all(folder.join("sub")).unwrap();
+    fs::write(folder.join("b.txt"), vec![2; 20]).unwrap();
+    fs::write(folder.join("sub").join("a.txt"), vec![1; 30]).unwrap();
+    fs::write(folder.join("a.txt"), vec![3; 10]).unwrap();
+
+    let options = CreateOptions {
+        trackers: vec!["udp://a.b:1337".to_string(), "udp://c.d:1337".to_string()],
+        piece_length: Some(16),
+        private: true,
+        ..Default::default()
+    };
+    let torrent = Torrent::from_bytes(&create_torrent(folder, &options).unwrap()).unwrap();
+    let summary = torrent.summary();
+
+    assert_eq!(summary.name, "spam");
+    assert_eq!(summary.size, 60);
+    assert_eq!(summary.pieces, 4);
+    assert!(summary.private);
+    assert_eq!(summary.announce.as_deref(), Some("udp://a.b:1337"));
+    assert_eq!(summary.announce_list.len(), 2);
+
+    let paths: Vec<&str> = summary.files.iter().map(|file| file.path.as_str()).collect();
+    assert_eq!(paths, vec!["spam/a.txt", "spam/b.txt", "spam/sub/a.txt"]);
+
+    // The pieces run across the files in the same order.
+    let data: Vec<u8> = [vec![3; 10], vec![2; 20], vec![1; 30]].concat();
+    for (piece_index, piece) in data.chunks(16).enumerate() {
+        assert!(verify_piece_data(&torrent, piece_index as u64, piece));
+    }
+
+    // A single file is named after the file.
+    let torrent = Torrent::from_bytes(&create_torrent(&folder.join("b.txt"), &CreateOptions::default()).unwrap()).unwrap();
+    assert_eq!(torrent.summary().files[0].path, "b.txt");
+    assert_eq!(torrent.num_pieces(), 1);
+
+    assert!(create_torrent(Path::new("test-files/test10/missing"), &CreateOptions::default()).is_err());
+
+    let _ = fs::remove_dir_all("test-files/test10");
+}
+
+#[test]
+fn test_pick_piece_length() {
+    assert_eq!(pick_piece_length(0), MIN_PIECE_LENGTH);
+    assert_eq!(pick_piece_length(700 * 1024 * 1024), 512 * 1024);
+    assert_eq!(pick_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+}
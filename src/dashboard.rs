@@ -0,0 +1,374 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use torrenter::{Event, SessionEvent, TorrentState, TorrentSummary};
+
+/// How far back the download rate is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The details shown for the selected torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DetailView {
+    #[default]
+    Files,
+    Pieces,
+    Peers,
+    Trackers,
+}
+
+impl DetailView {
+    pub const ALL: [DetailView; 4] = [DetailView::Files, DetailView::Pieces, DetailView::Peers, DetailView::Trackers];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            DetailView::Files => "Files",
+            DetailView::Pieces => "Pieces",
+            DetailView::Peers => "Peers",
+            DetailView::Trackers => "Trackers",
+        }
+    }
+
+    fn next(self) -> DetailView {
+        let position = DetailView::ALL.iter().position(|view| *view == self).unwrap_or(0);
+        DetailView::ALL[(position + 1) % DetailView::ALL.len()]
+    }
+}
+
+/// A tracker or a web seed of a torrent, and how the last exchange with it went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub url: String,
+    pub kind: &'static str,
+    pub status: String,
+}
+
+/// What the dashboard knows about a torrent, built up from the session events.
+#[derive(Debug, Clone)]
+pub struct TorrentView {
+    pub info_hash: String,
+    pub summary: TorrentSummary,
+    pub state: TorrentState,
+    pub verified: Vec<bool>,
+    pub peers: BTreeSet<SocketAddr>,
+    pub sources: Vec<Source>,
+    pub error: Option<String>,
+    /// When blocks were received and how long they were, for the download rate.
+    received: VecDeque<(Instant, u64)>,
+}
+
+impl TorrentView {
+    fn new(info_hash: String, summary: TorrentSummary) -> TorrentView {
+        let mut sources: Vec<Source> = summary
+            .announce
+            .iter()
+            .chain(summary.announce_list.iter().flatten())
+            .map(|url| Source { url: url.clone(), kind: "tracker", status: String::new() })
+            .collect();
+        sources.dedup_by(|a, b| a.url == b.url);
+
+        TorrentView {
+            info_hash,
+            verified: vec![false; summary.pieces as usize],
+            summary,
+            state: TorrentState::Paused,
+            peers: BTreeSet::new(),
+            sources,
+            error: None,
+            received: VecDeque::new(),
+        }
+    }
+
+    fn piece_len(&self, piece_index: u64) -> u64 {
+        let offset = piece_index * self.summary.piece_length;
+        self.summary.piece_length.min(self.summary.size.saturating_sub(offset))
+    }
+
+    /// The number of bytes that passed the hash check.
+    pub fn verified_bytes(&self) -> u64 {
+        (0..self.summary.pieces).filter(|i| self.verified[*i as usize]).map(|i| self.piece_len(i)).sum()
+    }
+
+    pub fn progress(&self) -> f64 {
+        if self.summary.size == 0 {
+            return 1.0;
+        }
+
+        self.verified_bytes() as f64 / self.summary.size as f64
+    }
+
+    /// Bytes per second received over the last few seconds.
+    pub fn download_rate(&self, now: Instant) -> f64 {
+        let bytes: u64 = self
+            .received
+            .iter()
+            .filter(|(at, _)| now.saturating_duration_since(*at) < RATE_WINDOW)
+            .map(|(_, length)| length)
+            .sum();
+
+        bytes as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    /// How long until the download is done at the current rate.
+    pub fn eta(&self, now: Instant) -> Option<Duration> {
+        let rate = self.download_rate(now);
+        let remaining = self.summary.size - self.verified_bytes();
+
+        if remaining == 0 {
+            return Some(Duration::from_secs(0));
+        }
+        if rate < 1.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+
+    /// How much of each file passed the hash check, between 0 and 1.
+    pub fn file_progress(&self) -> Vec<f64> {
+        let piece_length = self.summary.piece_length;
+        let mut offset = 0;
+
+        self.summary
+            .files
+            .iter()
+            .map(|file| {
+                let (start, end) = (offset, offset + file.length);
+                offset = end;
+
+                if file.length == 0 {
+                    return 1.0;
+                }
+
+                let verified: u64 = (start / piece_length..end.div_ceil(piece_length))
+                    .filter(|i| self.verified.get(*i as usize) == Some(&true))
+                    .map(|i| {
+                        let piece_start = i * piece_length;
+                        let piece_end = piece_start + self.piece_len(i);
+                        piece_end.min(end) - piece_start.max(start)
+                    })
+                    .sum();
+
+                verified as f64 / file.length as f64
+            })
+            .collect()
+    }
+
+    fn received(&mut self, length: u64, now: Instant) {
+        self.received.push_back((now, length));
+
+        while let Some((at, _)) = self.received.front() {
+            if now.saturating_duration_since(*at) < RATE_WINDOW {
+                break;
+            }
+            self.received.pop_front();
+        }
+    }
+
+    fn source_status(&mut self, url: &str, kind: &'static str, status: String) {
+        match self.sources.iter_mut().find(|source| source.url == url) {
+            Some(source) => source.status = status,
+            None => self.sources.push(Source { url: url.to_string(), kind, status }),
+        }
+    }
+}
+
+/// The state behind the terminal UI, only ever changed by session events and key presses.
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    pub torrents: Vec<TorrentView>,
+    pub selected: usize,
+    pub view: DetailView,
+    /// Events that were dropped because the dashboard fell behind.
+    pub missed_events: u64,
+}
+
+impl Dashboard {
+    pub fn apply(&mut self, event: SessionEvent, now: Instant) {
+        if let Event::TorrentAdded { summary } = event.event {
+            self.torrents.push(TorrentView::new(event.info_hash, *summary));
+            return;
+        }
+
+        let position = match self.torrents.iter().position(|torrent| torrent.info_hash == event.info_hash) {
+            Some(position) => position,
+            None => return,
+        };
+
+        let torrent = &mut self.torrents[position];
+
+        match event.event {
+            Event::TorrentAdded { .. } => {}
+            Event::TorrentRemoved => {
+                self.torrents.remove(position);
+                self.selected = self.selected.min(self.torrents.len().saturating_sub(1));
+            }
+            Event::StateChanged { state } => {
+                torrent.state = state;
+                if !matches!(state, TorrentState::Downloading | TorrentState::Seeding) {
+                    torrent.peers.clear();
+                }
+            }
+            Event::BlockReceived { length } => torrent.received(length, now),
+            Event::PieceVerified { piece } => {
+                if let Some(verified) = torrent.verified.get_mut(piece as usize) {
+                    *verified = true;
+                }
+            }
+            Event::PieceFailed { .. } => {}
+            Event::PeerConnected { address } => {
+                torrent.peers.insert(address);
+            }
+            Event::PeerDisconnected { address, .. } => {
+                torrent.peers.remove(&address);
+            }
+            Event::TrackerReplied { url, peers } => torrent.source_status(&url, "tracker", format!("{} peers", peers)),
+            Event::TrackerFailed { url, error } => torrent.source_status(&url, "tracker", error),
+            Event::WebSeedFailed { url, error, retry_in_secs } => {
+                torrent.source_status(&url, "web seed", format!("{}, retrying in {}s", error, retry_in_secs))
+            }
+            Event::Error { message } => torrent.error = Some(message),
+        }
+    }
+
+    pub fn selected(&self) -> Option<&TorrentView> {
+        self.torrents.get(self.selected)
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.torrents.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn next_view(&mut self) {
+        self.view = self.view.next();
+    }
+}
+
+/// Squeeze the verified pieces into a number of cells, each holding the fraction of its pieces that are verified.
+pub fn piece_map(verified: &[bool], cells: usize) -> Vec<f64> {
+    if verified.is_empty() || cells == 0 {
+        return Vec::new();
+    }
+
+    let cells = cells.min(verified.len());
+
+    (0..cells)
+        .map(|cell| {
+            let start = cell * verified.len() / cells;
+            let end = ((cell + 1) * verified.len() / cells).max(start + 1);
+            let pieces = &verified[start..end];
+
+            pieces.iter().filter(|verified| **verified).count() as f64 / pieces.len() as f64
+        })
+        .collect()
+}
+
+/// A size in bytes, e.g. 1.5 MiB.
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", value as u64, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// The time left on a download, e.g. 1h 05m or 42s.
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let seconds = match eta {
+        Some(eta) => eta.as_secs(),
+        None => return "∞".to_string(),
+    };
+
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[test]
+fn test_dashboard_apply_events() {
+    use torrenter::Torrent;
+
+    // Two files of 256842 and 222660 bytes in 15 pieces of 32768 bytes.
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let info_hash = torrent.info_hash_hex();
+    let event = |event: Event| SessionEvent { info_hash: info_hash.clone(), event };
+
+    let now = Instant::now();
+    let mut dashboard = Dashboard::default();
+    let address: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+    dashboard.apply(event(Event::TorrentAdded { summary: Box::new(torrent.summary()) }), now);
+    dashboard.apply(event(Event::StateChanged { state: TorrentState::Downloading }), now);
+    dashboard.apply(event(Event::PeerConnected { address }), now);
+    dashboard.apply(event(Event::TrackerReplied { url: "udp://tracker.opentrackr.org:1337".to_string(), peers: 7 }), now);
+
+    for piece in 0..8 {
+        dashboard.apply(event(Event::BlockReceived { length: 32768 }), now);
+        dashboard.apply(event(Event::PieceVerified { piece }), now);
+    }
+
+    // Events for torrents we don't know about are ignored.
+    dashboard.apply(SessionEvent { info_hash: "00".to_string(), event: Event::PieceVerified { piece: 9 } }, now);
+
+    let view = dashboard.selected().unwrap();
+    assert_eq!(view.state, TorrentState::Downloading);
+    assert_eq!(view.peers.len(), 1);
+    assert_eq!(view.sources, vec![Source { url: "udp://tracker.opentrackr.org:1337".to_string(), kind: "tracker", status: "7 peers".to_string() }]);
+    assert_eq!(view.verified_bytes(), 8 * 32768);
+
+    // The first file ends within piece 7, so it's complete and the second file has the rest of piece 7.
+    let files = view.file_progress();
+    assert_eq!(files[0], 1.0);
+    assert_eq!(files[1], (8 * 32768 - 256842) as f64 / 222660.0);
+
+    // 256 KiB over the rate window.
+    assert_eq!(view.download_rate(now), 8.0 * 32768.0 / RATE_WINDOW.as_secs_f64());
+    assert_eq!(view.download_rate(now + RATE_WINDOW), 0.0);
+    assert!(view.eta(now).is_some());
+    assert_eq!(view.eta(now + RATE_WINDOW), None);
+
+    dashboard.apply(event(Event::StateChanged { state: TorrentState::Paused }), now);
+    assert!(dashboard.selected().unwrap().peers.is_empty());
+
+    dashboard.apply(event(Event::TorrentRemoved), now);
+    assert!(dashboard.selected().is_none());
+}
+
+#[test]
+fn test_piece_map() {
+    let verified = [true, true, false, false, true, false];
+
+    assert_eq!(piece_map(&verified, 3), vec![1.0, 0.0, 0.5]);
+    assert_eq!(piece_map(&verified, 100), vec![1.0, 1.0, 0.0, 0.0, 1.0, 0.0]);
+    assert_eq!(piece_map(&verified, 4), vec![1.0, 0.5, 0.0, 0.5]);
+    assert!(piece_map(&[], 10).is_empty());
+}
+
+#[test]
+fn test_format() {
+    assert_eq!(format_bytes(512.0), "512 B");
+    assert_eq!(format_bytes(1536.0), "1.5 KiB");
+    assert_eq!(format_bytes(479502.0), "468.3 KiB");
+
+    assert_eq!(format_eta(Some(Duration::from_secs(42))), "42s");
+    assert_eq!(format_eta(Some(Duration::from_secs(65))), "1m 05s");
+    assert_eq!(format_eta(Some(Duration::from_secs(3900))), "1h 05m");
+    assert_eq!(format_eta(None), "∞");
+}
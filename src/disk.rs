@@ -0,0 +1,539 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::{debug, error, warn};
+
+use crate::download::PiecesManager;
+use crate::error::{Error, Result};
+use crate::events::{Event, TorrentEvents};
+use crate::message_handlers::PieceChannelPayload;
+use crate::pieces::Priority;
+use crate::queue::PieceBlock;
+use crate::reader::TorrentReader;
+use crate::storage::{FailedWrite, ResumeData, Storage};
+use crate::utils::torrents::Torrent;
+
+/// The number of jobs each disk worker will queue up before senders have to wait.
+///
+/// Once the queue is full, whoever is sending blocks (ultimately the peers) has to wait
+/// for the disk to catch up instead of piling more data into memory.
+const DISK_QUEUE_LEN: usize = 64;
+
+pub(crate) type SharedStorage = Arc<Mutex<Box<dyn Storage>>>;
+
+enum DiskJob {
+    Write {
+        storage: SharedStorage,
+        pieces: PiecesManager,
+        verified: Arc<Condvar>,
+        verified_notify: Arc<Notify>,
+        events: TorrentEvents,
+        payload: PieceChannelPayload,
+    },
+    Flush {
+        storage: SharedStorage,
+        pieces: PiecesManager,
+        events: TorrentEvents,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+    Delete {
+        storage: SharedStorage,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+    SaveResume {
+        storage: SharedStorage,
+        resume: ResumeData,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+    SetFilePriorities {
+        torrent: Arc<Torrent>,
+        storage: SharedStorage,
+        pieces: PiecesManager,
+        verified: Arc<Condvar>,
+        verified_notify: Arc<Notify>,
+        events: TorrentEvents,
+        priorities: Vec<Priority>,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// A pool of dedicated threads doing all of the blocking disk work.
+///
+/// Every torrent is pinned to a single worker so that its writes are done in the order they
+/// were received, and a piece is only verified once all of its blocks have been written.
+pub struct DiskPool {
+    workers: Vec<mpsc::Sender<DiskJob>>,
+    next_worker: AtomicUsize,
+}
+
+impl DiskPool {
+    pub fn new(num_workers: usize) -> DiskPool {
+        let mut workers = Vec::new();
+
+        for i in 0..num_workers.max(1) {
+            let (tx, mut rx) = mpsc::channel::<DiskJob>(DISK_QUEUE_LEN);
+
+            thread::Builder::new()
+                .name(format!("disk-worker-{}", i))
+                .spawn(move || {
+                    while let Some(job) = rx.blocking_recv() {
+                        run_job(job);
+                    }
+                })
+                .expect("Unable to start disk worker");
+
+            workers.push(tx);
+        }
+
+        DiskPool {
+            workers,
+            next_worker: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hand over the storage of a torrent to the pool.
+    pub fn add_torrent(&self, torrent: Arc<Torrent>, storage: Box<dyn Storage>, pieces: PiecesManager, events: TorrentEvents) -> TorrentDisk {
+        let worker = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+
+        TorrentDisk {
+            sender: self.workers[worker].clone(),
+            torrent,
+            storage: Arc::new(Mutex::new(storage)),
+            pieces,
+            verified: Arc::new(Condvar::new()),
+            verified_notify: Arc::new(Notify::new()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            events,
+        }
+    }
+}
+
+/// The handle a torrent uses to queue up disk work on its worker.
+#[derive(Clone)]
+pub struct TorrentDisk {
+    sender: mpsc::Sender<DiskJob>,
+    torrent: Arc<Torrent>,
+    storage: SharedStorage,
+    pieces: PiecesManager,
+    /// Notified every time pieces pass the hash check, paired with the pieces mutex.
+    verified: Arc<Condvar>,
+    /// Notified along with verified, for the task of the torrent.
+    verified_notify: Arc<Notify>,
+    /// Makes the readers fail instead of waiting while the torrent is stopped.
+    cancelled: Arc<AtomicBool>,
+    events: TorrentEvents,
+}
+
+impl TorrentDisk {
+    /// Queue a received block to be written, waiting if the worker is backed up.
+    ///
+    /// The block counts as received once it's written, and its piece is checked once all of
+    /// its blocks are.
+    pub async fn write(&self, payload: PieceChannelPayload) -> Result<()> {
+        let job = DiskJob::Write {
+            storage: self.storage.clone(),
+            pieces: self.pieces.clone(),
+            verified: self.verified.clone(),
+            verified_notify: self.verified_notify.clone(),
+            events: self.events.clone(),
+            payload,
+        };
+
+        self.sender.send(job).await.map_err(|_| Error::disk_stopped())?;
+
+        Ok(())
+    }
+
+    /// Wait for every queued write to be done and flushed to disk.
+    pub async fn flush(&self) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let job = DiskJob::Flush {
+            storage: self.storage.clone(),
+            pieces: self.pieces.clone(),
+            events: self.events.clone(),
+            done,
+        };
+
+        self.sender.send(job).await.map_err(|_| Error::disk_stopped())?;
+
+        result.await.map_err(|_| Error::disk_stopped())?.map_err(Error::Storage)
+    }
+
+    /// Wait for every queued write, then remove all of the downloaded data.
+    pub async fn delete(&self) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let job = DiskJob::Delete {
+            storage: self.storage.clone(),
+            done,
+        };
+
+        self.sender.send(job).await.map_err(|_| Error::disk_stopped())?;
+
+        result.await.map_err(|_| Error::disk_stopped())?.map_err(Error::Storage)
+    }
+
+    /// Wait for every queued write, then save the totals of the torrent with its data.
+    pub async fn save_resume(&self, resume: ResumeData) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let job = DiskJob::SaveResume {
+            storage: self.storage.clone(),
+            resume,
+            done,
+        };
+
+        self.sender.send(job).await.map_err(|_| Error::disk_stopped())?;
+
+        result.await.map_err(|_| Error::disk_stopped())?.map_err(Error::Storage)
+    }
+
+    /// Change which files are downloaded and in what order.
+    ///
+    /// Data that was already downloaded is moved in or out of the partfile as needed.
+    pub async fn set_file_priorities(&self, priorities: Vec<Priority>) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let job = DiskJob::SetFilePriorities {
+            torrent: self.torrent.clone(),
+            storage: self.storage.clone(),
+            pieces: self.pieces.clone(),
+            verified: self.verified.clone(),
+            verified_notify: self.verified_notify.clone(),
+            events: self.events.clone(),
+            priorities,
+            done,
+        };
+
+        self.sender.send(job).await.map_err(|_| Error::disk_stopped())?;
+
+        result.await.map_err(|_| Error::disk_stopped())?.map_err(Error::Storage)
+    }
+
+    /// Wait until pieces pass the hash check, including ones that did since the last wait.
+    pub async fn wait_verified(&self) {
+        self.verified_notify.notified().await
+    }
+
+    /// The path of a file inside the torrent.
+    pub fn file_path(&self, file_index: usize) -> Option<String> {
+        let file = self.torrent.layout.files().get(file_index)?;
+        Some(file.path.to_string_lossy().into_owned())
+    }
+
+    /// Open a reader over one of the files of the torrent, reads wait for the data to be verified.
+    pub fn reader(&self, file_index: usize) -> Option<TorrentReader> {
+        let file = self.torrent.layout.files().get(file_index)?;
+
+        Some(TorrentReader::new(
+            self.torrent.clone(),
+            self.storage.clone(),
+            self.pieces.clone(),
+            self.verified.clone(),
+            self.cancelled.clone(),
+            file.offset,
+            file.length,
+        ))
+    }
+
+    /// Read a block of a piece that passed the hash check, for a peer that asked for it.
+    pub async fn read_block(&self, piece_index: u64, begin: u64, length: u64) -> Result<Vec<u8>> {
+        let storage = self.storage.clone();
+
+        tokio::task::spawn_blocking(move || storage.lock().unwrap().read_block(piece_index, begin, length))
+            .await
+            .map_err(|_| Error::disk_stopped())?
+            .map_err(Error::Storage)
+    }
+
+    /// Wake up every reader waiting on a piece and make it fail, along with the reads that
+    /// come after, until resume_reads is called. For when the torrent stops downloading.
+    pub fn cancel_reads(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+
+        // Holding the lock means no reader is between checking the flag and waiting.
+        let _pieces = self.pieces.lock().unwrap();
+        self.verified.notify_all();
+    }
+
+    /// Let reads wait for their pieces again once the torrent is downloading.
+    pub fn resume_reads(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+
+fn run_job(job: DiskJob) {
+    match job {
+        DiskJob::Write { storage, pieces, verified, verified_notify, events, payload } => {
+            // Another peer sent the same block, and the piece was checked since.
+            if pieces.lock().unwrap().is_verified(payload.index) {
+                return;
+            }
+
+            let mut storage = storage.lock().unwrap();
+
+            let lost = match storage.write_block(payload.index, payload.begin, &payload.block) {
+                Ok(_) => vec![],
+                Err(e) => forget_lost_pieces(&pieces, &events, &e, Some(payload.index)),
+            };
+
+            // The write may have failed for blocks of other pieces only.
+            if !lost.contains(&payload.index) {
+                pieces.lock().unwrap().add_received(PieceBlock { index: payload.index, begin: payload.begin, length: None });
+                verify_if_received(&mut **storage, &pieces, &verified, &verified_notify, &events, payload.index);
+            }
+        }
+        DiskJob::Flush { storage, pieces, events, done } => {
+            let result = storage.lock().unwrap().flush();
+
+            if let Err(e) = &result {
+                forget_lost_pieces(&pieces, &events, e, None);
+            }
+            let _ = done.send(result);
+        }
+        DiskJob::Delete { storage, done } => {
+            let _ = done.send(storage.lock().unwrap().delete());
+        }
+        DiskJob::SaveResume { storage, resume, done } => {
+            let _ = done.send(storage.lock().unwrap().save_resume(&resume));
+        }
+        DiskJob::SetFilePriorities { torrent, storage, pieces, verified, verified_notify, events, priorities, done } => {
+            let mut storage = storage.lock().unwrap();
+            let _ = done.send(apply_file_priorities(&torrent, &mut **storage, &pieces, &events, &priorities));
+            verified.notify_all();
+            verified_notify.notify_one();
+        }
+    }
+}
+
+
+/// Switch the storage and the pieces over to new file priorities.
+///
+/// - Verified pieces that straddle a file changing between skipped and wanted are read back
+///   and written again, so their data ends up in the partfile or the real file as it should.
+/// - Verified pieces that are now entirely skipped are forgotten, their data stays where it is.
+/// - Pieces that are wanted again are checked in case their data is still on disk.
+fn apply_file_priorities(torrent: &Torrent, storage: &mut dyn Storage, pieces: &PiecesManager, events: &TorrentEvents, priorities: &[Priority]) -> io::Result<()> {
+    let is_skipped = |file_priorities: &[Priority], i: usize| file_priorities.get(i) == Some(&Priority::Skip);
+    let old_priorities = pieces.lock().unwrap().file_priorities().to_vec();
+
+    let mut moved_pieces = Vec::new();
+    let mut forgotten_pieces = Vec::new();
+    let mut wanted_pieces = Vec::new();
+
+    for piece_index in 0..torrent.num_pieces() {
+        let slices = torrent.layout.slices(torrent.piece_offset(piece_index), torrent.get_piece_len(piece_index));
+        let changed = slices.iter().any(|s| is_skipped(&old_priorities, s.file_index) != is_skipped(priorities, s.file_index));
+
+        if !changed {
+            continue;
+        }
+
+        let all_skipped = slices.iter().all(|s| is_skipped(priorities, s.file_index));
+        let verified = pieces.lock().unwrap().is_verified(piece_index);
+
+        match (verified, all_skipped) {
+            (true, true) => forgotten_pieces.push(piece_index),
+            (true, false) => {
+                let piece_len = torrent.get_piece_len(piece_index);
+                moved_pieces.push((piece_index, storage.read_block(piece_index, 0, piece_len)?));
+            }
+            (false, false) => wanted_pieces.push(piece_index),
+            (false, true) => {}
+        }
+    }
+
+    storage.set_file_priorities(priorities)?;
+
+    for (piece_index, data) in moved_pieces {
+        storage.write_block(piece_index, 0, &data)?;
+    }
+    storage.flush()?;
+
+    let mut newly_verified = Vec::new();
+    for piece_index in wanted_pieces {
+        if storage.verify_piece(piece_index)? {
+            newly_verified.push(piece_index);
+        }
+    }
+
+    let mut pieces = pieces.lock().unwrap();
+    pieces.set_file_priorities(torrent, priorities);
+
+    for piece_index in forgotten_pieces {
+        pieces.reset_piece(piece_index);
+    }
+    for piece_index in newly_verified {
+        pieces.mark_complete(piece_index);
+        events.send(Event::PieceVerified { piece: piece_index });
+    }
+
+    Ok(())
+}
+
+
+/// Forget about the pieces whose blocks didn't make it to the storage, so that they're
+/// downloaded again.
+///
+/// Those are the pieces of the FailedWrite in the error, or else the piece the failed
+/// call was about. Returns the pieces that were forgotten.
+fn forget_lost_pieces(pieces: &PiecesManager, events: &TorrentEvents, e: &io::Error, piece_index: Option<u64>) -> Vec<u64> {
+    let lost = match FailedWrite::pieces(e) {
+        Some(lost) => lost.to_vec(),
+        None => piece_index.into_iter().collect(),
+    };
+
+    for piece_index in &lost {
+        error!(info_hash = events.info_hash(), piece = piece_index, error = %e, "unable to write block");
+        events.send(Event::Error { message: format!("Unable to write piece {}: {}", piece_index, e) });
+    }
+
+    let mut pieces = pieces.lock().unwrap();
+    for piece_index in &lost {
+        pieces.reset_piece(*piece_index);
+    }
+
+    lost
+}
+
+
+/// Once every block of a piece has been written, check the piece against its hash.
+///
+/// A piece that doesn't match is thrown away so that it gets requested again.
+fn verify_if_received(storage: &mut dyn Storage, pieces: &PiecesManager, verified: &Condvar, verified_notify: &Notify, events: &TorrentEvents, piece_index: u64) {
+    if !pieces.lock().unwrap().piece_received(piece_index) {
+        return;
+    }
+
+    let valid = match storage.verify_piece(piece_index) {
+        Ok(valid) => valid,
+        // Blocks of other pieces failed to be written out before the piece could be read.
+        Err(e) if FailedWrite::pieces(&e).is_some() => {
+            if forget_lost_pieces(pieces, events, &e, None).contains(&piece_index) {
+                return;
+            }
+
+            storage.verify_piece(piece_index).unwrap_or(false)
+        }
+        Err(e) => {
+            error!(info_hash = events.info_hash(), piece = piece_index, error = %e, "unable to verify piece");
+            events.send(Event::Error { message: format!("Unable to verify piece {}: {}", piece_index, e) });
+            false
+        }
+    };
+
+    let mut pieces = pieces.lock().unwrap();
+
+    if valid {
+        pieces.add_verified(piece_index);
+        verified.notify_all();
+        verified_notify.notify_one();
+        debug!(info_hash = events.info_hash(), piece = piece_index, "piece verified");
+        events.send(Event::PieceVerified { piece: piece_index });
+    } else {
+        warn!(info_hash = events.info_hash(), piece = piece_index, "piece doesn't match its hash");
+        events.send(Event::PieceFailed { piece: piece_index });
+        pieces.reset_piece(piece_index);
+    }
+}
+
+#[tokio::test]
+async fn test_disk_pool_write_and_verify() {
+    use crate::pieces::Pieces;
+    use crate::storage::MemoryStorage;
+    use crate::utils::torrents::BLOCK_LEN;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let pieces: PiecesManager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+
+    let (sender, mut events) = tokio::sync::broadcast::channel(16);
+
+    let pool = DiskPool::new(2);
+    let disk = pool.add_torrent(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())), pieces.clone(), TorrentEvents::new(sender, &torrent));
+
+    // Both blocks of a piece arrive, but the data doesn't match the piece hash.
+    for begin in &[0, BLOCK_LEN] {
+        disk.write(PieceChannelPayload {
+            index: 0,
+            begin: *begin,
+            block: vec![0; torrent.get_block_len(0, begin / BLOCK_LEN) as usize],
+        }).await.unwrap();
+    }
+
+    disk.flush().await.unwrap();
+
+    {
+        let pieces = pieces.lock().unwrap();
+        assert!(!pieces.is_verified(0));
+        assert!(!pieces.piece_received(0));
+        assert_eq!(pieces.resets(), 1);
+    }
+
+    let event = events.recv().await.unwrap();
+    assert_eq!(event.info_hash, torrent.info_hash_hex());
+    assert!(matches!(event.event, Event::PieceFailed { piece: 0 }));
+}
+
+#[tokio::test]
+async fn test_disk_pool_failed_earlier_write() {
+    use std::path::Path;
+    use crate::pieces::Pieces;
+    use crate::storage::MemoryStorage;
+    use crate::utils::torrents::BLOCK_LEN;
+
+    /// Fails every write to piece 1 as if the blocks of piece 0 it was holding on to failed.
+    struct FailingStorage(MemoryStorage);
+
+    impl Storage for FailingStorage {
+        fn read_block(&mut self, piece_index: u64, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.0.read_block(piece_index, begin, length)
+        }
+
+        fn write_block(&mut self, piece_index: u64, begin: u64, block: &[u8]) -> io::Result<()> {
+            self.0.write_block(piece_index, begin, block)?;
+
+            match piece_index {
+                1 => Err(FailedWrite::io_error(vec![0], io::Error::other("disk full"))),
+                _ => Ok(()),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn verify_piece(&mut self, piece_index: u64) -> io::Result<bool> {
+            self.0.verify_piece(piece_index)
+        }
+
+        fn move_to(&mut self, _download_folder: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn delete(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let pieces: PiecesManager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    let (sender, mut events) = tokio::sync::broadcast::channel(16);
+
+    let pool = DiskPool::new(1);
+    let storage = FailingStorage(MemoryStorage::new(torrent.clone()));
+    let disk = pool.add_torrent(torrent.clone(), Box::new(storage), pieces.clone(), TorrentEvents::new(sender, &torrent));
+
+    // Both blocks of piece 0 and the first of piece 1 were written before.
+    for (piece_index, begin) in &[(0, 0), (0, BLOCK_LEN), (1, 0)] {
+        pieces.lock().unwrap().add_received(PieceBlock { index: *piece_index, begin: *begin, length: None });
+    }
+
+    disk.write(PieceChannelPayload { index: 1, begin: BLOCK_LEN, block: vec![0; BLOCK_LEN as usize] }).await.unwrap();
+    disk.flush().await.unwrap();
+
+    // Piece 0 is downloaded again, piece 1 was still checked.
+    assert!(!pieces.lock().unwrap().piece_received(0));
+    assert!(matches!(events.recv().await.unwrap().event, Event::Error { message } if message.starts_with("Unable to write piece 0")));
+    assert!(matches!(events.recv().await.unwrap().event, Event::PieceFailed { piece: 1 }));
+}
@@ -1,298 +1,960 @@
-use std::fs::{File, OpenOptions};
-use std::fs;
-use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::net::{Ipv4Addr, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
 
 use bytebuffer::ByteBuffer;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, warn, Instrument};
 
-use crate::message_handlers::{MessageHandler, PieceChannelPayload};
+use crate::error::{Error, Result};
+use crate::events::{Event, TorrentEvents};
+use crate::message_handlers::{MessageHandler, PieceChannelPayload, read_message, read_peer_handshake};
 use crate::messages::build_peer_handshake;
-use crate::pieces::Pieces;
+use crate::pieces::{Pieces, PickMode, Priority};
 use crate::queue::Queue;
-use crate::utils::Peer;
-use crate::utils::torrents::{DlFile, Torrent};
+use crate::rate_limit::{Bandwidth, is_local, LimiterChain};
+use crate::seeding::SeedGoals;
+use crate::session::TorrentState;
+use crate::settings::{Settings, SharedSettings};
+use crate::disk::{DiskPool, TorrentDisk};
+use crate::tracker::{announce, AnnounceEvent, AnnounceStats};
+use crate::storage::{AllocationMode, FsStorage, ResumeData, Storage};
+use crate::stream_server::{StreamServer, StreamTorrents};
+use crate::utils::AnnounceResp;
+use crate::utils::torrents::{MetainfoError, Torrent};
+use crate::web_seed::{run_web_seed, WebSeed};
 
 pub type PiecesManager = Arc<Mutex<Pieces>>;
 
-pub async fn download_torrent(peer_id: ByteBuffer, file_path: &str) -> anyhow::Result<()> {
-    let torrent = Arc::new(Torrent::new(file_path));
-    torrent.print();
-
+/// The number of peers a torrent is connected to at once, unless told otherwise.
+pub const DEFAULT_MAX_PEERS: usize = 50;
+
+/// How many messages of a peer are read ahead of the ones being handled.
+const PEER_MESSAGE_QUEUE_LEN: usize = 8;
+
+/// How often a peer that isn't sending anything is told about our new pieces, and unchoked
+/// if an upload slot is free.
+const PEER_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The least time between two announces to the tracker, whatever interval it asks for.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait before announcing again to a tracker that failed.
+const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Options that apply to a single download.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub download_folder: PathBuf,
+    pub allocation: AllocationMode,
+    /// The priority of each file in the torrent, files that aren't listed are Normal.
+    pub file_priorities: Vec<Priority>,
+    pub pick_mode: PickMode,
+    /// Where to serve the files of the torrent over HTTP while it downloads, e.g. 127.0.0.1:8888
+    pub stream_address: Option<String>,
+    /// Peers to connect to, the tracker is asked for peers when there are none.
+    pub peers: Vec<SocketAddr>,
+    /// The most peers to be connected to at once, both the ones we connect to and the ones connecting to us.
+    pub max_peers: usize,
+    /// The most bytes per second this torrent downloads, on top of the limit of the session.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    /// When the torrent stops seeding in a session, instead of the seed goals of the settings.
+    pub seed_goals: Option<SeedGoals>,
+}
 
-    let download_folder = torrent.info.name.clone();
-    create_download_folder(&download_folder);
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            download_folder: PathBuf::from("."),
+            allocation: AllocationMode::default(),
+            file_priorities: Vec::new(),
+            pick_mode: PickMode::default(),
+            stream_address: None,
+            peers: Vec::new(),
+            max_peers: DEFAULT_MAX_PEERS,
+            download_limit: None,
+            upload_limit: None,
+            seed_goals: None,
+        }
+    }
+}
 
-    let handshake = Arc::new(build_peer_handshake(&torrent.info_hash.unwrap(), &peer_id).to_bytes());
+pub async fn download_torrent(peer_id: ByteBuffer, file_path: &str, options: &DownloadOptions) -> Result<()> {
+    let torrent = Arc::new(Torrent::from_path(file_path)?);
 
+    let mut storage = FsStorage::new(&options.download_folder, torrent.clone()).map_err(Error::Storage)?;
+    storage.set_file_priorities(&options.file_priorities).map_err(Error::Storage)?;
+    storage.allocate(options.allocation).map_err(Error::Storage)?;
 
-    // let peers = get_torrent_peers(&torrent, &hashed_info, &peer_id)?;
+    download_with_storage(peer_id, torrent, Box::new(storage), options).await
+}
 
+/// Download a torrent into the given storage, it ends once every wanted piece is in.
+///
+/// The totals told to the tracker go on from the ones saved in the storage, and are saved
+/// there again at the end.
+pub async fn download_with_storage(peer_id: ByteBuffer, torrent: Arc<Torrent>, mut storage: Box<dyn Storage>, options: &DownloadOptions) -> Result<()> {
+    let info_hash = torrent.info_hash.ok_or(Error::Metainfo(MetainfoError::MissingInfo))?;
+    let resume = storage.load_resume().map_err(Error::Storage)?.unwrap_or_default();
+    let handshake = Arc::new(build_peer_handshake(&info_hash, &peer_id).to_bytes());
+    let pieces_manager = new_pieces_manager(&torrent, options);
+
+    let events = TorrentEvents::unobserved(&torrent);
+
+    let disk_pool = DiskPool::new(1);
+    let disk = disk_pool.add_torrent(torrent.clone(), storage, pieces_manager.clone(), events.clone());
+
+    if let Some(stream_address) = &options.stream_address {
+        let torrents: StreamTorrents = Arc::new(Mutex::new(HashMap::new()));
+        torrents.lock().unwrap().insert(torrent.info_hash_hex(), disk.clone());
+
+        let server = StreamServer::bind(stream_address, torrents)
+            .await
+            .map_err(|e| Error::Config(format!("Unable to stream at {}: {}", stream_address, e)))?;
+        if let Ok(address) = server.local_addr() {
+            info!("streaming files at http://{}/{}", address, torrent.info_hash_hex());
+        }
+        tokio::spawn(server.run());
+    }
 
-    // println!("{:?}", peers);
-    // [Peer { ip_addr: 1410415827, port: 6682 }]
-    let peer = Peer {
-        ip_addr: 0,
-        port: 0,
+    let settings = Settings::default();
+    let context = TorrentContext {
+        torrent,
+        handshake,
+        pieces: pieces_manager,
+        disk,
+        events,
+        upload_slots: PeerSlots::new(settings.upload_slots),
+        settings: Arc::new(RwLock::new(settings)),
+        bandwidth: Bandwidth::new(options.download_limit, options.upload_limit),
+        session_bandwidth: Bandwidth::default(),
+        lan_bandwidth: Bandwidth::default(),
+        downloaded: Arc::new(AtomicU64::new(resume.downloaded)),
+        uploaded: Arc::new(AtomicU64::new(resume.uploaded)),
     };
 
+    // Nobody can connect to a lone download, it only has the peers it connects to.
+    let (_, incoming) = mpsc::channel(1);
 
-    let (tx, mut rx) = mpsc::channel::<PieceChannelPayload>(32);
+    let span = info_span!("torrent", info_hash = %context.torrent.info_hash_hex());
 
-    let pieces_manager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    run_torrent(context.clone(), options, incoming, false).instrument(span).await?;
 
-    for i in 0..1 {
-        let file_sender = tx.clone();
-        let pm = pieces_manager.clone();
-        let torrent = torrent.clone();
-        let peer = peer.clone();
-        let hs = handshake.clone();
+    context.disk.save_resume(context.resume_data(resume.seeding_secs)).await
+}
 
-        tokio::spawn(async move {
-            download_from_peer(torrent, file_sender, peer, hs, pm).await;
+/// Set up the pieces of a torrent from the download options.
+pub fn new_pieces_manager(torrent: &Torrent, options: &DownloadOptions) -> PiecesManager {
+    let mut pieces = Pieces::new(torrent);
+    pieces.set_file_priorities(torrent, &options.file_priorities);
+    pieces.set_pick_mode(options.pick_mode);
+
+    Arc::new(Mutex::new(pieces))
+}
+
+/// Everything the tasks downloading a torrent share.
+#[derive(Clone)]
+pub struct TorrentContext {
+    pub torrent: Arc<Torrent>,
+    /// Our handshake for the torrent, with its info hash and our peer id.
+    pub handshake: Arc<Vec<u8>>,
+    pub pieces: PiecesManager,
+    pub disk: TorrentDisk,
+    pub events: TorrentEvents,
+    pub settings: SharedSettings,
+    /// The limits of the torrent itself.
+    pub bandwidth: Bandwidth,
+    pub session_bandwidth: Bandwidth,
+    pub lan_bandwidth: Bandwidth,
+    /// The bytes received from peers and web seeds, before they're checked.
+    pub downloaded: Arc<AtomicU64>,
+    /// The bytes of pieces sent to peers.
+    pub uploaded: Arc<AtomicU64>,
+    /// The peers we upload to, the others are choked.
+    pub upload_slots: PeerSlots,
+}
+
+impl TorrentContext {
+    /// What we tell the tracker about the torrent.
+    pub fn announce_stats(&self) -> AnnounceStats {
+        AnnounceStats {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.pieces.lock().unwrap().bytes_left(&self.torrent),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Announce the torrent to its tracker, the tracker client blocks until it answers.
+    fn announce_to_tracker(&self, event: AnnounceEvent) -> Result<AnnounceResp> {
+        let peer_id = ByteBuffer::from_bytes(&self.handshake[48..68]);
+        let (port, timeout) = {
+            let settings = self.settings.read().unwrap();
+            (settings.listen_port, settings.tracker_timeout)
+        };
+
+        announce(&self.torrent, &peer_id, port, self.announce_stats(), event, timeout)
+    }
+
+    /// Tell the tracker the torrent stopped, on a thread of its own since the task of the
+    /// torrent is gone by then.
+    pub(crate) fn announce_stopped(&self) {
+        if self.torrent.announce.is_none() {
+            return;
+        }
+        let context = self.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = context.announce_to_tracker(AnnounceEvent::Stopped) {
+                debug!(error = %e, "unable to tell the tracker the torrent stopped");
+            }
         });
     }
 
-    while let Some(payload) = rx.recv().await {
-        write_block_to_file(&download_folder, &torrent.info.files.as_ref().unwrap(), payload)
+    /// The totals of the torrent to save with its data.
+    pub fn resume_data(&self, seeding_secs: u64) -> ResumeData {
+        ResumeData {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            seeding_secs,
+        }
     }
 
-    Ok(())
+    /// The limiters for the bytes of a peer, the LAN ones stand in for the session ones for local peers.
+    fn peer_limiters(&self, address: SocketAddr, peer: Bandwidth) -> LimiterChain {
+        let session = if is_local(address.ip()) { &self.lan_bandwidth } else { &self.session_bandwidth };
+
+        LimiterChain::new(vec![peer, self.bandwidth.clone(), session.clone()])
+    }
+
+    fn web_seed_limiters(&self) -> LimiterChain {
+        LimiterChain::new(vec![self.bandwidth.clone(), self.session_bandwidth.clone()])
+    }
 }
 
-fn create_download_folder(name: &String) {
-    match fs::create_dir_all(name) {
-        Ok(_) => {}
-        Err(_) => {}
-    };
+/// Keeps track of how many peers a torrent is connected to, or uploads to.
+#[derive(Clone)]
+pub struct PeerSlots {
+    active: Arc<AtomicUsize>,
+    max: usize,
 }
 
-fn write_block_to_file(download_folder: &String, files: &Vec<DlFile>, payload: PieceChannelPayload) {
-    let mut file_offset = 0;
-    let mut write_pos = payload.offset;
-    let mut bytes_to_write = payload.block.clone();
+/// A connection counted by PeerSlots, the slot is given back when it's dropped.
+pub struct PeerSlot(Arc<AtomicUsize>);
 
+impl PeerSlots {
+    pub fn new(max: usize) -> PeerSlots {
+        PeerSlots { active: Arc::new(AtomicUsize::new(0)), max }
+    }
 
-    for file in files {
-        let file_end = file.length + file_offset;
+    /// Take a slot for a new peer, unless the torrent already has as many peers as it may.
+    pub fn take(&self) -> Option<PeerSlot> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| if active < self.max { Some(active + 1) } else { None })
+            .ok()
+            .map(|_| PeerSlot(self.active.clone()))
+    }
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-        // Check if the block is for this file
-        let for_this_file = write_pos < file_end;
+/// The tasks working on a torrent, they are all stopped when the set is dropped.
+///
+/// Aborting the task of a peer drops its connection, which closes it.
+#[derive(Default)]
+struct TaskSet {
+    handles: Vec<JoinHandle<()>>,
+}
 
-        if for_this_file {
+impl TaskSet {
+    /// Spawn a task within the current span, so that what it logs is tied to the torrent.
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, task: F) {
+        self.handles.push(tokio::spawn(task.in_current_span()));
+    }
+}
 
-            // Write either the full amount of bytes in the block
-            // or until the end of the file
-            let write_len = if file_end - write_pos < bytes_to_write.len() as u64 {
-                file_end - write_pos
-            } else {
-                bytes_to_write.len() as u64
-            } as usize;
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(|handle| handle.abort());
+    }
+}
 
-            // Calculate the starting position to write the bytes
-            let file_write_pos = write_pos - file_offset;
+/// Download a torrent from its peers and web seeds until every wanted piece is on disk.
+///
+/// Peers that connect to us are handed over through `incoming`, after the handshake.
+/// When the peer list of the options is empty the tracker is asked for peers instead, and
+/// announced to again at the interval it asks for. Peers over the max peers of the options
+/// are dropped.
+///
+/// With `seed` it goes on uploading to its peers once it's done, until its task is stopped.
+pub async fn run_torrent(context: TorrentContext, options: &DownloadOptions, mut incoming: mpsc::Receiver<std::net::TcpStream>, seed: bool) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel::<PieceChannelPayload>(32);
+    let mut tasks = TaskSet::default();
+    let torrent = context.torrent.clone();
+    let slots = PeerSlots::new(options.max_peers);
+    let connected: ConnectedPeers = Arc::new(Mutex::new(HashSet::new()));
+    let use_tracker = options.peers.is_empty();
+    let mut next_announce = None;
+
+    let peers = if use_tracker {
+        announce_torrent(&context, AnnounceEvent::Started, &mut next_announce).await
+    } else {
+        options.peers.clone()
+    };
+    connect_peers(&context, &tx, &slots, &mut tasks, &connected, peers);
 
-            let file_path = download_folder.clone() + "/" + &file.path.clone().join("/");
+    // Web seeds hand their pieces over through the same channel as the peers.
+    let url_list = torrent.web_seeds().into_iter().map(|url| WebSeed::url_list(&url));
+    let http_seeds = torrent.http_seeds().into_iter().map(|url| WebSeed::http_seed(&url));
 
-            let mut dl_file = OpenOptions::new().write(true).create(true).open(&file_path).expect("Unable to open file");
-            dl_file.seek(SeekFrom::Start(file_write_pos)).expect("Unable to set offset on file");
-            dl_file.write(&bytes_to_write[0..write_len]).expect("Unable to write to file");
+    for seed in url_list.chain(http_seeds) {
+        let span = info_span!("web_seed", url = %seed.url());
+        tasks.spawn(run_web_seed(torrent.clone(), seed, context.pieces.clone(), tx.clone(), context.events.clone(), context.web_seed_limiters()).instrument(span));
+    }
 
-            // Remove the bytes from the start of the slice
-            bytes_to_write.drain(0..write_len);
+    let mut seeding = false;
+    let mut check_done = true;
+    let mut downloading = context.pieces.lock().unwrap().bytes_left(&torrent) > 0;
 
-            // Calculate the write position for the next file
-            write_pos += write_len as u64;
+    loop {
+        // The torrent is done once every wanted piece passed its hash check, which is only
+        // looked at again when the disk verified pieces.
+        if !seeding && check_done && context.pieces.lock().unwrap().bytes_left(&torrent) == 0 {
+            context.disk.flush().await?;
+
+            // The tracker only hears about downloads that complete, not ones we had all along.
+            if std::mem::replace(&mut downloading, false) && use_tracker {
+                let peers = announce_torrent(&context, AnnounceEvent::Completed, &mut next_announce).await;
+                connect_peers(&context, &tx, &slots, &mut tasks, &connected, peers);
+            }
+
+            if !seed {
+                break;
+            }
+            info!("downloaded, seeding");
+            context.events.send(Event::StateChanged { state: TorrentState::Seeding });
+            seeding = true;
         }
+        check_done = false;
+
+        tokio::select! {
+            Some(stream) = incoming.recv() => {
+                // The handshake was read on a thread of the session, the rest is async.
+                let stream = stream.set_nonblocking(true).and_then(|_| TcpStream::from_std(stream));
+
+                let (slot, stream, peer_addr) = match (slots.take(), stream) {
+                    (Some(slot), Ok(stream)) => match stream.peer_addr() {
+                        Ok(peer_addr) => (slot, stream, peer_addr),
+                        Err(_) => continue,
+                    },
+                    _ => {
+                        debug!("dropping incoming peer, no slot left");
+                        continue;
+                    }
+                };
+                let (context, file_sender) = (context.clone(), tx.clone());
+
+                tasks.spawn(
+                    async move {
+                        log_peer_end(run_peer(context, file_sender, stream, false).await);
+                        drop(slot);
+                    }
+                    .instrument(info_span!("peer", address = %peer_addr, incoming = true)),
+                );
+            }
+            Some(payload) = rx.recv() => {
+                context.downloaded.fetch_add(payload.block.len() as u64, Ordering::Relaxed);
+                context.events.send(Event::BlockReceived { length: payload.block.len() as u64 });
+
+                // Waiting on the disk here means the channel fills up and the peers wait too.
+                context.disk.write(payload).await?;
+            }
+            _ = tokio::time::sleep_until(next_announce.unwrap_or_else(tokio::time::Instant::now)), if next_announce.is_some() => {
+                let peers = announce_torrent(&context, AnnounceEvent::None, &mut next_announce).await;
+                connect_peers(&context, &tx, &slots, &mut tasks, &connected, peers);
+            }
+            _ = context.disk.wait_verified(), if !seeding => check_done = true,
+            else => break,
+        }
+    }
 
-        file_offset += file.length;
+    context.disk.flush().await?;
 
-        // Stop if there is nothing left to write
-        if bytes_to_write.len() == 0 {
-            break;
+    if use_tracker {
+        announce_torrent(&context, AnnounceEvent::Stopped, &mut next_announce).await;
+    }
+
+    Ok(())
+}
+
+/// The peers a torrent connected to, so that the ones the tracker lists again are left alone.
+type ConnectedPeers = Arc<Mutex<HashSet<SocketAddr>>>;
+
+/// Connect to the peers the torrent isn't connected to yet, for as long as there are slots left.
+fn connect_peers(
+    context: &TorrentContext,
+    file_sender: &Sender<PieceChannelPayload>,
+    slots: &PeerSlots,
+    tasks: &mut TaskSet,
+    connected: &ConnectedPeers,
+    peers: Vec<SocketAddr>,
+) {
+    for peer_addr in peers {
+        if !connected.lock().unwrap().insert(peer_addr) {
+            continue;
         }
+        let slot = match slots.take() {
+            Some(slot) => slot,
+            None => {
+                connected.lock().unwrap().remove(&peer_addr);
+                break;
+            }
+        };
+        let (context, file_sender, connected) = (context.clone(), file_sender.clone(), connected.clone());
+
+        tasks.spawn(
+            async move {
+                log_peer_end(download_from_peer(context, file_sender, peer_addr).await);
+                connected.lock().unwrap().remove(&peer_addr);
+                drop(slot);
+            }
+            .instrument(info_span!("peer", address = %peer_addr)),
+        );
     }
 }
 
+/// Announce the torrent to its tracker and get the peers it knows of.
+///
+/// The next announce is set to the interval the tracker asks for, or a while later when it
+/// failed. Torrents without a tracker never announce again.
+async fn announce_torrent(context: &TorrentContext, event: AnnounceEvent, next_announce: &mut Option<tokio::time::Instant>) -> Vec<SocketAddr> {
+    let url = match &context.torrent.announce {
+        Some(url) => url.clone(),
+        None => {
+            *next_announce = None;
+            return Vec::new();
+        }
+    };
 
-#[test]
-fn test_write_block_to_file_1() {
-    let download_folder: String = String::from("test-files/test1/");
-    match fs::remove_dir_all(&download_folder) {
-        Ok(_) => {}
-        Err(_) => {}
+    // The tracker client blocks.
+    let announcing = context.clone();
+    let result = match tokio::task::spawn_blocking(move || announcing.announce_to_tracker(event)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Tracker("The tracker client crashed".to_string())),
     };
 
-    create_download_folder(&download_folder);
+    match result {
+        Ok(response) => {
+            info!(%url, ?event, peers = response.peers.len(), interval = response.interval, "tracker replied");
+            context.events.send(Event::TrackerReplied { url, peers: response.peers.len() });
 
-    // Setup
-    let f1 = DlFile {
-        path: vec!["file1.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+            let interval = Duration::from_secs(response.interval.max(0) as u64).max(MIN_ANNOUNCE_INTERVAL);
+            *next_announce = Some(tokio::time::Instant::now() + interval);
 
-    let f2 = DlFile {
-        path: vec!["file2.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+            response
+                .peers
+                .into_iter()
+                .map(|peer| SocketAddr::from((Ipv4Addr::from(peer.ip_addr), peer.port)))
+                .collect()
+        }
+        Err(e) => {
+            warn!(%url, ?event, error = %e, "tracker failed");
+            context.events.send(Event::TrackerFailed { url, error: e.to_string() });
 
-    let f3 = DlFile {
-        path: vec!["file3.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+            *next_announce = Some(tokio::time::Instant::now() + TRACKER_RETRY_INTERVAL);
+            Vec::new()
+        }
+    }
+}
 
-    let mut files: Vec<DlFile> = Vec::new();
-    files.push(f1);
-    files.push(f2);
-    files.push(f3);
+async fn download_from_peer(context: TorrentContext, file_sender: Sender<PieceChannelPayload>, peer_addr: SocketAddr) -> Result<()> {
+    let timeout = context.settings.read().unwrap().connect_timeout;
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(peer_addr))
+        .await
+        .map_err(|_| Error::PeerConnection(io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting to the peer")))?
+        .map_err(Error::PeerConnection)?;
 
-    let payload = PieceChannelPayload {
-        offset: 4,
-        block: vec![1; 8],
-    };
+    stream.write_all(&context.handshake).await.map_err(Error::PeerConnection)?;
 
-    // Logic
-    write_block_to_file(&download_folder, &files, payload);
+    run_peer(context, file_sender, stream, true).await
+}
 
-    // Test
-    let mut f = File::open(download_folder.clone() + "/file1.txt").expect("Couldn't open file");
-    let mut buffer = [0; 5];
-    f.read(&mut buffer).expect("Couldn't read to buffer");
-    assert_eq!(vec![0, 0, 0, 0, 1], buffer);
+/// Exchange messages with a peer until the connection is closed.
+///
+/// For peers we connected to, their handshake still has to be read. Peers that connected
+/// to us have already been through the handshake.
+async fn run_peer(context: TorrentContext, file_sender: Sender<PieceChannelPayload>, stream: TcpStream, read_handshake: bool) -> Result<()> {
+    let address = stream.peer_addr().map_err(Error::PeerConnection)?;
+    let mut connection = PeerConnection::new(context.events.clone(), address);
+    let result = exchange_messages(context, file_sender, stream, address, read_handshake).await;
+
+    if let Err(e) = &result {
+        connection.reason = e.to_string();
+    }
 
+    result
+}
 
-    let mut f = File::open(download_folder.clone() + "/file2.txt").expect("Couldn't open file");
-    let mut buffer = [0; 5];
-    f.read(&mut buffer).expect("Couldn't read to buffer");
-    assert_eq!(vec![1; 5], buffer);
+fn log_peer_end(result: Result<()>) {
+    match result {
+        Ok(_) => debug!("peer disconnected"),
+        Err(e) => debug!(error = %e, "peer disconnected"),
+    }
+}
 
+/// Reports a peer as connected, and as disconnected once its task ends, however it ends.
+struct PeerConnection {
+    events: TorrentEvents,
+    address: SocketAddr,
+    reason: String,
+}
 
-    let mut f = File::open(download_folder.clone() + "/file3.txt").expect("Couldn't open file");
-    let mut buffer = [0; 5];
-    f.read(&mut buffer).expect("Couldn't read to buffer");
-    assert_eq!(vec![1, 1, 0, 0, 0], buffer);
+impl PeerConnection {
+    fn new(events: TorrentEvents, address: SocketAddr) -> PeerConnection {
+        events.send(Event::PeerConnected { address });
 
+        PeerConnection { events, address, reason: "Stopped".to_string() }
+    }
+}
 
-    match fs::remove_dir_all(&download_folder) {
-        Ok(_) => {}
-        Err(_) => {}
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.events.send(Event::PeerDisconnected { address: self.address, reason: self.reason.clone() });
+    }
+}
+
+/// Ends without an error once neither side has anything left to get from the other, or with
+/// whatever went wrong with the peer.
+///
+/// Messages are read on their own while the ones that came in are handled, so that a peer
+/// can cancel the blocks it asked for while we're still sending the ones before.
+async fn exchange_messages<S: AsyncRead + AsyncWrite + Unpin>(
+    context: TorrentContext,
+    file_sender: Sender<PieceChannelPayload>,
+    stream: S,
+    address: SocketAddr,
+    read_handshake: bool,
+) -> Result<()> {
+    let peer_bandwidth = Bandwidth::default();
+    let limiters = context.peer_limiters(address, peer_bandwidth.clone());
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    if read_handshake {
+        read_peer_handshake(&mut reader, &context.torrent).await?;
+    }
+
+    let mut queue: Queue = Queue::new(&context.torrent);
+    let mut message_handler = MessageHandler::new(&context, &mut writer, file_sender, &mut queue);
+    message_handler.start().await?;
+
+    let (message_sender, mut messages) = mpsc::channel(PEER_MESSAGE_QUEUE_LEN);
+
+    tokio::select! {
+        result = read_messages(&context, &mut reader, &peer_bandwidth, &limiters, message_sender) => result,
+        result = handle_messages(&mut message_handler, &mut messages, &limiters) => result,
+    }
+}
+
+/// Read the messages of a peer and hand them over until the connection is closed.
+///
+/// Not reading from the peer until its bytes are paid for holds it back through TCP.
+async fn read_messages<R: AsyncRead + Unpin>(
+    context: &TorrentContext,
+    reader: &mut R,
+    peer_bandwidth: &Bandwidth,
+    limiters: &LimiterChain,
+    messages: Sender<ByteBuffer>,
+) -> Result<()> {
+    loop {
+        {
+            let settings = context.settings.read().unwrap();
+            peer_bandwidth.set_rates(settings.peer_download_limit, settings.peer_upload_limit);
+        }
+
+        let msg = read_message(reader).await?;
+        limiters.download(msg.len() as u64).await;
+
+        if messages.send(msg).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Handle the messages of a peer, and send it the blocks it asked for in between.
+async fn handle_messages<S: AsyncWrite + Unpin>(
+    message_handler: &mut MessageHandler<'_, S>,
+    messages: &mut mpsc::Receiver<ByteBuffer>,
+    limiters: &LimiterChain,
+) -> Result<()> {
+    while !message_handler.is_finished() {
+        message_handler.announce_pieces().await?;
+        message_handler.requeue_pieces().await?;
+        message_handler.update_interest().await?;
+        message_handler.unchoke_peer().await?;
+
+        // Blocks are only sent once the messages that are already in have been handled, so
+        // that a cancel lands before the block it cancels.
+        let recv_msg = if message_handler.has_requests() {
+            match messages.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Closed) => break,
+            }
+        } else {
+            match tokio::time::timeout(PEER_UPDATE_INTERVAL, messages.recv()).await {
+                Ok(Some(msg)) => Some(msg),
+                Ok(None) => break,
+                // Look for new pieces and free upload slots again.
+                Err(_) => None,
+            }
+        };
+
+        match recv_msg {
+            Some(msg) => message_handler.router(msg).await?,
+            None => message_handler.upload_block().await?,
+        }
+        limiters.upload(message_handler.take_sent()).await;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn check_handshake_msg(msg: &mut ByteBuffer) -> bool {
+    if msg.len() < 20 {
+        return false;
+    }
+
+    let protocol = match String::from_utf8(msg.to_bytes()[1..20].to_owned()) {
+        Ok(protocol) => protocol,
+        Err(_) => return false,
     };
+
+    protocol == "BitTorrent protocol"
 }
 
 
 #[test]
-fn test_write_block_to_file_2() {
-    let download_folder: String = String::from("test-files/test2/");
-    match fs::remove_dir_all(&download_folder) {
-        Ok(_) => {}
-        Err(_) => {}
-    };
-    create_download_folder(&download_folder);
+fn test_peer_slots() {
+    let slots = PeerSlots::new(2);
+
+    let first = slots.take().unwrap();
+    let second = slots.take().unwrap();
+    assert!(slots.take().is_none());
+
+    // A peer that goes away makes room for another one.
+    drop(first);
+    assert!(slots.take().is_some());
+    drop(second);
+    assert_eq!(slots.active.load(Ordering::SeqCst), 0);
+}
 
-    // Setup
-    let f1 = DlFile {
-        path: vec!["file1.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+/// A context for a torrent that is only downloaded from the peers a test hands it.
+#[cfg(test)]
+pub(crate) fn test_context(torrent: Arc<Torrent>, storage: Box<dyn Storage>) -> TorrentContext {
+    use crate::utils::gen_peer_id;
+
+    let handshake = Arc::new(build_peer_handshake(&torrent.info_hash.unwrap(), &gen_peer_id()).to_bytes());
+    let pieces = new_pieces_manager(&torrent, &DownloadOptions::default());
+    let events = TorrentEvents::unobserved(&torrent);
+    let disk = DiskPool::new(1).add_torrent(torrent.clone(), storage, pieces.clone(), events.clone());
+
+    TorrentContext {
+        torrent,
+        handshake,
+        pieces,
+        disk,
+        events,
+        settings: Arc::new(RwLock::new(Settings::default())),
+        upload_slots: PeerSlots::new(Settings::default().upload_slots),
+        bandwidth: Bandwidth::default(),
+        session_bandwidth: Bandwidth::default(),
+        lan_bandwidth: Bandwidth::default(),
+        downloaded: Arc::new(AtomicU64::new(0)),
+        uploaded: Arc::new(AtomicU64::new(0)),
+    }
+}
 
-    let f2 = DlFile {
-        path: vec!["file2.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+#[tokio::test]
+async fn test_silent_peer_does_not_block() {
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use crate::storage::MemoryStorage;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent)));
+    let (file_sender, _file_receiver) = mpsc::channel(1);
+    let (stream, mut peer) = tokio::io::duplex(1024);
+
+    // The peer never sends its handshake, which used to hold up the whole runtime thread.
+    let address = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let mut tasks = TaskSet::default();
+    tasks.spawn(async move {
+        let _ = exchange_messages(context, file_sender, stream, address, true).await;
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // Stopping the torrent closes the connection.
+    drop(tasks);
+    let mut buf = Vec::new();
+    assert_eq!(peer.read_to_end(&mut buf).await.unwrap(), 0);
+}
 
-    let f3 = DlFile {
-        path: vec!["file3.txt".to_owned()],
-        length: 5,
-        md5sum: None,
-    };
+/// A torrent of a single file with the given data, made in a test folder.
+#[cfg(test)]
+fn test_torrent_from_data(folder: &str, data: &[u8], create_options: &crate::create::CreateOptions) -> Arc<Torrent> {
+    use std::fs;
+    use std::path::Path;
+    use crate::create::create_torrent;
 
-    let mut files: Vec<DlFile> = Vec::new();
-    files.push(f1);
-    files.push(f2);
-    files.push(f3);
+    let folder = Path::new(folder);
+    fs::create_dir_all(folder).unwrap();
+    fs::write(folder.join("data.bin"), data).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&folder.join("data.bin"), create_options).unwrap()).unwrap());
+    let _ = fs::remove_dir_all(folder);
 
-    let payload = PieceChannelPayload {
-        offset: 9,
-        block: vec![1; 6],
-    };
+    torrent
+}
 
-    // Logic
-    write_block_to_file(&download_folder, &files, payload);
+/// Storage holding every piece of a torrent with the given data.
+#[cfg(test)]
+fn seed_storage(torrent: &Arc<Torrent>, data: &[u8]) -> crate::storage::MemoryStorage {
+    let mut storage = crate::storage::MemoryStorage::new(torrent.clone());
+    for piece_index in 0..torrent.num_pieces() {
+        let offset = torrent.piece_offset(piece_index) as usize;
+        storage.write_block(piece_index, 0, &data[offset..offset + torrent.get_piece_len(piece_index) as usize]).unwrap();
+    }
 
+    storage
+}
 
-    let mut f = File::open(download_folder.clone() + "/file2.txt").expect("Couldn't open file");
-    let mut buffer = [0; 5];
-    f.read(&mut buffer).expect("Couldn't read to buffer");
-    println!("{:?}", buffer);
-    assert_eq!(vec![0, 0, 0, 0, 1], buffer);
+/// A peer seeding every piece from the storage, for the first connection it gets.
+#[cfg(test)]
+async fn loopback_seed(torrent: Arc<Torrent>, storage: Box<dyn Storage>) -> (TorrentContext, SocketAddr, JoinHandle<Result<()>>) {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use crate::messages::HANDSHAKE_LEN;
 
+    let seed = test_context(torrent.clone(), storage);
+    (0..torrent.num_pieces()).for_each(|piece_index| seed.pieces.lock().unwrap().mark_complete(piece_index));
 
-    let mut f = File::open(download_folder.clone() + "/file3.txt").expect("Couldn't open file");
-    let mut buffer = [0; 5];
-    f.read(&mut buffer).expect("Couldn't read to buffer");
-    println!("{:?}", buffer);
-    assert_eq!(vec![1; 5], buffer);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let seed_addr = listener.local_addr().unwrap();
+    let seed_task = {
+        let seed = seed.clone();
 
-    match fs::remove_dir_all(&download_folder) {
-        Ok(_) => {}
-        Err(_) => {}
+        tokio::spawn(async move {
+            let (mut stream, address) = listener.accept().await.unwrap();
+            let mut handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&seed.handshake).await.unwrap();
+
+            // The seed doesn't download anything, nothing goes through its channel.
+            let (seed_sender, _seed_receiver) = mpsc::channel(1);
+            exchange_messages(seed, seed_sender, stream, address, false).await
+        })
     };
-}
 
-async fn download_from_peer(torrent: Arc<Torrent>, file_sender: Sender<PieceChannelPayload>, peer: Peer, handshake: Arc<Vec<u8>>, pieces: PiecesManager) -> anyhow::Result<()> {
-    let peer_addr = (Ipv4Addr::from(peer.ip_addr), peer.port);
+    (seed, seed_addr, seed_task)
+}
 
-    let mut queue: Queue = Queue::new(&torrent);
+#[tokio::test]
+async fn test_stream_from_loopback_swarm() {
+    use crate::create::CreateOptions;
+    use crate::http_client;
+    use crate::storage::MemoryStorage;
+
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let create_options = CreateOptions { piece_length: Some(64 * 1024), ..Default::default() };
+    let torrent = test_torrent_from_data("test-files/test20", &data, &create_options);
+    let (seed, seed_addr, seed_task) = loopback_seed(torrent.clone(), Box::new(seed_storage(&torrent, &data))).await;
+
+    // A download streamed over HTTP while its pieces come in from the seed.
+    let context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())));
+    let torrents: StreamTorrents = Arc::new(Mutex::new(HashMap::new()));
+    torrents.lock().unwrap().insert(torrent.info_hash_hex(), context.disk.clone());
+    let server = StreamServer::bind("127.0.0.1:0", torrents).await.unwrap();
+    let url = format!("http://{}/{}/0", server.local_addr().unwrap(), torrent.info_hash_hex());
+    tokio::spawn(server.run());
+
+    let response = tokio::spawn(async move { http_client::get(&url, Some((10_000, 79_999))).await });
+
+    let options = DownloadOptions { peers: vec![seed_addr], ..Default::default() };
+    let (_, incoming) = mpsc::channel(1);
+    run_torrent(context.clone(), &options, incoming, false).await.unwrap();
+
+    let response = response.await.unwrap().unwrap();
+    assert_eq!(response.status, 206);
+    assert_eq!(response.body, &data[10_000..80_000]);
+
+    // The download hangs up on the seed once it's done.
+    assert!(seed_task.await.is_ok());
+    assert_eq!(context.downloaded.load(Ordering::Relaxed), data.len() as u64);
+    assert_eq!(seed.uploaded.load(Ordering::Relaxed), data.len() as u64);
+}
 
-    // let mut stream = TcpStream::connect(peer_addr)?;
-    let mut stream = TcpStream::connect("127.0.0.1:14082").expect("Unable to connect to peer");
+#[tokio::test]
+async fn test_redownload_failed_piece() {
+    use std::path::Path;
+    use crate::create::CreateOptions;
+    use crate::storage::MemoryStorage;
 
-    println!("Connected to Peer!");
+    /// Sends a corrupt copy of the first piece the first time it's read.
+    struct CorruptOnce(MemoryStorage, bool);
 
-    stream.write(&handshake).expect("Unable to write to peer");
+    impl Storage for CorruptOnce {
+        fn read_block(&mut self, piece_index: u64, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+            let mut block = self.0.read_block(piece_index, begin, length)?;
+            if piece_index == 0 && !std::mem::replace(&mut self.1, true) {
+                block[0] ^= 0xff;
+            }
 
-    let mut message_handler = MessageHandler::new(&torrent, &mut stream, file_sender, pieces, &mut queue);
+            Ok(block)
+        }
 
-    let mut is_handshake = true;
-    loop {
-        if is_handshake {
-            message_handler.handshake();
-            is_handshake = false;
-        } else {
-            let recv_msg = message_handler.get_whole_msg();
-            message_handler.router(recv_msg).await;
+        fn write_block(&mut self, piece_index: u64, begin: u64, block: &[u8]) -> io::Result<()> {
+            self.0.write_block(piece_index, begin, block)
         }
-    }
 
-    Ok(())
-}
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
 
+        fn verify_piece(&mut self, piece_index: u64) -> io::Result<bool> {
+            self.0.verify_piece(piece_index)
+        }
 
-fn check_handshake_msg(msg: &mut ByteBuffer) -> bool {
-    if msg.len() < 20 {
-        return false;
-    }
+        fn move_to(&mut self, _download_folder: &Path) -> io::Result<()> {
+            Ok(())
+        }
 
-    let protocol = match String::from_utf8(msg.to_bytes()[1..20].to_owned()) {
-        Ok(protocol) => protocol,
-        Err(e) => {
-            return false;
+        fn delete(&mut self) -> io::Result<()> {
+            Ok(())
         }
-    };
+    }
 
-    let handshake = protocol == "BitTorrent protocol";
+    // Pieces of four blocks, the blocks of the first piece come in before it's checked.
+    let piece_length = 64 * 1024;
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent_from_data("test-files/test23", &data, &CreateOptions { piece_length: Some(piece_length), ..Default::default() });
+    let storage = CorruptOnce(seed_storage(&torrent, &data), false);
+    let (seed, seed_addr, seed_task) = loopback_seed(torrent.clone(), Box::new(storage)).await;
+
+    let context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())));
+    let options = DownloadOptions { peers: vec![seed_addr], ..Default::default() };
+    let (_, incoming) = mpsc::channel(1);
+    tokio::time::timeout(Duration::from_secs(10), run_torrent(context.clone(), &options, incoming, false)).await.unwrap().unwrap();
+
+    // The first piece failed its hash check and was asked for again from the same seed.
+    {
+        let pieces = context.pieces.lock().unwrap();
+        assert_eq!(pieces.bytes_left(&torrent), 0);
+        assert_eq!(pieces.resets(), 1);
+    }
+    assert!(seed_task.await.is_ok());
 
-    return handshake;
+    // Blocks still on their way to the disk at the end may be asked for twice.
+    assert!(context.downloaded.load(Ordering::Relaxed) >= data.len() as u64 + piece_length);
+    assert!(seed.uploaded.load(Ordering::Relaxed) >= data.len() as u64 + piece_length);
 }
 
+#[tokio::test]
+async fn test_tracker_events() {
+    use std::convert::TryInto;
+    use std::net::UdpSocket;
+    use crate::create::CreateOptions;
+    use crate::storage::MemoryStorage;
+
+    // A tracker that lists the seed, and keeps the events of the announces.
+    let tracker = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let create_options = CreateOptions { trackers: vec![format!("udp://{}", tracker.local_addr().unwrap())], ..Default::default() };
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent_from_data("test-files/test25", &data, &create_options);
+    let (_seed, seed_addr, _seed_task) = loopback_seed(torrent.clone(), Box::new(seed_storage(&torrent, &data))).await;
+
+    let tracker_task = thread::spawn(move || {
+        let mut events = Vec::new();
+        let mut buf = [0; 100];
+
+        while events.last() != Some(&(AnnounceEvent::Stopped as i32)) {
+            let (received, address) = tracker.recv_from(&mut buf).unwrap();
+            let mut response = Vec::new();
+
+            if received == 16 {
+                response.extend(0i32.to_be_bytes());
+                response.extend(&buf[12..16]);
+                response.extend(7i64.to_be_bytes());
+            } else {
+                events.push(i32::from_be_bytes(buf[80..84].try_into().unwrap()));
+                response.extend(1i32.to_be_bytes());
+                response.extend(&buf[12..16]);
+                [1800i32, 0, 1].iter().for_each(|value| response.extend(value.to_be_bytes()));
+                response.extend([127, 0, 0, 1]);
+                response.extend(seed_addr.port().to_be_bytes());
+            }
+            tracker.send_to(&response, address).unwrap();
+        }
 
+        events
+    });
 
+    let context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())));
+    let (_, incoming) = mpsc::channel(1);
+    run_torrent(context.clone(), &DownloadOptions::default(), incoming, false).await.unwrap();
 
+    let events: Vec<i32> = [AnnounceEvent::Started, AnnounceEvent::Completed, AnnounceEvent::Stopped].iter().map(|event| *event as i32).collect();
+    assert_eq!(tracker_task.join().unwrap(), events);
+    assert_eq!(context.pieces.lock().unwrap().bytes_left(&torrent), 0);
+}
+
+#[tokio::test]
+async fn test_upload_limit() {
+    use std::time::Instant;
+    use crate::message_handlers::read_message;
+    use crate::messages::{build_interested, build_request};
+    use crate::queue::PieceBlock;
+    use crate::storage::MemoryStorage;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let mut context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent)));
+    context.bandwidth = Bandwidth::new(None, Some(8 * 1024));
+    context.pieces.lock().unwrap().mark_complete(0);
+
+    let (file_sender, _file_receiver) = mpsc::channel(1);
+    let (stream, mut peer) = tokio::io::duplex(64 * 1024);
+    let address = SocketAddr::from(([10, 0, 0, 1], 6881));
+    let mut tasks = TaskSet::default();
+    tasks.spawn(async move {
+        let _ = exchange_messages(context, file_sender, stream, address, false).await;
+    });
+
+    // The bitfield and our interest in the other pieces, then the unchoke once the peer is interested.
+    read_message(&mut peer).await.unwrap();
+    read_message(&mut peer).await.unwrap();
+    peer.write_all(&build_interested().to_bytes()).await.unwrap();
+    assert_eq!(read_message(&mut peer).await.unwrap().to_bytes(), vec![0, 0, 0, 1, 1]);
+
+    // 24 KiB of blocks at 8 KiB per second, the first second worth goes out right away.
+    let started = Instant::now();
+    for i in 0..24 {
+        peer.write_all(&build_request(PieceBlock { index: 0, begin: i * 1024, length: Some(1024) }).to_bytes()).await.unwrap();
+    }
+    for _ in 0..24 {
+        assert_eq!(read_message(&mut peer).await.unwrap().len(), 13 + 1024);
+    }
+    assert!(started.elapsed() >= Duration::from_millis(1500), "{:?}", started.elapsed());
+}
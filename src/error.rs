@@ -0,0 +1,83 @@
+use std::fmt;
+use std::io;
+
+use crate::utils::torrents::MetainfoError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while downloading a torrent.
+///
+/// Whatever a tracker or a peer sends ends up as one of these at worst, never as a panic.
+#[derive(Debug)]
+pub enum Error {
+    /// The tracker couldn't be reached, or answered with something we don't understand.
+    Tracker(String),
+    /// A peer sent something that breaks the protocol.
+    PeerProtocol(String),
+    /// The connection to a peer failed or was closed.
+    PeerConnection(io::Error),
+    /// Reading or writing the data of a torrent failed.
+    Storage(io::Error),
+    Metainfo(MetainfoError),
+    /// An option has a value we can't work with.
+    Config(String),
+    /// The torrent isn't in the session, or not anymore.
+    UnknownTorrent,
+    /// The torrent with this info hash is already in the session.
+    DuplicateTorrent(String),
+    /// None of the peers of a magnet link sent us the metadata of the torrent.
+    Metadata(String),
+}
+
+impl Error {
+    /// The disk worker of a torrent is gone, which only happens when the process is going down.
+    pub(crate) fn disk_stopped() -> Error {
+        Error::Storage(io::Error::new(io::ErrorKind::BrokenPipe, "The disk worker has stopped"))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Tracker(e) => write!(f, "Tracker error: {}", e),
+            Error::PeerProtocol(e) => write!(f, "Peer protocol error: {}", e),
+            Error::PeerConnection(e) if e.kind() == io::ErrorKind::UnexpectedEof => write!(f, "The peer closed the connection"),
+            Error::PeerConnection(e) => write!(f, "Peer connection error: {}", e),
+            Error::Storage(e) => write!(f, "Storage error: {}", e),
+            Error::Metainfo(e) => write!(f, "{}", e),
+            Error::Config(e) => write!(f, "Invalid configuration: {}", e),
+            Error::UnknownTorrent => write!(f, "No such torrent"),
+            Error::DuplicateTorrent(info_hash) => write!(f, "The torrent {} is already in the session", info_hash),
+            Error::Metadata(e) => write!(f, "Unable to get the metadata: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PeerConnection(e) | Error::Storage(e) => Some(e),
+            Error::Metainfo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MetainfoError> for Error {
+    fn from(e: MetainfoError) -> Self {
+        Error::Metainfo(e)
+    }
+}
+
+#[test]
+fn test_display_error() {
+    let closed = Error::PeerConnection(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+    assert_eq!(closed.to_string(), "The peer closed the connection");
+
+    let protocol = Error::PeerProtocol("Piece 9 is out of range".to_string());
+    assert_eq!(protocol.to_string(), "Peer protocol error: Piece 9 is out of range");
+
+    let metainfo = Error::from(MetainfoError::MissingInfo);
+    assert_eq!(metainfo.to_string(), "The torrent file has no info dictionary");
+    assert!(std::error::Error::source(&metainfo).is_some());
+}
@@ -0,0 +1,83 @@
+use std::net::SocketAddr;
+
+use serde_derive::Serialize;
+use tokio::sync::broadcast;
+
+use crate::session::TorrentState;
+use crate::utils::torrents::{Torrent, TorrentSummary};
+
+/// How many events a subscriber can fall behind before it starts missing them.
+pub const EVENT_QUEUE_LEN: usize = 4096;
+
+pub type EventSender = broadcast::Sender<SessionEvent>;
+pub type EventReceiver = broadcast::Receiver<SessionEvent>;
+
+/// Something that happened to a torrent in the session.
+///
+/// Serialized as a flat object, e.g.
+///
+/// ```text
+/// {"info_hash":"06cb...","event":"piece_verified","piece":3}
+/// ```
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionEvent {
+    pub info_hash: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TorrentAdded { summary: Box<TorrentSummary> },
+    TorrentRemoved,
+    StateChanged { state: TorrentState },
+    /// A block was received from a peer or a web seed, before it's checked.
+    BlockReceived { length: u64 },
+    PieceVerified { piece: u64 },
+    PieceFailed { piece: u64 },
+    PeerConnected { address: SocketAddr },
+    PeerDisconnected { address: SocketAddr, reason: String },
+    TrackerReplied { url: String, peers: usize },
+    TrackerFailed { url: String, error: String },
+    WebSeedFailed { url: String, error: String, retry_in_secs: u64 },
+    /// The torrent can't go on, e.g. because its data can't be written.
+    Error { message: String },
+}
+
+/// Sends the events of a single torrent.
+///
+/// Nobody has to be listening, events sent without subscribers are dropped.
+#[derive(Debug, Clone)]
+pub struct TorrentEvents {
+    sender: EventSender,
+    info_hash: String,
+}
+
+impl TorrentEvents {
+    pub fn new(sender: EventSender, torrent: &Torrent) -> TorrentEvents {
+        TorrentEvents { sender, info_hash: torrent.info_hash_hex() }
+    }
+
+    /// Events for a torrent that's downloaded on its own, outside of a session.
+    pub fn unobserved(torrent: &Torrent) -> TorrentEvents {
+        TorrentEvents::new(broadcast::channel(1).0, torrent)
+    }
+
+    pub fn info_hash(&self) -> &str {
+        &self.info_hash
+    }
+
+    pub fn send(&self, event: Event) {
+        let _ = self.sender.send(SessionEvent { info_hash: self.info_hash.clone(), event });
+    }
+}
+
+#[test]
+fn test_serialize_event() {
+    let event = SessionEvent { info_hash: "ab".to_string(), event: Event::PieceVerified { piece: 3 } };
+    assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"info_hash":"ab","event":"piece_verified","piece":3}"#);
+
+    let event = SessionEvent { info_hash: "ab".to_string(), event: Event::TorrentRemoved };
+    assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"info_hash":"ab","event":"torrent_removed"}"#);
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::{Host, Url};
+
+/// How long a request can take before we give up on the server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The largest response body we'll accept, a piece is never anywhere near this big.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Get the value of a header, names are compared case insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Make a GET request, following redirects.
+///
+/// Only plain http is supported. When a range is given, both ends included,
+/// the server is asked for just those bytes.
+pub async fn get(url: &str, range: Option<(u64, u64)>) -> anyhow::Result<HttpResponse> {
+    let mut url = Url::parse(url)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, request(&url, range))
+            .await
+            .map_err(|_| anyhow!("Request to {} timed out", url))??;
+
+        match (response.status, response.header("Location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => url = url.join(location)?,
+            _ => return Ok(response),
+        }
+    }
+
+    bail!("Too many redirects for {}", url)
+}
+
+async fn request(url: &Url, range: Option<(u64, u64)>) -> anyhow::Result<HttpResponse> {
+    if url.scheme() != "http" {
+        bail!("Unsupported url scheme: {}", url.scheme());
+    }
+
+    let host = url.host_str().ok_or_else(|| anyhow!("The url {} has no host", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    // IPv6 literals keep their brackets in the Host header but not when connecting.
+    let address = match url.host() {
+        Some(Host::Ipv6(address)) => address.to_string(),
+        _ => host.to_string(),
+    };
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: torrenter\r\n", target, host);
+    if let Some((start, end)) = range {
+        request.push_str(&format!("Range: bytes={}-{}\r\n", start, end));
+    }
+    request.push_str("\r\n");
+
+    let mut stream = TcpStream::connect((address.as_str(), port)).await?;
+    stream.write_all(request.as_bytes()).await?;
+
+    read_response(&mut stream).await
+}
+
+/// Read a response off the wire, the body is delimited by the content length,
+/// chunked encoding or the end of the connection.
+pub async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<HttpResponse> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 16 * 1024];
+
+    let head_len = loop {
+        if let Some(position) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            bail!("The connection closed before the response headers were received");
+        }
+        if buf.len() > MAX_BODY_LEN {
+            bail!("The response headers are too large");
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("Invalid HTTP status line"))?;
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut response = HttpResponse { status, headers, body: buf.split_off(head_len) };
+
+    let chunked = response.header("Transfer-Encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    let content_length = response.header("Content-Length").and_then(|value| value.parse::<usize>().ok());
+
+    if content_length.is_some_and(|len| len > MAX_BODY_LEN) {
+        bail!("The response body is too large");
+    }
+
+    while content_length.is_none_or(|len| response.body.len() < len) {
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            break;
+        }
+        if response.body.len() > MAX_BODY_LEN {
+            bail!("The response body is too large");
+        }
+
+        response.body.extend_from_slice(&chunk[..len]);
+    }
+
+    if let Some(len) = content_length {
+        if response.body.len() < len {
+            bail!("The connection closed after {} of {} bytes", response.body.len(), len);
+        }
+        response.body.truncate(len);
+    }
+
+    if chunked {
+        response.body = decode_chunked(&response.body)?;
+    }
+
+    Ok(response)
+}
+
+/// Join the chunks of a body sent with chunked transfer encoding.
+fn decode_chunked(mut data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| anyhow!("Truncated chunked body"))?;
+
+        let size_line = String::from_utf8_lossy(&data[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| anyhow!("Invalid chunk size: {}", size_hex))?;
+
+        data = &data[line_end + 2..];
+
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            bail!("Truncated chunked body");
+        }
+
+        body.extend_from_slice(&data[..size]);
+        data = data.get(size + 2..).unwrap_or(&[]);
+    }
+}
+
+#[tokio::test]
+async fn test_read_response() {
+    let raw: &[u8] = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\nContent-Range: bytes 0-4/10\r\n\r\nhello";
+    let response = read_response(&mut &raw[..]).await.unwrap();
+
+    assert_eq!(response.status, 206);
+    assert_eq!(response.header("content-range"), Some("bytes 0-4/10"));
+    assert_eq!(response.body, b"hello");
+
+    let raw: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;x=y\r\n world\r\n0\r\n\r\n";
+    let response = read_response(&mut &raw[..]).await.unwrap();
+    assert_eq!(response.body, b"hello world");
+
+    let raw: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 50\r\n\r\nshort";
+    assert!(read_response(&mut &raw[..]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_ipv6() {
+    use tokio::net::TcpListener;
+    use crate::http_server::{read_request, write_response};
+
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request(&mut stream, 0).await.unwrap().unwrap();
+        let host = request.header("host").unwrap_or_default().to_string();
+        write_response(&mut stream, "200 OK", &[("Content-Length", host.len().to_string())], host.as_bytes()).await.unwrap();
+    });
+
+    let response = get(&format!("http://[::1]:{}/file", port), None).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, format!("[::1]:{}", port).into_bytes());
+}
@@ -0,0 +1,136 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The most we'll read of the request line and headers before giving up on a request.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// How long a client gets to send its whole request, so that a slow one can't hold its
+/// connection open.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What our small HTTP servers get from a client.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path with the query, as the client sent it.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of a header, names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a request, with its body when it has a Content-Length.
+///
+/// Returns None for requests that aren't HTTP, or with a head or body that's too long.
+/// A request that takes longer than REQUEST_TIMEOUT is a TimedOut error.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S, max_body_len: usize) -> io::Result<Option<Request>> {
+    read_request_within(stream, max_body_len, REQUEST_TIMEOUT).await
+}
+
+async fn read_request_within<S: AsyncRead + Unpin>(stream: &mut S, max_body_len: usize, timeout: Duration) -> io::Result<Option<Request>> {
+    match tokio::time::timeout(timeout, read_whole_request(stream, max_body_len)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out reading the request")),
+    }
+}
+
+async fn read_whole_request<S: AsyncRead + Unpin>(stream: &mut S, max_body_len: usize) -> io::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+
+    let head_len = loop {
+        if let Some(position) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+
+        let len = stream.read(&mut chunk).await?;
+
+        if len == 0 || buf.len() > MAX_HEAD_LEN {
+            return Ok(None);
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(None),
+    };
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = Request { method, path, headers, body: buf.split_off(head_len) };
+
+    let content_length = match request.header("content-length").map(|value| value.parse::<usize>()) {
+        None => 0,
+        Some(Ok(content_length)) if content_length <= max_body_len => content_length,
+        Some(_) => return Ok(None),
+    };
+
+    while request.body.len() < content_length {
+        let len = stream.read(&mut chunk).await?;
+
+        if len == 0 {
+            return Ok(None);
+        }
+
+        request.body.extend_from_slice(&chunk[..len]);
+    }
+    request.body.truncate(content_length);
+
+    Ok(Some(request))
+}
+
+pub async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(body).await
+}
+
+#[tokio::test]
+async fn test_read_request() {
+    let read = |bytes: &'static [u8], max_body_len: usize| async move { read_request(&mut &bytes[..], max_body_len).await.unwrap() };
+
+    let request = read(b"POST /api/torrents?a=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 5\r\n\r\nhello", 10).await.unwrap();
+    assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/api/torrents?a=1"));
+    assert_eq!(request.header("Content-Length"), Some("5"));
+    assert_eq!(request.header("host"), Some("localhost"));
+    assert_eq!(request.body, b"hello");
+
+    let request = read(b"GET / HTTP/1.1\r\nRange: bytes=0-1\r\n\r\n", 0).await.unwrap();
+    assert_eq!(request.header("range"), Some("bytes=0-1"));
+    assert!(request.body.is_empty());
+
+    // Too long, cut short or not HTTP at all.
+    assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world", 10).await.is_none());
+    assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel", 10).await.is_none());
+    assert!(read(b"POST / HTTP/1.1\r\nContent-Length: five\r\n\r\n", 10).await.is_none());
+    assert!(read(b"hello\r\n\r\n", 10).await.is_none());
+
+    // A client that stops halfway through is cut off.
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    let error = read_request_within(&mut server, 0, Duration::from_millis(50)).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+}
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use crate::utils::torrents::{DlFile, Info, MetainfoError};
+
+/// Most filesystems limit a single path component to 255 bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+
+/// Names that can't be used as files on Windows, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A file in the torrent and where its bytes sit within the torrent's contiguous data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutFile {
+    /// Path relative to the download directory, including the torrent name.
+    pub path: PathBuf,
+    pub length: u64,
+    /// Offset of the first byte of this file within the torrent.
+    pub offset: u64,
+}
+
+/// A part of a byte range within the torrent that lands inside a single file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    /// Position within the file.
+    pub file_offset: u64,
+    pub length: u64,
+}
+
+/// The files that make up a torrent, built from either the single-file or multi-file form.
+///
+/// ```text
+/// single-file: <name>
+/// multi-file:  <name>/<path...>
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileLayout {
+    files: Vec<LayoutFile>,
+    total_length: u64,
+}
+
+impl FileLayout {
+    pub fn from_info(info: &Info) -> Result<FileLayout, MetainfoError> {
+        match &info.files {
+            Some(files) => FileLayout::multi_file(&info.name, files),
+            None => FileLayout::single_file(&info.name, info.length.unwrap_or(0)),
+        }
+    }
+
+    /// A torrent with a single file stored directly under its name.
+    pub fn single_file(name: &str, length: u64) -> Result<FileLayout, MetainfoError> {
+        Ok(FileLayout {
+            files: vec![LayoutFile {
+                path: build_safe_path(name, &[])?,
+                length,
+                offset: 0,
+            }],
+            total_length: length,
+        })
+    }
+
+    /// A torrent with many files stored in a folder named after the torrent.
+    ///
+    /// Paths that end up the same once sanitized, like `a?` and `a_`, get a number before
+    /// the extension so that the files don't overwrite each other: `a_`, `a_.1`.
+    pub fn multi_file(name: &str, files: &[DlFile]) -> Result<FileLayout, MetainfoError> {
+        let mut layout_files = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        // Every file and directory in use, and the files alone.
+        let mut taken = HashSet::new();
+        let mut file_paths = HashSet::new();
+
+        for file in files {
+            let path = build_safe_path(name, &file.path)?;
+
+            if path.ancestors().skip(1).any(|dir| file_paths.contains(dir)) {
+                return Err(MetainfoError::PathConflict(path.display().to_string()));
+            }
+
+            let path = unique_path(path, &taken);
+            taken.extend(path.ancestors().filter(|dir| !dir.as_os_str().is_empty()).map(Path::to_path_buf));
+            file_paths.insert(path.clone());
+
+            layout_files.push(LayoutFile {
+                path,
+                length: file.length,
+                offset,
+            });
+
+            offset += file.length;
+        }
+
+        Ok(FileLayout {
+            files: layout_files,
+            total_length: offset,
+        })
+    }
+
+    pub fn files(&self) -> &[LayoutFile] {
+        &self.files
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    /// Split a byte range of the torrent into the slices of each file it covers.
+    ///
+    /// Empty files are skipped as no bytes can ever land in them.
+    pub fn slices(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let mut slices = Vec::new();
+        let end = offset + length;
+
+        for (file_index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+
+            if file_end <= offset || file.length == 0 {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+
+            slices.push(FileSlice {
+                file_index,
+                file_offset: start - file.offset,
+                length: stop - start,
+            });
+        }
+
+        slices
+    }
+}
+
+
+/// Build the relative path of a file from the torrent name and its path components.
+///
+/// Every component is sanitized, and the torrent is refused if the result could
+/// point anywhere other than inside the download directory.
+fn build_safe_path(name: &str, components: &[String]) -> Result<PathBuf, MetainfoError> {
+    let mut path = PathBuf::new();
+
+    for component in std::iter::once(name).chain(components.iter().map(String::as_str)) {
+        path.push(sanitize_component(component)?);
+    }
+
+    if !is_contained(&path) {
+        return Err(MetainfoError::UnsafePath(path.display().to_string()));
+    }
+
+    Ok(path)
+}
+
+
+/// The path with a number added before its extension if it's already taken: `a.txt`,
+/// `a.1.txt`, `a.2.txt`.
+fn unique_path(path: PathBuf, taken: &HashSet<PathBuf>) -> PathBuf {
+    if !taken.contains(&path) {
+        return path;
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+
+    (1..)
+        .map(|i| path.with_file_name(format!("{}.{}{}", stem, i, extension)))
+        .find(|path| !taken.contains(path))
+        .unwrap_or(path)
+}
+
+
+/// Clean up a single path component so that it is a valid file name on its own.
+///
+/// - `..` and `.` are refused as they refer to other directories.
+/// - Path separators and characters that aren't allowed in file names are replaced with `_`.
+/// - Reserved device names get a trailing `_` so they are treated as regular files.
+/// - Names that are too long are truncated, keeping the extension.
+pub fn sanitize_component(component: &str) -> Result<String, MetainfoError> {
+    let trimmed = component.trim_end_matches(['.', ' ']);
+
+    if component == ".." || component == "." || trimmed.is_empty() {
+        return Err(MetainfoError::UnsafePath(component.to_owned()));
+    }
+
+    let mut sanitized: String = trimmed
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let stem = sanitized.split('.').next().unwrap_or("").to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        sanitized.insert(stem.len(), '_');
+    }
+
+    if sanitized.len() > MAX_COMPONENT_LEN {
+        sanitized = truncate_component(&sanitized);
+    }
+
+    Ok(sanitized)
+}
+
+
+/// Shorten a file name to fit within the component limit while keeping its extension.
+fn truncate_component(component: &str) -> String {
+    let extension = match component.rfind('.') {
+        Some(i) if component.len() - i <= 16 => &component[i..],
+        _ => "",
+    };
+
+    let mut stem_len = MAX_COMPONENT_LEN - extension.len();
+    while !component.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+
+    format!("{}{}", &component[..stem_len], extension)
+}
+
+
+/// Check that a path only contains plain names, so it can't leave the directory it's joined to.
+pub fn is_contained(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[cfg(test)]
+fn test_files() -> Vec<DlFile> {
+    vec![
+        DlFile { path: vec!["a.txt".to_owned()], length: 5, md5sum: None },
+        DlFile { path: vec!["sub".to_owned(), "b.txt".to_owned()], length: 0, md5sum: None },
+        DlFile { path: vec!["c.txt".to_owned()], length: 5, md5sum: None },
+    ]
+}
+
+#[test]
+fn test_multi_file_layout() {
+    let layout = FileLayout::multi_file("name", &test_files()).unwrap();
+
+    assert_eq!(layout.total_length(), 10);
+    assert_eq!(layout.files()[1].path, PathBuf::from("name/sub/b.txt"));
+    assert_eq!(layout.files()[2].offset, 5);
+}
+
+#[test]
+fn test_single_file_layout() {
+    let layout = FileLayout::single_file("movie.mp4", 100).unwrap();
+
+    assert_eq!(layout.files().len(), 1);
+    assert_eq!(layout.files()[0].path, PathBuf::from("movie.mp4"));
+    assert_eq!(layout.slices(90, 20), vec![FileSlice { file_index: 0, file_offset: 90, length: 10 }]);
+}
+
+#[test]
+fn test_layout_slices() {
+    let layout = FileLayout::multi_file("name", &test_files()).unwrap();
+
+    // Spans the end of the first file, skips the empty one, then into the last.
+    assert_eq!(layout.slices(3, 4), vec![
+        FileSlice { file_index: 0, file_offset: 3, length: 2 },
+        FileSlice { file_index: 2, file_offset: 0, length: 2 },
+    ]);
+
+    assert_eq!(layout.slices(5, 5), vec![FileSlice { file_index: 2, file_offset: 0, length: 5 }]);
+}
+
+#[test]
+fn test_sanitize_component() {
+    assert_eq!(sanitize_component("file.txt").unwrap(), "file.txt");
+    assert_eq!(sanitize_component("a/b\\c").unwrap(), "a_b_c");
+    assert_eq!(sanitize_component("/etc").unwrap(), "_etc");
+    assert_eq!(sanitize_component("C:").unwrap(), "C_");
+    assert_eq!(sanitize_component("con.txt").unwrap(), "con_.txt");
+    assert_eq!(sanitize_component("LPT1").unwrap(), "LPT1_");
+    assert_eq!(sanitize_component("trailing. ").unwrap(), "trailing");
+
+    assert!(sanitize_component("..").is_err());
+    assert!(sanitize_component(".").is_err());
+    assert!(sanitize_component("...").is_err());
+
+    let long = format!("{}.mkv", "a".repeat(300));
+    let truncated = sanitize_component(&long).unwrap();
+    assert_eq!(truncated.len(), MAX_COMPONENT_LEN);
+    assert!(truncated.ends_with(".mkv"));
+
+    let long_unicode = "é".repeat(200);
+    assert!(sanitize_component(&long_unicode).unwrap().len() <= MAX_COMPONENT_LEN);
+}
+
+#[test]
+fn test_layout_refuses_escaping_paths() {
+    let files = vec![DlFile { path: vec!["..".to_owned(), "..".to_owned(), "evil".to_owned()], length: 1, md5sum: None }];
+    assert!(FileLayout::multi_file("name", &files).is_err());
+    assert!(FileLayout::single_file("..", 1).is_err());
+
+    let files = vec![DlFile { path: vec!["/etc".to_owned(), "passwd".to_owned()], length: 1, md5sum: None }];
+    let layout = FileLayout::multi_file("name", &files).unwrap();
+    assert_eq!(layout.files()[0].path, PathBuf::from("name/_etc/passwd"));
+}
+
+#[test]
+fn test_layout_disambiguates_colliding_paths() {
+    let file = |path: &[&str]| DlFile { path: path.iter().map(|c| c.to_string()).collect(), length: 1, md5sum: None };
+
+    let files = vec![file(&["a?.txt"]), file(&["a_.txt"]), file(&["a_.txt"]), file(&["dir", "b"]), file(&["dir"])];
+    let layout = FileLayout::multi_file("name", &files).unwrap();
+    let paths: Vec<&Path> = layout.files().iter().map(|file| file.path.as_path()).collect();
+    assert_eq!(paths, vec![
+        Path::new("name/a_.txt"),
+        Path::new("name/a_.1.txt"),
+        Path::new("name/a_.2.txt"),
+        Path::new("name/dir/b"),
+        Path::new("name/dir.1"),
+    ]);
+
+    // A file can't be used as a directory by a later file.
+    let files = vec![file(&["a"]), file(&["a", "b"])];
+    assert!(matches!(FileLayout::multi_file("name", &files), Err(MetainfoError::PathConflict(_))));
+}
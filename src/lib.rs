@@ -0,0 +1,70 @@
+//! A BitTorrent client you can embed.
+//!
+//! A Session runs any number of torrents side by side, each one controlled through its
+//! TorrentHandle. What happens to them can be followed on the event stream of the session.
+//!
+//! ```no_run
+//! use torrenter::{gen_peer_id, Session, Settings, Torrent};
+//!
+//! # async fn run() -> torrenter::Result<()> {
+//! let session = Session::new(gen_peer_id(), Settings::from_path("torrenter.toml")?)?;
+//! let mut events = session.subscribe();
+//!
+//! let torrent = Torrent::from_path("ubuntu.torrent")?;
+//! let handle = session.add_torrent(torrent, session.settings().download_options())?;
+//!
+//! while let Ok(event) = events.recv().await {
+//!     println!("{:?}", event);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Torrents have to be added from within a tokio runtime.
+
+pub mod api_server;
+pub mod create;
+pub mod download;
+pub mod error;
+pub mod events;
+pub mod logging;
+pub mod magnet;
+pub mod pieces;
+pub mod rate_limit;
+pub mod schedule;
+pub mod seeding;
+pub mod session;
+pub mod settings;
+pub mod storage;
+pub mod stream_server;
+pub mod tracker;
+
+mod bencode;
+mod disk;
+mod http_client;
+mod http_server;
+mod layout;
+mod message_handlers;
+mod metadata;
+// Every message of the protocol can be built, not all of them are sent yet.
+#[allow(dead_code)]
+mod messages;
+mod queue;
+mod reader;
+mod transmission_rpc;
+mod utils;
+mod web_seed;
+
+pub use crate::download::{DEFAULT_MAX_PEERS, DownloadOptions};
+pub use crate::error::{Error, Result};
+pub use crate::events::{Event, EventReceiver, SessionEvent};
+pub use crate::pieces::{PickMode, Priority};
+pub use crate::seeding::{SeedAction, SeedGoals};
+pub use crate::session::{InfoHash, Session, SessionStats, TorrentHandle, TorrentState, TorrentStatus};
+pub use crate::settings::{EncryptionPolicy, Settings};
+pub use crate::storage::{AllocationMode, FsStorage, MemoryStorage, ResumeData, Storage};
+pub use crate::utils::gen_peer_id;
+pub use crate::utils::torrents::{FileSummary, MetainfoError, Torrent, TorrentSummary};
+
+/// The port we listen on for peers unless told otherwise.
+pub const PORT: i16 = 6682;
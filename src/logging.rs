@@ -0,0 +1,97 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::{Error, Result};
+
+/// The filter used when neither the command line nor RUST_LOG asks for one.
+pub const DEFAULT_LOG_FILTER: &str = "warn";
+
+/// Send the logs of the whole process to a file, or to stderr without one.
+///
+/// The filter takes a level, or levels per module like RUST_LOG does:
+///
+/// ```text
+/// debug
+/// warn,torrenter::message_handlers=trace
+/// ```
+///
+/// Every line carries the spans it was logged in, so a single peer can be followed with e.g.
+///
+/// ```text
+/// grep 'peer{address=1.2.3.4:6881}' torrenter.log
+/// ```
+pub fn init(filter: &str, file: Option<&Path>) -> Result<()> {
+    let filter = EnvFilter::try_new(filter).map_err(|e| Error::Config(format!("Invalid log filter {}: {}", filter, e)))?;
+
+    let subscriber: Box<dyn Subscriber + Send + Sync> = match file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| Error::Config(format!("Unable to open the log file {}: {}", path.display(), e)))?;
+
+            Box::new(subscriber(filter, Mutex::new(file), false))
+        }
+        None => Box::new(subscriber(filter, io::stderr, true)),
+    };
+
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| Error::Config(e.to_string()))
+}
+
+fn subscriber<W>(filter: EnvFilter, writer: W, ansi: bool) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .finish()
+}
+
+#[test]
+fn test_log_spans() {
+    use std::sync::Arc;
+
+    use tracing::{debug, info_span, trace};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = subscriber(EnvFilter::try_new("debug").unwrap(), move || writer.clone(), false);
+
+    tracing::subscriber::with_default(subscriber, || {
+        let _torrent = info_span!("torrent", info_hash = "06cb").entered();
+        let _peer = info_span!("peer", address = "127.0.0.1:6881").entered();
+
+        debug!("unchoked");
+        trace!("not logged at the debug level");
+    });
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("torrent{info_hash=\"06cb\"}:peer{address=\"127.0.0.1:6881\"}"));
+    assert!(logs.contains("unchoked"));
+    assert!(!logs.contains("not logged"));
+
+    assert!(EnvFilter::try_new("warn,torrenter::download=trace").is_ok());
+    assert!(EnvFilter::try_new("torrenter=loud").is_err());
+}
@@ -0,0 +1,115 @@
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, bail};
+use url::Url;
+
+use crate::session::{info_hash_from_hex, InfoHash};
+
+/// The parts of a magnet link we understand.
+///
+/// ```text
+/// magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&tr=<tracker>&x.pe=<ip:port>
+/// ```
+///
+/// The info hash is either 40 hex characters or 32 base32 characters. Peers given by host
+/// name instead of address are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    /// Peers to ask for the metadata, besides the ones of the trackers.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> anyhow::Result<MagnetLink> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            bail!("Not a magnet link: {}", uri);
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or_else(|| anyhow!("The magnet link has no BitTorrent info hash"))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+fn parse_info_hash(hash: &str) -> anyhow::Result<InfoHash> {
+    let info_hash = match hash.len() {
+        40 => info_hash_from_hex(hash),
+        32 => decode_base32(hash).and_then(|bytes| bytes.try_into().ok()),
+        _ => None,
+    };
+
+    info_hash.ok_or_else(|| anyhow!("Invalid info hash in the magnet link: {}", hash))
+}
+
+/// Decode RFC 4648 base32 without padding.
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[test]
+fn test_parse_magnet_link() {
+    let expected: InfoHash = [0xdd, 0x82, 0x55, 0xec, 0xdc, 0x7c, 0xa5, 0x5f, 0xb0, 0xbb, 0xf8, 0x13, 0x23, 0xd8, 0x70, 0x62, 0xdb, 0x1f, 0x6d, 0x1c];
+
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&dn=Big+Buck+Bunny&tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337").unwrap();
+    assert_eq!(magnet.info_hash, expected);
+    assert_eq!(magnet.name.as_deref(), Some("Big Buck Bunny"));
+    assert_eq!(magnet.trackers, vec!["udp://explodie.org:6969", "udp://tracker.opentrackr.org:1337"]);
+
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4").unwrap();
+    assert_eq!(magnet.info_hash, expected);
+    assert_eq!(magnet.name, None);
+
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&x.pe=10.0.0.1%3A6881&x.pe=peer.example.com%3A6881").unwrap();
+    assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+
+    assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+    assert!(MagnetLink::parse("magnet:?xt=urn:btih:abcd").is_err());
+    assert!(MagnetLink::parse("http://example.com/?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c").is_err());
+}
@@ -1,30 +1,23 @@
-#![feature(const_int_pow)]
+use clap::Parser;
 
-// TODO: Remove this once finished.
-// Don't show warnings for unused code when developping.
-#![allow(dead_code)]
-#![allow(unused_variables)]
+use crate::cli::Cli;
 
-use crate::download::download_torrent;
-use crate::utils::gen_peer_id;
-
-mod utils;
-mod messages;
-mod download;
-mod tracker;
-mod message_handlers;
-mod pieces;
-mod queue;
-
-const PORT: i16 = 6682;
+mod cli;
+mod dashboard;
+mod tui;
 
 
 #[tokio::main]
 async fn main() {
-    let peer_id = gen_peer_id();
-    download_torrent(peer_id, "test-tor.torrent").await;
-}
-
-
+    let cli = Cli::parse();
 
+    if let Err(e) = cli.init_logging() {
+        cli.print_error(&e);
+        std::process::exit(1);
+    }
 
+    if let Err(e) = cli.run().await {
+        cli.print_error(&e);
+        std::process::exit(1);
+    }
+}
@@ -1,237 +1,581 @@
-use std::io::prelude::*;
-use std::net::{Shutdown, TcpStream};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
 
-use anyhow::{anyhow, Result};
 use bytebuffer::ByteBuffer;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::Sender;
+use tracing::{debug, trace};
 
-use crate::download::PiecesManager;
+use crate::download::{check_handshake_msg, PeerSlot, TorrentContext};
+use crate::error::{Error, Result};
 use crate::messages;
-use crate::messages::{GenericPayload, parse};
+use crate::messages::{GenericPayload, HANDSHAKE_LEN, parse};
 use crate::queue::{PieceBlock, Queue};
-use crate::utils::torrents::Torrent;
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+
+/// The longest message we take from a peer, blocks are 16 KiB and this fits the bitfield of 8M pieces.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// The longest block a peer may ask for, most ask for 16 KiB.
+const MAX_REQUEST_LEN: u64 = 128 * 1024;
+
+/// How many blocks a peer may ask for before we've sent them.
+const MAX_PEER_REQUESTS: usize = 256;
 
 pub struct PieceChannelPayload {
-    pub offset: u64,
+    pub index: u64,
+    pub begin: u64,
     pub block: Vec<u8>,
 }
 
-pub struct MessageHandler<'a> {
-    torrent: &'a Torrent,
-    stream: &'a mut TcpStream,
+pub struct MessageHandler<'a, S> {
+    context: &'a TorrentContext,
+    stream: &'a mut S,
     file_sender: Sender<PieceChannelPayload>,
-    pieces: PiecesManager,
     queue: &'a mut Queue<'a>,
+    /// The bytes sent to the peer since take_sent was last called.
+    sent: u64,
+    /// The pieces the peer has told us about, they no longer count as available once it's gone.
+    peer_pieces: Vec<bool>,
+    peer_piece_count: u64,
+    /// The pieces we've told the peer about, and the verifications of the pieces by then.
+    announced: Vec<bool>,
+    announced_verifications: u64,
+    /// The resets of the pieces by the time the pieces of the peer were last queued.
+    queued_resets: u64,
+    /// Whether we last told the peer we're interested.
+    am_interested: bool,
+    peer_interested: bool,
+    /// Held while the peer is unchoked, see TorrentContext::upload_slots.
+    upload_slot: Option<PeerSlot>,
+    /// The blocks the peer asked for that haven't been sent yet.
+    peer_requests: VecDeque<PieceBlock>,
 }
 
-impl MessageHandler<'_> {
-    pub fn new<'a>(torrent: &'a Torrent, stream: &'a mut TcpStream, file_sender: Sender<PieceChannelPayload>, pieces: PiecesManager, queue: &'a mut Queue<'a>) -> MessageHandler<'a> {
+impl<'a, S: AsyncWrite + Unpin> MessageHandler<'a, S> {
+    pub fn new(
+        context: &'a TorrentContext,
+        stream: &'a mut S,
+        file_sender: Sender<PieceChannelPayload>,
+        queue: &'a mut Queue<'a>,
+    ) -> MessageHandler<'a, S> {
+        let num_pieces = context.torrent.num_pieces() as usize;
+
         MessageHandler {
-            torrent,
+            context,
             stream,
             file_sender,
-            pieces,
             queue,
+            sent: 0,
+            peer_pieces: vec![false; num_pieces],
+            peer_piece_count: 0,
+            announced: vec![false; num_pieces],
+            announced_verifications: 0,
+            queued_resets: 0,
+            am_interested: false,
+            peer_interested: false,
+            upload_slot: None,
+            peer_requests: VecDeque::new(),
         }
     }
 
+    /// Get the bytes sent to the peer since the last call, for the upload limits.
+    pub fn take_sent(&mut self) -> u64 {
+        std::mem::take(&mut self.sent)
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.stream.write_all(msg).await.map_err(Error::PeerConnection)?;
+        self.sent += msg.len() as u64;
+
+        Ok(())
+    }
+
+    /// Whether neither side has anything left to get from the other: every wanted piece is in
+    /// and the peer has them all.
+    pub fn is_finished(&self) -> bool {
+        self.peer_piece_count == self.context.torrent.num_pieces() && self.context.pieces.lock().unwrap().is_done()
+    }
+
+    /// Whether the peer asked for blocks we haven't sent yet.
+    pub fn has_requests(&self) -> bool {
+        !self.peer_requests.is_empty()
+    }
+
     /// Route and parse all the messages.
     /// Each message will be routed to their corresponding handler.
     ///
-    ///     0 : choke
-    ///     1 : unchoke
-    ///     4 : have
-    ///     5 : bitfield
-    ///     7 : piece
+    /// - 0 : choke
+    /// - 1 : unchoke
+    /// - 2 : interested
+    /// - 3 : not interested
+    /// - 4 : have
+    /// - 5 : bitfield
+    /// - 6 : request
+    /// - 7 : piece
+    /// - 8 : cancel
     ///
     pub async fn router(&mut self, msg: ByteBuffer) -> Result<()> {
-        if msg.len() == 0 {
-            return Err(anyhow!("Peer connection closed"));
-        }
-
-        let parsed_msg = parse(msg);
+        let parsed_msg = parse(msg)?;
 
         match parsed_msg.id {
             0 => self.choke(),
-            1 => self.unchoke(),
-            4 => self.have(parsed_msg.payload),
-            5 => self.bitfield(parsed_msg.payload),
-            7 => {
-                self.piece(parsed_msg.payload).await;
+            1 => self.unchoke().await?,
+            2 => self.peer_interested().await?,
+            3 => self.peer_not_interested().await?,
+            4 => self.have(parsed_msg.payload).await?,
+            5 => self.bitfield(parsed_msg.payload)?,
+            6 => self.request(parsed_msg.payload)?,
+            7 => self.piece(parsed_msg.payload).await?,
+            8 => self.cancel(parsed_msg.payload),
+            // Messages we don't act on, like the DHT port.
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+
+    /// Tell the peer which pieces we have, then that we're interested if we still need some.
+    pub async fn start(&mut self) -> Result<()> {
+        let bitfield = {
+            let pieces = self.context.pieces.lock().unwrap();
+            self.announced_verifications = pieces.verifications();
+            self.queued_resets = pieces.resets();
+
+            for (piece_index, announced) in self.announced.iter_mut().enumerate() {
+                *announced = pieces.is_verified(piece_index as u64);
             }
-            _ => {
-                println!("Unknown message ID: {:?}", parsed_msg.id);
+
+            build_bitfield(&self.announced)
+        };
+
+        // The bitfield can be left out when we have nothing.
+        if self.announced.contains(&true) {
+            let send_msg = messages::build_bitfield(&ByteBuffer::from_bytes(&bitfield));
+            self.send(&send_msg.to_bytes()).await?;
+        }
+
+        self.update_interest().await
+    }
+
+    /// Let the peer know whether we're interested, when that changed since we last did.
+    ///
+    /// We lose interest once every block is in, and it comes back when a piece fails its
+    /// hash check. The connection is kept for as long as the peer downloads from us.
+    pub async fn update_interest(&mut self) -> Result<()> {
+        let interested = !self.context.pieces.lock().unwrap().is_done();
+        if interested == self.am_interested {
+            return Ok(());
+        }
+        self.am_interested = interested;
+
+        if interested {
+            debug!("sending interested");
+            self.send(&messages::build_interested().to_bytes()).await
+        } else {
+            debug!("every piece is in, sending not interested");
+            self.send(&messages::build_not_interested().to_bytes()).await
+        }
+    }
+
+    /// Queue the pieces of the peer again once pieces were thrown away, so that the blocks
+    /// of the pieces that failed their hash check are requested again.
+    pub async fn requeue_pieces(&mut self) -> Result<()> {
+        {
+            let pieces = self.context.pieces.lock().unwrap();
+            if pieces.resets() == self.queued_resets {
+                return Ok(());
+            }
+            self.queued_resets = pieces.resets();
+
+            for (piece_index, has_piece) in self.peer_pieces.iter().enumerate() {
+                if *has_piece && pieces.wants_piece(piece_index as u64) {
+                    self.queue.queue(piece_index as u64);
+                }
             }
         }
 
-        return Ok(());
+        self.request_piece().await
     }
 
+    /// Tell the peer about the pieces that passed the hash check since we last did.
+    pub async fn announce_pieces(&mut self) -> Result<()> {
+        let mut haves = Vec::new();
 
-    /// Get an entire message from a peer.
-    ///
-    /// Even though pieces should be around 16kB, it's possible that they can be larger.
-    /// If this is the case, this function will fail to receive a piece.
-    pub fn get_whole_msg(&mut self) -> ByteBuffer {
-        let mut whole_msg: ByteBuffer = ByteBuffer::new();
+        {
+            let pieces = self.context.pieces.lock().unwrap();
+            if pieces.verifications() == self.announced_verifications {
+                return Ok(());
+            }
+            self.announced_verifications = pieces.verifications();
+
+            for (piece_index, announced) in self.announced.iter_mut().enumerate() {
+                if !*announced && pieces.is_verified(piece_index as u64) {
+                    *announced = true;
+                    haves.extend(messages::build_have(piece_index as u32).to_bytes());
+                }
+            }
+        }
 
-        // Get the length from the first message.
-        let buf: &mut [u8; 1028 * 36] = &mut [0; 1028 * 36];
-        let len = self.stream.read(buf).expect("Unable to receive from peer");
-        whole_msg.write_bytes(&buf[0..len]);
+        if haves.is_empty() {
+            return Ok(());
+        }
 
-        return whole_msg;
+        self.send(&haves).await
     }
 
+    /// Start uploading to the peer if it's interested and there's an upload slot for it.
+    pub async fn unchoke_peer(&mut self) -> Result<()> {
+        if !self.peer_interested || self.upload_slot.is_some() {
+            return Ok(());
+        }
+
+        self.upload_slot = self.context.upload_slots.take();
+        if self.upload_slot.is_none() {
+            return Ok(());
+        }
 
-    /// Establish the initial contact with a peer, immediately afterwards we send an intersted message.
-    pub fn handshake(&mut self) {
-        let buf: &mut [u8; 1028] = &mut [0; 1028];
-        self.stream.read(buf).expect("Handshake has failed");
-        self.interested();
+        debug!("unchoking peer");
+        self.send(&messages::build_unchoke().to_bytes()).await
     }
 
-    /// Let the peer know we're interesting in communicating.
-    pub fn interested(&mut self) {
-        let send_msg = messages::build_interested();
-        self.stream.write(&send_msg.to_bytes()).expect("Unable to send interested");
-        println!("SENT INTERESTED!");
+    /// Stop uploading to the peer and give its upload slot to another one.
+    async fn choke_peer(&mut self) -> Result<()> {
+        self.peer_requests.clear();
+
+        if self.upload_slot.take().is_none() {
+            return Ok(());
+        }
+
+        debug!("choking peer");
+        self.send(&messages::build_choke().to_bytes()).await
     }
 
-    /// The peer has stopped communication with us
+    /// The peer doesn't want to send us anything for now, wait for it to unchoke us again.
+    ///
+    /// The peer drops the requests it hasn't answered yet.
     fn choke(&mut self) {
-        println!("CHOKED");
-        self.stream.shutdown(Shutdown::Both).expect("The peer has choked us");
+        debug!("choked");
+        self.queue.choked = true;
+        self.queue.requested = 0;
     }
 
     /// Start to requst pieces from a peer
-    fn unchoke(&mut self) {
-        println!("UNCHOKING");
+    async fn unchoke(&mut self) -> Result<()> {
+        debug!("unchoked");
         self.queue.choked = false;
-        self.request_piece();
+        self.request_piece().await
+    }
+
+    /// The peer would like to download from us.
+    async fn peer_interested(&mut self) -> Result<()> {
+        debug!("peer interested");
+        self.peer_interested = true;
+        self.unchoke_peer().await
+    }
+
+    async fn peer_not_interested(&mut self) -> Result<()> {
+        debug!("peer not interested");
+        self.peer_interested = false;
+        self.choke_peer().await
     }
 
 
     /// A peer has indicted that they have a certain piece.
-    fn have(&mut self, payload: GenericPayload) {
-        println!("HAVE");
-        let piece_index = payload.index;
-        let queue_empty = self.queue.len() == 0;
-
-        self.queue.queue(piece_index as u64);
-        if queue_empty {
-            self.request_piece()
+    async fn have(&mut self, payload: GenericPayload) -> Result<()> {
+        let piece_index = payload.piece_index.unwrap_or_default() as u64;
+        trace!(piece = piece_index, "have");
+
+        if piece_index >= self.context.torrent.num_pieces() {
+            return Err(Error::PeerProtocol(format!("The peer has piece {} of {}", piece_index, self.context.torrent.num_pieces())));
         }
+
+        if !std::mem::replace(&mut self.peer_pieces[piece_index as usize], true) {
+            self.peer_piece_count += 1;
+            self.context.pieces.lock().unwrap().add_availability(piece_index);
+        }
+
+        self.queue.queue(piece_index);
+        self.request_piece().await
     }
 
     /// Handle bitfield messages which indicate which are the pieces that the peer has.
     ///
     /// For example, the a bitfield of 01111 indicates that the peer is missing the first piece but has all the others.
     ///
-    fn bitfield(&mut self, payload: GenericPayload) {
-        println!("BITFIELD");
+    fn bitfield(&mut self, payload: GenericPayload) -> Result<()> {
+        let bf = payload.bitfield.map(|bitfield| bitfield.to_bytes()).unwrap_or_default();
+        let num_pieces = self.context.torrent.num_pieces();
+
+        if bf.len() as u64 != num_pieces.div_ceil(8) {
+            return Err(Error::PeerProtocol(format!("A bitfield of {} bytes for {} pieces", bf.len(), num_pieces)));
+        }
 
-        let bf = payload.bitfield.as_ref().unwrap().to_bytes();
-        let available_pieces = parse_bitfield(bf);
+        // The spare bits at the end should be zero, but they don't hurt anyone.
+        let available_pieces: Vec<u64> = parse_bitfield(bf).into_iter().filter(|index| *index < num_pieces).collect();
+        debug!(pieces = available_pieces.len(), "bitfield");
 
         // Add piece indexes to the download queue
+        let mut pieces = self.context.pieces.lock().unwrap();
         for piece_index in available_pieces {
+            if !std::mem::replace(&mut self.peer_pieces[piece_index as usize], true) {
+                self.peer_piece_count += 1;
+                pieces.add_availability(piece_index);
+            }
             self.queue.queue(piece_index);
         }
+
+        Ok(())
     }
 
 
-    /// Handle piece message
+    /// The peer asks for a block, it's sent by upload_block.
     ///
-    /// - Add piece to the recieved vec
-    /// - Write to file
-    /// - Request new pieces if not finished
-    async fn piece(&mut self, payload: GenericPayload) {
+    /// Requests while the peer is choked and requests for pieces we don't have are dropped,
+    /// the peer asks again once it's unchoked or for another piece.
+    fn request(&mut self, payload: GenericPayload) -> Result<()> {
         let piece_block = PieceBlock {
             index: payload.index as u64,
             begin: payload.begin as u64,
+            length: payload.length.map(u64::from),
+        };
+        trace!(piece = piece_block.index, begin = piece_block.begin, "block requested");
+        check_request(&self.context.torrent, &piece_block)?;
+
+        if self.upload_slot.is_none() || !self.context.pieces.lock().unwrap().is_verified(piece_block.index) {
+            return Ok(());
+        }
+        if self.peer_requests.len() >= MAX_PEER_REQUESTS {
+            return Err(Error::PeerProtocol(format!("The peer asked for more than {} blocks at once", MAX_PEER_REQUESTS)));
+        }
+
+        self.peer_requests.push_back(piece_block);
+
+        Ok(())
+    }
+
+    /// The peer no longer wants a block it asked for.
+    fn cancel(&mut self, payload: GenericPayload) {
+        let (index, begin, length) = (payload.index as u64, payload.begin as u64, payload.length.map(u64::from));
+        trace!(piece = index, begin, "request cancelled");
+
+        self.peer_requests.retain(|block| (block.index, block.begin, block.length) != (index, begin, length));
+    }
+
+    /// Send the peer the next block it asked for, read from the pieces on disk.
+    pub async fn upload_block(&mut self) -> Result<()> {
+        let piece_block = match self.peer_requests.pop_front() {
+            Some(piece_block) => piece_block,
+            None => return Ok(()),
+        };
+        let length = piece_block.length.unwrap_or_default();
+
+        let block = self.context.disk.read_block(piece_block.index, piece_block.begin, length).await?;
+        let payload = GenericPayload {
+            index: piece_block.index as u32,
+            begin: piece_block.begin as u32,
             length: None,
+            piece_index: None,
+            block: Some(ByteBuffer::from_bytes(&block)),
+            bitfield: None,
         };
 
-        // Calculate the index offset on where we have to write the received piece.
-        let offset = payload.index as u64 * self.torrent.info.piece_length + payload.begin as u64;
+        trace!(piece = piece_block.index, begin = piece_block.begin, length, "block sent");
+        self.send(&messages::build_piece(&payload).to_bytes()).await?;
+        self.context.uploaded.fetch_add(length, Ordering::Relaxed);
+
+        Ok(())
+    }
+
 
+    /// Handle piece message
+    ///
+    /// - Check that it's a block we could have asked for
+    /// - Write to file, the block counts as received once it's written
+    /// - Request new pieces
+    async fn piece(&mut self, payload: GenericPayload) -> Result<()> {
         let payload = PieceChannelPayload {
-            offset,
-            block: payload.block.unwrap().to_bytes(),
+            index: payload.index as u64,
+            begin: payload.begin as u64,
+            block: payload.block.map(|block| block.to_bytes()).unwrap_or_default(),
         };
 
-        let download_finished: bool;
+        trace!(piece = payload.index, begin = payload.begin, length = payload.block.len(), "block received");
+        check_block(&self.context.torrent, &payload)?;
+        self.queue.requested = self.queue.requested.saturating_sub(1);
 
         {
-            let mut pieces = self.pieces.lock().unwrap();
-            pieces.add_received(piece_block.clone());
+            // Send message to the channel, nobody is receiving when the torrent is being stopped.
+            let _ = self.file_sender.send(payload).await;
+        };
+
+        self.request_piece().await
+    }
+
+
+    /// Request blocks from the job queue until the pipeline of the peer is full.
+    ///
+    /// The requests are sent together once the pieces are unlocked.
+    async fn request_piece(&mut self) -> Result<()> {
+
+        // Don't request anything if we're choked, the peer will let us know when it unchokes us.
+        if self.queue.choked {
+            return Ok(());
         }
 
-        {
-            // Send message to the channel
-            self.file_sender.send(payload).await;
-        };
+        let pipeline_depth = self.context.settings.read().unwrap().pipeline_depth;
+        let mut requests = Vec::new();
 
         {
-            let pieces = self.pieces.lock().unwrap();
-            download_finished = pieces.is_done();
+            let mut pieces = self.context.pieces.lock().unwrap();
+
+            // Grab the first block of the piece the picker wants most
+            while self.queue.requested < pipeline_depth {
+                let piece_block = match self.queue.deque_by_rank(|index| pieces.wants_piece(index).then(|| pieces.pick_rank(index))) {
+                    Some(piece_block) => piece_block,
+                    None => break,
+                };
+
+                // Check if that piece is still needed and request if so
+                if pieces.needed(piece_block) {
+                    trace!(piece = piece_block.index, begin = piece_block.begin, "requesting");
+                    requests.extend(messages::build_request(piece_block).to_bytes());
+                    pieces.add_requested(piece_block);
+                    self.queue.requested += 1;
+                }
+            }
         }
 
-        // Shutdown if finished
-        if download_finished {
-            println!("Torrent downloaded!");
-            self.stream.shutdown(Shutdown::Both).expect("Unable to shutdown stream");
+        if requests.is_empty() {
+            return Ok(());
+        }
 
-            // Otherwise, request new pieces
-        } else {
-            self.request_piece();
+        self.send(&requests).await
+    }
+}
+
+impl<S> Drop for MessageHandler<'_, S> {
+    /// The peer is gone however its connection ended, so its pieces are no longer available.
+    fn drop(&mut self) {
+        let peer_pieces: Vec<u64> = (0..self.peer_pieces.len() as u64).filter(|i| self.peer_pieces[*i as usize]).collect();
+        if let Ok(mut pieces) = self.context.pieces.lock() {
+            pieces.remove_availability(&peer_pieces);
         }
     }
+}
 
+/// Read the next whole message from a peer, length prefix included.
+///
+/// Keep-alives are skipped. A length over MAX_MESSAGE_LEN is a protocol error rather than
+/// an allocation the peer gets to pick.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ByteBuffer> {
+    loop {
+        let mut prefix = [0; 4];
+        reader.read_exact(&mut prefix).await.map_err(Error::PeerConnection)?;
+
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len == 0 {
+            continue;
+        }
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::PeerProtocol(format!("A message of {} bytes is too long", len)));
+        }
 
-    /// Request the first block in the job queue.
-    fn request_piece(&mut self) {
+        let mut msg = vec![0; 4 + len];
+        msg[..4].copy_from_slice(&prefix);
+        reader.read_exact(&mut msg[4..]).await.map_err(Error::PeerConnection)?;
 
-        // Don't request anything if we're choked.
-        // TODO: Add error handling to retry if we're choked.
-        if self.queue.choked {
-            println!("We're choked!");
-            return;
-        }
+        return Ok(ByteBuffer::from_bytes(&msg));
+    }
+}
 
-        let mut pieces = self.pieces.lock().unwrap();
+/// Read the handshake of a peer we connected to.
+pub async fn read_peer_handshake<R: AsyncRead + Unpin>(reader: &mut R, torrent: &Torrent) -> Result<()> {
+    let mut handshake = [0; HANDSHAKE_LEN];
+    reader.read_exact(&mut handshake).await.map_err(Error::PeerConnection)?;
 
-        while self.queue.len() > 0 {
+    check_peer_handshake(&handshake, torrent)
+}
 
-            // Grab the first piece in the queue
-            let piece_block = self.queue.deque().unwrap();
+/// Check the handshake of a peer we connected to, it has to be for the torrent we asked for.
+fn check_peer_handshake(handshake: &[u8; HANDSHAKE_LEN], torrent: &Torrent) -> Result<()> {
+    if !check_handshake_msg(&mut ByteBuffer::from_bytes(handshake)) {
+        return Err(Error::PeerProtocol("Not a BitTorrent handshake".to_string()));
+    }
 
-            // Check if that piece is still needed and request if so
-            if pieces.needed(piece_block) {
-                let request = messages::build_request(piece_block);
-                self.stream.write(&*request.to_bytes());
-                pieces.add_requested(piece_block);
+    if torrent.info_hash.as_ref().map(|info_hash| &info_hash[..]) != Some(&handshake[28..48]) {
+        return Err(Error::PeerProtocol("The handshake is for another torrent".to_string()));
+    }
 
-                break;
-            }
-        }
+    Ok(())
+}
+
+/// Check that a block is one we could have asked for, before it's counted or written.
+fn check_block(torrent: &Torrent, payload: &PieceChannelPayload) -> Result<()> {
+    let (index, begin) = (payload.index, payload.begin);
+
+    if index >= torrent.num_pieces() {
+        return Err(Error::PeerProtocol(format!("Piece {} is out of range", index)));
+    }
+
+    let block_index = begin / BLOCK_LEN;
+    if begin % BLOCK_LEN != 0 || block_index >= torrent.get_blocks_per_piece(index) {
+        return Err(Error::PeerProtocol(format!("Piece {} has no block at {}", index, begin)));
+    }
+
+    let expected = torrent.get_block_len(index, block_index);
+    if payload.block.len() as u64 != expected {
+        return Err(Error::PeerProtocol(format!("The block at {} of piece {} is {} bytes instead of {}", begin, index, payload.block.len(), expected)));
     }
+
+    Ok(())
+}
+
+/// Check that a peer asks for a block that lies within a piece, and isn't too long.
+fn check_request(torrent: &Torrent, piece_block: &PieceBlock) -> Result<()> {
+    let (index, begin, length) = (piece_block.index, piece_block.begin, piece_block.length.unwrap_or_default());
+
+    if index >= torrent.num_pieces() {
+        return Err(Error::PeerProtocol(format!("Piece {} is out of range", index)));
+    }
+    if length == 0 || length > MAX_REQUEST_LEN || begin + length > torrent.get_piece_len(index) {
+        return Err(Error::PeerProtocol(format!("Piece {} has no block of {} bytes at {}", index, length, begin)));
+    }
+
+    Ok(())
+}
+
+/// Build a bitfield from whether we have each piece, the reverse of parse_bitfield.
+///
+/// ```text
+/// [true, false, true] is 1010 0000
+/// ```
+fn build_bitfield(has: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0; has.len().div_ceil(8)];
+
+    for (i, _) in has.iter().enumerate().filter(|(_, has)| **has) {
+        bitfield[i / 8] |= 0x80 >> (i % 8);
+    }
+
+    bitfield
 }
 
 /// Parse the bitfield.
 ///
-///     For example: a bitfield of 255 is 1111 1111 in binary
-///     This means that the peer has the pieces 8 pieces
+/// ```text
+/// For example: a bitfield of 255 is 1111 1111 in binary
+/// This means that the peer has the pieces 8 pieces
 ///
-///     A bitfield of 1111 1110 means that the peer has 7 pieces, excluding the last piece.
-///     A bitfield of 0111 1111 means that the first piece is missing.
+/// A bitfield of 1111 1110 means that the peer has 7 pieces, excluding the last piece.
+/// A bitfield of 0111 1111 means that the first piece is missing.
+/// ```
 fn parse_bitfield(bitfield: Vec<u8>) -> Vec<u64> {
     let mut piece_indexes: Vec<u64> = Vec::new();
 
     // Iterate over all bytes
     for (i, b) in bitfield.iter().enumerate() {
-        let mut byte = b.clone();
+        let mut byte = *b;
 
         // Iterate over each bit
         for j in 0..8 {
@@ -239,11 +583,11 @@ fn parse_bitfield(bitfield: Vec<u8>) -> Vec<u64> {
             if byte % 2 > 0 {
                 piece_indexes.push((i * 8 + 7 - j) as u64);
             }
-            byte = byte / 2;
+            byte /= 2;
         }
     }
 
-    return piece_indexes;
+    piece_indexes
 }
 
 
@@ -257,5 +601,131 @@ fn test_parse_bitfield() {
     let bitfield: Vec<u8> = vec![255];
     let piece_indexes = parse_bitfield(bitfield);
     assert_eq!(piece_indexes, vec![7, 6, 5, 4, 3, 2, 1, 0]);
+
+    let bitfield = build_bitfield(&[true, false, true, false, false, false, false, false, true]);
+    assert_eq!(bitfield, vec![0b1010_0000, 0b1000_0000]);
+    assert_eq!(parse_bitfield(bitfield), vec![2, 0, 8]);
+}
+
+
+#[tokio::test]
+async fn test_read_message() {
+    let mut bytes: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 5, 4, 0, 0, 0, 3, 0, 0];
+
+    // The keep-alive is skipped and messages are split on their length prefix.
+    assert_eq!(read_message(&mut bytes).await.unwrap().to_bytes(), vec![0, 0, 0, 1, 1]);
+    assert_eq!(read_message(&mut bytes).await.unwrap().to_bytes(), vec![0, 0, 0, 5, 4, 0, 0, 0, 3]);
+    assert!(matches!(read_message(&mut bytes).await, Err(Error::PeerConnection(_))));
+
+    let mut too_long: &[u8] = &[0xff, 0xff, 0xff, 0xff, 7];
+    assert!(matches!(read_message(&mut too_long).await, Err(Error::PeerProtocol(_))));
+}
+
+#[test]
+fn test_check_block() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let block = |index, begin, len| PieceChannelPayload { index, begin, block: vec![0; len] };
+
+    assert!(check_block(&torrent, &block(0, 0, BLOCK_LEN as usize)).is_ok());
+    assert!(check_block(&torrent, &block(torrent.num_pieces(), 0, BLOCK_LEN as usize)).is_err());
+    assert!(check_block(&torrent, &block(0, 1, BLOCK_LEN as usize)).is_err());
+    assert!(check_block(&torrent, &block(0, 0, 10)).is_err());
+    assert!(check_block(&torrent, &block(0, 1 << 30, BLOCK_LEN as usize)).is_err());
+
+    let mut handshake = [0; HANDSHAKE_LEN];
+    handshake.copy_from_slice(&messages::build_peer_handshake(&torrent.info_hash.unwrap(), &ByteBuffer::from_bytes(&[1; 20])).to_bytes());
+    assert!(check_peer_handshake(&handshake, &torrent).is_ok());
+
+    handshake[30] ^= 1;
+    assert!(check_peer_handshake(&handshake, &torrent).is_err());
 }
 
+#[tokio::test]
+async fn test_peer_availability() {
+    use std::cmp::Reverse;
+    use std::sync::Arc;
+
+    use crate::download::test_context;
+    use crate::storage::MemoryStorage;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let context = test_context(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())));
+    let availability = |piece_index| context.pieces.lock().unwrap().pick_rank(piece_index).4;
+
+    let (mut stream, _peer) = tokio::io::duplex(1024);
+    let (file_sender, _file_receiver) = tokio::sync::mpsc::channel(1);
+    let mut queue = Queue::new(&torrent);
+    let mut handler = MessageHandler::new(&context, &mut stream, file_sender, &mut queue);
+
+    // Pieces 0 and 1 from the bitfield, then 1 again and 2 from haves.
+    handler.router(ByteBuffer::from_bytes(&[0, 0, 0, 3, 5, 0b1100_0000, 0])).await.unwrap();
+    handler.router(ByteBuffer::from_bytes(&[0, 0, 0, 5, 4, 0, 0, 0, 1])).await.unwrap();
+    handler.router(ByteBuffer::from_bytes(&[0, 0, 0, 5, 4, 0, 0, 0, 2])).await.unwrap();
+    assert_eq!((availability(0), availability(1), availability(2), availability(3)), (Reverse(1), Reverse(1), Reverse(1), Reverse(0)));
+
+    // Whatever ends the connection, the peer's pieces are no longer available.
+    drop(handler);
+    assert_eq!((availability(0), availability(1), availability(2)), (Reverse(0), Reverse(0), Reverse(0)));
+}
+
+#[tokio::test]
+async fn test_upload_blocks() {
+    use std::sync::Arc;
+
+    use crate::download::{PeerSlots, test_context};
+    use crate::storage::{MemoryStorage, Storage};
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let data: Vec<u8> = (0..torrent.get_piece_len(0)).map(|i| i as u8).collect();
+    let mut storage = MemoryStorage::new(torrent.clone());
+    storage.write_block(0, 0, &data).unwrap();
+
+    let mut context = test_context(torrent.clone(), Box::new(storage));
+    context.upload_slots = PeerSlots::new(1);
+    context.pieces.lock().unwrap().mark_complete(0);
+
+    let (mut stream, mut peer) = tokio::io::duplex(64 * 1024);
+    let (file_sender, _file_receiver) = tokio::sync::mpsc::channel(1);
+    let mut queue = Queue::new(&torrent);
+    let mut handler = MessageHandler::new(&context, &mut stream, file_sender, &mut queue);
+    let request = |index, begin, length| messages::build_request(PieceBlock { index, begin, length: Some(length) });
+
+    // The peer learns that we have piece 0, and that we want the others.
+    handler.start().await.unwrap();
+    let bitfield = read_message(&mut peer).await.unwrap().to_bytes();
+    assert_eq!(&bitfield[4..6], &[5, 0b1000_0000]);
+    assert_eq!(read_message(&mut peer).await.unwrap().to_bytes(), vec![0, 0, 0, 1, 2]);
+
+    // Every upload slot is taken, requests of a choked peer are dropped.
+    let other_peer = context.upload_slots.take().unwrap();
+    handler.router(messages::build_interested()).await.unwrap();
+    handler.router(request(0, 0, BLOCK_LEN)).await.unwrap();
+    assert!(!handler.has_requests());
+
+    drop(other_peer);
+    handler.unchoke_peer().await.unwrap();
+    assert_eq!(read_message(&mut peer).await.unwrap().to_bytes(), vec![0, 0, 0, 1, 1]);
+
+    // Pieces we don't have are dropped too, and cancelled blocks aren't sent.
+    handler.router(request(0, 0, BLOCK_LEN)).await.unwrap();
+    handler.router(request(0, BLOCK_LEN, BLOCK_LEN)).await.unwrap();
+    handler.router(request(1, 0, BLOCK_LEN)).await.unwrap();
+    let mut cancel = request(0, BLOCK_LEN, BLOCK_LEN).to_bytes();
+    cancel[4] = 8;
+    handler.router(ByteBuffer::from_bytes(&cancel)).await.unwrap();
+
+    handler.upload_block().await.unwrap();
+    assert!(!handler.has_requests());
+    let piece = read_message(&mut peer).await.unwrap().to_bytes();
+    assert_eq!(&piece[4..13], &[7, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&piece[13..], &data[..BLOCK_LEN as usize]);
+    assert_eq!(context.uploaded.load(Ordering::Relaxed), BLOCK_LEN);
+
+    // A peer that's no longer interested gives its slot back.
+    handler.router(messages::build_not_interested()).await.unwrap();
+    assert_eq!(read_message(&mut peer).await.unwrap().to_bytes(), vec![0, 0, 0, 1, 0]);
+    assert!(context.upload_slots.take().is_some());
+
+    assert!(matches!(handler.router(request(0, torrent.get_piece_len(0), BLOCK_LEN)).await, Err(Error::PeerProtocol(_))));
+    assert!(matches!(handler.router(request(0, 0, 1 << 20)).await, Err(Error::PeerProtocol(_))));
+}
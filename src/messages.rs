@@ -1,8 +1,9 @@
 use bytebuffer::ByteBuffer;
 use rand::Rng;
 
+use crate::error::{Error, Result};
 use crate::queue::PieceBlock;
-use crate::utils::torrents;
+use crate::tracker::{AnnounceEvent, AnnounceStats};
 
 #[derive(Debug)]
 pub struct GenericPayload {
@@ -16,96 +17,109 @@ pub struct GenericPayload {
 
 #[derive(Debug)]
 pub struct Msg {
-    size: u32,
     pub id: u8,
     pub payload: GenericPayload,
 }
 
 
-pub fn get_msg_id(msg: &mut ByteBuffer) -> u8 {
-    if msg.len() > 4 {
-        msg.to_bytes()[4]
-    } else { 0 }
-}
-
-
-pub fn parse(mut msg: ByteBuffer) -> Msg {
-    let mut rest: ByteBuffer = ByteBuffer::new();
-    let size = msg.read_u32();
-    let mut index: u32 = 0;
-    let mut begin: u32 = 0;
-
-    let id = get_msg_id(&mut msg);
-
-    let mut payload_bytes: ByteBuffer = ByteBuffer::new();
+/// Parse a whole message, length prefix included.
+///
+/// Messages that are too short for their id are a protocol error. Ids we don't know,
+/// like the ones of extensions we haven't asked for, are passed on with an empty payload.
+pub fn parse(msg: ByteBuffer) -> Result<Msg> {
+    let bytes = msg.to_bytes();
 
-    if msg.len() > 5 {
-        payload_bytes.write_bytes(&msg.to_bytes()[5..msg.len()]);
-    } else {
-        payload_bytes.write_u8(0);
-    };
+    if bytes.len() < 5 {
+        return Err(Error::PeerProtocol(format!("A message of {} bytes is too short", bytes.len())));
+    }
 
-    match id {
-        // if message request, piece or cancel
-        6 | 7 | 8 | 9 => {
-            rest.write_bytes(&payload_bytes.to_bytes()[8..payload_bytes.len()]);
-            index = payload_bytes.read_u32();
-            begin = payload_bytes.read_u32();
-        }
-        _ => {}
+    let size = read_u32(&bytes, 0);
+    if size as usize != bytes.len() - 4 {
+        return Err(Error::PeerProtocol(format!("The message length {} doesn't match its {} bytes", size, bytes.len() - 4)));
     }
 
+    let id = bytes[4];
+    let rest = &bytes[5..];
+
     let mut payload = GenericPayload {
-        index,
-        begin,
+        index: 0,
+        begin: 0,
         length: None,
         block: None,
         bitfield: None,
         piece_index: None,
     };
 
+    let min_len = match id {
+        4 => 4,
+        6 | 8 => 12,
+        7 => 8,
+        9 => 2,
+        _ => 0,
+    };
+    if rest.len() < min_len {
+        return Err(Error::PeerProtocol(format!("The payload of message {} is {} bytes, expected at least {}", id, rest.len(), min_len)));
+    }
+
     // Fill payload with different data depending on the message type.
     match id {
         // Choke, unchoke, interested, uninterested.
-        0 | 1 | 2 | 3 => payload.length = Some(rest.len() as u32),
+        0..=3 => payload.length = Some(0),
         // Have
-        4 => payload.piece_index = Some(rest.read_u32()),
+        4 => payload.piece_index = Some(read_u32(rest, 0)),
         // Bitfield
-        5 => payload.bitfield = Some(payload_bytes),
+        5 => payload.bitfield = Some(ByteBuffer::from_bytes(rest)),
         // Request, cancel
-        6 | 8 => payload.length = Some(rest.read_u32()),
+        6 | 8 => {
+            payload.index = read_u32(rest, 0);
+            payload.begin = read_u32(rest, 4);
+            payload.length = Some(read_u32(rest, 8));
+        }
         // Piece
-        7 => payload.block = Some(rest),
+        7 => {
+            payload.index = read_u32(rest, 0);
+            payload.begin = read_u32(rest, 4);
+            payload.block = Some(ByteBuffer::from_bytes(&rest[8..]));
+        }
         _ => {}
     };
 
-
-    return Msg {
-        size,
+    Ok(Msg {
         id,
         payload,
-    };
+    })
 }
 
+/// Read a big endian u32, the caller checks that the bytes are there.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+
+    u32::from_be_bytes(buf)
+}
+
+/// The length of a handshake: pstrlen, pstr, reserved, info hash and peer id.
+pub const HANDSHAKE_LEN: usize = 68;
+
 /// The handshake is a required message and must be the first message transmitted by the client.
 /// It is (49+len(pstr)) bytes long.
 ///
-///     handshake: <pstrlen><pstr><reserved><info_hash><peer_id>
+/// handshake: `<pstrlen><pstr><reserved><info_hash><peer_id>`
 ///
-///     pstrlen: string length of <pstr>, as a single raw byte
+/// pstrlen: string length of `<pstr>`, as a single raw byte
 ///
-///     pstr: string identifier of the protocol
+/// pstr: string identifier of the protocol
 ///
-///     reserved: eight (8) reserved bytes. All current implementations use all zeroes.
-///     Each bit in these bytes can be used to change the behavior of the protocol.
-///     An email from Bram suggests that trailing bits should be used first, so that leading bits may be used to change the meaning of trailing bits.
+/// reserved: eight (8) reserved bytes. All current implementations use all zeroes.
+/// Each bit in these bytes can be used to change the behavior of the protocol.
+/// An email from Bram suggests that trailing bits should be used first, so that leading bits may be used to change the meaning of trailing bits.
 ///
-///     info_hash: 20-byte SHA1 hash of the info key in the metainfo file. This is the same info_hash that is transmitted in tracker requests.
+/// info_hash: 20-byte SHA1 hash of the info key in the metainfo file. This is the same info_hash that is transmitted in tracker requests.
 ///
-///     peer_id: 20-byte string used as a unique ID for the client.
-///     This is usually the same peer_id that is transmitted in tracker requests (but not always e.g. an anonymity option in Azureus).
+/// peer_id: 20-byte string used as a unique ID for the client.
+/// This is usually the same peer_id that is transmitted in tracker requests (but not always e.g. an anonymity option in Azureus).
 ///
-///    In version 1.0 of the BitTorrent protocol, pstrlen = 19, and pstr = "BitTorrent protocol".
+/// In version 1.0 of the BitTorrent protocol, pstrlen = 19, and pstr = "BitTorrent protocol".
 pub fn build_peer_handshake(info_hash: &[u8; 20], peer_id: &ByteBuffer) -> ByteBuffer {
     let mut handshake: ByteBuffer = ByteBuffer::new();
     handshake.write_u8(19);
@@ -114,7 +128,7 @@ pub fn build_peer_handshake(info_hash: &[u8; 20], peer_id: &ByteBuffer) -> ByteB
     handshake.write_bytes(info_hash);
     handshake.write_bytes(&peer_id.to_bytes());
 
-    return handshake;
+    handshake
 }
 
 
@@ -128,7 +142,7 @@ pub fn build_keep_alive() -> ByteBuffer {
 
     buf.write_u32(0);
 
-    return buf;
+    buf
 }
 
 
@@ -139,7 +153,7 @@ pub fn build_choke() -> ByteBuffer {
     buf.write_u32(1);
     buf.write_u8(0);
 
-    return buf;
+    buf
 }
 
 
@@ -150,7 +164,7 @@ pub fn build_unchoke() -> ByteBuffer {
     buf.write_u32(1);
     buf.write_u8(1);
 
-    return buf;
+    buf
 }
 
 
@@ -162,7 +176,7 @@ pub fn build_interested() -> ByteBuffer {
     buf.write_u32(1);
     buf.write_u8(2);
 
-    return buf;
+    buf
 }
 
 
@@ -174,7 +188,7 @@ pub fn build_not_interested() -> ByteBuffer {
     buf.write_u32(1);
     buf.write_u8(3);
 
-    return buf;
+    buf
 }
 
 
@@ -187,7 +201,7 @@ pub fn build_have(piece_index: u32) -> ByteBuffer {
     buf.write_u8(4);
     buf.write_u32(piece_index);
 
-    return buf;
+    buf
 }
 
 /// The bitfield message may only be sent immediately after
@@ -203,7 +217,7 @@ pub fn build_bitfield(bitfield: &ByteBuffer) -> ByteBuffer {
     buf.write_u8(5);
     buf.write_bytes(&bitfield.to_bytes());
 
-    return buf;
+    buf
 }
 
 
@@ -225,17 +239,17 @@ pub fn build_request(payload: PieceBlock) -> ByteBuffer {
     buf.write_u32(payload.begin as u32);
     buf.write_u32(payload.length.unwrap() as u32);
 
-    return buf;
+    buf
 }
 
 
-///     The piece message is variable length, where X is the length of the block. The payload contains the following information:
+/// The piece message is variable length, where X is the length of the block. The payload contains the following information:
 ///
-///     index: integer specifying the zero-based piece index
-///     begin: integer specifying the zero-based byte offset within the piece
-///     block: block of data, which is a subset of the piece specified by index.
+/// - index: integer specifying the zero-based piece index
+/// - begin: integer specifying the zero-based byte offset within the piece
+/// - block: block of data, which is a subset of the piece specified by index.
 ///
-///     piece: <len=0009+X><id=7><index><begin><block>
+/// piece: `<len=0009+X><id=7><index><begin><block>`
 pub fn build_piece(payload: &GenericPayload) -> ByteBuffer {
     let mut buf: ByteBuffer = ByteBuffer::new();
 
@@ -246,7 +260,7 @@ pub fn build_piece(payload: &GenericPayload) -> ByteBuffer {
     buf.write_u32(payload.begin);
     buf.write_bytes(&payload.block.as_ref().unwrap().to_bytes());
 
-    return buf;
+    buf
 }
 
 
@@ -270,7 +284,7 @@ pub fn build_cancel(payload: GenericPayload) -> ByteBuffer {
     buf.write_u32(payload.begin);
     buf.write_u32(payload.length.unwrap_or(0));
 
-    return buf;
+    buf
 }
 
 
@@ -289,7 +303,24 @@ pub fn build_port(port: u16) -> ByteBuffer {
 
     buf.write_u16(port);
 
-    return buf;
+    buf
+}
+
+/// The extension protocol (BEP 10) wraps its messages in message id 20. The extended message
+/// id 0 is the extended handshake, the others are the ids the receiving peer gave its extensions
+/// in its own extended handshake.
+///
+/// extended: <len=0002+X><id=20><extended message id><bencoded payload>
+pub fn build_extended(extended_id: u8, payload: &[u8]) -> ByteBuffer {
+    let mut buf: ByteBuffer = ByteBuffer::new();
+
+    buf.write_u32(payload.len() as u32 + 2);
+    buf.write_u8(20);
+    buf.write_u8(extended_id);
+
+    buf.write_bytes(payload);
+
+    buf
 }
 
 
@@ -309,14 +340,16 @@ pub fn build_conn_req() -> ByteBuffer {
     buffer.write_i32(action);
     buffer.write_i32(transaction_id);
 
-    return buffer;
+    buffer
 }
 
 pub fn build_announce_req(
-    torrent: &torrents::Torrent,
+    info_hash: &[u8; 20],
     connection_id: i64,
     peer_id: &ByteBuffer,
-    port: i16,
+    port: u16,
+    stats: &AnnounceStats,
+    event: AnnounceEvent,
 ) -> ByteBuffer {
     // Offset  Size    Name    Value
 
@@ -330,17 +363,17 @@ pub fn build_announce_req(
     // 12      32-bit integer  transaction_id
     announce_req.write_i32(rng.gen::<i32>());
     // 16      20-byte string  info_hash
-    announce_req.write_bytes(&*torrent.info_hash.as_ref().unwrap());
+    announce_req.write_bytes(info_hash);
     // 36      20-byte string  peer_id
     announce_req.write_bytes(&peer_id.to_bytes());
     // 56      64-bit integer  downloaded
-    announce_req.write_i64(0);
+    announce_req.write_u64(stats.downloaded);
     // 64      64-bit integer  left
-    announce_req.write_u64(torrent.size.unwrap());
+    announce_req.write_u64(stats.left);
     // 72      64-bit integer  uploaded
-    announce_req.write_i64(0);
+    announce_req.write_u64(stats.uploaded);
     // 80      32-bit integer  event           0 // 0: none; 1: completed; 2: started; 3: stopped
-    announce_req.write_i32(0);
+    announce_req.write_i32(event as i32);
     // 84      32-bit integer  IP address      0 // default
     announce_req.write_i32(0);
     // 88      32-bit integer  key
@@ -348,7 +381,69 @@ pub fn build_announce_req(
     // 92      32-bit integer  num_want        -1 // default
     announce_req.write_i32(-1);
     // 96      16-bit integer  port
-    announce_req.write_i16(port);
+    announce_req.write_u16(port);
+
+    announce_req
+}
+
+pub fn build_scrape_req(connection_id: i64, info_hash: &[u8; 20]) -> ByteBuffer {
+    // Offset  Size    Name    Value
+
+    let mut scrape_req = ByteBuffer::new();
+    let mut rng = rand::thread_rng();
+
+    // 0       64-bit integer  connection_id
+    scrape_req.write_i64(connection_id);
+    // 8       32-bit integer  action          2 // scrape
+    scrape_req.write_i32(2);
+    // 12      32-bit integer  transaction_id
+    scrape_req.write_i32(rng.gen::<i32>());
+    // 16      20-byte string  info_hash
+    scrape_req.write_bytes(info_hash);
+
+    scrape_req
+}
+
+
+#[test]
+fn test_parse_messages() {
+    let msg = parse(build_have(7)).unwrap();
+    assert_eq!((msg.id, msg.payload.piece_index), (4, Some(7)));
+
+    let mut piece = ByteBuffer::new();
+    piece.write_u32(9 + 3);
+    piece.write_u8(7);
+    piece.write_u32(2);
+    piece.write_u32(16384);
+    piece.write_bytes(&[1, 2, 3]);
+
+    let msg = parse(piece).unwrap();
+    assert_eq!((msg.payload.index, msg.payload.begin), (2, 16384));
+    assert_eq!(msg.payload.block.unwrap().to_bytes(), vec![1, 2, 3]);
+
+    let msg = parse(build_bitfield(&ByteBuffer::from_bytes(&[0b1010_0000]))).unwrap();
+    assert_eq!(msg.payload.bitfield.unwrap().to_bytes(), vec![0b1010_0000]);
+
+    // Anything short or inconsistent is an error, not a panic.
+    for bytes in [vec![], vec![0, 0, 0], vec![0, 0, 0, 1], vec![0, 0, 0, 1, 4], vec![0, 0, 0, 9, 7, 0, 0, 0, 1], vec![0, 0, 0, 3, 6, 0, 0]] {
+        assert!(parse(ByteBuffer::from_bytes(&bytes)).is_err(), "{:?}", bytes);
+    }
+
+    // Unknown ids, like the extension protocol, are left for the handler to ignore.
+    assert_eq!(parse(ByteBuffer::from_bytes(&[0, 0, 0, 3, 20, 0, 1])).unwrap().id, 20);
+}
+
+#[test]
+fn test_build_announce_req() {
+    use std::convert::TryInto;
+
+    let torrent = crate::utils::torrents::Torrent::from_path("test-tor.torrent").unwrap();
+    let stats = AnnounceStats { downloaded: 1000, left: 2000, uploaded: 3000 };
+    let request = build_announce_req(&torrent.info_hash.unwrap(), 42, &ByteBuffer::from_bytes(&[1; 20]), 6881, &stats, AnnounceEvent::Completed).to_bytes();
 
-    return announce_req;
+    let read_u64 = |offset: usize| u64::from_be_bytes(request[offset..offset + 8].try_into().unwrap());
+    assert_eq!(request.len(), 98);
+    assert_eq!((read_u64(56), read_u64(64), read_u64(72)), (1000, 2000, 3000));
+    assert_eq!(&request[80..84], &1i32.to_be_bytes());
+    assert_eq!(&request[96..], &6881u16.to_be_bytes());
 }
@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use bytebuffer::ByteBuffer;
+use serde_derive::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::bencode;
+use crate::download::check_handshake_msg;
+use crate::error::{Error, Result};
+use crate::magnet::MagnetLink;
+use crate::message_handlers::read_message;
+use crate::messages::{build_extended, build_peer_handshake, HANDSHAKE_LEN};
+use crate::session::InfoHash;
+use crate::settings::Settings;
+use crate::tracker::{announce_info_hash, AnnounceEvent, AnnounceStats};
+use crate::utils::torrents::{hash_torrent_info, Torrent};
+
+/// The metadata is sent in pieces of 16 KiB, only the last one can be shorter.
+const METADATA_PIECE_LEN: usize = 16 * 1024;
+/// The largest metadata we accept, way beyond the info dictionary of any real torrent.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+/// The extended message id peers have to use for the ut_metadata messages they send us.
+const UT_METADATA_ID: u8 = 1;
+/// The bit of the reserved bytes of the handshake that tells the extension protocol (BEP 10) is supported.
+const EXTENSION_BIT: u8 = 0x10;
+/// How long a single peer gets to send us the whole metadata.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const METADATA_REQUEST: i64 = 0;
+const METADATA_DATA: i64 = 1;
+const METADATA_REJECT: i64 = 2;
+
+/// The part of the extended handshake of a peer we need.
+#[derive(Debug, Deserialize)]
+struct ExtendedHandshake {
+    /// The extended message ids the peer gave to the extensions it supports.
+    #[serde(default)]
+    m: HashMap<String, i64>,
+    #[serde(default)]
+    metadata_size: Option<i64>,
+}
+
+/// The dictionary in front of a ut_metadata message, the data of a piece follows it.
+#[derive(Debug, Deserialize)]
+struct MetadataHeader {
+    msg_type: i64,
+    piece: i64,
+}
+
+/// Get the torrent of a magnet link by fetching its info dictionary from peers (BEP 9).
+///
+/// The peers of the magnet link and the ones given are asked first, then the ones of its
+/// trackers, one at a time until one sends metadata matching the info hash.
+pub(crate) async fn fetch_metadata(magnet: &MagnetLink, peer_id: &ByteBuffer, settings: &Settings, peers: &[SocketAddr]) -> Result<Torrent> {
+    let mut candidates: Vec<SocketAddr> = magnet.peers.iter().chain(peers).copied().collect();
+    candidates.extend(tracker_peers(magnet, peer_id, settings).await);
+
+    let mut seen = HashSet::new();
+    candidates.retain(|address| seen.insert(*address));
+
+    if candidates.is_empty() {
+        return Err(Error::Metadata("The magnet link has no peers and its trackers gave none".to_string()));
+    }
+
+    let mut handshake = build_peer_handshake(&magnet.info_hash, peer_id).to_bytes();
+    handshake[25] |= EXTENSION_BIT;
+
+    for address in &candidates {
+        let fetching = fetch_from_peer(*address, &magnet.info_hash, &handshake, settings.connect_timeout);
+        let result = match tokio::time::timeout(PEER_TIMEOUT, fetching).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::PeerConnection(io::Error::new(io::ErrorKind::TimedOut, "Timed out fetching the metadata"))),
+        };
+
+        match result {
+            Ok(info_bytes) => {
+                info!(%address, size = info_bytes.len(), "fetched the metadata");
+                return Ok(Torrent::from_bytes(&build_metainfo(&info_bytes, &magnet.trackers))?);
+            }
+            Err(e) => debug!(%address, error = %e, "unable to fetch the metadata"),
+        }
+    }
+
+    Err(Error::Metadata(format!("None of the {} peers sent it", candidates.len())))
+}
+
+/// The peers the trackers of a magnet link know of, the trackers that fail are skipped.
+async fn tracker_peers(magnet: &MagnetLink, peer_id: &ByteBuffer, settings: &Settings) -> Vec<SocketAddr> {
+    let mut peers = Vec::new();
+
+    for url in &magnet.trackers {
+        let (tracker, info_hash, peer_id) = (url.clone(), magnet.info_hash, peer_id.to_bytes());
+        let (port, timeout) = (settings.listen_port, settings.tracker_timeout);
+        // The size isn't known without the metadata, anything left keeps us from passing for a seed.
+        let stats = AnnounceStats { left: 1, ..Default::default() };
+
+        // The tracker client blocks.
+        let announcing = tokio::task::spawn_blocking(move || {
+            announce_info_hash(&tracker, &info_hash, &ByteBuffer::from_bytes(&peer_id), port, stats, AnnounceEvent::None, timeout)
+        });
+
+        match announcing.await {
+            Ok(Ok(response)) => {
+                debug!(%url, peers = response.peers.len(), "tracker replied");
+                peers.extend(response.peers.iter().map(|peer| SocketAddr::from((Ipv4Addr::from(peer.ip_addr), peer.port))));
+            }
+            Ok(Err(e)) => debug!(%url, error = %e, "tracker failed"),
+            Err(_) => debug!(%url, "the tracker client crashed"),
+        }
+    }
+
+    peers
+}
+
+/// Ask a single peer for the metadata, what it sends has to hash to the info hash.
+async fn fetch_from_peer(address: SocketAddr, info_hash: &InfoHash, handshake: &[u8], connect_timeout: Duration) -> Result<Vec<u8>> {
+    let mut stream = tokio::time::timeout(connect_timeout, TcpStream::connect(address))
+        .await
+        .map_err(|_| Error::PeerConnection(io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting to the peer")))?
+        .map_err(Error::PeerConnection)?;
+
+    stream.write_all(handshake).await.map_err(Error::PeerConnection)?;
+
+    let mut peer_handshake = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut peer_handshake).await.map_err(Error::PeerConnection)?;
+
+    if !check_handshake_msg(&mut ByteBuffer::from_bytes(&peer_handshake)) {
+        return Err(Error::PeerProtocol("Not a BitTorrent handshake".to_string()));
+    }
+    if peer_handshake[28..48] != info_hash[..] {
+        return Err(Error::PeerProtocol("The handshake is for another torrent".to_string()));
+    }
+    if peer_handshake[25] & EXTENSION_BIT == 0 {
+        return Err(Error::PeerProtocol("The peer doesn't support the extension protocol".to_string()));
+    }
+
+    let extended_handshake = format!("d1:md11:ut_metadatai{}eee", UT_METADATA_ID);
+    stream.write_all(&build_extended(0, extended_handshake.as_bytes()).to_bytes()).await.map_err(Error::PeerConnection)?;
+
+    let mut metadata: Option<Metadata> = None;
+
+    loop {
+        let msg = read_message(&mut stream).await?.to_bytes();
+        // Only extended messages matter here, the bitfield and the like are for the download.
+        if msg.len() < 6 || msg[4] != 20 {
+            continue;
+        }
+        let payload = &msg[6..];
+
+        match (msg[5], metadata.as_mut()) {
+            (0, None) => {
+                let (ut_metadata, size) = read_extended_handshake(payload)?;
+                let requested = Metadata::new(size);
+
+                for piece in 0..requested.received.len() {
+                    let request = format!("d8:msg_typei{}e5:piecei{}ee", METADATA_REQUEST, piece);
+                    stream.write_all(&build_extended(ut_metadata, request.as_bytes()).to_bytes()).await.map_err(Error::PeerConnection)?;
+                }
+
+                metadata = Some(requested);
+            }
+            (UT_METADATA_ID, Some(metadata)) => {
+                if !metadata.add_piece(payload)? {
+                    continue;
+                }
+                if hash_torrent_info(&metadata.data) != *info_hash {
+                    return Err(Error::PeerProtocol("The metadata doesn't match the info hash".to_string()));
+                }
+
+                return Ok(std::mem::take(&mut metadata.data));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The extended message id the peer wants for ut_metadata and the size of the metadata.
+fn read_extended_handshake(payload: &[u8]) -> Result<(u8, usize)> {
+    let invalid = |e: String| Error::PeerProtocol(format!("Invalid extended handshake: {}", e));
+
+    // Check the nesting depth before serde recurses into it.
+    bencode::skip_value(payload, 0).map_err(|e| invalid(e.to_string()))?;
+    let handshake: ExtendedHandshake = serde_bencode::from_bytes(payload).map_err(|e| invalid(e.to_string()))?;
+
+    let ut_metadata = handshake
+        .m
+        .get("ut_metadata")
+        .and_then(|&id| u8::try_from(id).ok())
+        .filter(|&id| id != 0)
+        .ok_or_else(|| Error::PeerProtocol("The peer doesn't serve metadata".to_string()))?;
+    let size = handshake
+        .metadata_size
+        .and_then(|size| usize::try_from(size).ok())
+        .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+        .ok_or_else(|| Error::PeerProtocol(format!("Invalid metadata size: {:?}", handshake.metadata_size)))?;
+
+    Ok((ut_metadata, size))
+}
+
+/// The metadata as it comes in from a peer, piece by piece.
+struct Metadata {
+    data: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl Metadata {
+    fn new(size: usize) -> Metadata {
+        let pieces = size.div_ceil(METADATA_PIECE_LEN);
+
+        Metadata { data: vec![0; size], received: vec![false; pieces] }
+    }
+
+    /// Store the piece a ut_metadata message carries, true once every piece is in.
+    fn add_piece(&mut self, payload: &[u8]) -> Result<bool> {
+        let invalid = |e: String| Error::PeerProtocol(format!("Invalid metadata message: {}", e));
+
+        let header_end = bencode::skip_value(payload, 0).map_err(|e| invalid(e.to_string()))?;
+        let header: MetadataHeader = serde_bencode::from_bytes(&payload[..header_end]).map_err(|e| invalid(e.to_string()))?;
+
+        match header.msg_type {
+            METADATA_DATA => {}
+            METADATA_REJECT => return Err(Error::PeerProtocol(format!("The peer rejected the request for metadata piece {}", header.piece))),
+            // We don't serve metadata, the requests of the peer go unanswered.
+            _ => return Ok(false),
+        }
+
+        let piece = usize::try_from(header.piece)
+            .ok()
+            .filter(|&piece| piece < self.received.len())
+            .ok_or_else(|| invalid(format!("Piece {} is out of range", header.piece)))?;
+        let start = piece * METADATA_PIECE_LEN;
+        let end = (start + METADATA_PIECE_LEN).min(self.data.len());
+
+        let block = &payload[header_end..];
+        if block.len() != end - start {
+            return Err(invalid(format!("Piece {} has {} bytes instead of {}", piece, block.len(), end - start)));
+        }
+
+        self.data[start..end].copy_from_slice(block);
+        self.received[piece] = true;
+
+        Ok(self.received.iter().all(|&received| received))
+    }
+}
+
+/// A metainfo file around an info dictionary, announcing to the trackers of the magnet link.
+///
+/// Only the first UDP tracker is announced to, the others go in the announce list.
+fn build_metainfo(info: &[u8], trackers: &[String]) -> Vec<u8> {
+    let string = |s: &str| format!("{}:{}", s.len(), s);
+    let mut metainfo = b"d".to_vec();
+
+    if let Some(announce) = trackers.iter().find(|tracker| tracker.starts_with("udp://")).or_else(|| trackers.first()) {
+        metainfo.extend(format!("8:announce{}", string(announce)).bytes());
+        metainfo.extend(b"13:announce-listl");
+        for tracker in trackers {
+            metainfo.extend(format!("l{}e", string(tracker)).bytes());
+        }
+        metainfo.push(b'e');
+    }
+
+    metainfo.extend(b"4:info");
+    metainfo.extend(info);
+    metainfo.push(b'e');
+
+    metainfo
+}
+
+#[cfg(test)]
+fn test_info(pieces: usize) -> Vec<u8> {
+    let mut info = format!("d6:lengthi{}e4:name4:test12:piece lengthi16384e6:pieces{}:", pieces * 16384, pieces * 20).into_bytes();
+    info.extend((0..pieces * 20).map(|i| i as u8));
+    info.push(b'e');
+
+    info
+}
+
+/// A peer that serves `info` as its metadata, or rejects every request for it.
+#[cfg(test)]
+async fn serve_metadata(listener: tokio::net::TcpListener, info: Vec<u8>, reject: bool) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    let mut handshake = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut handshake).await.unwrap();
+    assert_ne!(handshake[25] & EXTENSION_BIT, 0);
+    handshake[48..].copy_from_slice(&[7; 20]);
+    stream.write_all(&handshake).await.unwrap();
+    stream.write_all(&crate::messages::build_unchoke().to_bytes()).await.unwrap();
+
+    let extended_handshake = format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", info.len());
+    stream.write_all(&build_extended(0, extended_handshake.as_bytes()).to_bytes()).await.unwrap();
+
+    loop {
+        let msg = match read_message(&mut stream).await {
+            Ok(msg) => msg.to_bytes(),
+            Err(_) => return,
+        };
+        if msg[4] != 20 || msg[5] != 3 {
+            continue;
+        }
+
+        let request: MetadataHeader = serde_bencode::from_bytes(&msg[6..]).unwrap();
+        let piece = request.piece as usize;
+        let reply = if reject {
+            format!("d8:msg_typei2e5:piecei{}ee", piece).into_bytes()
+        } else {
+            let data = &info[piece * METADATA_PIECE_LEN..info.len().min((piece + 1) * METADATA_PIECE_LEN)];
+            let mut reply = format!("d8:msg_typei1e5:piecei{}e10:total_sizei{}ee", piece, info.len()).into_bytes();
+            reply.extend(data);
+            reply
+        };
+
+        stream.write_all(&build_extended(UT_METADATA_ID, &reply).to_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_metadata() {
+    use crate::utils::gen_peer_id;
+
+    // Two metadata pieces, the last one shorter.
+    let info = test_info(1000);
+    assert!(info.len() > METADATA_PIECE_LEN);
+    let info_hash = hash_torrent_info(&info);
+
+    let rejecting = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let serving = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = format!(
+        "magnet:?xt=urn:btih:{}&tr=udp%3A%2F%2F127.0.0.1%3A1&x.pe={}&x.pe={}",
+        info_hash.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        rejecting.local_addr().unwrap(),
+        serving.local_addr().unwrap()
+    );
+    tokio::spawn(serve_metadata(rejecting, info.clone(), true));
+    tokio::spawn(serve_metadata(serving, info.clone(), false));
+
+    let magnet = MagnetLink::parse(&uri).unwrap();
+    let settings = Settings { tracker_timeout: Duration::from_millis(100), ..Default::default() };
+    let torrent = fetch_metadata(&magnet, &gen_peer_id(), &settings, &[]).await.unwrap();
+
+    assert_eq!(torrent.info_hash, Some(info_hash));
+    assert_eq!(torrent.info_bytes, info);
+    assert_eq!(torrent.name(), "test");
+    assert_eq!(torrent.announce.as_deref(), Some("udp://127.0.0.1:1"));
+}
+
+#[tokio::test]
+async fn test_fetch_metadata_checks_info_hash() {
+    use crate::utils::gen_peer_id;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let info = test_info(10);
+    let mut info_hash = hash_torrent_info(&info);
+    info_hash[0] ^= 1;
+
+    // The peer answers the handshake with whatever info hash it got, then sends other metadata.
+    tokio::spawn(serve_metadata(listener, info, false));
+
+    let magnet = MagnetLink { info_hash, name: None, trackers: vec![], peers: vec![] };
+    let result = fetch_metadata(&magnet, &gen_peer_id(), &Settings::default(), &[address]).await;
+    assert!(matches!(result, Err(Error::Metadata(_))));
+
+    // Without peers there is no one to ask.
+    let result = fetch_metadata(&magnet, &gen_peer_id(), &Settings::default(), &[]).await;
+    assert!(matches!(result, Err(Error::Metadata(_))));
+}
+
+#[test]
+fn test_build_metainfo() {
+    let info = test_info(1);
+    let trackers = vec!["http://tracker.example.com/announce".to_string(), "udp://tracker.example.com:6969".to_string()];
+
+    let torrent = Torrent::from_bytes(&build_metainfo(&info, &trackers)).unwrap();
+    assert_eq!(torrent.announce.as_deref(), Some("udp://tracker.example.com:6969"));
+    assert_eq!(torrent.info_hash, Some(hash_torrent_info(&info)));
+
+    let torrent = Torrent::from_bytes(&build_metainfo(&info, &[])).unwrap();
+    assert_eq!(torrent.announce, None);
+}
@@ -1,107 +1,576 @@
+use std::cmp::Reverse;
+
+use crate::layout::FileLayout;
 use crate::queue::PieceBlock;
-use crate::utils::torrents::{BLOCK_LEN, calculate_torrent_size, Torrent};
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+
+/// How eagerly a file, or the pieces it's made of, should be downloaded.
+///
+/// Pieces with a higher priority are requested first, skipped pieces aren't requested at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+/// The number of pieces ahead of the reader that are fetched before anything else in deadline mode.
+pub const DEADLINE_WINDOW: u64 = 8;
+
+/// The order in which wanted pieces are requested.
+///
+/// - RarestFirst: pieces the fewest peers have come first, which keeps the swarm healthy.
+/// - Sequential: pieces are fetched in order starting at the reader position.
+/// - Deadline: the pieces right ahead of the reader come first, the rest is rarest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickMode {
+    #[default]
+    RarestFirst,
+    Sequential,
+    Deadline,
+}
+
+impl std::str::FromStr for PickMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rarest-first" => Ok(PickMode::RarestFirst),
+            "sequential" => Ok(PickMode::Sequential),
+            "deadline" => Ok(PickMode::Deadline),
+            _ => Err(format!("Unknown pick mode: {}", s)),
+        }
+    }
+}
+
+/// How a piece ranks when picking what to request next, the highest rank is requested first.
+///
+/// ```text
+/// (in the deadline window, priority, ahead of the reader, closest to the reader, rarest, lowest index)
+/// ```
+///
+pub type PickRank = (bool, Priority, bool, Reverse<u64>, Reverse<u32>, Reverse<u64>);
 
 #[derive(Debug, Clone)]
 pub struct Pieces {
     requested: Vec<Vec<bool>>,
     received: Vec<Vec<bool>>,
-    percent_received: f32,
+    verified: Vec<bool>,
+    priorities: Vec<Priority>,
+    file_priorities: Vec<Priority>,
+    availability: Vec<u32>,
+    pick_mode: PickMode,
+    reader_piece: u64,
+    /// Whether each piece is wanted, along with the number of blocks of the wanted pieces
+    /// and how many of them have been requested and received.
+    wanted: Vec<bool>,
+    wanted_blocks: usize,
+    wanted_requested: usize,
+    wanted_received: usize,
+    /// How many times a piece has passed the hash check, so that peers can tell when there
+    /// are new pieces to announce.
+    verifications: u64,
+    /// How many times a piece has been thrown away, so that peers can ask for it again.
+    resets: u64,
 }
 
 impl Pieces {
     pub fn new(torrent: &Torrent) -> Pieces {
+        let received = build_pieces_vec(torrent);
+
         Pieces {
             requested: build_pieces_vec(torrent),
-            received: build_pieces_vec(torrent),
-            percent_received: 0.0,
+            wanted: vec![true; torrent.num_pieces() as usize],
+            wanted_blocks: received.iter().map(Vec::len).sum(),
+            wanted_requested: 0,
+            wanted_received: 0,
+            received,
+            verified: vec![false; torrent.num_pieces() as usize],
+            priorities: vec![Priority::Normal; torrent.num_pieces() as usize],
+            file_priorities: vec![Priority::Normal; torrent.layout.files().len()],
+            availability: vec![0; torrent.num_pieces() as usize],
+            pick_mode: PickMode::default(),
+            reader_piece: 0,
+            verifications: 0,
+            resets: 0,
+        }
+    }
+
+    /// Set the priority of every piece from the priorities of the files in the torrent.
+    pub fn set_file_priorities(&mut self, torrent: &Torrent, file_priorities: &[Priority]) {
+        self.file_priorities = (0..torrent.layout.files().len())
+            .map(|i| file_priorities.get(i).copied().unwrap_or_default())
+            .collect();
+        self.priorities = piece_priorities(torrent, &torrent.layout, file_priorities);
+        self.wanted = self.priorities.iter().map(|priority| *priority != Priority::Skip).collect();
+        self.wanted_blocks = 0;
+        self.wanted_requested = 0;
+        self.wanted_received = 0;
+
+        let pieces = self.wanted.iter().zip(&self.requested).zip(&self.received);
+
+        for ((_, requested), received) in pieces.filter(|((wanted, _), _)| **wanted) {
+            self.wanted_blocks += received.len();
+            self.wanted_requested += count_received(requested);
+            self.wanted_received += count_received(received);
+        }
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    pub fn piece_priority(&self, piece_index: u64) -> Priority {
+        self.priorities[piece_index as usize]
+    }
+
+    pub fn set_pick_mode(&mut self, pick_mode: PickMode) {
+        self.pick_mode = pick_mode;
+    }
+
+    pub fn pick_mode(&self) -> PickMode {
+        self.pick_mode
+    }
+
+    /// Move the reader to a piece, the pieces right after it become the most urgent.
+    pub fn set_reader_piece(&mut self, piece_index: u64) {
+        self.reader_piece = piece_index;
+    }
+
+    /// A peer has let us know it has a piece.
+    pub fn add_availability(&mut self, piece_index: u64) {
+        if let Some(count) = self.availability.get_mut(piece_index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// A peer that had these pieces has gone away.
+    pub fn remove_availability(&mut self, piece_indexes: &[u64]) {
+        for piece_index in piece_indexes {
+            if let Some(count) = self.availability.get_mut(*piece_index as usize) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Rank a piece for the picker according to the pick mode, see PickRank.
+    pub fn pick_rank(&self, piece_index: u64) -> PickRank {
+        let priority = self.piece_priority(piece_index);
+        let ahead = piece_index >= self.reader_piece;
+        let distance = piece_index.abs_diff(self.reader_piece);
+        let availability = self.availability[piece_index as usize];
+
+        match self.pick_mode {
+            PickMode::RarestFirst => (false, priority, false, Reverse(0), Reverse(availability), Reverse(piece_index)),
+            PickMode::Sequential => (false, priority, ahead, Reverse(0), Reverse(0), Reverse(piece_index)),
+            PickMode::Deadline => {
+                if ahead && distance < DEADLINE_WINDOW {
+                    (true, priority, true, Reverse(distance), Reverse(0), Reverse(0))
+                } else {
+                    (false, priority, false, Reverse(0), Reverse(availability), Reverse(piece_index))
+                }
+            }
         }
     }
 
+    /// Pick the next whole piece to fetch from a source that has every piece, like a web seed.
+    ///
+    /// Pieces nobody has asked for yet come first. Once everything has been asked for, pieces
+    /// that are still missing blocks are handed out again so a slow peer can't stall the end.
+    /// Every block of the picked piece is flagged as requested.
+    pub fn pick_piece(&mut self) -> Option<u64> {
+        let wanted = |i: &u64| self.piece_priority(*i) != Priority::Skip && !self.is_verified(*i);
+        let unrequested = (0..self.verified.len() as u64)
+            .filter(wanted)
+            .filter(|i| self.requested[*i as usize].iter().all(|block| !block))
+            .max_by_key(|i| self.pick_rank(*i));
+
+        let piece_index = unrequested.or_else(|| {
+            (0..self.verified.len() as u64)
+                .filter(wanted)
+                .filter(|i| !self.piece_received(*i))
+                .max_by_key(|i| self.pick_rank(*i))
+        })?;
+
+        self.update_requested(piece_index as usize, |requested| requested.iter_mut().for_each(|block| *block = true));
+
+        Some(piece_index)
+    }
+
     /// Flag the requested block as true
     pub fn add_requested(&mut self, piece_block: PieceBlock) {
         let block_index = piece_block.begin / BLOCK_LEN;
-        self.requested[piece_block.index as usize][block_index as usize] = true;
+        self.update_requested(piece_block.index as usize, |requested| requested[block_index as usize] = true);
+    }
+
+    /// Change which blocks of a piece count as requested, keeping count of the requested
+    /// blocks of the wanted pieces.
+    fn update_requested<F: FnOnce(&mut Vec<bool>)>(&mut self, piece_index: usize, change: F) {
+        let wanted = self.wanted[piece_index];
+
+        if wanted {
+            self.wanted_requested -= count_received(&self.requested[piece_index]);
+        }
+        change(&mut self.requested[piece_index]);
+        if wanted {
+            self.wanted_requested += count_received(&self.requested[piece_index]);
+        }
     }
 
 
     /// Flag the received block as true
     pub fn add_received(&mut self, piece_block: PieceBlock) {
         let block_index = piece_block.begin / BLOCK_LEN;
-        self.received[piece_block.index as usize][block_index as usize] = true;
-        self.percent_received = calculate_downloaded_percent(&self.received);
-        println!("Downloaded: {}", self.percent_received);
+        let block = &mut self.received[piece_block.index as usize][block_index as usize];
+
+        if !*block && self.wanted[piece_block.index as usize] {
+            self.wanted_received += 1;
+        }
+        *block = true;
+    }
+
+    /// Check if every block of a piece has been received.
+    pub fn piece_received(&self, piece_index: u64) -> bool {
+        self.received[piece_index as usize].iter().all(|block| *block)
+    }
+
+    /// Flag a piece as having passed the hash check.
+    pub fn add_verified(&mut self, piece_index: u64) {
+        if !std::mem::replace(&mut self.verified[piece_index as usize], true) {
+            self.verifications += 1;
+        }
+    }
+
+    /// Check if a piece has passed the hash check.
+    pub fn is_verified(&self, piece_index: u64) -> bool {
+        self.verified[piece_index as usize]
+    }
+
+    /// Goes up every time a piece passes the hash check.
+    pub fn verifications(&self) -> u64 {
+        self.verifications
+    }
+
+    /// Goes up every time a piece is thrown away.
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// The bytes of the wanted pieces that haven't passed the hash check yet.
+    pub fn bytes_left(&self, torrent: &Torrent) -> u64 {
+        (0..torrent.num_pieces())
+            .filter(|&piece_index| !self.verified[piece_index as usize] && self.priorities[piece_index as usize] != Priority::Skip)
+            .map(|piece_index| torrent.get_piece_len(piece_index))
+            .sum()
+    }
+
+    /// Flag every block of a piece as received and the piece as verified,
+    /// for pieces that were found to already be on disk.
+    pub fn mark_complete(&mut self, piece_index: u64) {
+        let index = piece_index as usize;
+
+        if self.wanted[index] {
+            self.wanted_received += self.received[index].len() - count_received(&self.received[index]);
+        }
+
+        self.update_requested(index, |requested| requested.iter_mut().for_each(|block| *block = true));
+        self.received[index].iter_mut().for_each(|block| *block = true);
+        self.add_verified(piece_index);
+    }
+
+    /// Forget about every block of a piece so that it will be requested again.
+    pub fn reset_piece(&mut self, piece_index: u64) {
+        let piece_index = piece_index as usize;
+
+        if self.wanted[piece_index] {
+            self.wanted_received -= count_received(&self.received[piece_index]);
+        }
+
+        self.update_requested(piece_index, |requested| requested.iter_mut().for_each(|block| *block = false));
+        self.received[piece_index].iter_mut().for_each(|block| *block = false);
+        self.verified[piece_index] = false;
+        self.resets += 1;
+    }
+
+    /// Forget that the blocks of a piece which haven't arrived were requested, so that they
+    /// are asked for again. The blocks that did arrive, from whoever sent them, are kept.
+    pub fn release_piece(&mut self, piece_index: u64) {
+        let piece_index = piece_index as usize;
+        let received = self.received[piece_index].clone();
+
+        self.update_requested(piece_index, |requested| requested.copy_from_slice(&received));
     }
 
     /// Find out of a piece_block as been requested.
     ///
     /// If the piece has been requested and we still haven't received the piece, it will return false.
+    /// Blocks of skipped pieces are never needed.
     pub fn needed(&mut self, piece_block: PieceBlock) -> bool {
-        let mut requested_all_pieces = true;
         let block_index = piece_block.begin / BLOCK_LEN;
 
-        // Check if all pieces have been requested
-        for piece in &self.requested {
-            for block in piece {
-                if !block {
-                    requested_all_pieces = false;
-                    break;
-                }
-            }
-            if !requested_all_pieces {
-                break;
-            }
+        if self.piece_priority(piece_block.index) == Priority::Skip {
+            return false;
         }
 
         // If all of the pieces have been requested, replace requested with a copy of received.
         // This is used to refresh the list of requested pieces.
-        if requested_all_pieces {
+        if self.wanted_requested == self.wanted_blocks {
             self.requested = self.received.clone();
+            self.wanted_requested = self.wanted_received;
         }
 
-        return !self.requested[piece_block.index as usize][block_index as usize];
+        !self.requested[piece_block.index as usize][block_index as usize]
+    }
+
+    /// Whether a piece still has to be downloaded: it's wanted and hasn't passed the hash check.
+    pub fn wants_piece(&self, piece_index: u64) -> bool {
+        self.wanted[piece_index as usize] && !self.verified[piece_index as usize]
+    }
+
+    /// The percentage of the wanted blocks that have been received.
+    pub fn percent_received(&self) -> f32 {
+        calculate_downloaded_percent(self.wanted_received, self.wanted_blocks)
     }
 
     /// Check if every piece and block has been received
     pub fn is_done(&self) -> bool {
-        return self.percent_received == 100.0;
+        self.wanted_received == self.wanted_blocks
     }
 }
 
+/// Work out the priority of each piece from the priorities of the files it overlaps.
+///
+/// A piece takes the highest priority of its files, so a piece that straddles a skipped
+/// file and a wanted file is still downloaded. Files without a priority are Normal.
+pub fn piece_priorities(torrent: &Torrent, layout: &FileLayout, file_priorities: &[Priority]) -> Vec<Priority> {
+    let mut priorities = Vec::with_capacity(torrent.num_pieces() as usize);
+
+    for piece_index in 0..torrent.num_pieces() {
+        let offset = torrent.piece_offset(piece_index);
+        let piece_len = torrent.get_piece_len(piece_index);
+
+        let priority = layout
+            .slices(offset, piece_len)
+            .iter()
+            .map(|slice| file_priorities.get(slice.file_index).copied().unwrap_or_default())
+            .max()
+            .unwrap_or(Priority::Skip);
+
+        priorities.push(priority);
+    }
+
+    priorities
+}
+
+
+/// The number of blocks of a piece that have been received.
+fn count_received(blocks: &[bool]) -> usize {
+    blocks.iter().filter(|block| **block).count()
+}
+
+
 /// Calculate the percentage of blocks that have been received.
-fn calculate_downloaded_percent(pieces: &Vec<Vec<bool>>) -> f32 {
-    let mut total_blocks: f32 = 0.0;
-    let mut downloaded: f32 = 0.0;
+fn calculate_downloaded_percent(downloaded: usize, total_blocks: usize) -> f32 {
+    // Nothing left to download when every piece is skipped.
+    if total_blocks == 0 {
+        return 100.0;
+    }
 
-    for piece in pieces {
-        for block in piece {
-            total_blocks += 1.0;
+    downloaded as f32 / total_blocks as f32 * 100.0
+}
 
-            if *block {
-                downloaded += 1.0;
-            }
+#[test]
+fn test_reset_piece() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+
+    for begin in &[0, BLOCK_LEN] {
+        let block = PieceBlock { index: 3, begin: *begin, length: None };
+        pieces.add_requested(block);
+        pieces.add_received(block);
+    }
+    assert!(pieces.piece_received(3));
+    assert!(!pieces.piece_received(2));
+
+    // Receiving a block twice doesn't count it twice.
+    let percent = pieces.percent_received();
+    assert!(percent > 0.0);
+    pieces.add_received(PieceBlock { index: 3, begin: 0, length: None });
+    assert_eq!(pieces.percent_received(), percent);
+
+    pieces.add_verified(3);
+    assert!(pieces.is_verified(3));
+
+    pieces.reset_piece(3);
+    assert!(!pieces.piece_received(3));
+    assert_eq!(pieces.percent_received(), 0.0);
+    assert!(!pieces.is_verified(3));
+    assert!(pieces.needed(PieceBlock { index: 3, begin: 0, length: None }));
+}
+
+#[test]
+fn test_release_piece() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+
+    // A peer sent the first block while a web seed was fetching the whole piece.
+    let first = PieceBlock { index: 0, begin: 0, length: None };
+    let second = PieceBlock { index: 0, begin: BLOCK_LEN, length: None };
+    assert_eq!(pieces.pick_piece(), Some(0));
+    pieces.add_received(first);
+
+    pieces.release_piece(0);
+    assert!(!pieces.needed(first));
+    assert!(pieces.needed(second));
+    assert!(pieces.percent_received() > 0.0);
+}
+
+#[test]
+fn test_piece_priorities() {
+    // file1.zip is 256842 bytes, so piece 7 (229376..262144) straddles both files.
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let priorities = piece_priorities(&torrent, &torrent.layout, &[Priority::Skip, Priority::High]);
+
+    assert_eq!(priorities[0], Priority::Skip);
+    assert_eq!(priorities[6], Priority::Skip);
+    assert_eq!(priorities[7], Priority::High);
+    assert_eq!(priorities[14], Priority::High);
+
+    let priorities = piece_priorities(&torrent, &torrent.layout, &[Priority::Low]);
+    assert_eq!(priorities[0], Priority::Low);
+    assert_eq!(priorities[14], Priority::Normal);
+}
+
+#[test]
+fn test_skipped_pieces_not_needed() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+    pieces.set_file_priorities(&torrent, &[Priority::Normal, Priority::Skip]);
+
+    assert!(pieces.needed(PieceBlock { index: 0, begin: 0, length: None }));
+    assert!(!pieces.needed(PieceBlock { index: 10, begin: 0, length: None }));
+
+    // The download is done once every wanted piece is in.
+    for piece_index in 0..8 {
+        for block_index in 0..torrent.get_blocks_per_piece(piece_index) {
+            pieces.add_received(PieceBlock { index: piece_index, begin: block_index * BLOCK_LEN, length: None });
         }
     }
+    assert!(pieces.is_done());
 
-    let percent = downloaded / total_blocks * 100.0;
+    // Skipped pieces aren't left to download either.
+    let wanted: u64 = (0..8).map(|piece_index| torrent.get_piece_len(piece_index)).sum();
+    assert_eq!(pieces.bytes_left(&torrent), wanted);
+    pieces.add_verified(0);
+    assert_eq!(pieces.bytes_left(&torrent), wanted - torrent.get_piece_len(0));
+}
+
+#[test]
+fn test_needed_once_everything_is_requested() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+    pieces.set_file_priorities(&torrent, &[Priority::Normal, Priority::Skip]);
+    let first = PieceBlock { index: 0, begin: 0, length: None };
+    let second = PieceBlock { index: 1, begin: 0, length: None };
 
-    return percent;
+    for piece_index in 0..8 {
+        for block_index in 0..torrent.get_blocks_per_piece(piece_index) {
+            pieces.add_requested(PieceBlock { index: piece_index, begin: block_index * BLOCK_LEN, length: None });
+        }
+    }
+    pieces.add_received(first);
+
+    // Once every wanted block is asked for, the ones that haven't come in are asked for again.
+    assert!(!pieces.needed(first));
+    assert!(pieces.needed(second));
+    assert!(pieces.wants_piece(1));
+    assert!(!pieces.wants_piece(10));
+
+    pieces.add_verified(1);
+    assert!(!pieces.wants_piece(1));
+}
+
+#[test]
+fn test_pick_rank() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+
+    pieces.add_availability(2);
+    pieces.add_availability(3);
+    pieces.add_availability(3);
+    let best = |pieces: &Pieces, indexes: &[u64]| *indexes.iter().max_by_key(|i| pieces.pick_rank(**i)).unwrap();
+
+    // Nobody has piece 0, so it's the rarest.
+    assert_eq!(best(&pieces, &[3, 2, 0]), 0);
+
+    pieces.set_pick_mode(PickMode::Sequential);
+    pieces.set_reader_piece(2);
+    assert_eq!(best(&pieces, &[0, 4, 2]), 2);
+    assert_eq!(best(&pieces, &[0, 1]), 0);
+
+    // Pieces ahead of the reader beat everything else, even higher priority files.
+    pieces.set_pick_mode(PickMode::Deadline);
+    pieces.set_file_priorities(&torrent, &[Priority::Normal, Priority::High]);
+    pieces.set_reader_piece(3);
+    assert_eq!(best(&pieces, &[14, 5, 4]), 4);
+    assert_eq!(best(&pieces, &[14, 0, 1]), 14);
+    assert_eq!(best(&pieces, &[0, 1, 2]), 0);
+}
+
+#[test]
+fn test_pick_piece() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut pieces = Pieces::new(&torrent);
+    pieces.set_pick_mode(PickMode::Sequential);
+    pieces.set_file_priorities(&torrent, &[Priority::Skip, Priority::Normal]);
+
+    // The first piece of the second file, the skipped pieces before it are left alone.
+    assert_eq!(pieces.pick_piece(), Some(7));
+    assert_eq!(pieces.pick_piece(), Some(8));
+
+    for piece_index in 9..15 {
+        pieces.add_requested(PieceBlock { index: piece_index, begin: 0, length: None });
+    }
+
+    // Everything has been asked for, so the pieces still missing blocks are handed out again.
+    assert_eq!(pieces.pick_piece(), Some(7));
+
+    for piece_index in 7..15 {
+        pieces.add_verified(piece_index);
+    }
+    assert_eq!(pieces.pick_piece(), None);
 }
 
 #[test]
 fn test_calculate_downloaded_percent() {
-    let pieces: Vec<Vec<bool>> = vec![vec![true, true]; 5];
-    let percent_downloaded = calculate_downloaded_percent(&pieces);
+    let percent_downloaded = calculate_downloaded_percent(10, 10);
     assert_eq!(percent_downloaded, 100.0);
 
 
-    let pieces: Vec<Vec<bool>> = vec![vec![true, false]; 5];
-    let percent_downloaded = calculate_downloaded_percent(&pieces);
+    let percent_downloaded = calculate_downloaded_percent(5, 10);
     assert_eq!(percent_downloaded, 50.0);
 
 
-    let pieces: Vec<Vec<bool>> = vec![vec![true, true, false]; 5];
-    let percent_downloaded = calculate_downloaded_percent(&pieces);
+    let percent_downloaded = calculate_downloaded_percent(10, 15);
     assert_eq!(percent_downloaded, 66.66667);
+
+    assert_eq!(calculate_downloaded_percent(0, 0), 100.0);
 }
 
 
@@ -116,10 +585,10 @@ fn build_pieces_vec(torrent: &Torrent) -> Vec<Vec<bool>> {
     let mut vec: Vec<Vec<bool>> = vec![vec![false; 0]; num_pieces];
 
     // For each piece, fill it with a vec which is the length of blocks for that piece
-    for i in 0..num_pieces {
+    for (i, blocks) in vec.iter_mut().enumerate() {
         let blocks_per_piece = torrent.get_blocks_per_piece(i as u64);
-        vec[i] = vec![false; blocks_per_piece as usize];
+        *blocks = vec![false; blocks_per_piece as usize];
     }
 
-    return vec;
+    vec
 }
@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::collections::BTreeMap;
 
 use crate::utils::torrents::{BLOCK_LEN, Torrent};
 
@@ -9,48 +9,91 @@ pub struct PieceBlock {
     pub length: Option<u64>,
 }
 
-/// Job queue which tracks the blocks and pieces that need to be downloaded from a given peer
+/// Job queue which tracks the pieces that need to be downloaded from a given peer
+///
+/// Each piece is queued once along with the next of its blocks to ask for, so picking a block
+/// only ranks the pieces and not every block of them.
 pub struct Queue<'a> {
     torrent: &'a Torrent,
     pub(crate) choked: bool,
-    pub(crate) pieces: VecDeque<PieceBlock>,
+    /// The index of the next block to ask for, by piece.
+    pub(crate) pieces: BTreeMap<u64, u64>,
+    /// The blocks we asked the peer for that haven't come in yet.
+    pub(crate) requested: usize,
 }
 
 impl Queue<'_> {
-    pub fn new(torrent: &Torrent) -> Queue {
+    pub fn new(torrent: &Torrent) -> Queue<'_> {
         Queue {
             choked: true,
-            pieces: VecDeque::new(),
+            pieces: BTreeMap::new(),
+            requested: 0,
             torrent,
         }
     }
 
-    /// Add the blocks from a given piece_index into the job queue
+    /// Add the blocks from a given piece_index into the job queue, a piece that's already in
+    /// starts over from its first block.
     pub fn queue(&mut self, piece_index: u64) {
-        let num_blocks = self.torrent.get_blocks_per_piece(piece_index);
-
-        for i in 0..num_blocks {
-            let piece_block = PieceBlock {
-                index: piece_index,
-                begin: i * BLOCK_LEN,
-                length: Some(self.torrent.get_block_len(piece_index, i)),
-            };
-            self.pieces.push_back(piece_block);
-        }
+        self.pieces.insert(piece_index, 0);
     }
 
-    /// Remove the first item from the pieces queue.
-    pub fn deque(&mut self) -> Option<PieceBlock> {
-        return self.pieces.pop_front();
-    }
+    /// Remove the next block of the highest ranked piece in the queue.
+    ///
+    /// Pieces ranked None aren't wanted anymore and are dropped from the queue.
+    pub fn deque_by_rank<K: Ord, F: Fn(u64) -> Option<K>>(&mut self, rank: F) -> Option<PieceBlock> {
+        let mut best: Option<(u64, K)> = None;
 
-    /// Get the first item in pieces queue.
-    pub fn peek(&self) -> PieceBlock {
-        return self.pieces[0];
-    }
+        self.pieces.retain(|&piece_index, _| match rank(piece_index) {
+            Some(piece_rank) => {
+                if best.as_ref().is_none_or(|(_, r)| piece_rank > *r) {
+                    best = Some((piece_index, piece_rank));
+                }
+                true
+            }
+            None => false,
+        });
+
+        let (piece_index, _) = best?;
+        let block_index = self.pieces[&piece_index];
 
-    /// Get the length of the pieces queue.
-    pub fn len(&self) -> usize {
-        return self.pieces.len();
+        if block_index + 1 < self.torrent.get_blocks_per_piece(piece_index) {
+            self.pieces.insert(piece_index, block_index + 1);
+        } else {
+            self.pieces.remove(&piece_index);
+        }
+
+        Some(PieceBlock {
+            index: piece_index,
+            begin: block_index * BLOCK_LEN,
+            length: Some(self.torrent.get_block_len(piece_index, block_index)),
+        })
     }
 }
+
+
+#[test]
+fn test_deque_by_rank() {
+    use crate::pieces::Priority;
+
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut queue = Queue::new(&torrent);
+    queue.queue(0);
+    queue.queue(5);
+    queue.queue(9);
+
+    let priority = |index: u64| Some(if index == 5 { Priority::High } else { Priority::Normal });
+
+    let first = queue.deque_by_rank(priority).unwrap();
+    assert_eq!((first.index, first.begin), (5, 0));
+    let second = queue.deque_by_rank(priority).unwrap();
+    assert_eq!((second.index, second.begin), (5, BLOCK_LEN));
+    assert_eq!(queue.deque_by_rank(priority).unwrap().index, 0);
+    assert_eq!(queue.pieces.len(), 2);
+
+    // Queueing a piece again starts it over, pieces that aren't wanted are dropped.
+    queue.queue(5);
+    assert_eq!(queue.deque_by_rank(priority).unwrap().begin, 0);
+    assert_eq!(queue.deque_by_rank(|index| (index != 5).then_some(index)).unwrap().index, 9);
+    assert_eq!(queue.pieces.keys().collect::<Vec<_>>(), vec![&0, &9]);
+}
@@ -0,0 +1,280 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_derive::Deserialize;
+
+/// A token bucket limiting how many bytes go through per second.
+///
+/// The bucket holds up to a second worth of bytes, so a burst after being idle is allowed.
+/// Taking more bytes than there are in the bucket puts it in debt, which is paid back
+/// by waiting. That way a block bigger than the whole bucket still gets through.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.max(1),
+            tokens: bytes_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `amount` bytes from the bucket and get how long to wait before sending them.
+    pub fn reserve(&mut self, amount: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_second as f64;
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - amount as f64;
+        self.last_refill = now;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    /// Wait until `amount` bytes can go through.
+    pub async fn acquire(&mut self, amount: u64) {
+        let wait = self.reserve(amount, Instant::now());
+
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A rate limiter shared by many tasks, whose rate can be changed while they use it.
+#[derive(Debug, Clone, Default)]
+pub struct SharedLimiter(Arc<Mutex<Option<RateLimiter>>>);
+
+impl SharedLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> SharedLimiter {
+        SharedLimiter(Arc::new(Mutex::new(bytes_per_second.map(RateLimiter::new))))
+    }
+
+    /// Change the rate, None lifts the limit.
+    pub fn set_rate(&self, bytes_per_second: Option<u64>) {
+        let mut limiter = self.0.lock().unwrap();
+
+        if limiter.as_ref().map(|limiter| limiter.bytes_per_second) != bytes_per_second {
+            *limiter = bytes_per_second.map(RateLimiter::new);
+        }
+    }
+
+    /// Take `amount` bytes and get how long to wait before sending them, see RateLimiter::reserve.
+    pub fn reserve(&self, amount: u64, now: Instant) -> Duration {
+        match self.0.lock().unwrap().as_mut() {
+            Some(limiter) => limiter.reserve(amount, now),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Wait until `amount` bytes can go through.
+    ///
+    /// The lock isn't held while waiting, every task takes its share and waits for it on its own.
+    pub async fn acquire(&self, amount: u64) {
+        let wait = self.reserve(amount, Instant::now());
+
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The download and upload limiters of a peer, a torrent or the whole session.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    pub download: SharedLimiter,
+    pub upload: SharedLimiter,
+}
+
+impl Bandwidth {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Bandwidth {
+        Bandwidth { download: SharedLimiter::new(download), upload: SharedLimiter::new(upload) }
+    }
+
+    pub fn set_rates(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+/// The limiters the bytes of a peer go through, e.g. its own, its torrent's and the session's.
+///
+/// The bytes are taken from all of them at once and wait for the slowest. A peer waits for
+/// its share before reading or sending more, so the peers sharing a limiter take turns
+/// and split its rate evenly between them.
+#[derive(Debug, Clone, Default)]
+pub struct LimiterChain(Vec<Bandwidth>);
+
+impl LimiterChain {
+    pub fn new(bandwidths: Vec<Bandwidth>) -> LimiterChain {
+        LimiterChain(bandwidths)
+    }
+
+    pub async fn download(&self, amount: u64) {
+        wait(self.0.iter().map(|bandwidth| bandwidth.download.reserve(amount, Instant::now())).max()).await;
+    }
+
+    pub async fn upload(&self, amount: u64) {
+        wait(self.0.iter().map(|bandwidth| bandwidth.upload.reserve(amount, Instant::now())).max()).await;
+    }
+}
+
+async fn wait(duration: Option<Duration>) {
+    match duration {
+        Some(duration) if duration > Duration::from_secs(0) => tokio::time::sleep(duration).await,
+        _ => {}
+    }
+}
+
+/// Whether an address is on the local network, peers there get the LAN limits instead of the session ones.
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        // Unique local fc00::/7 and link local fe80::/10.
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// A rate in bytes per second, written as a number with an optional K, M or G suffix.
+///
+/// ```text
+/// 500    500 bytes per second
+/// 64K    64 KiB per second
+/// 1.5M   1.5 MiB per second
+/// ```
+///
+/// In a settings file it can also be a plain number of bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RateValue")]
+pub struct Rate(pub u64);
+
+/// Read an optional rate from a settings file as bytes per second.
+pub(crate) fn deserialize_rate<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let rate: Option<Rate> = serde::Deserialize::deserialize(deserializer)?;
+
+    Ok(rate.map(|Rate(rate)| rate))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RateValue {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RateValue> for Rate {
+    type Error = String;
+
+    fn try_from(value: RateValue) -> Result<Self, Self::Error> {
+        match value {
+            RateValue::Bytes(0) => Err("Invalid rate: 0".to_string()),
+            RateValue::Bytes(bytes) => Ok(Rate(bytes)),
+            RateValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1024.0),
+            Some((i, 'm' | 'M')) => (&s[..i], 1024.0 * 1024.0),
+            Some((i, 'g' | 'G')) => (&s[..i], 1024.0 * 1024.0 * 1024.0),
+            _ => (s, 1.0),
+        };
+
+        match number.parse::<f64>() {
+            Ok(number) if number.is_finite() && number > 0.0 => Ok(Rate((number * multiplier) as u64)),
+            _ => Err(format!("Invalid rate: {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(1000);
+    limiter.last_refill = start;
+
+    // A full bucket lets a second worth of bytes through right away.
+    assert_eq!(limiter.reserve(1000, start), Duration::from_secs(0));
+
+    // The next bytes have to wait for the bucket to fill up again.
+    assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+
+    // Being idle for longer than a second doesn't build up more than a second of bytes.
+    let later = start + Duration::from_secs(10);
+    assert_eq!(limiter.reserve(1000, later), Duration::from_secs(0));
+    assert_eq!(limiter.reserve(2000, later), Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_shared_limiter() {
+    let limiter = SharedLimiter::new(Some(1000));
+    limiter.acquire(1000).await;
+    assert!(limiter.0.lock().unwrap().as_ref().unwrap().tokens < 1.0);
+
+    // Changing the rate starts over with a full bucket, the same rate keeps the bucket as it is.
+    limiter.set_rate(Some(1000));
+    assert!(limiter.0.lock().unwrap().as_ref().unwrap().tokens < 1.0);
+    limiter.set_rate(Some(2000));
+    assert_eq!(limiter.0.lock().unwrap().as_ref().unwrap().tokens, 2000.0);
+
+    limiter.set_rate(None);
+    limiter.acquire(u64::MAX).await;
+}
+
+#[test]
+fn test_limiter_chain() {
+    let now = Instant::now();
+    let peer = Bandwidth::new(Some(1000), None);
+    let session = Bandwidth::new(Some(500), Some(100));
+    let chain = LimiterChain::new(vec![peer.clone(), session.clone()]);
+
+    // Bytes are taken from every limiter, the slowest sets the wait.
+    let reserve = |amount| chain.0.iter().map(|bandwidth| bandwidth.download.reserve(amount, now)).max().unwrap();
+    assert_eq!(reserve(1000), Duration::from_secs(1));
+    assert_eq!(peer.download.reserve(0, now), Duration::from_secs(0));
+    assert_eq!(session.download.reserve(0, now), Duration::from_secs(1));
+
+    // A peer without an upload limit is still held back by the session.
+    assert_eq!(chain.0.iter().map(|bandwidth| bandwidth.upload.reserve(200, now)).max().unwrap(), Duration::from_secs(1));
+}
+
+#[test]
+fn test_is_local() {
+    assert!(is_local("192.168.1.20".parse().unwrap()));
+    assert!(is_local("10.0.0.1".parse().unwrap()));
+    assert!(is_local("127.0.0.1".parse().unwrap()));
+    assert!(is_local("fd12:3456::1".parse().unwrap()));
+    assert!(is_local("fe80::1".parse().unwrap()));
+    assert!(!is_local("8.8.8.8".parse().unwrap()));
+    assert!(!is_local("2001:db8::1".parse().unwrap()));
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!("500".parse(), Ok(Rate(500)));
+    assert_eq!("64K".parse(), Ok(Rate(64 * 1024)));
+    assert_eq!("1.5m".parse(), Ok(Rate(1024 * 1024 * 3 / 2)));
+    assert_eq!("2G".parse(), Ok(Rate(2 * 1024 * 1024 * 1024)));
+
+    assert!("".parse::<Rate>().is_err());
+    assert!("K".parse::<Rate>().is_err());
+    assert!("-5".parse::<Rate>().is_err());
+    assert!("fast".parse::<Rate>().is_err());
+}
@@ -0,0 +1,248 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::disk::SharedStorage;
+use crate::download::PiecesManager;
+use crate::pieces::Priority;
+use crate::utils::torrents::Torrent;
+
+/// Reads a file of a torrent while it's still being downloaded.
+///
+/// A read blocks until the piece holding the data has been downloaded and verified, or fails
+/// if the torrent is stopped while it waits. Every read also moves the reader position of the torrent, so that in sequential and
+/// deadline mode the pieces right after it are requested first.
+pub struct TorrentReader {
+    torrent: Arc<Torrent>,
+    storage: SharedStorage,
+    pieces: PiecesManager,
+    verified: Arc<Condvar>,
+    /// Set while the torrent isn't downloading, so that nothing waits for pieces that won't come.
+    cancelled: Arc<AtomicBool>,
+    /// Set once whoever reads has given up, see cancel_guard.
+    abandoned: Arc<AtomicBool>,
+    file_offset: u64,
+    file_length: u64,
+    position: u64,
+}
+
+impl TorrentReader {
+    pub(crate) fn new(
+        torrent: Arc<Torrent>,
+        storage: SharedStorage,
+        pieces: PiecesManager,
+        verified: Arc<Condvar>,
+        cancelled: Arc<AtomicBool>,
+        file_offset: u64,
+        file_length: u64,
+    ) -> TorrentReader {
+        TorrentReader {
+            torrent,
+            storage,
+            pieces,
+            verified,
+            cancelled,
+            abandoned: Arc::new(AtomicBool::new(false)),
+            file_offset,
+            file_length,
+            position: 0,
+        }
+    }
+
+    /// The length of the file being read.
+    pub fn len(&self) -> u64 {
+        self.file_length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_length == 0
+    }
+
+    /// Get a guard that makes the reads of this reader fail once it's dropped, including one
+    /// that is waiting on a piece. For readers handed to another thread by an async task, so
+    /// that the thread doesn't wait forever for a piece nobody wants anymore.
+    pub fn cancel_guard(&self) -> CancelGuard {
+        CancelGuard {
+            pieces: self.pieces.clone(),
+            verified: self.verified.clone(),
+            abandoned: self.abandoned.clone(),
+        }
+    }
+
+    /// Wait for a piece to pass the hash check.
+    fn wait_for_piece(&self, piece_index: u64) -> io::Result<()> {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.set_reader_piece(piece_index);
+
+        while !pieces.is_verified(piece_index) {
+            // A skipped piece is never going to be downloaded.
+            if pieces.piece_priority(piece_index) == Priority::Skip {
+                return Err(io::Error::other(format!("Piece {} belongs to a skipped file", piece_index)));
+            }
+
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Err(io::Error::other(format!("The torrent was stopped while waiting for piece {}", piece_index)));
+            }
+            if self.abandoned.load(Ordering::SeqCst) {
+                return Err(io::Error::other(format!("The read of piece {} was cancelled", piece_index)));
+            }
+
+            pieces = self.verified.wait(pieces).unwrap();
+        }
+
+        Ok(())
+    }
+}
+
+/// Cancels the reads of a TorrentReader when dropped, see TorrentReader::cancel_guard.
+pub struct CancelGuard {
+    pieces: PiecesManager,
+    verified: Arc<Condvar>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.abandoned.store(true, Ordering::SeqCst);
+
+        // Holding the lock means the reader is either waiting or about to check the flag.
+        if let Ok(_pieces) = self.pieces.lock() {
+            self.verified.notify_all();
+        }
+    }
+}
+
+impl Read for TorrentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.file_length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let offset = self.file_offset + self.position;
+        let piece_index = offset / self.torrent.info.piece_length;
+        let begin = offset - self.torrent.piece_offset(piece_index);
+
+        // Never read past the end of the piece or of the file.
+        let length = (buf.len() as u64)
+            .min(self.torrent.get_piece_len(piece_index) - begin)
+            .min(self.file_length - self.position);
+
+        self.wait_for_piece(piece_index)?;
+
+        let data = self.storage.lock().unwrap().read_block(piece_index, begin, length)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.position += data.len() as u64;
+
+        Ok(data.len())
+    }
+}
+
+impl Seek for TorrentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.file_length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative position")),
+        }
+    }
+}
+
+#[test]
+fn test_reader_waits_for_verified_pieces() {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use crate::pieces::{Pieces, PickMode};
+    use crate::storage::MemoryStorage;
+
+    // file1.zip is 256842 bytes long, pieces are 32768 bytes.
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let piece_length = torrent.info.piece_length;
+    let storage: SharedStorage = Arc::new(Mutex::new(Box::new(MemoryStorage::new(torrent.clone()))));
+    let pieces: PiecesManager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    let verified = Arc::new(Condvar::new());
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    for piece_index in 0..torrent.num_pieces() {
+        let data = vec![piece_index as u8; torrent.get_piece_len(piece_index) as usize];
+        storage.lock().unwrap().write_block(piece_index, 0, &data).unwrap();
+    }
+    pieces.lock().unwrap().add_verified(0);
+    pieces.lock().unwrap().set_pick_mode(PickMode::Deadline);
+
+    let file = &torrent.layout.files()[1];
+    let mut reader = TorrentReader::new(torrent.clone(), storage.clone(), pieces.clone(), verified.clone(), cancelled, file.offset, file.length);
+
+    // The second file starts at the end of piece 7.
+    let mut buf = vec![0; 10];
+    let handle = thread::spawn(move || {
+        reader.read_exact(&mut buf).unwrap();
+        (reader, buf)
+    });
+
+    thread::sleep(std::time::Duration::from_millis(50));
+    assert!(!handle.is_finished());
+
+    // The reader is waiting on piece 7, so it's now the most urgent.
+    assert!(pieces.lock().unwrap().pick_rank(7).0);
+
+    {
+        let mut pieces = pieces.lock().unwrap();
+        pieces.add_verified(7);
+        verified.notify_all();
+    }
+
+    let (mut reader, buf) = handle.join().unwrap();
+    assert_eq!(buf, vec![7; 10]);
+
+    // Reads stop at the end of the file.
+    reader.seek(SeekFrom::End(-4)).unwrap();
+    pieces.lock().unwrap().add_verified(torrent.num_pieces() - 1);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, vec![(torrent.num_pieces() - 1) as u8; 4]);
+
+    assert!(reader.seek(SeekFrom::Current(-(piece_length as i64) * 100)).is_err());
+}
+
+#[test]
+fn test_reader_fails_when_stopped() {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use crate::disk::DiskPool;
+    use crate::events::TorrentEvents;
+    use crate::pieces::Pieces;
+    use crate::storage::MemoryStorage;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let pieces: PiecesManager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    let disk_pool = DiskPool::new(1);
+    let disk = disk_pool.add_torrent(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())), pieces, TorrentEvents::unobserved(&torrent));
+
+    let mut reader = disk.reader(0).unwrap();
+    let handle = thread::spawn(move || reader.read(&mut [0; 10]));
+
+    thread::sleep(std::time::Duration::from_millis(50));
+    disk.cancel_reads();
+    assert!(handle.join().unwrap().is_err());
+
+    // Reads wait again once the torrent is started again.
+    disk.resume_reads();
+    let mut reader = disk.reader(0).unwrap();
+    let handle = thread::spawn(move || reader.read(&mut [0; 10]));
+
+    thread::sleep(std::time::Duration::from_millis(50));
+    assert!(!handle.is_finished());
+    disk.cancel_reads();
+    assert!(handle.join().unwrap().is_err());
+}
@@ -0,0 +1,87 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+/// When the alternative speed limits are on, e.g. during work hours
+///
+/// ```text
+/// [alt_speed_schedule]
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// begin = "09:00"
+/// end = "18:00"
+/// ```
+///
+/// Without days it's every day. A schedule that ends before it begins runs overnight,
+/// from 22:00 to 06:00 starts on the given days and ends the morning after. One that
+/// begins and ends at the same time lasts the whole day.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    #[serde(default = "every_day", deserialize_with = "weekdays")]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "time_of_day")]
+    pub begin: NaiveTime,
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: NaiveTime,
+}
+
+impl Schedule {
+    /// Whether the schedule is on at a given local time.
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        let on_day = |day: Weekday| self.days.contains(&day);
+
+        if self.begin == self.end {
+            on_day(day)
+        } else if self.begin < self.end {
+            on_day(day) && self.begin <= time && time < self.end
+        } else {
+            (on_day(day) && time >= self.begin) || (on_day(day.pred()) && time < self.end)
+        }
+    }
+}
+
+fn every_day() -> Vec<Weekday> {
+    vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun]
+}
+
+fn weekdays<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|day| day.parse().map_err(|_| serde::de::Error::custom(format!("Invalid day: {}", day))))
+        .collect()
+}
+
+fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(|_| serde::de::Error::custom(format!("Invalid time of day, expected HH:MM: {}", time)))
+}
+
+#[test]
+fn test_schedule_is_active() {
+    use chrono::NaiveDate;
+
+    // The 3rd of January 2022 is a Monday.
+    let at = |day: u32, hour: u32, minute: u32| NaiveDate::from_ymd_opt(2022, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+
+    let work_hours: Schedule = toml::from_str("days = [\"mon\", \"tue\", \"wed\", \"thu\", \"fri\"]\nbegin = \"09:00\"\nend = \"18:00\"").unwrap();
+    assert!(work_hours.is_active(at(3, 9, 0)));
+    assert!(work_hours.is_active(at(7, 17, 59)));
+    assert!(!work_hours.is_active(at(3, 18, 0)));
+    assert!(!work_hours.is_active(at(3, 8, 59)));
+    assert!(!work_hours.is_active(at(8, 12, 0)));
+
+    // Friday night runs on into Saturday morning.
+    let overnight: Schedule = toml::from_str("days = [\"friday\"]\nbegin = \"22:00\"\nend = \"06:00\"").unwrap();
+    assert!(overnight.is_active(at(7, 23, 0)));
+    assert!(overnight.is_active(at(8, 5, 59)));
+    assert!(!overnight.is_active(at(7, 5, 0)));
+    assert!(!overnight.is_active(at(8, 22, 30)));
+
+    let all_day: Schedule = toml::from_str("begin = \"00:00\"\nend = \"00:00\"").unwrap();
+    assert!(all_day.is_active(at(9, 12, 0)));
+
+    assert!(toml::from_str::<Schedule>("begin = \"9am\"\nend = \"18:00\"").is_err());
+    assert!(toml::from_str::<Schedule>("days = [\"someday\"]\nbegin = \"09:00\"\nend = \"18:00\"").is_err());
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+/// When a finished torrent has seeded enough, e.g. in a settings file
+///
+/// ```text
+/// [seed_goals]
+/// ratio = 2.0
+/// time = 86400
+/// idle_time = 3600
+/// action = "remove"
+/// ```
+///
+/// The torrent reaches its goal with whichever comes first: uploading `ratio` times what it
+/// downloaded, seeding for `time` seconds in all, or `idle_time` seconds of seeding without
+/// uploading anything. A goal that isn't set never ends the seeding.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeedGoals {
+    pub ratio: Option<f64>,
+    #[serde(deserialize_with = "optional_seconds")]
+    pub time: Option<Duration>,
+    #[serde(deserialize_with = "optional_seconds")]
+    pub idle_time: Option<Duration>,
+    pub action: SeedAction,
+}
+
+/// What happens to a torrent once it reaches its seeding goal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedAction {
+    /// Stop it like a pause, it stays in the session.
+    #[default]
+    Stop,
+    /// Take it out of the session, its data stays on disk.
+    Remove,
+}
+
+/// How far a seeding torrent got, to check it against its goals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeedProgress {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// The size of the torrent, the ratio is taken against it when nothing was downloaded.
+    pub size: u64,
+    pub seeding_time: Duration,
+    pub idle_time: Duration,
+}
+
+impl SeedGoals {
+    /// Whether a torrent that got this far has seeded enough.
+    pub fn reached(&self, progress: &SeedProgress) -> bool {
+        self.ratio.is_some_and(|ratio| progress.ratio() >= ratio)
+            || self.time.is_some_and(|time| progress.seeding_time >= time)
+            || self.idle_time.is_some_and(|idle_time| progress.idle_time >= idle_time)
+    }
+
+    /// Make sure the goals can be reached.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.ratio.is_none_or(|ratio| ratio.is_finite() && ratio >= 0.0)
+    }
+}
+
+impl SeedProgress {
+    /// The bytes uploaded for every byte downloaded.
+    pub fn ratio(&self) -> f64 {
+        let downloaded = if self.downloaded > 0 { self.downloaded } else { self.size };
+
+        self.uploaded as f64 / downloaded.max(1) as f64
+    }
+}
+
+fn optional_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+#[test]
+fn test_seed_goals() {
+    let progress = SeedProgress {
+        downloaded: 1000,
+        uploaded: 1500,
+        size: 1000,
+        seeding_time: Duration::from_secs(60),
+        idle_time: Duration::from_secs(10),
+    };
+
+    assert!(!SeedGoals::default().reached(&progress));
+    assert!(SeedGoals { ratio: Some(1.5), ..Default::default() }.reached(&progress));
+    assert!(!SeedGoals { ratio: Some(2.0), ..Default::default() }.reached(&progress));
+    assert!(SeedGoals { ratio: Some(2.0), time: Some(Duration::from_secs(60)), ..Default::default() }.reached(&progress));
+    assert!(!SeedGoals { idle_time: Some(Duration::from_secs(11)), ..Default::default() }.reached(&progress));
+
+    // A torrent we had all along is measured against its size.
+    assert_eq!(SeedProgress { downloaded: 0, uploaded: 500, ..progress }.ratio(), 0.5);
+    assert_eq!(SeedProgress { downloaded: 0, uploaded: 0, size: 0, ..progress }.ratio(), 0.0);
+
+    assert!(!SeedGoals { ratio: Some(-1.0), ..Default::default() }.is_valid());
+    assert!(!SeedGoals { ratio: Some(f64::NAN), ..Default::default() }.is_valid());
+}
@@ -0,0 +1,1336 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytebuffer::ByteBuffer;
+use chrono::Local;
+use serde_derive::Serialize;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::disk::DiskPool;
+use crate::error::{Error, Result};
+use crate::events::{Event, EVENT_QUEUE_LEN, EventReceiver, EventSender, TorrentEvents};
+use crate::disk::TorrentDisk;
+use crate::download::{check_handshake_msg, DownloadOptions, new_pieces_manager, PeerSlots, run_torrent, TorrentContext};
+use crate::magnet::MagnetLink;
+use crate::messages::{build_peer_handshake, HANDSHAKE_LEN};
+use crate::metadata;
+use crate::rate_limit::Bandwidth;
+use crate::seeding::{SeedAction, SeedProgress};
+use crate::settings::{Settings, SharedSettings};
+use crate::storage::{FsStorage, ResumeData, Storage};
+use crate::stream_server::StreamTorrents;
+use crate::pieces::Priority;
+use crate::utils::torrents::{MetainfoError, Torrent};
+
+/// How many peers that connected to us can wait to be picked up by their torrent.
+const INCOMING_QUEUE_LEN: usize = 16;
+
+/// How many peers that connected to us can be sending their handshake at once, the ones
+/// beyond that are disconnected right away.
+const MAX_PENDING_HANDSHAKES: usize = 32;
+
+/// How often the schedule of the alternative speed limits is looked at.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often the download rates are sampled to find the slow torrents of the queue, the seed
+/// goals are checked and the totals of the torrents are saved.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type InfoHash = [u8; 20];
+
+/// Parse an info hash written as 40 hex characters.
+pub fn info_hash_from_hex(hex: &str) -> Option<InfoHash> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut info_hash = [0; 20];
+    for (i, byte) in info_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(info_hash)
+}
+
+type SessionTorrents = Arc<Mutex<HashMap<InfoHash, SessionTorrent>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    /// Waiting for other torrents to finish, see Settings::max_active_downloads and
+    /// Settings::max_active_seeds.
+    Queued,
+    /// Done downloading and uploading to its peers, until it reaches its seed goals.
+    Seeding,
+    Finished,
+}
+
+/// A snapshot of a torrent in the session.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub state: TorrentState,
+    pub progress: f32,
+    /// The size of every file in the torrent, and what's left of the wanted ones.
+    pub size: u64,
+    pub left: u64,
+    pub download_folder: PathBuf,
+    /// Bytes per second over the last few seconds.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub queue_position: usize,
+    /// The bytes downloaded and uploaded since the torrent was first added, saved with its
+    /// data when the storage can keep them, see Storage::save_resume.
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+/// A snapshot of the whole session.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStats {
+    pub torrents: usize,
+    pub downloading: usize,
+    pub queued: usize,
+    pub paused: usize,
+    pub seeding: usize,
+    pub finished: usize,
+    /// The bytes downloaded and uploaded by the torrents in the session, see TorrentStatus.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub alt_speed: bool,
+    pub listen_addr: Option<SocketAddr>,
+}
+
+/// Whether the alternative speed limits are on.
+#[derive(Debug, Default)]
+struct AltSpeed {
+    /// What the schedule of the settings says.
+    scheduled: bool,
+    /// Set by hand, it holds until the schedule switches the next time.
+    manual: Option<bool>,
+}
+
+impl AltSpeed {
+    fn is_on(&self) -> bool {
+        self.manual.unwrap_or(self.scheduled)
+    }
+}
+
+/// Set the limits of the session from its settings, the alternative ones when they're on.
+fn apply_session_limits(settings: &Settings, alt_speed: bool, bandwidth: &Bandwidth) {
+    if alt_speed {
+        bandwidth.set_rates(settings.alt_download_limit, settings.alt_upload_limit);
+    } else {
+        bandwidth.set_rates(settings.download_limit, settings.upload_limit);
+    }
+}
+
+struct SessionTorrent {
+    context: TorrentContext,
+    options: DownloadOptions,
+    /// Set while the torrent is running, peers that connect to us are handed over through it.
+    incoming: Option<mpsc::Sender<TcpStream>>,
+    task: Option<JoinHandle<()>>,
+    /// Where the torrent is in the download queue, the lower the sooner it starts.
+    queue_position: usize,
+    /// Waiting in the queue for its turn to download.
+    queued: bool,
+    /// Set when the task of the torrent ends, finished or not, so that it gives up its place.
+    ended: Arc<AtomicBool>,
+    /// When the download rate was last sampled and the bytes downloaded by then.
+    sample: (Instant, u64),
+    /// Since when the torrent has been downloading slower than the slow torrent rate.
+    slow_since: Option<Instant>,
+    /// Bytes per second between the last two samples.
+    download_rate: u64,
+    /// When the seeding was last sampled and the bytes uploaded by then.
+    upload_sample: (Instant, u64),
+    /// Bytes per second uploaded between the last two samples.
+    upload_rate: u64,
+    /// How long the torrent has seeded, over every run of it.
+    seeding_time: Duration,
+    /// Since when the torrent has been seeding without uploading anything.
+    idle_since: Instant,
+    /// The totals last saved with the data, they're only saved again once they change.
+    saved: ResumeData,
+    queue_changed: Arc<Notify>,
+}
+
+impl SessionTorrent {
+    fn start(&mut self) {
+        let (sender, receiver) = mpsc::channel(INCOMING_QUEUE_LEN);
+        let context = self.context.clone();
+        let options = self.options.clone();
+        let (ended, queue_changed) = (Arc::new(AtomicBool::new(false)), self.queue_changed.clone());
+
+        self.incoming = Some(sender);
+        self.queued = false;
+        self.ended = ended.clone();
+        self.sample = (Instant::now(), self.context.downloaded.load(Ordering::Relaxed));
+        self.upload_sample = (Instant::now(), self.context.uploaded.load(Ordering::Relaxed));
+        self.slow_since = None;
+        self.idle_since = Instant::now();
+        self.context.disk.resume_reads();
+
+        // A finished torrent says it's seeding once its task has flushed the data.
+        if !self.is_done() {
+            self.context.events.send(Event::StateChanged { state: TorrentState::Downloading });
+        }
+
+        let span = info_span!("torrent", info_hash = %context.torrent.info_hash_hex());
+
+        self.task = Some(tokio::spawn(
+            async move {
+                match run_torrent(context.clone(), &options, receiver, true).await {
+                    Ok(_) => {
+                        info!("finished");
+                        context.events.send(Event::StateChanged { state: TorrentState::Finished });
+                    }
+                    Err(e) => {
+                        error!(error = %e, "stopped on an error");
+                        context.events.send(Event::Error { message: e.to_string() });
+                    }
+                }
+
+                ended.store(true, Ordering::SeqCst);
+                queue_changed.notify_one();
+            }
+            .instrument(span),
+        ));
+    }
+
+    /// Stop every task of the torrent, aborting the task drops its peers and web seeds too.
+    ///
+    /// Readers waiting on pieces fail rather than wait for the torrent to start again. The
+    /// tracker is told the torrent stopped, if it was told it started.
+    fn stop(&mut self) {
+        if self.is_running() && self.options.peers.is_empty() {
+            self.context.announce_stopped();
+        }
+
+        self.incoming = None;
+        self.queued = false;
+        self.download_rate = 0;
+        self.upload_rate = 0;
+        self.context.disk.cancel_reads();
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    fn status(&self) -> TorrentStatus {
+        let pieces = self.context.pieces.lock().unwrap();
+
+        let state = if self.queued {
+            TorrentState::Queued
+        } else if pieces.is_done() && self.is_running() {
+            TorrentState::Seeding
+        } else if pieces.is_done() {
+            TorrentState::Finished
+        } else if self.task.is_none() {
+            TorrentState::Paused
+        } else {
+            TorrentState::Downloading
+        };
+
+        TorrentStatus {
+            info_hash: self.context.torrent.info_hash_hex(),
+            name: self.context.torrent.info.name.clone(),
+            state,
+            progress: pieces.percent_received(),
+            size: self.context.torrent.size.unwrap_or_default(),
+            left: pieces.bytes_left(&self.context.torrent),
+            download_folder: self.options.download_folder.clone(),
+            download_rate: self.download_rate,
+            upload_rate: self.upload_rate,
+            queue_position: self.queue_position,
+            downloaded: self.context.downloaded.load(Ordering::Relaxed),
+            uploaded: self.context.uploaded.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.context.pieces.lock().unwrap().is_done()
+    }
+
+    fn is_running(&self) -> bool {
+        self.task.is_some() && !self.ended.load(Ordering::SeqCst)
+    }
+
+    fn is_seeding(&self) -> bool {
+        self.is_running() && self.is_done()
+    }
+
+    /// Whether the torrent is downloading or waiting to, and so needs a place among the active ones.
+    fn wants_to_download(&self) -> bool {
+        (self.task.is_some() || self.queued) && !self.ended.load(Ordering::SeqCst) && !self.is_done()
+    }
+
+    /// Whether the torrent is seeding or waiting to, and so needs a place among the active seeds.
+    fn wants_to_seed(&self) -> bool {
+        (self.task.is_some() || self.queued) && !self.ended.load(Ordering::SeqCst) && self.is_done()
+    }
+
+    /// Look at how fast the torrent downloaded since the last sample, to know when it turns slow.
+    fn sample_rate(&mut self, slow_rate: Option<u64>, now: Instant) {
+        let downloaded = self.context.downloaded.load(Ordering::Relaxed);
+        let (sampled_at, sampled) = std::mem::replace(&mut self.sample, (now, downloaded));
+        let elapsed = now.duration_since(sampled_at).as_secs_f64();
+        if elapsed > 0.0 {
+            self.download_rate = ((downloaded - sampled) as f64 / elapsed) as u64;
+        }
+
+        let slow = self.task.is_some() && slow_rate.is_some_and(|rate| ((downloaded - sampled) as f64) < rate as f64 * elapsed);
+        if !slow {
+            self.slow_since = None;
+        } else if self.slow_since.is_none() {
+            self.slow_since = Some(now);
+        }
+    }
+
+    /// Count the time spent seeding since the last sample, and look at how fast the torrent uploaded.
+    fn sample_seeding(&mut self, now: Instant) {
+        let uploaded = self.context.uploaded.load(Ordering::Relaxed);
+        let (sampled_at, sampled) = std::mem::replace(&mut self.upload_sample, (now, uploaded));
+        let elapsed = now.duration_since(sampled_at).as_secs_f64();
+        if elapsed > 0.0 {
+            self.upload_rate = ((uploaded - sampled) as f64 / elapsed) as u64;
+        }
+        let seeding = self.is_seeding();
+
+        if seeding {
+            self.seeding_time += now.duration_since(sampled_at);
+        }
+        if !seeding || uploaded != sampled {
+            self.idle_since = now;
+        }
+    }
+
+    fn seed_progress(&self, now: Instant) -> SeedProgress {
+        SeedProgress {
+            downloaded: self.context.downloaded.load(Ordering::Relaxed),
+            uploaded: self.context.uploaded.load(Ordering::Relaxed),
+            size: self.context.torrent.size.unwrap_or_default(),
+            seeding_time: self.seeding_time,
+            idle_time: now.duration_since(self.idle_since),
+        }
+    }
+
+    /// The totals to save with the data, from now on they count as saved.
+    fn take_resume(&mut self) -> ResumeData {
+        self.saved = self.context.resume_data(self.seeding_time.as_secs());
+        self.saved
+    }
+
+    /// The totals to save with the data, if they changed since they were last saved.
+    fn unsaved_resume(&mut self) -> Option<(TorrentDisk, ResumeData)> {
+        if self.context.resume_data(self.seeding_time.as_secs()) == self.saved {
+            return None;
+        }
+
+        Some((self.context.disk.clone(), self.take_resume()))
+    }
+
+    /// Whether the torrent has been slow for long enough not to count towards the active ones.
+    fn is_slow(&self, settings: &Settings, now: Instant) -> bool {
+        settings.slow_torrent_rate.is_some() && self.slow_since.is_some_and(|since| now.duration_since(since) >= settings.slow_torrent_time)
+    }
+}
+
+/// Start and stop torrents so that no more than the max active downloads and the max active
+/// seeds of the settings run at once, the first ones in the queue first. Slow torrents go on
+/// without taking a place.
+fn check_queue(torrents: &mut HashMap<InfoHash, SessionTorrent>, settings: &Settings, now: Instant) {
+    let downloads = torrents.values_mut().filter(|torrent| torrent.wants_to_download() && !torrent.is_slow(settings, now)).collect();
+    limit_active(downloads, settings.max_active_downloads);
+
+    let seeds = torrents.values_mut().filter(|torrent| torrent.wants_to_seed()).collect();
+    limit_active(seeds, settings.max_active_seeds);
+}
+
+/// Run the first max_active torrents in the queue and queue the others.
+fn limit_active(mut candidates: Vec<&mut SessionTorrent>, max_active: Option<usize>) {
+    candidates.sort_by_key(|torrent| torrent.queue_position);
+
+    let max_active = max_active.unwrap_or(usize::MAX);
+
+    for (active, torrent) in candidates.into_iter().enumerate() {
+        if active < max_active {
+            if torrent.queued {
+                torrent.start();
+            }
+        } else if !torrent.queued {
+            torrent.stop();
+            torrent.queued = true;
+            torrent.context.events.send(Event::StateChanged { state: TorrentState::Queued });
+        }
+    }
+}
+
+/// Stop or remove the seeding torrents that reached their seed goals, the goals of their
+/// download options or else the ones of the settings.
+///
+/// The removed torrents are handed back, so that their totals can be saved.
+fn check_seed_goals(torrents: &mut HashMap<InfoHash, SessionTorrent>, stream_torrents: &StreamTorrents, settings: &Settings, now: Instant) -> Vec<SessionTorrent> {
+    let reached: Vec<(InfoHash, SeedAction)> = torrents
+        .iter()
+        .filter(|(_, torrent)| torrent.is_seeding())
+        .filter_map(|(info_hash, torrent)| {
+            let goals = torrent.options.seed_goals.unwrap_or(settings.seed_goals);
+            goals.reached(&torrent.seed_progress(now)).then_some((*info_hash, goals.action))
+        })
+        .collect();
+    let mut removed = Vec::new();
+
+    for (info_hash, action) in reached {
+        let torrent = torrents.get_mut(&info_hash).unwrap();
+        info!(info_hash = %torrent.context.torrent.info_hash_hex(), ?action, "seed goal reached");
+
+        match action {
+            SeedAction::Stop => {
+                torrent.stop();
+                torrent.context.events.send(Event::StateChanged { state: TorrentState::Finished });
+            }
+            SeedAction::Remove => removed.extend(remove_torrent(torrents, stream_torrents, &info_hash, settings)),
+        }
+    }
+
+    removed
+}
+
+/// Stop a torrent and take it out of the session, the torrents behind it move up the queue.
+fn remove_torrent(torrents: &mut HashMap<InfoHash, SessionTorrent>, stream_torrents: &StreamTorrents, info_hash: &InfoHash, settings: &Settings) -> Option<SessionTorrent> {
+    let mut torrent = torrents.remove(info_hash)?;
+
+    torrent.stop();
+    torrent.context.events.send(Event::TorrentRemoved);
+    stream_torrents.lock().unwrap().remove(&torrent.context.torrent.info_hash_hex());
+
+    for other in torrents.values_mut().filter(|other| other.queue_position > torrent.queue_position) {
+        other.queue_position -= 1;
+    }
+    check_queue(torrents, settings, Instant::now());
+
+    Some(torrent)
+}
+
+/// Many torrents downloading side by side.
+///
+/// The torrents share our peer id, a single listen port and the disk pool. Peers that connect
+/// to the listen port are handed to the torrent matching the info hash of their handshake.
+pub struct Session {
+    peer_id: ByteBuffer,
+    listen_addr: Option<SocketAddr>,
+    disk_pool: DiskPool,
+    torrents: SessionTorrents,
+    stream_torrents: StreamTorrents,
+    events: EventSender,
+    settings: SharedSettings,
+    /// The limits of the whole session, every torrent takes its bytes from them.
+    bandwidth: Bandwidth,
+    /// The limits of all the peers on the local network, which don't count towards the session ones.
+    lan_bandwidth: Bandwidth,
+    alt_speed: Arc<Mutex<AltSpeed>>,
+    /// Started along with the first torrent, it moves the queue on when torrents end or turn slow.
+    queue_task: Mutex<Option<JoinHandle<()>>>,
+    queue_changed: Arc<Notify>,
+}
+
+impl Session {
+    /// Create an empty session, torrents have to be added from within the tokio runtime.
+    pub fn new(peer_id: ByteBuffer, settings: Settings) -> Result<Session> {
+        settings.check()?;
+
+        let session = Session {
+            peer_id,
+            listen_addr: None,
+            disk_pool: DiskPool::new(settings.disk_workers),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            stream_torrents: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(EVENT_QUEUE_LEN).0,
+            bandwidth: Bandwidth::new(settings.download_limit, settings.upload_limit),
+            lan_bandwidth: Bandwidth::new(settings.lan_download_limit, settings.lan_upload_limit),
+            settings: Arc::new(RwLock::new(settings)),
+            alt_speed: Arc::new(Mutex::new(AltSpeed::default())),
+            queue_task: Mutex::new(None),
+            queue_changed: Arc::new(Notify::new()),
+        };
+
+        let (settings, alt_speed, bandwidth) = (Arc::downgrade(&session.settings), Arc::downgrade(&session.alt_speed), session.bandwidth.clone());
+        thread::Builder::new()
+            .name("session-scheduler".to_string())
+            .spawn(move || follow_schedule(settings, alt_speed, bandwidth))
+            .map_err(|e| Error::Config(format!("Unable to start the scheduler: {}", e)))?;
+
+        Ok(session)
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Change the settings of a running session.
+    ///
+    /// The limits, pipeline depth, timeouts, queue and seed goals apply to the running torrents right away, the
+    /// download folder and max peers to the torrents added from now on. The listen port and
+    /// the disk workers can't be changed without creating a new session.
+    pub fn set_settings(&self, settings: Settings) -> Result<()> {
+        settings.check()?;
+
+        let mut current = self.settings.write().unwrap();
+        if settings.listen_port != current.listen_port && self.listen_addr.is_some() {
+            return Err(Error::Config("The listen port can't be changed while listening".to_string()));
+        }
+        if settings.disk_workers != current.disk_workers {
+            return Err(Error::Config("The disk workers can't be changed in a running session".to_string()));
+        }
+
+        apply_session_limits(&settings, self.alt_speed(), &self.bandwidth);
+        self.lan_bandwidth.set_rates(settings.lan_download_limit, settings.lan_upload_limit);
+        *current = settings;
+
+        check_queue(&mut self.torrents.lock().unwrap(), &current, Instant::now());
+
+        Ok(())
+    }
+
+    /// Whether the alternative speed limits of the settings are in use instead of the usual ones.
+    pub fn alt_speed(&self) -> bool {
+        self.alt_speed.lock().unwrap().is_on()
+    }
+
+    /// Turn the alternative speed limits on or off by hand, until the schedule switches them
+    /// the next time. None goes back to following the schedule.
+    pub fn set_alt_speed(&self, on: Option<bool>) {
+        // The settings are always locked before the alternative speed.
+        let settings = self.settings.read().unwrap();
+        let mut alt_speed = self.alt_speed.lock().unwrap();
+        alt_speed.manual = on;
+
+        apply_session_limits(&settings, alt_speed.is_on(), &self.bandwidth);
+    }
+
+    /// Start accepting peers on the listen port, shared by every torrent of the session.
+    ///
+    /// Without it we only download from the peers we connect to ourselves.
+    pub fn listen(&mut self) -> Result<SocketAddr> {
+        if let Some(listen_addr) = self.listen_addr {
+            return Ok(listen_addr);
+        }
+
+        let port = self.settings.read().unwrap().listen_port;
+        let listen_error = |e| Error::Config(format!("Unable to listen on port {}: {}", port, e));
+
+        let listener = TcpListener::bind(("0.0.0.0", port)).map_err(listen_error)?;
+        let listen_addr = listener.local_addr().map_err(listen_error)?;
+        let (torrents, settings) = (self.torrents.clone(), self.settings.clone());
+
+        thread::Builder::new()
+            .name("session-listener".to_string())
+            .spawn(move || accept_peers(listener, torrents, settings, MAX_PENDING_HANDSHAKES))
+            .map_err(listen_error)?;
+
+        self.listen_addr = Some(listen_addr);
+
+        Ok(listen_addr)
+    }
+
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listen_addr
+    }
+
+    /// Get the events of every torrent in the session from now on.
+    pub fn subscribe(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
+
+    /// The torrents of the session by info hash, for serving them with a StreamServer.
+    pub fn stream_torrents(&self) -> StreamTorrents {
+        self.stream_torrents.clone()
+    }
+
+    /// Get the torrent of a magnet link from the peers of the link, the given ones and the ones
+    /// of its trackers, to add it like any other.
+    pub async fn fetch_metadata(&self, magnet: &MagnetLink, peers: &[SocketAddr]) -> Result<Torrent> {
+        metadata::fetch_metadata(magnet, &self.peer_id, &self.settings(), peers).await
+    }
+
+    /// Add a torrent that downloads into the download folder of its options, at the end of the queue.
+    pub fn add_torrent(&self, torrent: Torrent, options: DownloadOptions) -> Result<TorrentHandle> {
+        let torrent = Arc::new(torrent);
+
+        let mut storage = FsStorage::new(&options.download_folder, torrent.clone()).map_err(Error::Storage)?;
+        storage.set_file_priorities(&options.file_priorities).map_err(Error::Storage)?;
+        storage.allocate(options.allocation).map_err(Error::Storage)?;
+
+        self.add_torrent_with_storage(torrent, Box::new(storage), options)
+    }
+
+    /// Add a torrent that downloads into the given storage, at the end of the queue.
+    ///
+    /// It starts right away unless the session already downloads as many torrents as it may.
+    /// Its totals go on from the ones saved in the storage. They're saved there every few
+    /// seconds while it runs, and when it's paused or removed.
+    pub fn add_torrent_with_storage(&self, torrent: Arc<Torrent>, mut storage: Box<dyn Storage>, options: DownloadOptions) -> Result<TorrentHandle> {
+        let info_hash = torrent.info_hash.ok_or(Error::Metainfo(MetainfoError::MissingInfo))?;
+        let resume = storage.load_resume().map_err(Error::Storage)?.unwrap_or_default();
+        // The settings are always locked before the torrents.
+        let settings = self.settings.read().unwrap();
+        let mut torrents = self.torrents.lock().unwrap();
+
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::DuplicateTorrent(torrent.info_hash_hex()));
+        }
+
+        let events = TorrentEvents::new(self.events.clone(), &torrent);
+        events.send(Event::TorrentAdded { summary: Box::new(torrent.summary()) });
+
+        let pieces = new_pieces_manager(&torrent, &options);
+        let disk = self.disk_pool.add_torrent(torrent.clone(), storage, pieces.clone(), events.clone());
+        let handshake = Arc::new(build_peer_handshake(&info_hash, &self.peer_id).to_bytes());
+
+        self.stream_torrents.lock().unwrap().insert(torrent.info_hash_hex(), disk.clone());
+
+        let session_torrent = SessionTorrent {
+            context: TorrentContext {
+                torrent,
+                handshake,
+                pieces,
+                disk,
+                events,
+                settings: self.settings.clone(),
+                upload_slots: PeerSlots::new(settings.upload_slots),
+                bandwidth: Bandwidth::new(options.download_limit, options.upload_limit),
+                session_bandwidth: self.bandwidth.clone(),
+                lan_bandwidth: self.lan_bandwidth.clone(),
+                downloaded: Arc::new(AtomicU64::new(resume.downloaded)),
+                uploaded: Arc::new(AtomicU64::new(resume.uploaded)),
+            },
+            options,
+            incoming: None,
+            task: None,
+            queue_position: torrents.len(),
+            queued: true,
+            ended: Arc::new(AtomicBool::new(false)),
+            sample: (Instant::now(), 0),
+            slow_since: None,
+            download_rate: 0,
+            upload_sample: (Instant::now(), resume.uploaded),
+            upload_rate: 0,
+            seeding_time: Duration::from_secs(resume.seeding_secs),
+            idle_since: Instant::now(),
+            saved: resume,
+            queue_changed: self.queue_changed.clone(),
+        };
+        torrents.insert(info_hash, session_torrent);
+        check_queue(&mut torrents, &settings, Instant::now());
+
+        if let Some(torrent) = torrents.get(&info_hash).filter(|torrent| torrent.queued) {
+            torrent.context.events.send(Event::StateChanged { state: TorrentState::Queued });
+        }
+
+        let mut queue_task = self.queue_task.lock().unwrap();
+        if queue_task.is_none() {
+            *queue_task = Some(tokio::spawn(manage_queue(self.torrents.clone(), self.stream_torrents.clone(), self.settings.clone(), self.queue_changed.clone())));
+        }
+
+        Ok(self.handle(info_hash))
+    }
+
+    /// The handle of a torrent in the session.
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<TorrentHandle> {
+        if self.torrents.lock().unwrap().contains_key(info_hash) {
+            Some(self.handle(*info_hash))
+        } else {
+            None
+        }
+    }
+
+    /// The status of every torrent in the session.
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let mut statuses: Vec<TorrentStatus> = self.torrents.lock().unwrap().values().map(|torrent| torrent.status()).collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        statuses
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let torrents = self.torrents();
+        let count = |state: TorrentState| torrents.iter().filter(|torrent| torrent.state == state).count();
+
+        SessionStats {
+            torrents: torrents.len(),
+            downloading: count(TorrentState::Downloading),
+            queued: count(TorrentState::Queued),
+            paused: count(TorrentState::Paused),
+            seeding: count(TorrentState::Seeding),
+            finished: count(TorrentState::Finished),
+            downloaded: torrents.iter().map(|torrent| torrent.downloaded).sum(),
+            uploaded: torrents.iter().map(|torrent| torrent.uploaded).sum(),
+            alt_speed: self.alt_speed(),
+            listen_addr: self.listen_addr,
+        }
+    }
+
+    fn handle(&self, info_hash: InfoHash) -> TorrentHandle {
+        TorrentHandle {
+            info_hash,
+            torrents: self.torrents.clone(),
+            stream_torrents: self.stream_torrents.clone(),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+/// A torrent in a session, to check on it and control it.
+///
+/// Handles are cheap to clone and can outlive the torrent, once it has been removed from
+/// the session everything fails with Error::UnknownTorrent.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    info_hash: InfoHash,
+    torrents: SessionTorrents,
+    stream_torrents: StreamTorrents,
+    settings: SharedSettings,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    pub fn status(&self) -> Result<TorrentStatus> {
+        self.with_torrent(|torrent| torrent.status())
+    }
+
+    /// Stop downloading or seeding, everything that was already received is flushed to disk
+    /// and the totals are saved.
+    ///
+    /// The next torrent in the queue takes its place.
+    pub async fn pause(&self) -> Result<()> {
+        let (disk, resume) = self.update_queue(|torrent| {
+            torrent.stop();
+            torrent.context.events.send(Event::StateChanged { state: torrent.status().state });
+            (torrent.context.disk.clone(), torrent.take_resume())
+        })?;
+
+        disk.flush().await?;
+        disk.save_resume(resume).await
+    }
+
+    /// Start downloading again after a pause, or wait in the queue for its turn.
+    ///
+    /// A finished torrent starts seeding again, until it reaches its seed goals again.
+    pub fn resume(&self) -> Result<()> {
+        let resumed = self.update_queue(|torrent| {
+            let resumed = torrent.task.is_none() && !torrent.queued;
+            torrent.queued |= resumed;
+            resumed
+        })?;
+
+        self.with_torrent(|torrent| {
+            if resumed && torrent.queued {
+                torrent.context.events.send(Event::StateChanged { state: TorrentState::Queued });
+            }
+        })
+    }
+
+    pub fn queue_position(&self) -> Result<usize> {
+        self.with_torrent(|torrent| torrent.queue_position)
+    }
+
+    /// Move the torrent to another place in the download queue, 0 is the first one.
+    ///
+    /// The torrents in between move by one place, torrents that lose their turn are queued.
+    pub fn set_queue_position(&self, position: usize) -> Result<()> {
+        let settings = self.settings.read().unwrap();
+        let mut torrents = self.torrents.lock().unwrap();
+
+        let from = torrents.get(&self.info_hash).ok_or(Error::UnknownTorrent)?.queue_position;
+        let to = position.min(torrents.len() - 1);
+
+        for torrent in torrents.values_mut() {
+            let position = torrent.queue_position;
+
+            torrent.queue_position = if position == from {
+                to
+            } else if from < position && position <= to {
+                position - 1
+            } else if to <= position && position < from {
+                position + 1
+            } else {
+                position
+            };
+        }
+        check_queue(&mut torrents, &settings, Instant::now());
+
+        Ok(())
+    }
+
+    /// Change which files are downloaded and in what order, see Priority.
+    ///
+    /// Pieces that were already downloaded are kept, pieces of files that are skipped from now
+    /// on stop being downloaded.
+    pub async fn set_file_priorities(&self, priorities: Vec<Priority>) -> Result<()> {
+        let disk = self.with_torrent(|torrent| {
+            torrent.options.file_priorities = priorities.clone();
+            torrent.context.disk.clone()
+        })?;
+
+        disk.set_file_priorities(priorities).await
+    }
+
+    /// Change the download and upload limits of the torrent in bytes per second, None lifts a limit.
+    ///
+    /// The limits of the session still apply on top of them.
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) -> Result<()> {
+        self.with_torrent(|torrent| {
+            torrent.options.download_limit = download;
+            torrent.options.upload_limit = upload;
+            torrent.context.bandwidth.set_rates(download, upload);
+        })
+    }
+
+    /// Stop the torrent and take it out of the session, optionally deleting what was downloaded.
+    ///
+    /// The totals are deleted along with the data, or else saved with it.
+    pub async fn remove(&self, delete_data: bool) -> Result<()> {
+        let mut torrent = {
+            let settings = self.settings.read().unwrap();
+            let mut torrents = self.torrents.lock().unwrap();
+
+            remove_torrent(&mut torrents, &self.stream_torrents, &self.info_hash, &settings).ok_or(Error::UnknownTorrent)?
+        };
+
+        let disk = torrent.context.disk.clone();
+
+        if delete_data {
+            disk.delete().await
+        } else {
+            disk.flush().await?;
+            disk.save_resume(torrent.take_resume()).await
+        }
+    }
+
+    fn with_torrent<T, F: FnOnce(&mut SessionTorrent) -> T>(&self, f: F) -> Result<T> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let torrent = torrents.get_mut(&self.info_hash).ok_or(Error::UnknownTorrent)?;
+
+        Ok(f(torrent))
+    }
+
+    /// Like with_torrent, then start and stop the torrents of the queue to match the change.
+    fn update_queue<T, F: FnOnce(&mut SessionTorrent) -> T>(&self, f: F) -> Result<T> {
+        // The settings are always locked before the torrents.
+        let settings = self.settings.read().unwrap();
+        let mut torrents = self.torrents.lock().unwrap();
+
+        let result = f(torrents.get_mut(&self.info_hash).ok_or(Error::UnknownTorrent)?);
+        check_queue(&mut torrents, &settings, Instant::now());
+
+        Ok(result)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(queue_task) = self.queue_task.lock().unwrap().take() {
+            queue_task.abort();
+        }
+        self.torrents.lock().unwrap().values_mut().for_each(|torrent| torrent.stop());
+    }
+}
+
+/// Move the queue on when torrents end, and sample the download rates to find the slow ones.
+///
+/// Every sample also ends the seeding of the torrents that reached their seed goals, and
+/// saves the totals that changed.
+async fn manage_queue(torrents: SessionTorrents, stream_torrents: StreamTorrents, settings: SharedSettings, queue_changed: Arc<Notify>) {
+    let mut interval = tokio::time::interval(QUEUE_INTERVAL);
+
+    loop {
+        let sample = tokio::select! {
+            _ = interval.tick() => true,
+            _ = queue_changed.notified() => false,
+        };
+
+        let saves: Vec<(TorrentDisk, ResumeData)> = {
+            let settings = settings.read().unwrap();
+            let mut torrents = torrents.lock().unwrap();
+            let now = Instant::now();
+
+            if !sample {
+                check_queue(&mut torrents, &settings, now);
+                continue;
+            }
+
+            torrents.values_mut().for_each(|torrent| {
+                torrent.sample_rate(settings.slow_torrent_rate, now);
+                torrent.sample_seeding(now);
+            });
+            let removed = check_seed_goals(&mut torrents, &stream_torrents, &settings, now);
+            check_queue(&mut torrents, &settings, now);
+
+            let removed = removed.into_iter().map(|mut torrent| (torrent.context.disk.clone(), torrent.take_resume()));
+            torrents.values_mut().filter_map(|torrent| torrent.unsaved_resume()).chain(removed).collect()
+        };
+
+        for (disk, resume) in saves {
+            if let Err(e) = disk.save_resume(resume).await {
+                warn!(error = %e, "unable to save the totals of a torrent");
+            }
+        }
+    }
+}
+
+/// Switch the alternative speed limits on and off as the schedule says, until the session is gone.
+///
+/// Switching clears what was set by hand, so that the schedule takes over again.
+fn follow_schedule(settings: Weak<RwLock<Settings>>, alt_speed: Weak<Mutex<AltSpeed>>, bandwidth: Bandwidth) {
+    while let (Some(settings), Some(alt_speed)) = (settings.upgrade(), alt_speed.upgrade()) {
+        let settings = settings.read().unwrap();
+        let scheduled = settings.alt_speed_schedule.as_ref().is_some_and(|schedule| schedule.is_active(Local::now().naive_local()));
+
+        let mut alt_speed = alt_speed.lock().unwrap();
+        if alt_speed.scheduled != scheduled {
+            info!(on = scheduled, "alternative speed limits switched by the schedule");
+            alt_speed.scheduled = scheduled;
+            alt_speed.manual = None;
+
+            apply_session_limits(&settings, alt_speed.is_on(), &bandwidth);
+        }
+
+        drop((alt_speed, settings));
+        thread::sleep(SCHEDULE_INTERVAL);
+    }
+}
+
+/// Accept the peers connecting to the session, each one gets its own thread for the handshake.
+///
+/// No more than max_handshakes threads run at once, so that peers that connect and never send
+/// anything can't pile them up.
+fn accept_peers(listener: TcpListener, torrents: SessionTorrents, settings: SharedSettings, max_handshakes: usize) {
+    let handshakes = PeerSlots::new(max_handshakes);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "unable to accept peer");
+                continue;
+            }
+        };
+
+        let slot = match handshakes.take() {
+            Some(slot) => slot,
+            None => {
+                debug!(address = ?stream.peer_addr().ok(), "refusing peer, too many handshakes");
+                continue;
+            }
+        };
+        let torrents = torrents.clone();
+        let handshake_timeout = settings.read().unwrap().handshake_timeout;
+
+        thread::spawn(move || {
+            let address = stream.peer_addr().ok();
+
+            if let Err(e) = route_peer(stream, &torrents, handshake_timeout) {
+                debug!(?address, error = %e, "incoming peer dropped");
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Read the handshake of a peer and hand it over to the torrent it asked for.
+///
+/// Peers asking for a torrent we don't have, or one that's paused, are disconnected.
+fn route_peer(mut stream: TcpStream, torrents: &SessionTorrents, handshake_timeout: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(handshake_timeout))?;
+
+    let info_hash = read_handshake(&mut stream)?;
+    let (our_handshake, incoming) = find_running_torrent(torrents, &info_hash)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown or paused torrent"))?;
+
+    stream.write_all(&our_handshake)?;
+    stream.set_read_timeout(None)?;
+
+    incoming
+        .blocking_send(stream)
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The torrent has stopped"))
+}
+
+/// Read the handshake of a peer that connected to us, and get the info hash it wants.
+fn read_handshake<R: Read>(stream: &mut R) -> io::Result<InfoHash> {
+    let mut handshake = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut handshake)?;
+
+    if !check_handshake_msg(&mut ByteBuffer::from_bytes(&handshake)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a BitTorrent handshake"));
+    }
+
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&handshake[28..48]);
+
+    Ok(info_hash)
+}
+
+/// Find our handshake and the incoming peer channel of a torrent, if it's running.
+fn find_running_torrent(torrents: &SessionTorrents, info_hash: &InfoHash) -> Option<(Arc<Vec<u8>>, mpsc::Sender<TcpStream>)> {
+    match torrents.lock().unwrap().get(info_hash) {
+        Some(SessionTorrent { context, incoming: Some(incoming), .. }) => Some((context.handshake.clone(), incoming.clone())),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_session_add_pause_resume_remove() {
+    use crate::storage::MemoryStorage;
+    use crate::utils::gen_peer_id;
+
+    let session = Session::new(gen_peer_id(), Settings::default()).unwrap();
+    let mut events = session.subscribe();
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let options = DownloadOptions {
+        peers: vec!["127.0.0.1:1".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let add = |options: DownloadOptions| session.add_torrent_with_storage(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())), options);
+    let handle = add(options.clone()).unwrap();
+    let info_hash = handle.info_hash();
+    assert!(matches!(add(options), Err(Error::DuplicateTorrent(_))));
+
+    assert_eq!(session.torrents()[0].state, TorrentState::Downloading);
+    assert_eq!((session.stats().torrents, session.stats().downloading), (1, 1));
+    assert!(session.stream_torrents().lock().unwrap().contains_key(&torrent.info_hash_hex()));
+
+    // Incoming peers are only routed to running torrents.
+    assert!(find_running_torrent(&session.torrents, &info_hash).is_some());
+    session.torrent(&info_hash).unwrap().pause().await.unwrap();
+    assert_eq!(handle.status().unwrap().state, TorrentState::Paused);
+    assert!(find_running_torrent(&session.torrents, &info_hash).is_none());
+
+    handle.resume().unwrap();
+    assert_eq!(session.torrents()[0].state, TorrentState::Downloading);
+
+    handle.remove(false).await.unwrap();
+    assert!(session.torrents().is_empty());
+    assert!(session.torrent(&info_hash).is_none());
+    assert!(session.stream_torrents().lock().unwrap().is_empty());
+    assert!(matches!(handle.resume(), Err(Error::UnknownTorrent)));
+
+    let mut names = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.info_hash, torrent.info_hash_hex());
+        names.push(serde_json::to_value(&event.event).unwrap()["event"].as_str().unwrap().to_string());
+    }
+    assert_eq!(names, vec!["torrent_added", "state_changed", "state_changed", "state_changed", "torrent_removed"]);
+}
+
+#[tokio::test]
+async fn test_session_queue() {
+    use std::fs;
+    use std::path::Path;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::storage::MemoryStorage;
+    use crate::utils::gen_peer_id;
+    use TorrentState::*;
+
+    let folder = Path::new("test-files/test11");
+    fs::create_dir_all(folder).unwrap();
+
+    let settings = Settings { max_active_downloads: Some(2), ..Default::default() };
+    let session = Session::new(gen_peer_id(), settings.clone()).unwrap();
+    let options = DownloadOptions {
+        peers: vec!["127.0.0.1:1".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let handles: Vec<TorrentHandle> = (0..3)
+        .map(|i| {
+            let path = folder.join(format!("{}.txt", i));
+            fs::write(&path, vec![i; 100]).unwrap();
+
+            let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&path, &CreateOptions::default()).unwrap()).unwrap());
+            session.add_torrent_with_storage(torrent.clone(), Box::new(MemoryStorage::new(torrent)), options.clone()).unwrap()
+        })
+        .collect();
+    let states = || handles.iter().map(|handle| handle.status().map(|status| status.state).ok()).collect::<Vec<_>>();
+    let positions = || handles.iter().map(|handle| handle.queue_position().ok()).collect::<Vec<_>>();
+
+    assert_eq!(states(), vec![Some(Downloading), Some(Downloading), Some(Queued)]);
+
+    // Moving a torrent up the queue takes the place of the last active one.
+    handles[2].set_queue_position(0).unwrap();
+    assert_eq!(positions(), vec![Some(1), Some(2), Some(0)]);
+    assert_eq!(states(), vec![Some(Downloading), Some(Queued), Some(Downloading)]);
+
+    handles[0].pause().await.unwrap();
+    assert_eq!(states(), vec![Some(Paused), Some(Downloading), Some(Downloading)]);
+    // Resumed torrents are back in their place, before the one that took over from them.
+    handles[0].resume().unwrap();
+    assert_eq!(states(), vec![Some(Downloading), Some(Queued), Some(Downloading)]);
+
+    handles[2].remove(false).await.unwrap();
+    assert_eq!(positions(), vec![Some(0), Some(1), None]);
+    assert_eq!(states(), vec![Some(Downloading), Some(Downloading), None]);
+
+    // Slow torrents go on without taking a place.
+    session
+        .set_settings(Settings { max_active_downloads: Some(1), slow_torrent_rate: Some(1000), slow_torrent_time: Duration::from_secs(0), ..settings })
+        .unwrap();
+    assert_eq!(states(), vec![Some(Downloading), Some(Queued), None]);
+    {
+        let later = Instant::now() + Duration::from_secs(1);
+        let settings = session.settings();
+        let mut torrents = session.torrents.lock().unwrap();
+
+        torrents.values_mut().for_each(|torrent| torrent.sample_rate(settings.slow_torrent_rate, later));
+        check_queue(&mut torrents, &settings, later);
+    }
+    assert_eq!(states(), vec![Some(Downloading), Some(Downloading), None]);
+
+    let _ = fs::remove_dir_all(folder);
+}
+
+#[tokio::test]
+async fn test_session_seed_queue() {
+    use std::fs;
+    use std::path::Path;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::storage::MemoryStorage;
+    use crate::utils::gen_peer_id;
+    use TorrentState::*;
+
+    let folder = Path::new("test-files/test24");
+    fs::create_dir_all(folder).unwrap();
+
+    let settings = Settings { max_active_downloads: Some(1), max_active_seeds: Some(1), ..Default::default() };
+    let session = Session::new(gen_peer_id(), settings).unwrap();
+    let options = DownloadOptions {
+        peers: vec!["127.0.0.1:1".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let handles: Vec<TorrentHandle> = (0..3)
+        .map(|i| {
+            let path = folder.join(format!("{}.txt", i));
+            fs::write(&path, vec![i; 100]).unwrap();
+
+            let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&path, &CreateOptions::default()).unwrap()).unwrap());
+            session.add_torrent_with_storage(torrent.clone(), Box::new(MemoryStorage::new(torrent)), options.clone()).unwrap()
+        })
+        .collect();
+    let states = || handles.iter().map(|handle| handle.status().map(|status| status.state).ok()).collect::<Vec<_>>();
+
+    // The first two torrents are finished, the last one still downloads next to the seeds.
+    for handle in &handles[..2] {
+        handle.pause().await.unwrap();
+        session.torrents.lock().unwrap()[&handle.info_hash()].context.pieces.lock().unwrap().mark_complete(0);
+        handle.resume().unwrap();
+    }
+    assert_eq!(states(), vec![Some(Seeding), Some(Queued), Some(Downloading)]);
+    assert_eq!(session.stats().seeding, 1);
+
+    // A seed that stops makes room for the next one in the queue.
+    handles[0].pause().await.unwrap();
+    assert_eq!(states(), vec![Some(Finished), Some(Seeding), Some(Downloading)]);
+    handles[0].resume().unwrap();
+    assert_eq!(states(), vec![Some(Seeding), Some(Queued), Some(Downloading)]);
+
+    let _ = fs::remove_dir_all(folder);
+}
+
+#[tokio::test]
+async fn test_session_seed_goals() {
+    use std::fs;
+    use std::path::Path;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::seeding::SeedGoals;
+    use crate::storage::MemoryStorage;
+    use crate::utils::gen_peer_id;
+    use TorrentState::*;
+
+    let folder = Path::new("test-files/test21");
+    fs::create_dir_all(folder).unwrap();
+
+    let settings = Settings { seed_goals: SeedGoals { ratio: Some(1.0), ..Default::default() }, ..Default::default() };
+    let session = Session::new(gen_peer_id(), settings).unwrap();
+
+    let add = |i: u8, seed_goals| {
+        let path = folder.join(format!("{}.txt", i));
+        fs::write(&path, vec![i; 100]).unwrap();
+
+        let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&path, &CreateOptions::default()).unwrap()).unwrap());
+        let options = DownloadOptions { peers: vec!["127.0.0.1:1".parse().unwrap()], seed_goals, ..Default::default() };
+        session.add_torrent_with_storage(torrent.clone(), Box::new(MemoryStorage::new(torrent)), options).unwrap()
+    };
+    let ratio = add(0, None);
+    let removed = add(1, Some(SeedGoals { time: Some(Duration::from_secs(60)), action: SeedAction::Remove, ..Default::default() }));
+    let idle = add(2, Some(SeedGoals { idle_time: Some(Duration::from_secs(60)), ..Default::default() }));
+    let handles = [&ratio, &removed, &idle];
+    let removed_hex = removed.status().unwrap().info_hash;
+    let states = || handles.iter().map(|handle| handle.status().map(|status| status.state).ok()).collect::<Vec<_>>();
+
+    // Finished torrents seed once they're resumed.
+    for handle in handles {
+        handle.pause().await.unwrap();
+        session.torrents.lock().unwrap()[&handle.info_hash()].context.pieces.lock().unwrap().mark_complete(0);
+        handle.resume().unwrap();
+    }
+    assert_eq!(states(), vec![Some(Seeding), Some(Seeding), Some(Seeding)]);
+    assert_eq!(session.stats().seeding, 3);
+
+    let check_goals = |now: Instant| {
+        let settings = session.settings();
+        let mut torrents = session.torrents.lock().unwrap();
+
+        torrents.values_mut().for_each(|torrent| torrent.sample_seeding(now));
+        check_seed_goals(&mut torrents, &session.stream_torrents, &settings, now).len()
+    };
+
+    // The first torrent goes by the ratio of the settings, nothing was downloaded so it's taken against the size.
+    assert_eq!(check_goals(Instant::now()), 0);
+    session.torrents.lock().unwrap()[&ratio.info_hash()].context.uploaded.store(100, Ordering::Relaxed);
+    assert_eq!(check_goals(Instant::now()), 0);
+    assert_eq!(states(), vec![Some(Finished), Some(Seeding), Some(Seeding)]);
+
+    // A minute later the second one seeded for long enough, and the third one was idle for long enough.
+    assert_eq!(check_goals(Instant::now() + Duration::from_secs(61)), 1);
+    assert_eq!(states(), vec![Some(Finished), None, Some(Finished)]);
+    assert!(!session.stream_torrents().lock().unwrap().contains_key(&removed_hex));
+
+    // Resuming a torrent past its goals seeds until the goals are checked again.
+    ratio.resume().unwrap();
+    assert_eq!(ratio.status().unwrap().state, Seeding);
+    assert_eq!(check_goals(Instant::now()), 0);
+    assert_eq!(ratio.status().unwrap().state, Finished);
+
+    let _ = fs::remove_dir_all(folder);
+}
+
+#[tokio::test]
+async fn test_session_resume_data() {
+    use std::fs;
+    use std::path::Path;
+    use crate::utils::gen_peer_id;
+
+    let folder = Path::new("test-files/test22");
+    let _ = fs::remove_dir_all(folder);
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let saved = ResumeData { downloaded: 1000, uploaded: 500, seeding_secs: 60 };
+    FsStorage::new(folder, torrent.clone()).unwrap().save_resume(&saved).unwrap();
+
+    let session = Session::new(gen_peer_id(), Settings::default()).unwrap();
+    let options = DownloadOptions { peers: vec!["127.0.0.1:1".parse().unwrap()], ..Default::default() };
+    let add = || session.add_torrent_with_storage(torrent.clone(), Box::new(FsStorage::new(folder, torrent.clone()).unwrap()), options.clone()).unwrap();
+    let load = || FsStorage::new(folder, torrent.clone()).unwrap().load_resume().unwrap();
+
+    // The totals go on from the saved ones, and are saved again when the torrent is paused or removed.
+    let handle = add();
+    let status = handle.status().unwrap();
+    assert_eq!((status.downloaded, status.uploaded), (1000, 500));
+
+    session.torrents.lock().unwrap()[&handle.info_hash()].context.uploaded.fetch_add(100, Ordering::Relaxed);
+    handle.pause().await.unwrap();
+    assert_eq!(load(), Some(ResumeData { uploaded: 600, ..saved }));
+
+    session.torrents.lock().unwrap()[&handle.info_hash()].context.downloaded.fetch_add(100, Ordering::Relaxed);
+    handle.remove(false).await.unwrap();
+    assert_eq!(load(), Some(ResumeData { downloaded: 1100, uploaded: 600, ..saved }));
+
+    // They're deleted along with the data.
+    add().remove(true).await.unwrap();
+    assert_eq!(load(), None);
+
+    let _ = fs::remove_dir_all(folder);
+}
+
+#[tokio::test]
+async fn test_session_settings() {
+    use crate::utils::gen_peer_id;
+
+    let session = Session::new(gen_peer_id(), Settings::default()).unwrap();
+    let settings = Settings { pipeline_depth: 10, download_limit: Some(1000), ..Default::default() };
+
+    session.set_settings(settings.clone()).unwrap();
+    assert_eq!(session.settings(), settings);
+
+    assert!(session.set_settings(Settings { disk_workers: 1, ..settings.clone() }).is_err());
+
+    // The alternative limits replace the session ones while they're on.
+    session.set_settings(Settings { alt_download_limit: Some(10), ..settings.clone() }).unwrap();
+    session.set_alt_speed(Some(true));
+    assert!(session.alt_speed());
+    assert_eq!(session.bandwidth.download.reserve(20, std::time::Instant::now()), Duration::from_secs(1));
+    session.set_alt_speed(None);
+    assert!(!session.alt_speed());
+
+    assert!(session.set_settings(Settings { dht: true, ..settings }).is_err());
+    assert!(Session::new(gen_peer_id(), Settings { pipeline_depth: 0, ..Default::default() }).is_err());
+}
+
+#[test]
+fn test_info_hash_from_hex() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+
+    assert_eq!(info_hash_from_hex(&torrent.info_hash_hex()), torrent.info_hash);
+    assert_eq!(info_hash_from_hex(&torrent.info_hash_hex().to_uppercase()), torrent.info_hash);
+    assert_eq!(info_hash_from_hex("06cb"), None);
+    assert_eq!(info_hash_from_hex(&"zz".repeat(20)), None);
+}
+
+#[test]
+fn test_accept_peers_limits_handshakes() {
+    use std::io::ErrorKind;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let settings = Arc::new(RwLock::new(Settings { handshake_timeout: Duration::from_secs(5), ..Default::default() }));
+    thread::spawn(move || accept_peers(listener, Arc::new(Mutex::new(HashMap::new())), settings, 1));
+
+    // The first peer never sends its handshake and holds on to the only place.
+    let mut silent = TcpStream::connect(address).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // The next one is disconnected without waiting for its handshake.
+    let mut refused = TcpStream::connect(address).unwrap();
+    refused.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let read = refused.read(&mut [0; 1]);
+    assert!(matches!(read, Ok(0)) || matches!(read, Err(ref e) if e.kind() == ErrorKind::ConnectionReset), "{:?}", read);
+
+    // The silent peer is still waited on.
+    silent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(matches!(silent.read(&mut [0; 1]), Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)));
+}
+
+#[test]
+fn test_read_handshake() {
+    use crate::utils::gen_peer_id;
+
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let handshake = build_peer_handshake(&torrent.info_hash.unwrap(), &gen_peer_id()).to_bytes();
+
+    assert_eq!(read_handshake(&mut &handshake[..]).unwrap(), torrent.info_hash.unwrap());
+    assert!(read_handshake(&mut &handshake[..40]).is_err());
+
+    let mut not_bittorrent = handshake.clone();
+    not_bittorrent[1..20].copy_from_slice(b"GET / HTTP/1.1\r\n\r\nX");
+    assert!(read_handshake(&mut &not_bittorrent[..]).is_err());
+}
@@ -0,0 +1,298 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+use crate::download::{DEFAULT_MAX_PEERS, DownloadOptions};
+use crate::error::{Error, Result};
+use crate::rate_limit::deserialize_rate;
+use crate::schedule::Schedule;
+use crate::seeding::SeedGoals;
+use crate::PORT;
+
+/// The settings of a session, shared with its torrents so that changes reach them while they run.
+pub type SharedSettings = Arc<RwLock<Settings>>;
+
+/// How many blocks we ask a peer for at once unless told otherwise.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+
+/// How many peers a torrent uploads to at once unless told otherwise.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// Ways to talk to peers. Encryption isn't implemented, so only Disabled is accepted and the
+/// other policies are refused by Settings::check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    #[default]
+    Disabled,
+    Enabled,
+    Required,
+}
+
+/// How a session runs, read from a TOML file like
+///
+/// ```text
+/// listen_port = 6682
+/// download_dir = "/home/me/downloads"
+/// max_peers = 50
+/// upload_slots = 4
+/// pipeline_depth = 5
+/// download_limit = "2M"
+/// upload_limit = "500K"
+/// tracker_timeout = 5
+/// alt_download_limit = "1M"
+/// max_active_downloads = 3
+/// max_active_seeds = 5
+///
+/// # Not implemented, so these are the only values accepted: turning any of them on is an error.
+/// dht = false
+/// pex = false
+/// lsd = false
+/// encryption = "disabled"
+///
+/// [alt_speed_schedule]
+/// begin = "09:00"
+/// end = "18:00"
+///
+/// [seed_goals]
+/// ratio = 2.0
+/// action = "stop"
+/// ```
+///
+/// Every key is optional, timeouts are in seconds and limits in bytes per second like
+/// rate_limit::Rate. See Session::set_settings for the ones that can be changed while torrents run.
+///
+/// Peers on the local network get the LAN limits instead of the session ones, every peer
+/// also has limits of its own. A torrent can have limits of its own too, see DownloadOptions.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The port peers connect to, see Session::listen.
+    pub listen_port: u16,
+    /// Where torrents are downloaded to, unless their download options say otherwise.
+    pub download_dir: PathBuf,
+    /// The threads writing and checking the pieces of every torrent.
+    pub disk_workers: usize,
+    /// The most peers a torrent is connected to at once, for torrents added from now on.
+    pub max_peers: usize,
+    /// How many peers a torrent uploads to at once, for torrents added from now on. The
+    /// others are choked until a slot is free.
+    pub upload_slots: usize,
+    /// How many blocks we ask a peer for before waiting for them to come in.
+    pub pipeline_depth: usize,
+    /// The most bytes per second the whole session downloads, there's no limit when it isn't set.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub download_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub upload_limit: Option<u64>,
+    /// The most bytes per second downloaded from all the peers on the local network.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub lan_download_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub lan_upload_limit: Option<u64>,
+    /// The most bytes per second downloaded from a single peer.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub peer_download_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub peer_upload_limit: Option<u64>,
+    /// The session limits while the alternative speed limits are on, see Session::set_alt_speed.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub alt_download_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub alt_upload_limit: Option<u64>,
+    /// When the alternative speed limits turn on by themselves.
+    pub alt_speed_schedule: Option<Schedule>,
+    /// The most torrents downloading at once, the others wait in the queue. There's no limit when it isn't set.
+    pub max_active_downloads: Option<usize>,
+    /// The most finished torrents seeding at once, the others wait in the queue. There's no limit when it isn't set.
+    pub max_active_seeds: Option<usize>,
+    /// Torrents downloading slower than this for slow_torrent_time don't count towards the
+    /// active ones, so that the next torrent in the queue starts next to them.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub slow_torrent_rate: Option<u64>,
+    #[serde(deserialize_with = "seconds")]
+    pub slow_torrent_time: Duration,
+    /// When finished torrents stop seeding, unless their download options have goals of their own.
+    pub seed_goals: SeedGoals,
+    /// How long the tracker gets to answer.
+    #[serde(deserialize_with = "seconds")]
+    pub tracker_timeout: Duration,
+    /// How long a peer gets to accept our connection.
+    #[serde(deserialize_with = "seconds")]
+    pub connect_timeout: Duration,
+    /// How long a peer that connects to us gets to send its handshake.
+    #[serde(deserialize_with = "seconds")]
+    pub handshake_timeout: Duration,
+    /// Find peers through the DHT (BEP 5). It isn't implemented, true is refused as a
+    /// configuration error rather than silently ignored.
+    pub dht: bool,
+    /// Exchange peers with other peers (BEP 11), not implemented either, like dht.
+    pub pex: bool,
+    /// Find peers on the local network (BEP 14), not implemented either, like dht.
+    pub lsd: bool,
+    /// Encrypt the connections to peers, not implemented: anything but EncryptionPolicy::Disabled
+    /// is refused.
+    pub encryption: EncryptionPolicy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            listen_port: PORT as u16,
+            download_dir: PathBuf::from("."),
+            disk_workers: 4,
+            max_peers: DEFAULT_MAX_PEERS,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            download_limit: None,
+            upload_limit: None,
+            lan_download_limit: None,
+            lan_upload_limit: None,
+            peer_download_limit: None,
+            peer_upload_limit: None,
+            alt_download_limit: None,
+            alt_upload_limit: None,
+            alt_speed_schedule: None,
+            max_active_downloads: None,
+            max_active_seeds: None,
+            slow_torrent_rate: None,
+            slow_torrent_time: Duration::from_secs(60),
+            seed_goals: SeedGoals::default(),
+            tracker_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            dht: false,
+            pex: false,
+            lsd: false,
+            encryption: EncryptionPolicy::Disabled,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Settings> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| Error::Config(format!("Unable to read {}: {}", path.display(), e)))?;
+
+        let settings: Settings = toml::from_str(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        settings.check()?;
+
+        Ok(settings)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Settings> {
+        let settings: Settings = toml::from_str(text).map_err(|e| Error::Config(e.to_string()))?;
+        settings.check()?;
+
+        Ok(settings)
+    }
+
+    /// Make sure the settings can be used, the ones of features that aren't implemented have to
+    /// keep them off.
+    pub fn check(&self) -> Result<()> {
+        let unsupported = [(self.dht, "dht", "DHT"), (self.pex, "pex", "Peer exchange"), (self.lsd, "lsd", "Local service discovery")];
+
+        if let Some((_, key, feature)) = unsupported.iter().find(|(enabled, _, _)| *enabled) {
+            return Err(Error::Config(format!("{} isn't implemented, {} has to be false", feature, key)));
+        }
+        if self.encryption != EncryptionPolicy::Disabled {
+            return Err(Error::Config("Encrypted connections aren't implemented, encryption has to be \"disabled\"".to_string()));
+        }
+        if self.disk_workers == 0 || self.max_peers == 0 || self.upload_slots == 0 || self.pipeline_depth == 0 {
+            return Err(Error::Config("disk_workers, max_peers, upload_slots and pipeline_depth have to be at least 1".to_string()));
+        }
+        if self.max_active_downloads == Some(0) || self.max_active_seeds == Some(0) {
+            return Err(Error::Config("max_active_downloads and max_active_seeds have to be at least 1".to_string()));
+        }
+        if !self.seed_goals.is_valid() {
+            return Err(Error::Config("The seed ratio can't be negative".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// The download options of a torrent added with these settings.
+    pub fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            download_folder: self.download_dir.clone(),
+            max_peers: self.max_peers,
+            ..Default::default()
+        }
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+#[test]
+fn test_parse_settings() {
+    use crate::seeding::SeedAction;
+
+    let settings = Settings::from_toml(
+        r#"
+        listen_port = 7000
+        download_dir = "downloads"
+        pipeline_depth = 10
+        download_limit = "2M"
+        lan_upload_limit = 1000
+        tracker_timeout = 20
+        alt_download_limit = "1M"
+        max_active_downloads = 3
+        max_active_seeds = 2
+        slow_torrent_rate = "10K"
+
+        [alt_speed_schedule]
+        days = ["sat", "sun"]
+        begin = "10:00"
+        end = "12:00"
+
+        [seed_goals]
+        ratio = 1.5
+        idle_time = 600
+        action = "remove"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(settings.listen_port, 7000);
+    assert_eq!(settings.download_dir, PathBuf::from("downloads"));
+    assert_eq!(settings.pipeline_depth, 10);
+    assert_eq!(settings.download_limit, Some(2 * 1024 * 1024));
+    assert_eq!(settings.lan_upload_limit, Some(1000));
+    assert_eq!(settings.upload_limit, None);
+    assert_eq!(settings.alt_download_limit, Some(1024 * 1024));
+    assert_eq!(settings.alt_speed_schedule.as_ref().unwrap().days.len(), 2);
+    assert_eq!(settings.max_active_downloads, Some(3));
+    assert_eq!(settings.max_active_seeds, Some(2));
+    assert_eq!(settings.slow_torrent_rate, Some(10 * 1024));
+    assert_eq!(settings.tracker_timeout, Duration::from_secs(20));
+    assert_eq!(
+        settings.seed_goals,
+        SeedGoals { ratio: Some(1.5), time: None, idle_time: Some(Duration::from_secs(600)), action: SeedAction::Remove }
+    );
+    assert_eq!(settings.max_peers, DEFAULT_MAX_PEERS);
+    assert_eq!(settings.download_options().download_folder, PathBuf::from("downloads"));
+
+    assert_eq!(Settings::from_toml("").unwrap(), Settings::default());
+    assert_eq!(Settings::from_toml("download_limit = 1000").unwrap().download_limit, Some(1000));
+    assert!(Settings::from_toml("download_limit = 0").is_err());
+
+    assert!(Settings::from_toml("listen_prot = 7000").is_err());
+    assert!(Settings::from_toml("pipeline_depth = 0").is_err());
+    assert!(Settings::from_toml("upload_slots = 0").is_err());
+    assert!(Settings::from_toml("max_active_downloads = 0").is_err());
+    assert!(Settings::from_toml("max_active_seeds = 0").is_err());
+    assert!(Settings::from_toml("[seed_goals]\nratio = -1.0").is_err());
+    assert!(Settings::from_toml("[seed_goals]\naction = \"delete\"").is_err());
+    assert!(Settings::from_toml("download_limit = \"fast\"").is_err());
+    assert!(Settings::from_toml("dht = false\npex = false\nlsd = false").is_ok());
+    let error = Settings::from_toml("dht = true").unwrap_err();
+    assert_eq!(error.to_string(), "Invalid configuration: DHT isn't implemented, dht has to be false");
+    assert!(Settings::from_toml("encryption = \"required\"").is_err());
+    assert!(Settings::from_toml("encryption = \"disabled\"").is_ok());
+}
@@ -0,0 +1,1304 @@
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use fs2::FileExt;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::layout::{FileLayout, is_contained};
+use crate::pieces::Priority;
+use crate::utils::torrents::Torrent;
+
+/// Where the pieces of a torrent are read from and written to.
+///
+/// Offsets are given relative to a piece, so an implementation is free to store
+/// pieces however it likes, e.g. as files on disk or in a content-addressed store.
+pub trait Storage: Send {
+    /// Read `length` bytes of a piece starting at `begin`.
+    fn read_block(&mut self, piece_index: u64, begin: u64, length: u64) -> io::Result<Vec<u8>>;
+
+    /// Write a block of a piece starting at `begin`.
+    ///
+    /// Storages that hold on to blocks before writing them may fail here, or in any other
+    /// call, for blocks of other pieces. They say which pieces were lost with a FailedWrite
+    /// inside the error, any other error of a write is about the block itself.
+    fn write_block(&mut self, piece_index: u64, begin: u64, block: &[u8]) -> io::Result<()>;
+
+    /// Make sure everything that has been written has reached the underlying store.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Check the stored piece against its hash in the torrent.
+    fn verify_piece(&mut self, piece_index: u64) -> io::Result<bool>;
+
+    /// Move the stored data to a new download folder.
+    fn move_to(&mut self, download_folder: &Path) -> io::Result<()>;
+
+    /// Remove all of the stored data.
+    fn delete(&mut self) -> io::Result<()>;
+
+    /// Let the storage know which files are wanted.
+    ///
+    /// Pieces that straddle a skipped file and a wanted file are still downloaded,
+    /// so the storage has to keep the skipped file's part of them somewhere.
+    fn set_file_priorities(&mut self, _priorities: &[Priority]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Get what was saved by save_resume, None when the storage has nothing saved.
+    fn load_resume(&mut self) -> io::Result<Option<ResumeData>> {
+        Ok(None)
+    }
+
+    /// Keep the totals of the torrent along with its data, storages that can't keep them
+    /// start over from zero every time.
+    fn save_resume(&mut self, _resume: &ResumeData) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// The totals of a torrent that outlive a run of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResumeData {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// How long the torrent has seeded in all.
+    pub seeding_secs: u64,
+}
+
+
+/// The pieces whose blocks were lost when a write failed, carried inside an io::Error.
+#[derive(Debug)]
+pub struct FailedWrite {
+    pub pieces: Vec<u64>,
+    pub error: io::Error,
+}
+
+impl FailedWrite {
+    /// The io::Error for a failed write of the given pieces.
+    pub fn io_error(pieces: Vec<u64>, error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), FailedWrite { pieces, error })
+    }
+
+    /// The pieces that were lost according to an error of a storage, if it says.
+    pub fn pieces(e: &io::Error) -> Option<&[u64]> {
+        let failed = e.get_ref()?.downcast_ref::<FailedWrite>()?;
+        Some(&failed.pieces)
+    }
+}
+
+impl fmt::Display for FailedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pieces {:?})", self.error, self.pieces)
+    }
+}
+
+impl std::error::Error for FailedWrite {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+
+/// The pieces covered by the bytes at an offset of the torrent.
+fn pieces_in(torrent: &Torrent, offset: u64, length: usize) -> Vec<u64> {
+    let piece_length = torrent.info.piece_length;
+    let last_byte = offset + (length as u64).max(1) - 1;
+
+    (offset / piece_length..=last_byte / piece_length).collect()
+}
+
+
+/// The offset of a block within the torrent, or an InvalidInput error if any of it is past
+/// the end of the torrent.
+fn block_offset(torrent: &Torrent, piece_index: u64, begin: u64, length: u64) -> io::Result<u64> {
+    piece_index
+        .checked_mul(torrent.info.piece_length)
+        .and_then(|offset| offset.checked_add(begin))
+        .filter(|offset| offset.checked_add(length).is_some_and(|end| end <= torrent.size.unwrap_or_default()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Block is past the end of the torrent"))
+}
+
+
+/// Check that the data of a whole piece matches the piece's hash.
+pub fn verify_piece_data(torrent: &Torrent, piece_index: u64, data: &[u8]) -> bool {
+    let mut hasher = Sha1::new();
+    let mut hash = [0; 20];
+
+    hasher.input(data);
+    hasher.result(&mut hash);
+
+    torrent.piece_hash(piece_index) == Some(&hash[..])
+}
+
+
+/// How the files of a torrent are created on disk when the download starts.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AllocationMode {
+    /// Files are created at their full size without reserving any disk blocks.
+    #[default]
+    Sparse,
+    /// Disk blocks are reserved for the whole file up front, e.g. with fallocate.
+    Full,
+    /// Files are filled with zeros, for filesystems that don't support either of the above.
+    Zero,
+}
+
+impl std::str::FromStr for AllocationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(AllocationMode::Sparse),
+            "full" => Ok(AllocationMode::Full),
+            "zero" => Ok(AllocationMode::Zero),
+            _ => Err(format!("Unknown allocation mode: {}", s)),
+        }
+    }
+}
+
+
+/// The number of files kept open at once by each FsStorage.
+pub const DEFAULT_OPEN_FILES: usize = 64;
+
+/// The largest write that contiguous blocks are merged into before hitting the disk.
+const MAX_COALESCED_WRITE: usize = 1024 * 1024;
+
+/// The key of the partfile in the file handle cache, which can't clash with a file index.
+const PARTFILE_KEY: usize = usize::MAX;
+
+
+/// Stores the torrent as regular files using the torrent's file layout.
+///
+/// ```text
+/// single-file: <download folder>/<name>
+/// multi-file:  <download folder>/<name>/<path...>
+/// ```
+///
+/// Open file handles are cached so that each block doesn't have to open its file again,
+/// and blocks that arrive one after another are merged into a single larger write.
+///
+/// Data belonging to skipped files is kept in a partfile instead of creating the skipped files.
+/// Each piece with skipped data gets a slot of a whole piece in the partfile, so it only grows
+/// with the pieces that straddle a skipped file rather than with the size of the torrent.
+/// The header has an entry per piece holding its slot plus one, or zero if it has no slot.
+///
+/// ```text
+/// partfile: <download folder>/.<info hash>.parts
+///           [u32 per piece][slot 0][slot 1]...
+/// ```
+///
+/// The totals of the torrent are kept as JSON next to the partfile, see ResumeData.
+///
+/// ```text
+/// resume file: <download folder>/.<info hash>.resume
+/// ```
+pub struct FsStorage {
+    torrent: Arc<Torrent>,
+    download_folder: PathBuf,
+    files: FileCache,
+    pending: Option<PendingWrite>,
+    skipped: Vec<bool>,
+    /// The partfile slot of each piece, read from the header the first time it's needed.
+    part_slots: Option<Vec<Option<u32>>>,
+}
+
+/// Where a part of a byte range of the torrent is stored.
+struct SliceTarget {
+    /// The key in the file handle cache.
+    key: usize,
+    path: PathBuf,
+    position: u64,
+    length: usize,
+}
+
+/// Contiguous blocks that haven't been written yet.
+struct PendingWrite {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl FsStorage {
+    /// Create the storage, along with every directory the torrent's files will be written to.
+    pub fn new<P: AsRef<Path>>(download_folder: P, torrent: Arc<Torrent>) -> io::Result<FsStorage> {
+        let download_folder = download_folder.as_ref().to_path_buf();
+        create_download_folder(&download_folder, &torrent.layout)?;
+
+        Ok(FsStorage {
+            skipped: vec![false; torrent.layout.files().len()],
+            torrent,
+            download_folder,
+            files: FileCache::new(DEFAULT_OPEN_FILES),
+            pending: None,
+            part_slots: None,
+        })
+    }
+
+    /// Change how many file handles are kept open at once.
+    pub fn set_max_open_files(&mut self, max_open_files: usize) {
+        self.files = FileCache::new(max_open_files.max(1));
+    }
+
+    /// Read the data of the files that aren't on disk from the partfile, where a download that
+    /// skipped them left it. The file priorities aren't saved, this is how the data of a torrent
+    /// is checked once its download is gone.
+    pub fn skip_missing_files(&mut self) {
+        if !self.partfile_path().exists() {
+            return;
+        }
+
+        self.skipped = (0..self.skipped.len()).map(|file_index| !self.file_path(file_index).exists()).collect();
+    }
+
+    fn file_path(&self, file_index: usize) -> PathBuf {
+        self.download_folder.join(&self.torrent.layout.files()[file_index].path)
+    }
+
+    fn partfile_path(&self) -> PathBuf {
+        self.download_folder.join(format!(".{}.parts", self.torrent.info_hash_hex()))
+    }
+
+    fn resume_path(&self) -> PathBuf {
+        self.download_folder.join(format!(".{}.resume", self.torrent.info_hash_hex()))
+    }
+
+    /// Get where each part of a byte range of the torrent is stored.
+    ///
+    /// Slices of skipped files are split at piece boundaries as every piece has its own slot
+    /// in the partfile. Slots are only handed out for writes, reading a piece without one is
+    /// a NotFound error like reading a missing file.
+    fn targets(&mut self, offset: u64, length: u64, writable: bool) -> io::Result<Vec<SliceTarget>> {
+        let piece_length = self.torrent.info.piece_length;
+        let mut targets = Vec::new();
+
+        for slice in self.torrent.layout.slices(offset, length) {
+            if !self.skipped[slice.file_index] {
+                targets.push(SliceTarget {
+                    key: slice.file_index,
+                    path: self.file_path(slice.file_index),
+                    position: slice.file_offset,
+                    length: slice.length as usize,
+                });
+                continue;
+            }
+
+            let mut start = self.torrent.layout.files()[slice.file_index].offset + slice.file_offset;
+            let end = start + slice.length;
+
+            while start < end {
+                let piece_index = start / piece_length;
+                let stop = end.min((piece_index + 1) * piece_length);
+                let slot = self.part_slot(piece_index, writable)?;
+
+                targets.push(SliceTarget {
+                    key: PARTFILE_KEY,
+                    path: self.partfile_path(),
+                    position: self.part_header_len() + slot as u64 * piece_length + start % piece_length,
+                    length: (stop - start) as usize,
+                });
+                start = stop;
+            }
+        }
+
+        Ok(targets)
+    }
+
+    fn part_header_len(&self) -> u64 {
+        self.torrent.num_pieces() * 4
+    }
+
+    /// The partfile slots of every piece, read from the partfile's header if they aren't loaded yet.
+    fn load_part_slots(&mut self) -> io::Result<&mut Vec<Option<u32>>> {
+        if self.part_slots.is_none() {
+            let mut header = vec![0; self.part_header_len() as usize];
+
+            match File::open(self.partfile_path()) {
+                // A partfile cut short has no slots for the pieces past its end.
+                Ok(partfile) => {
+                    let mut read = Vec::new();
+                    partfile.take(header.len() as u64).read_to_end(&mut read)?;
+                    header[..read.len()].copy_from_slice(&read);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+
+            let slots = header
+                .chunks(4)
+                .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]).checked_sub(1))
+                .collect();
+            self.part_slots = Some(slots);
+        }
+
+        Ok(self.part_slots.as_mut().unwrap())
+    }
+
+    /// Get the partfile slot of a piece, giving it the first free slot if it's being written.
+    fn part_slot(&mut self, piece_index: u64, writable: bool) -> io::Result<u32> {
+        let slots = self.load_part_slots()?;
+
+        if let Some(slot) = slots[piece_index as usize] {
+            return Ok(slot);
+        }
+        if !writable {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "The piece isn't in the partfile"));
+        }
+
+        let mut used = vec![false; slots.len()];
+        for slot in slots.iter().flatten() {
+            if let Some(used) = used.get_mut(*slot as usize) {
+                *used = true;
+            }
+        }
+        let slot = used.iter().position(|used| !used).unwrap_or(used.len()) as u32;
+
+        self.write_part_slot(piece_index, Some(slot))?;
+        Ok(slot)
+    }
+
+    /// Store the slot of a piece in the partfile's header.
+    fn write_part_slot(&mut self, piece_index: u64, slot: Option<u32>) -> io::Result<()> {
+        let partfile_path = self.partfile_path();
+        let entry = slot.map_or(0, |slot| slot + 1).to_le_bytes();
+
+        let partfile = self.files.get(PARTFILE_KEY, &partfile_path, true)?;
+        partfile.seek(SeekFrom::Start(piece_index * 4))?;
+        partfile.write_all(&entry)?;
+
+        self.load_part_slots()?[piece_index as usize] = slot;
+        Ok(())
+    }
+
+    /// Make sure there's enough free space for the rest of the torrent, then create its files.
+    ///
+    /// Files that already exist keep their contents, they are only grown to their full size.
+    pub fn allocate(&mut self, mode: AllocationMode) -> io::Result<()> {
+        self.check_free_space()?;
+
+        for (file_index, file) in self.torrent.layout.files().iter().enumerate() {
+            if self.skipped[file_index] {
+                continue;
+            }
+
+            let file_path = self.file_path(file_index);
+            let dl_file = self.files.get(file_index, &file_path, true)?;
+            let current_len = dl_file.metadata()?.len();
+
+            if current_len >= file.length {
+                continue;
+            }
+
+            match mode {
+                AllocationMode::Sparse => dl_file.set_len(file.length)?,
+                AllocationMode::Full => dl_file.allocate(file.length)?,
+                AllocationMode::Zero => {
+                    let zeros = vec![0; MAX_COALESCED_WRITE];
+                    let mut remaining = file.length - current_len;
+
+                    dl_file.seek(SeekFrom::Start(current_len))?;
+                    while remaining > 0 {
+                        let write_len = remaining.min(zeros.len() as u64) as usize;
+                        dl_file.write_all(&zeros[..write_len])?;
+                        remaining -= write_len as u64;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the disk doesn't have enough room for the parts of the torrent that aren't on it yet.
+    pub fn check_free_space(&self) -> io::Result<()> {
+        let mut needed = 0;
+
+        for (file_index, file) in self.torrent.layout.files().iter().enumerate() {
+            if self.skipped[file_index] {
+                continue;
+            }
+
+            let allocated = match File::open(self.file_path(file_index)) {
+                Ok(f) => f.allocated_size()?.min(file.length),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+
+            needed += file.length - allocated;
+        }
+
+        let available = fs2::available_space(&self.download_folder)?;
+
+        if needed > available {
+            return Err(io::Error::other(format!(
+                "Not enough free disk space: {} bytes needed but only {} bytes available",
+                needed, available
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Write the bytes at an offset of the torrent into every file they cover.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut bytes_written = 0;
+
+        for target in self.targets(offset, data.len() as u64, true)? {
+            let write_len = target.length;
+
+            let dl_file = self.files.get(target.key, &target.path, true)?;
+            dl_file.seek(SeekFrom::Start(target.position))?;
+            dl_file.write_all(&data[bytes_written..bytes_written + write_len])?;
+
+            bytes_written += write_len;
+        }
+
+        Ok(())
+    }
+
+    /// Write out any blocks that are still waiting to be merged.
+    ///
+    /// A failure is a FailedWrite with the pieces of those blocks, not of whatever block
+    /// is being read or written when they're written out.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if let Some(pending) = self.pending.take() {
+            self.write_at(pending.offset, &pending.data)
+                .map_err(|e| FailedWrite::io_error(pieces_in(&self.torrent, pending.offset, pending.data.len()), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn read_block(&mut self, piece_index: u64, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+        let offset = block_offset(&self.torrent, piece_index, begin, length)?;
+        self.write_pending()?;
+
+        let mut block = vec![0; length as usize];
+        let mut bytes_read = 0;
+
+        for target in self.targets(offset, length, false)? {
+            let read_len = target.length;
+
+            let dl_file = self.files.get(target.key, &target.path, false)?;
+            dl_file.seek(SeekFrom::Start(target.position))?;
+            dl_file.read_exact(&mut block[bytes_read..bytes_read + read_len])?;
+
+            bytes_read += read_len;
+        }
+
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece_index: u64, begin: u64, block: &[u8]) -> io::Result<()> {
+        let offset = block_offset(&self.torrent, piece_index, begin, block.len() as u64)?;
+
+        // Merge the block with the previous one if it continues right where that one ended.
+        if let Some(pending) = &mut self.pending {
+            let contiguous = pending.offset + pending.data.len() as u64 == offset;
+
+            if contiguous && pending.data.len() + block.len() <= MAX_COALESCED_WRITE {
+                pending.data.extend_from_slice(block);
+                return Ok(());
+            }
+        }
+
+        // The block is kept even if the earlier ones fail, they're separate pieces.
+        let flushed = self.write_pending();
+
+        if block.len() >= MAX_COALESCED_WRITE {
+            return match (flushed, self.write_at(offset, block)) {
+                (flushed, Ok(())) => flushed,
+                (flushed, Err(e)) => {
+                    let mut pieces = flushed.err().as_ref().and_then(FailedWrite::pieces).unwrap_or_default().to_vec();
+                    pieces.extend(pieces_in(&self.torrent, offset, block.len()));
+                    Err(FailedWrite::io_error(pieces, e))
+                }
+            };
+        }
+
+        self.pending = Some(PendingWrite {
+            offset,
+            data: block.to_vec(),
+        });
+
+        flushed
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.files.sync_all()
+    }
+
+    fn verify_piece(&mut self, piece_index: u64) -> io::Result<bool> {
+        let piece_len = self.torrent.get_piece_len(piece_index);
+
+        let data = match self.read_block(piece_index, 0, piece_len) {
+            Ok(data) => data,
+            // A file that is missing or too short can't hold a valid piece.
+            Err(e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        Ok(verify_piece_data(&self.torrent, piece_index, &data))
+    }
+
+    fn move_to(&mut self, download_folder: &Path) -> io::Result<()> {
+        self.flush()?;
+        self.files.clear();
+        create_download_folder(download_folder, &self.torrent.layout)?;
+
+        for file in self.torrent.layout.files() {
+            let from = self.download_folder.join(&file.path);
+            let to = download_folder.join(&file.path);
+
+            move_file(&from, &to)?;
+        }
+
+        let (partfile, resume_file) = (self.partfile_path(), self.resume_path());
+        remove_empty_dirs(&self.download_folder, &self.torrent.layout);
+        self.download_folder = download_folder.to_path_buf();
+        move_file(&partfile, &self.partfile_path())?;
+        move_file(&resume_file, &self.resume_path())?;
+
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        self.pending = None;
+        self.files.clear();
+
+        for file in self.torrent.layout.files() {
+            remove_file_if_exists(&self.download_folder.join(&file.path))?;
+        }
+
+        remove_file_if_exists(&self.partfile_path())?;
+        remove_file_if_exists(&self.resume_path())?;
+        self.part_slots = None;
+        remove_empty_dirs(&self.download_folder, &self.torrent.layout);
+
+        Ok(())
+    }
+
+    fn set_file_priorities(&mut self, priorities: &[Priority]) -> io::Result<()> {
+        self.write_pending()?;
+
+        for (file_index, skipped) in self.skipped.iter_mut().enumerate() {
+            *skipped = priorities.get(file_index) == Some(&Priority::Skip);
+        }
+
+        // Nothing else can end up in the partfile once every file is wanted.
+        if !self.skipped.contains(&true) {
+            self.files.remove(PARTFILE_KEY);
+            self.part_slots = None;
+            return remove_file_if_exists(&self.partfile_path());
+        }
+
+        // Free the slots of pieces that no longer hold any skipped data so they can be reused.
+        let piece_length = self.torrent.info.piece_length;
+        let slotted: Vec<u64> = self.load_part_slots()?
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(piece_index, _)| piece_index as u64)
+            .collect();
+
+        for piece_index in slotted {
+            let slices = self.torrent.layout.slices(piece_index * piece_length, self.torrent.get_piece_len(piece_index));
+
+            if !slices.iter().any(|slice| self.skipped[slice.file_index]) {
+                self.write_part_slot(piece_index, None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_resume(&mut self) -> io::Result<Option<ResumeData>> {
+        let text = match fs::read_to_string(self.resume_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        serde_json::from_str(&text).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn save_resume(&mut self, resume: &ResumeData) -> io::Result<()> {
+        // Written aside and renamed, so that a crash halfway leaves the last totals in place.
+        let path = self.resume_path();
+        let written = path.with_extension("resume.tmp");
+
+        fs::create_dir_all(&self.download_folder)?;
+        fs::write(&written, serde_json::to_vec(resume)?)?;
+        fs::rename(&written, &path)
+    }
+}
+
+
+/// Move a file if it exists.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+
+    // Renaming fails across filesystems, so fall back to copying.
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// A least recently used cache of open file handles, indexed by the file's position in the layout.
+struct FileCache {
+    capacity: usize,
+    /// The most recently used file is at the end.
+    files: Vec<CachedFile>,
+}
+
+struct CachedFile {
+    file_index: usize,
+    writable: bool,
+    file: File,
+}
+
+impl FileCache {
+    fn new(capacity: usize) -> FileCache {
+        FileCache {
+            capacity,
+            files: Vec::new(),
+        }
+    }
+
+    /// Get an open handle to a file, opening it if it isn't cached yet.
+    ///
+    /// Files that are only read are opened read-only so that reading a missing file
+    /// doesn't create it. They are opened again if a write is needed later. A file that
+    /// was replaced by a symlink isn't opened, it could point anywhere.
+    fn get(&mut self, file_index: usize, path: &Path, writable: bool) -> io::Result<&mut File> {
+        let cached = self.files.iter().position(|f| f.file_index == file_index && (f.writable || !writable));
+
+        let cached_file = match cached {
+            Some(i) => self.files.remove(i),
+            None => {
+                // Drop a read-only handle that is being replaced by a writable one.
+                self.files.retain(|f| f.file_index != file_index);
+
+                if is_symlink(path) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("Refusing to follow the symlink at {}", path.display()),
+                    ));
+                }
+
+                let file = if writable {
+                    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?
+                } else {
+                    File::open(path)?
+                };
+
+                if self.files.len() >= self.capacity {
+                    self.files.remove(0);
+                }
+
+                CachedFile { file_index, writable, file }
+            }
+        };
+
+        self.files.push(cached_file);
+        Ok(&mut self.files.last_mut().unwrap().file)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        for cached in self.files.iter().filter(|f| f.writable) {
+            cached.file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, file_index: usize) {
+        self.files.retain(|f| f.file_index != file_index);
+    }
+
+    fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+
+/// Create the download folder along with every nested directory used by the torrent's files.
+///
+/// The layout paths are already sanitized, but a directory inside the download folder
+/// could be a symlink pointing elsewhere. The directories are created one at a time and
+/// each is resolved and checked to still be inside the download folder before anything
+/// is created in it. The files themselves mustn't be symlinks either.
+fn create_download_folder(download_folder: &Path, layout: &FileLayout) -> io::Result<()> {
+    fs::create_dir_all(download_folder)?;
+    let root = fs::canonicalize(download_folder)?;
+
+    for file in layout.files() {
+        let outside = || io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Refusing to write outside of the download folder: {}", file.path.display()),
+        );
+
+        if !is_contained(&file.path) {
+            return Err(outside());
+        }
+
+        let mut dir = root.clone();
+
+        for component in file.path.parent().into_iter().flat_map(Path::components) {
+            let next = dir.join(component);
+
+            if !next.exists() {
+                fs::create_dir(&next)?;
+            }
+
+            dir = fs::canonicalize(&next)?;
+            if !dir.starts_with(&root) {
+                return Err(outside());
+            }
+        }
+
+        if file.path.file_name().is_some_and(|name| is_symlink(&dir.join(name))) {
+            return Err(outside());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether there's a symlink at the path, rather than whatever it points to.
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+
+/// Remove the directories of a torrent's files, deepest first, if nothing else is left in them.
+fn remove_empty_dirs(download_folder: &Path, layout: &FileLayout) {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+
+    for file in layout.files() {
+        for dir in file.path.ancestors().skip(1) {
+            if dir.as_os_str().is_empty() || dirs.iter().any(|d| d == dir) {
+                continue;
+            }
+            dirs.push(dir.to_path_buf());
+        }
+    }
+
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    for dir in dirs {
+        // Fails if the directory isn't empty, which is what we want.
+        let _ = fs::remove_dir(download_folder.join(dir));
+    }
+}
+
+
+/// Keeps the whole torrent in memory, useful for tests or for data that never has to touch the disk.
+pub struct MemoryStorage {
+    torrent: Arc<Torrent>,
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new(torrent: Arc<Torrent>) -> MemoryStorage {
+        let size = torrent.size.unwrap_or(0) as usize;
+
+        MemoryStorage {
+            torrent,
+            data: vec![0; size],
+        }
+    }
+
+    /// Get the range of the data covered by a block, if it is inside the torrent.
+    fn range(&self, piece_index: u64, begin: u64, length: u64) -> io::Result<std::ops::Range<usize>> {
+        let start = block_offset(&self.torrent, piece_index, begin, length)? as usize;
+
+        Ok(start..start + length as usize)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&mut self, piece_index: u64, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+        let range = self.range(piece_index, begin, length)?;
+        Ok(self.data[range].to_vec())
+    }
+
+    fn write_block(&mut self, piece_index: u64, begin: u64, block: &[u8]) -> io::Result<()> {
+        let range = self.range(piece_index, begin, block.len() as u64)?;
+        self.data[range].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn verify_piece(&mut self, piece_index: u64) -> io::Result<bool> {
+        let piece_len = self.torrent.get_piece_len(piece_index);
+        let range = self.range(piece_index, 0, piece_len)?;
+
+        Ok(verify_piece_data(&self.torrent, piece_index, &self.data[range]))
+    }
+
+    fn move_to(&mut self, _download_folder: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        self.data.iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn remove_test_folder(folder: &str) {
+    let _ = fs::remove_dir_all(folder);
+}
+
+
+/// Three files of 5 bytes in a single 15 byte piece, with the piece hash of the given data.
+#[cfg(test)]
+fn build_test_torrent(piece_data: &[u8]) -> Arc<Torrent> {
+    let mut hasher = Sha1::new();
+    let mut hash = [0; 20];
+    hasher.input(piece_data);
+    hasher.result(&mut hash);
+
+    let mut metainfo = b"d8:announce14:udp://a.b:13374:infod5:filesl\
+        d6:lengthi5e4:pathl9:file1.txtee\
+        d6:lengthi5e4:pathl9:file2.txtee\
+        d6:lengthi5e4:pathl9:file3.txteee\
+        4:name4:test12:piece lengthi15e6:pieces20:".to_vec();
+    metainfo.extend_from_slice(&hash);
+    metainfo.extend_from_slice(b"ee");
+
+    Arc::new(Torrent::from_bytes(&metainfo).unwrap())
+}
+
+#[test]
+fn test_write_block_to_file_1() {
+    let download_folder: String = String::from("test-files/test1/");
+    remove_test_folder(&download_folder);
+
+    // Setup
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+
+    // Logic
+    storage.write_block(0, 4, &[1; 8]).unwrap();
+    storage.flush().unwrap();
+
+    // Test
+    let mut f = File::open(download_folder.clone() + "/test/file1.txt").expect("Couldn't open file");
+    let mut buffer = [0; 5];
+    f.read_exact(&mut buffer).expect("Couldn't read to buffer");
+    assert_eq!(vec![0, 0, 0, 0, 1], buffer);
+
+
+    let mut f = File::open(download_folder.clone() + "/test/file2.txt").expect("Couldn't open file");
+    let mut buffer = [0; 5];
+    f.read_exact(&mut buffer).expect("Couldn't read to buffer");
+    assert_eq!(vec![1; 5], buffer);
+
+
+    let mut f = File::open(download_folder.clone() + "/test/file3.txt").expect("Couldn't open file");
+    let mut buffer = [0; 2];
+    f.read_exact(&mut buffer).expect("Couldn't read to buffer");
+    assert_eq!(vec![1, 1], buffer);
+
+    assert_eq!(storage.read_block(0, 3, 4).unwrap(), vec![0, 1, 1, 1]);
+
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_write_block_to_file_2() {
+    let download_folder: String = String::from("test-files/test2/");
+    remove_test_folder(&download_folder);
+
+    // Setup
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+
+    // Logic
+    storage.write_block(0, 9, &[1; 6]).unwrap();
+    storage.flush().unwrap();
+
+
+    let mut f = File::open(download_folder.clone() + "/test/file2.txt").expect("Couldn't open file");
+    let mut buffer = [0; 5];
+    f.read_exact(&mut buffer).expect("Couldn't read to buffer");
+    assert_eq!(vec![0, 0, 0, 0, 1], buffer);
+
+
+    let mut f = File::open(download_folder.clone() + "/test/file3.txt").expect("Couldn't open file");
+    let mut buffer = [0; 5];
+    f.read_exact(&mut buffer).expect("Couldn't read to buffer");
+    assert_eq!(vec![1; 5], buffer);
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_move_and_delete() {
+    let download_folder: String = String::from("test-files/test5/");
+    let moved_folder: String = String::from("test-files/test5-moved/");
+    for folder in &[&download_folder, &moved_folder] {
+        remove_test_folder(folder);
+    }
+
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+    assert!(!storage.verify_piece(0).unwrap());
+
+    storage.write_block(0, 0, &[7; 15]).unwrap();
+    assert!(storage.verify_piece(0).unwrap());
+
+    // The totals go wherever the data goes.
+    let resume = ResumeData { downloaded: 15, uploaded: 30, seeding_secs: 60 };
+    assert_eq!(storage.load_resume().unwrap(), None);
+    storage.save_resume(&resume).unwrap();
+
+    storage.move_to(Path::new(&moved_folder)).unwrap();
+    assert!(!Path::new(&download_folder).join("test").exists());
+    assert_eq!(storage.read_block(0, 0, 15).unwrap(), vec![7; 15]);
+    assert_eq!(storage.load_resume().unwrap(), Some(resume));
+
+    storage.delete().unwrap();
+    assert!(!Path::new(&moved_folder).join("test").exists());
+    assert_eq!(storage.load_resume().unwrap(), None);
+
+    for folder in &[&download_folder, &moved_folder] {
+        remove_test_folder(folder);
+    }
+}
+
+#[test]
+fn test_write_block_single_file() {
+    let download_folder: String = String::from("test-files/test3/");
+    remove_test_folder(&download_folder);
+
+    let metainfo = "d8:announce14:udp://a.b:13374:infod6:lengthi10e4:name10:single.bin12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let torrent = Arc::new(Torrent::from_bytes(metainfo.as_bytes()).unwrap());
+    let mut storage = FsStorage::new(&download_folder, torrent).unwrap();
+
+    storage.write_block(0, 2, &[1; 3]).unwrap();
+    storage.flush().unwrap();
+
+    let contents = fs::read(download_folder.clone() + "/single.bin").expect("Couldn't read file");
+    assert_eq!(vec![0, 0, 1, 1, 1], contents);
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_coalesce_and_cache() {
+    let download_folder: String = String::from("test-files/test6/");
+    remove_test_folder(&download_folder);
+
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+    storage.set_max_open_files(2);
+
+    // Contiguous blocks are held back and merged until something needs them on disk.
+    storage.write_block(0, 0, &[7; 4]).unwrap();
+    storage.write_block(0, 4, &[7; 4]).unwrap();
+    assert!(!Path::new(&download_folder).join("test/file1.txt").exists());
+
+    // A block somewhere else writes out the merged blocks first.
+    storage.write_block(0, 12, &[7; 3]).unwrap();
+    assert_eq!(fs::read(download_folder.clone() + "test/file1.txt").unwrap(), vec![7; 5]);
+
+    // Fill the gap, the write spans all three files while only two handles can stay open.
+    storage.write_block(0, 8, &[7; 4]).unwrap();
+    assert!(storage.verify_piece(0).unwrap());
+    assert!(storage.files.len() <= 2);
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_read_does_not_create_files() {
+    let download_folder: String = String::from("test-files/test7/");
+    remove_test_folder(&download_folder);
+
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+
+    assert!(!storage.verify_piece(0).unwrap());
+    assert!(!Path::new(&download_folder).join("test/file1.txt").exists());
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_partfile() {
+    let download_folder: String = String::from("test-files/test9/");
+    remove_test_folder(&download_folder);
+
+    let torrent = build_test_torrent(&[7; 15]);
+    let partfile = format!("{}.{}.parts", download_folder, torrent.info_hash_hex());
+    let mut storage = FsStorage::new(&download_folder, torrent).unwrap();
+
+    // The piece covers all three files, only the middle one is wanted.
+    storage.set_file_priorities(&[Priority::Skip, Priority::High, Priority::Skip]).unwrap();
+    storage.allocate(AllocationMode::Sparse).unwrap();
+    storage.write_block(0, 0, &[7; 15]).unwrap();
+    assert!(storage.verify_piece(0).unwrap());
+
+    assert!(!Path::new(&download_folder).join("test/file1.txt").exists());
+    assert!(!Path::new(&download_folder).join("test/file3.txt").exists());
+    assert_eq!(fs::read(download_folder.clone() + "test/file2.txt").unwrap(), vec![7; 5]);
+    // The header entry of the single piece, then its slot.
+    assert_eq!(fs::read(&partfile).unwrap().len(), 4 + 15);
+
+    storage.delete().unwrap();
+    assert!(!Path::new(&partfile).exists());
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_partfile_slots() {
+    let download_folder: String = String::from("test-files/test16/");
+    remove_test_folder(&download_folder);
+
+    // 64 pieces of 64 bytes, piece 62 straddles the end of the skipped file and the wanted one.
+    let mut metainfo = b"d8:announce14:udp://a.b:13374:infod5:filesl\
+        d6:lengthi4000e4:pathl7:big.binee\
+        d6:lengthi96e4:pathl9:small.txteee\
+        4:name4:test12:piece lengthi64e6:pieces1280:".to_vec();
+    metainfo.extend_from_slice(&[0; 1280]);
+    metainfo.extend_from_slice(b"ee");
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+    let partfile = format!("{}.{}.parts", download_folder, torrent.info_hash_hex());
+
+    let mut storage = FsStorage::new(&download_folder, torrent.clone()).unwrap();
+    storage.set_file_priorities(&[Priority::Skip, Priority::Normal]).unwrap();
+    storage.write_block(62, 0, &[7; 64]).unwrap();
+    storage.flush().unwrap();
+
+    // A header entry per piece and the skipped half of a single slot, rather than the 4000
+    // bytes of the skipped file.
+    assert_eq!(fs::read(&partfile).unwrap().len(), 64 * 4 + 32);
+    assert_eq!(fs::read(download_folder.clone() + "test/small.txt").unwrap(), vec![7; 32]);
+    assert_eq!(storage.read_block(62, 0, 64).unwrap(), vec![7; 64]);
+    assert_eq!(storage.read_block(61, 0, 64).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // The slots are read back from the header.
+    let mut storage = FsStorage::new(&download_folder, torrent).unwrap();
+    storage.set_file_priorities(&[Priority::Skip, Priority::Normal]).unwrap();
+    assert_eq!(storage.read_block(62, 0, 64).unwrap(), vec![7; 64]);
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_skip_missing_files() {
+    let download_folder: String = String::from("test-files/test26/");
+    remove_test_folder(&download_folder);
+
+    let torrent = build_test_torrent(&[7; 15]);
+    let mut storage = FsStorage::new(&download_folder, torrent.clone()).unwrap();
+    storage.set_file_priorities(&[Priority::Skip, Priority::High, Priority::Skip]).unwrap();
+    storage.write_block(0, 0, &[7; 15]).unwrap();
+    storage.flush().unwrap();
+
+    // Without the priorities of the download the skipped files look missing.
+    let mut storage = FsStorage::new(&download_folder, torrent.clone()).unwrap();
+    assert!(!storage.verify_piece(0).unwrap());
+    storage.skip_missing_files();
+    assert!(storage.verify_piece(0).unwrap());
+
+    // Without a partfile the missing files are just missing.
+    storage.delete().unwrap();
+    let mut storage = FsStorage::new(&download_folder, torrent).unwrap();
+    storage.skip_missing_files();
+    assert!(!storage.verify_piece(0).unwrap());
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_fs_storage_allocate() {
+    let download_folder: String = String::from("test-files/test8/");
+    remove_test_folder(&download_folder);
+
+    for mode in &[AllocationMode::Sparse, AllocationMode::Full, AllocationMode::Zero] {
+        remove_test_folder(&download_folder);
+
+        let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[7; 15])).unwrap();
+        storage.write_block(0, 0, &[7; 2]).unwrap();
+        storage.flush().unwrap();
+
+        storage.allocate(*mode).unwrap();
+
+        // Files have their full size and existing data is kept.
+        for i in 1..4 {
+            let path = format!("{}test/file{}.txt", download_folder, i);
+            assert_eq!(fs::metadata(&path).unwrap().len(), 5);
+        }
+        assert_eq!(storage.read_block(0, 0, 3).unwrap(), vec![7, 7, 0]);
+
+        // The files that weren't written to take no disk blocks when sparse, and have them
+        // reserved or filled with zeros otherwise.
+        storage.flush().unwrap();
+        for i in 2..4 {
+            let path = format!("{}test/file{}.txt", download_folder, i);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let blocks = fs::metadata(&path).unwrap().blocks();
+                match mode {
+                    AllocationMode::Sparse => assert_eq!(blocks, 0, "{:?}", mode),
+                    AllocationMode::Full | AllocationMode::Zero => assert!(blocks > 0, "{:?}", mode),
+                }
+            }
+            assert_eq!(fs::read(&path).unwrap(), vec![0; 5]);
+        }
+    }
+
+    remove_test_folder(&download_folder);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_create_download_folder_nested() {
+    let test_folder = "test-files/test4/";
+    let download_folder: String = format!("{}downloads/", test_folder);
+    remove_test_folder(test_folder);
+
+    let files = vec![crate::utils::torrents::DlFile {
+        path: vec!["a".to_owned(), "b".to_owned(), "c.txt".to_owned()],
+        length: 1,
+        md5sum: None,
+    }];
+    let layout = FileLayout::multi_file("test", &files).unwrap();
+    create_download_folder(Path::new(&download_folder), &layout).unwrap();
+    assert!(Path::new(&download_folder).join("test/a/b").is_dir());
+
+    // A symlink inside the download folder must not be followed outside of it, and nothing
+    // may be created where it points.
+    let outside = fs::canonicalize(test_folder).unwrap().join("outside");
+    fs::create_dir(&outside).unwrap();
+    fs::remove_dir_all(download_folder.clone() + "test/a").unwrap();
+    std::os::unix::fs::symlink(&outside, download_folder.clone() + "test/a").unwrap();
+    assert!(create_download_folder(Path::new(&download_folder), &layout).is_err());
+    assert!(!outside.join("b").exists());
+
+    // A symlink that stays inside is fine.
+    fs::remove_file(download_folder.clone() + "test/a").unwrap();
+    fs::create_dir(download_folder.clone() + "test/real").unwrap();
+    std::os::unix::fs::symlink(fs::canonicalize(download_folder.clone() + "test/real").unwrap(), download_folder.clone() + "test/a").unwrap();
+    create_download_folder(Path::new(&download_folder), &layout).unwrap();
+    assert!(Path::new(&download_folder).join("test/real/b").is_dir());
+
+    // Neither is a file of the torrent that was replaced by a symlink.
+    fs::write(outside.join("c.txt"), b"x").unwrap();
+    std::os::unix::fs::symlink(outside.join("c.txt"), download_folder.clone() + "test/real/b/c.txt").unwrap();
+    assert!(create_download_folder(Path::new(&download_folder), &layout).is_err());
+    assert!(FileCache::new(1).get(0, &Path::new(&download_folder).join("test/a/b/c.txt"), true).is_err());
+    assert_eq!(fs::read(outside.join("c.txt")).unwrap(), b"x");
+
+    remove_test_folder(test_folder);
+}
+
+#[test]
+fn test_blocks_past_the_end() {
+    let download_folder: String = String::from("test-files/test14/");
+    remove_test_folder(&download_folder);
+
+    let torrent = build_test_torrent(&[1; 15]);
+    let mut fs_storage = FsStorage::new(&download_folder, torrent.clone()).unwrap();
+    let mut memory_storage = MemoryStorage::new(torrent);
+
+    for storage in [&mut fs_storage as &mut dyn Storage, &mut memory_storage] {
+        storage.write_block(0, 10, &[1; 5]).unwrap();
+        assert_eq!(storage.read_block(0, 10, 5).unwrap(), vec![1; 5]);
+
+        for (piece_index, begin, length) in [(0, 10, 6), (1, 0, 1), (0, u64::MAX, 1), (u64::MAX, 0, 1)] {
+            let write = storage.write_block(piece_index, begin, &vec![2; length as usize]).unwrap_err();
+            assert_eq!(write.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(storage.read_block(piece_index, begin, length).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // Nothing of the refused writes made it in.
+        assert_eq!(storage.read_block(0, 10, 5).unwrap(), vec![1; 5]);
+    }
+
+    remove_test_folder(&download_folder);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_fs_storage_failed_pending_write() {
+    let download_folder: String = String::from("test-files/test15/");
+    remove_test_folder(&download_folder);
+
+    let mut storage = FsStorage::new(&download_folder, build_test_torrent(&[1; 15])).unwrap();
+    // The first file can't be opened, its blocks fail once they're written out.
+    fs::create_dir_all(download_folder.clone() + "test/file1.txt").unwrap();
+
+    storage.write_block(0, 0, &[1; 5]).unwrap();
+    let e = storage.write_block(0, 10, &[3; 5]).unwrap_err();
+    assert_eq!(FailedWrite::pieces(&e), Some(&[0][..]));
+
+    // The block that came with the failure was kept.
+    storage.flush().unwrap();
+    assert_eq!(fs::read(download_folder.clone() + "test/file3.txt").unwrap(), vec![3; 5]);
+
+    let e = storage.write_block(0, 0, &[1; 15]).and_then(|_| storage.flush()).unwrap_err();
+    assert_eq!(FailedWrite::pieces(&e), Some(&[0][..]));
+
+    remove_test_folder(&download_folder);
+}
+
+#[test]
+fn test_memory_storage_verify_piece() {
+    let mut storage = MemoryStorage::new(build_test_torrent(&[1; 15]));
+
+    assert!(!storage.verify_piece(0).unwrap());
+
+    storage.write_block(0, 0, &[1; 15]).unwrap();
+    assert!(storage.verify_piece(0).unwrap());
+
+    storage.write_block(0, 1, &[2, 3]).unwrap();
+    assert_eq!(storage.read_block(0, 0, 4).unwrap(), vec![1, 2, 3, 1]);
+    assert!(!storage.verify_piece(0).unwrap());
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let mut storage = MemoryStorage::new(torrent.clone());
+
+    // Reading past the end of the torrent fails rather than panicking.
+    let last_piece = torrent.num_pieces() - 1;
+    assert!(storage.read_block(last_piece, 0, torrent.info.piece_length).is_err());
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+use crate::disk::TorrentDisk;
+use crate::http_server::{read_request, write_response};
+use crate::reader::TorrentReader;
+
+/// The torrents being served, by the hex encoded info hash.
+pub type StreamTorrents = Arc<Mutex<HashMap<String, TorrentDisk>>>;
+
+/// How much of a file is read from the torrent and sent at a time.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// A small HTTP server exposing the files of torrents while they download.
+///
+/// Every file is available at `/<info hash>/<file index>`, and `/<info hash>` lists them.
+/// Range requests are supported so media players can seek, reads wait for the pieces
+/// to be downloaded and verified.
+pub struct StreamServer {
+    listener: TcpListener,
+    torrents: StreamTorrents,
+}
+
+impl StreamServer {
+    pub async fn bind(addr: &str, torrents: StreamTorrents) -> io::Result<StreamServer> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(StreamServer { listener, torrents })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the listener fails, every connection gets its own task.
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let torrents = self.torrents.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, torrents).await {
+                    warn!(error = %e, "stream connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// A single range of bytes, both ends included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Parse the value of a Range header against a file of the given length.
+///
+/// ```text
+/// bytes=0-499     the first 500 bytes
+/// bytes=500-      everything from byte 500
+/// bytes=-500      the last 500 bytes
+/// ```
+///
+/// Only a single range is supported. Returns None if the range can't be satisfied.
+pub fn parse_range(value: &str, file_length: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+
+    if spec.contains(',') || file_length == 0 {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+
+        ByteRange { start: file_length.saturating_sub(suffix), end: file_length - 1 }
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() { file_length - 1 } else { end.parse::<u64>().ok()?.min(file_length - 1) };
+
+        ByteRange { start, end }
+    };
+
+    if range.start > range.end || range.start >= file_length {
+        return None;
+    }
+
+    Some(range)
+}
+
+/// Guess the content type from the file extension, so that players know what they get.
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" | "srt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, torrents: StreamTorrents) -> io::Result<()> {
+    let request = match read_request(&mut stream, 0).await? {
+        Some(request) => request,
+        None => return write_response(&mut stream, "400 Bad Request", &[], b"").await,
+    };
+
+    if request.method != "GET" && request.method != "HEAD" {
+        return write_response(&mut stream, "405 Method Not Allowed", &[("Allow", "GET, HEAD".to_string())], b"").await;
+    }
+
+    let mut segments = request.path.trim_start_matches('/').split('/');
+    let info_hash = segments.next().unwrap_or("").to_ascii_lowercase();
+    let file_index = segments.next();
+
+    let disk = torrents.lock().unwrap().get(&info_hash).cloned();
+    let disk = match disk {
+        Some(disk) => disk,
+        None => return write_response(&mut stream, "404 Not Found", &[], b"").await,
+    };
+
+    let file_index = match file_index {
+        Some(file_index) if !file_index.is_empty() => file_index,
+        _ => {
+            let listing = file_listing(&disk, &info_hash);
+            let headers = [("Content-Type", "text/plain; charset=utf-8".to_string()), ("Content-Length", listing.len().to_string())];
+            return write_response(&mut stream, "200 OK", &headers, listing.as_bytes()).await;
+        }
+    };
+
+    let file = file_index.parse().ok().and_then(|index| Some((disk.file_path(index)?, disk.reader(index)?)));
+    let (file_path, reader) = match file {
+        Some(file) => file,
+        None => return write_response(&mut stream, "404 Not Found", &[], b"").await,
+    };
+
+    let file_length = reader.len();
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Content-Type", content_type(&file_path).to_string()),
+    ];
+
+    let (status, range) = match request.header("range") {
+        None if file_length == 0 => ("200 OK", None),
+        None => ("200 OK", Some(ByteRange { start: 0, end: file_length - 1 })),
+        Some(value) => match parse_range(value, file_length) {
+            Some(range) => {
+                headers.push(("Content-Range", format!("bytes {}-{}/{}", range.start, range.end, file_length)));
+                ("206 Partial Content", Some(range))
+            }
+            None => {
+                headers.push(("Content-Range", format!("bytes */{}", file_length)));
+                return write_response(&mut stream, "416 Range Not Satisfiable", &headers, b"").await;
+            }
+        },
+    };
+
+    let content_length = range.map_or(0, |range| range.end - range.start + 1);
+    headers.push(("Content-Length", content_length.to_string()));
+    write_response(&mut stream, status, &headers, b"").await?;
+
+    match range {
+        Some(range) if request.method == "GET" => send_range(&mut stream, reader, range).await,
+        _ => Ok(()),
+    }
+}
+
+fn file_listing(disk: &TorrentDisk, info_hash: &str) -> String {
+    let mut listing = String::new();
+    let mut index = 0;
+
+    while let Some(path) = disk.file_path(index) {
+        listing.push_str(&format!("/{}/{}\t{}\n", info_hash, index, path));
+        index += 1;
+    }
+
+    listing
+}
+
+/// Stream a range of a file, the reads happen on the blocking pool since they wait on the download.
+///
+/// A read waiting on a piece is cancelled when the client goes away or the connection is
+/// dropped, so that it doesn't hold on to a blocking thread.
+async fn send_range<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, mut reader: TorrentReader, range: ByteRange) -> io::Result<()> {
+    let _cancel = reader.cancel_guard();
+    let (mut client, mut stream) = tokio::io::split(stream);
+
+    reader.seek(SeekFrom::Start(range.start))?;
+    let mut remaining = range.end - range.start + 1;
+
+    while remaining > 0 {
+        let chunk_len = remaining.min(CHUNK_LEN as u64) as usize;
+
+        let read = tokio::task::spawn_blocking(move || {
+            let mut chunk = vec![0; chunk_len];
+            let result = reader.read_exact(&mut chunk).map(|_| chunk);
+            (reader, result)
+        });
+
+        let (returned_reader, chunk) = tokio::select! {
+            read = read => read.map_err(io::Error::other)?,
+            _ = closed(&mut client) => return Ok(()),
+        };
+
+        reader = returned_reader;
+        stream.write_all(&chunk?).await?;
+        remaining -= chunk_len as u64;
+    }
+
+    Ok(())
+}
+
+/// Wait for the client to close its side of the connection, anything else it sends is ignored.
+async fn closed<R: AsyncRead + Unpin>(client: &mut R) {
+    let mut buf = [0; 256];
+
+    while let Ok(len) = client.read(&mut buf).await {
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-499", 1000), Some(ByteRange { start: 0, end: 499 }));
+    assert_eq!(parse_range("bytes=500-", 1000), Some(ByteRange { start: 500, end: 999 }));
+    assert_eq!(parse_range("bytes=-100", 1000), Some(ByteRange { start: 900, end: 999 }));
+    assert_eq!(parse_range("bytes=-5000", 1000), Some(ByteRange { start: 0, end: 999 }));
+    assert_eq!(parse_range("bytes=900-5000", 1000), Some(ByteRange { start: 900, end: 999 }));
+
+    assert_eq!(parse_range("bytes=1000-", 1000), None);
+    assert_eq!(parse_range("bytes=5-1", 1000), None);
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+    assert_eq!(parse_range("items=0-1", 1000), None);
+    assert_eq!(parse_range("bytes=-0", 1000), None);
+}
+
+#[tokio::test]
+async fn test_stream_server_range_request() {
+    use tokio::io::AsyncReadExt;
+    use crate::disk::DiskPool;
+    use crate::events::TorrentEvents;
+    use crate::pieces::Pieces;
+    use crate::storage::MemoryStorage;
+    use crate::utils::torrents::Torrent;
+
+    let torrent = Arc::new(Torrent::from_path("test-tor.torrent").unwrap());
+    let pieces = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    for piece_index in 0..torrent.num_pieces() {
+        pieces.lock().unwrap().add_verified(piece_index);
+    }
+
+    let pool = DiskPool::new(1);
+    let disk = pool.add_torrent(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())), pieces, TorrentEvents::unobserved(&torrent));
+
+    let torrents: StreamTorrents = Arc::new(Mutex::new(HashMap::new()));
+    torrents.lock().unwrap().insert(torrent.info_hash_hex(), disk);
+
+    let request = |path: String, range: &'static str| {
+        let torrents = torrents.clone();
+
+        async move {
+            let (mut client, server) = tokio::io::duplex(CHUNK_LEN);
+            tokio::spawn(handle_connection(server, torrents));
+
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nRange: {}\r\n\r\n", path, range);
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        }
+    };
+
+    let response = request(format!("/{}/1", torrent.info_hash_hex()), "bytes=10-19").await;
+    let head_len = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&response[..head_len]);
+
+    assert!(head.starts_with("HTTP/1.1 206 Partial Content"));
+    assert!(head.contains(&format!("Content-Range: bytes 10-19/{}", torrent.layout.files()[1].length)));
+    assert_eq!(response.len() - head_len, 10);
+
+    let response = request(format!("/{}/1", torrent.info_hash_hex()), "bytes=999999999-").await;
+    assert!(response.starts_with(b"HTTP/1.1 416"));
+
+    let response = request("/0000/0".to_string(), "bytes=0-1").await;
+    assert!(response.starts_with(b"HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn test_stream_server_waits_for_pieces() {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::disk::DiskPool;
+    use crate::events::TorrentEvents;
+    use crate::message_handlers::PieceChannelPayload;
+    use crate::pieces::Pieces;
+    use crate::queue::PieceBlock;
+    use crate::storage::MemoryStorage;
+    use crate::utils::torrents::Torrent;
+
+    let folder = Path::new("test-files/test17");
+    let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+    fs::create_dir_all(folder).unwrap();
+    fs::write(folder.join("data.bin"), &data).unwrap();
+    let options = CreateOptions { piece_length: Some(16 * 1024), ..Default::default() };
+    let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&folder.join("data.bin"), &options).unwrap()).unwrap());
+    let _ = fs::remove_dir_all(folder);
+
+    let pieces = Arc::new(Mutex::new(Pieces::new(&torrent)));
+    let pool = DiskPool::new(1);
+    let disk = pool.add_torrent(torrent.clone(), Box::new(MemoryStorage::new(torrent.clone())), pieces.clone(), TorrentEvents::unobserved(&torrent));
+
+    let torrents: StreamTorrents = Arc::new(Mutex::new(HashMap::new()));
+    torrents.lock().unwrap().insert(torrent.info_hash_hex(), disk.clone());
+    let path = format!("/{}/0", torrent.info_hash_hex());
+
+    let request = |range: &'static str| {
+        let (mut client, server) = tokio::io::duplex(CHUNK_LEN);
+        tokio::spawn(handle_connection(server, torrents.clone()));
+        let request = format!("GET {} HTTP/1.1\r\nRange: {}\r\n\r\n", path, range);
+
+        async move {
+            client.write_all(request.as_bytes()).await.unwrap();
+            client
+        }
+    };
+
+    // A client that goes away while its read waits on a piece doesn't leave the read behind.
+    let readers = || Arc::strong_count(&pieces);
+    let idle = readers();
+    let client = request("bytes=0-9").await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(readers() > idle);
+
+    drop(client);
+    for _ in 0..100 {
+        if readers() == idle {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(readers(), idle);
+
+    // The response waits for the pieces to be verified, which happens after the request.
+    let mut client = request("bytes=20000-39999").await;
+    let mut response = tokio::spawn(async move {
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(50), &mut response).await.is_err());
+
+    for piece_index in 0..torrent.num_pieces() {
+        let offset = torrent.piece_offset(piece_index) as usize;
+        let block = data[offset..offset + torrent.get_piece_len(piece_index) as usize].to_vec();
+
+        pieces.lock().unwrap().add_received(PieceBlock { index: piece_index, begin: 0, length: None });
+        disk.write(PieceChannelPayload { index: piece_index, begin: 0, block }).await.unwrap();
+    }
+
+    let response = response.await.unwrap();
+    let head_len = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+    assert!(response.starts_with(b"HTTP/1.1 206"));
+    assert_eq!(&response[head_len..], &data[20_000..40_000]);
+}
@@ -1,18 +1,91 @@
+use std::fmt;
 use std::fmt::Debug;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::path::Path;
 
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
-use serde_bencode::{de, ser};
+use serde_bencode::de;
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 
-pub static BLOCK_LEN: u64 = 2_u64.pow(14) as u64;
+use crate::bencode;
+use crate::layout::FileLayout;
 
+pub static BLOCK_LEN: u64 = 2_u64.pow(14);
+
+// DHT nodes, kept from the metainfo although there's no DHT to give them to.
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 struct Node(String, i64);
 
+/// The url-list key can hold either a single url or a list of them.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for UrlList {
+    fn default() -> Self {
+        UrlList::Many(Vec::new())
+    }
+}
+
+/// Reasons a torrent file can't be loaded.
+#[derive(Debug)]
+pub enum MetainfoError {
+    Io(io::Error),
+    Bencode(String),
+    MissingInfo,
+    InvalidPieceLength,
+    InvalidPieces(usize),
+    PieceCountMismatch { expected: u64, actual: u64 },
+    SizeOverflow,
+    InvalidLayout(&'static str),
+    EmptyPath,
+    UnsafePath(String),
+    /// A file whose path goes through another file of the torrent.
+    PathConflict(String),
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetainfoError::Io(e) => write!(f, "Unable to read the torrent file: {}", e),
+            MetainfoError::Bencode(e) => write!(f, "Invalid bencode in the torrent file: {}", e),
+            MetainfoError::MissingInfo => write!(f, "The torrent file has no info dictionary"),
+            MetainfoError::InvalidPieceLength => write!(f, "The piece length must be greater than zero"),
+            MetainfoError::InvalidPieces(len) => write!(f, "The pieces length ({}) is not a multiple of 20", len),
+            MetainfoError::PieceCountMismatch { expected, actual } => {
+                write!(f, "Expected {} piece hashes for the torrent size but found {}", expected, actual)
+            }
+            MetainfoError::SizeOverflow => write!(f, "The file lengths add up to more than 2^64 bytes"),
+            MetainfoError::InvalidLayout(reason) => write!(f, "Invalid file layout: {}", reason),
+            MetainfoError::EmptyPath => write!(f, "The torrent contains an empty name or file path"),
+            MetainfoError::UnsafePath(path) => write!(f, "The torrent contains an unsafe file path: {}", path),
+            MetainfoError::PathConflict(path) => write!(f, "The torrent uses a file as a directory: {}", path),
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {}
+
+impl From<io::Error> for MetainfoError {
+    fn from(e: io::Error) -> Self {
+        MetainfoError::Io(e)
+    }
+}
+
+impl From<serde_bencode::Error> for MetainfoError {
+    fn from(e: serde_bencode::Error) -> Self {
+        MetainfoError::Bencode(e.to_string())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DlFile {
     pub(crate) path: Vec<String>,
@@ -48,39 +121,150 @@ pub struct Torrent {
     #[serde(default)]
     pub announce: Option<String>,
     #[serde(default)]
+    #[allow(dead_code)]
     nodes: Option<Vec<Node>>,
     #[serde(default)]
+    #[allow(dead_code)]
     encoding: Option<String>,
     #[serde(default)]
     httpseeds: Option<Vec<String>>,
     #[serde(default)]
+    #[serde(rename = "url-list")]
+    url_list: UrlList,
+    #[serde(default)]
     #[serde(rename = "announce-list")]
     announce_list: Option<Vec<Vec<String>>>,
     #[serde(default)]
     #[serde(rename = "creation date")]
     creation_date: Option<i64>,
+    #[serde(default)]
     #[serde(rename = "comment")]
     comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
     created_by: Option<String>,
+    #[serde(skip)]
     pub(crate) size: Option<u64>,
+    #[serde(skip)]
     pub(crate) info_hash: Option<[u8; 20]>,
+    /// The exact bytes of the bencoded info dictionary, as they appear in the metainfo file.
+    #[serde(skip)]
+    pub(crate) info_bytes: Vec<u8>,
+    /// Where each file of the torrent is stored, for both single and multi-file torrents.
+    #[serde(skip)]
+    pub(crate) layout: FileLayout,
+}
+
+/// The metainfo of a torrent in a form that's easy to print or serialize.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentSummary {
+    pub name: String,
+    pub info_hash: String,
+    pub size: u64,
+    pub piece_length: u64,
+    pub pieces: u64,
+    pub private: bool,
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Seconds since the unix epoch.
+    pub creation_date: Option<i64>,
+    pub files: Vec<FileSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSummary {
+    /// Path relative to the download folder, including the torrent name.
+    pub path: String,
+    pub length: u64,
 }
 
 impl Torrent {
-    /// Take a torrent file path and convert it into a Torrent struct.
-    pub fn new(file_path: &str) -> Torrent {
-        let mut handle = File::open(file_path).expect("Could not load the file");
+    /// Load a torrent file from disk and convert it into a Torrent struct.
+    pub fn from_path<P: AsRef<Path>>(file_path: P) -> Result<Torrent, MetainfoError> {
+        let mut handle = File::open(file_path)?;
         let mut buffer = Vec::new();
 
-        handle.read_to_end(&mut buffer).expect("Couldn't read all of the torrent file");
+        handle.read_to_end(&mut buffer)?;
+
+        Torrent::from_bytes(&buffer)
+    }
+
+    /// Convert the contents of a torrent file into a Torrent struct.
+    ///
+    /// The metainfo is validated so that a malformed torrent is rejected here
+    /// instead of causing a panic once the download has started.
+    pub fn from_bytes(buffer: &[u8]) -> Result<Torrent, MetainfoError> {
+        // Check the nesting depth before serde recurses into the data.
+        bencode::skip_value(buffer, 0).map_err(|e| MetainfoError::Bencode(e.to_string()))?;
+
+        let mut torrent = de::from_bytes::<Torrent>(buffer)?;
+        let (info_start, info_end) = bencode::find_dict_value(buffer, b"info")
+            .map_err(|e| MetainfoError::Bencode(e.to_string()))?
+            .ok_or(MetainfoError::MissingInfo)?;
+
+        validate_info(&torrent.info)?;
+
+        torrent.info_bytes = buffer[info_start..info_end].to_vec();
+        torrent.size = calculate_torrent_size(&torrent.info);
+        torrent.layout = FileLayout::from_info(&torrent.info)?;
+        torrent.info_hash = Some(hash_torrent_info(&torrent.info_bytes));
 
-        let mut torrent = de::from_bytes::<Torrent>(&buffer).expect("Couldn't load the torrent into the torrent struct");
-        torrent.size = Some(calculate_torrent_size(&torrent.info));
-        torrent.info_hash = Some(hash_torrent_info(&torrent.info));
+        Ok(torrent)
+    }
+
+
+    /// The name of the torrent, the file or top directory it downloads to.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
 
-        return torrent;
+    /// The info hash as a lowercase hex string.
+    pub fn info_hash_hex(&self) -> String {
+        self.info_hash.unwrap_or_default().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The BEP 19 web seeds, urls of servers that host the files of the torrent.
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            UrlList::One(url) => vec![url.clone()],
+            UrlList::Many(urls) => urls.clone(),
+        };
+
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
+    }
+
+    /// The BEP 17 http seeds, scripts that serve pieces of the torrent.
+    pub fn http_seeds(&self) -> Vec<String> {
+        self.httpseeds.iter().flatten().filter(|url| !url.is_empty()).cloned().collect()
+    }
+
+    /// Get the path components of a file, as they appear in the metainfo file.
+    pub fn file_path_components(&self, file_index: usize) -> Option<Vec<String>> {
+        match &self.info.files {
+            Some(files) => files.get(file_index).map(|file| file.path.clone()),
+            None if file_index == 0 => Some(Vec::new()),
+            None => None,
+        }
+    }
+
+    /// The number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u64 {
+        (self.info.pieces.len() / 20) as u64
+    }
+
+    /// Get the 20 byte SHA1 hash of a piece.
+    pub fn piece_hash(&self, piece_index: u64) -> Option<&[u8]> {
+        let start = piece_index as usize * 20;
+        self.info.pieces.get(start..start + 20)
+    }
+
+    /// The offset of the first byte of a piece within the torrent.
+    pub fn piece_offset(&self, piece_index: u64) -> u64 {
+        piece_index * self.info.piece_length
     }
 
 
@@ -94,7 +278,7 @@ impl Torrent {
         let last_piece_length = total_length % piece_length;
         let last_piece_index = total_length / piece_length;
 
-        return if last_piece_index == piece_index { last_piece_length } else { piece_length };
+        if last_piece_index == piece_index { last_piece_length } else { piece_length }
     }
 
 
@@ -102,16 +286,8 @@ impl Torrent {
     pub fn get_blocks_per_piece(&self, piece_index: u64) -> u64 {
         let piece_len = self.get_piece_len(piece_index);
 
-        let blocks_per_piece: u64;
-
         // Round up if it's the last piece
-        if piece_len % BLOCK_LEN > 0 {
-            blocks_per_piece = piece_len / BLOCK_LEN + 1;
-        } else {
-            blocks_per_piece = piece_len / BLOCK_LEN;
-        }
-
-        return blocks_per_piece;
+        piece_len.div_ceil(BLOCK_LEN)
     }
 
 
@@ -124,51 +300,41 @@ impl Torrent {
         let last_piece_len = piece_len % BLOCK_LEN;
         let last_piece_index = piece_len / BLOCK_LEN;
 
-        return if block_index == last_piece_index { last_piece_len } else { BLOCK_LEN };
+        if block_index == last_piece_index { last_piece_len } else { BLOCK_LEN }
     }
 
-    pub fn print(&self) {
-        println!("name:\t\t{}", self.info.name);
-        println!("announce:\t{:?}", self.announce);
-        println!("nodes:\t\t{:?}", self.nodes);
-        if let &Some(ref al) = &self.announce_list {
-            for a in al {
-                println!("announce list:\t{}", a[0]);
-            }
-        }
-        println!("httpseeds:\t{:?}", self.httpseeds);
-        println!("creation date:\t{:?}", self.creation_date);
-        println!("comment:\t{:?}", self.comment);
-        println!("created by:\t{:?}", self.created_by);
-        println!("encoding:\t{:?}", self.encoding);
-        println!("piece length:\t{:?}", self.info.piece_length);
-        println!("private:\t{:?}", self.info.private);
-        println!("root hash:\t{:?}", self.info.root_hash);
-        println!("md5sum:\t\t{:?}", self.info.md5sum);
-        println!("path:\t\t{:?}", self.info.path);
-        if let &Some(ref files) = &self.info.files {
-            for f in files {
-                println!("file path:\t{:?}", f.path);
-                println!("file length:\t{}", f.length);
-                println!("file md5sum:\t{:?}", f.md5sum);
-            }
+    /// Everything worth showing about the torrent, e.g. for the info command.
+    pub fn summary(&self) -> TorrentSummary {
+        let files = self
+            .layout
+            .files()
+            .iter()
+            .map(|file| FileSummary { path: file.path.to_string_lossy().into_owned(), length: file.length })
+            .collect();
+
+        TorrentSummary {
+            name: self.info.name.clone(),
+            info_hash: self.info_hash_hex(),
+            size: self.size.unwrap_or_default(),
+            piece_length: self.info.piece_length,
+            pieces: self.num_pieces(),
+            private: self.info.private == Some(1),
+            announce: self.announce.clone(),
+            announce_list: self.announce_list.clone().unwrap_or_default(),
+            web_seeds: self.web_seeds(),
+            http_seeds: self.http_seeds(),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            creation_date: self.creation_date,
+            files,
         }
-        println!("size:\t\t{:?}", self.size);
-    }
-
-    pub fn print_info(&self) {
-        println!("name:\t{:?}", self.info.name);
-        println!("piece_length:\t{:?}", self.info.piece_length);
-        println!("md5sum:\t{:?}", self.info.md5sum);
-        println!("length:\t{:?}", self.info.length);
-        println!("root_hash:\t{:?}", self.info.root_hash);
     }
 }
 
 
 #[test]
 fn test_get_piece_len() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
 
     // Test length of last piece
     let piece_len = torrent.get_piece_len(14);
@@ -182,7 +348,7 @@ fn test_get_piece_len() {
 
 #[test]
 fn test_get_block_len() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
 
     // Test length of all the other pieces
     let piece_len = torrent.get_block_len(14, 0);
@@ -198,12 +364,12 @@ fn test_get_block_len() {
 fn test_blocks_per_piece() {
 
     // Test that the last piece has two blocks and isn't missing a block.
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
     let piece_len = torrent.get_blocks_per_piece(14);
     assert_eq!(piece_len, 2);
 
 
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
     let piece_len = torrent.get_blocks_per_piece(13);
     assert_eq!(piece_len, 2);
 }
@@ -213,51 +379,233 @@ fn test_blocks_per_piece() {
 ///
 /// If many files add up the length of each of each file
 /// otherwise, take the length of a single file.
-pub fn calculate_torrent_size(torrent_info: &Info) -> u64 {
-    let mut size: u64 = 0;
-
-    if let &Some(ref files) = &torrent_info.files {
-        for f in files {
-            size += f.length;
-        }
+/// Returns None when the lengths add up to more than a u64 holds.
+pub fn calculate_torrent_size(torrent_info: &Info) -> Option<u64> {
+    if let Some(files) = &torrent_info.files {
+        files.iter().try_fold(0u64, |size, f| size.checked_add(f.length))
     } else {
-        size += &torrent_info.length.unwrap_or_else(|| 0);
+        Some(torrent_info.length.unwrap_or(0))
     }
-    return size;
 }
 
 #[test]
 fn test_calculate_torrent_size() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
     let torrent_size = calculate_torrent_size(&torrent.info);
-    assert_eq!(torrent_size, 479502);
+    assert_eq!(torrent_size, Some(479502));
+
+    let file = DlFile { path: vec!["a".to_string()], length: u64::MAX, md5sum: None };
+    let info = Info { files: Some(vec![file.clone(), file]), ..Default::default() };
+    assert_eq!(calculate_torrent_size(&info), None);
+}
+
+
+/// Check that the info dictionary describes a torrent we can actually download.
+///
+/// - The piece hashes must be 20 bytes each and there must be one for every piece.
+/// - Exactly one of `length` (single file) or `files` (multiple files) must be set.
+/// - The name and every file path must be non-empty.
+pub fn validate_info(info: &Info) -> Result<(), MetainfoError> {
+    if info.piece_length == 0 {
+        return Err(MetainfoError::InvalidPieceLength);
+    }
+
+    if !info.pieces.len().is_multiple_of(20) {
+        return Err(MetainfoError::InvalidPieces(info.pieces.len()));
+    }
+
+    match (&info.length, &info.files) {
+        (Some(_), Some(_)) => return Err(MetainfoError::InvalidLayout("both length and files are set")),
+        (None, None) => return Err(MetainfoError::InvalidLayout("neither length nor files are set")),
+        (None, Some(files)) if files.is_empty() => return Err(MetainfoError::InvalidLayout("the files list is empty")),
+        _ => {}
+    }
+
+    if info.name.is_empty() {
+        return Err(MetainfoError::EmptyPath);
+    }
+
+    if let Some(files) = &info.files {
+        for file in files {
+            if file.path.is_empty() || file.path.iter().any(|component| component.is_empty()) {
+                return Err(MetainfoError::EmptyPath);
+            }
+        }
+    }
+
+    let size = calculate_torrent_size(info).ok_or(MetainfoError::SizeOverflow)?;
+    let expected = size.div_ceil(info.piece_length);
+    let actual = (info.pieces.len() / 20) as u64;
+
+    if expected != actual {
+        return Err(MetainfoError::PieceCountMismatch { expected, actual });
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+fn build_test_torrent(info: &str) -> Vec<u8> {
+    format!("d8:announce14:udp://a.b:13374:info{}e", info).into_bytes()
+}
+
+#[test]
+fn test_from_bytes_single_file() {
+    let torrent = Torrent::from_bytes(&build_test_torrent(
+        "d6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+    )).unwrap();
+
+    assert_eq!(torrent.size, Some(10));
+    assert_eq!(torrent.layout, FileLayout::single_file("spam", 10).unwrap());
+    assert_eq!(torrent.comment, None);
+    assert!(torrent.info_hash.is_some());
+}
+
+#[test]
+fn test_web_seeds() {
+    let info = "d6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    let torrent = Torrent::from_bytes(format!("d8:url-list13:http://a.b/f/4:info{}e", info).as_bytes()).unwrap();
+    assert_eq!(torrent.web_seeds(), vec!["http://a.b/f/"]);
+
+    let torrent = Torrent::from_bytes(format!("d8:url-listl10:http://a/b0:10:http://c/de4:info{}e", info).as_bytes()).unwrap();
+    assert_eq!(torrent.web_seeds(), vec!["http://a/b", "http://c/d"]);
+
+    let torrent = Torrent::from_bytes(&build_test_torrent(info)).unwrap();
+    assert!(torrent.web_seeds().is_empty());
+}
+
+#[test]
+fn test_summary() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let summary = torrent.summary();
+
+    assert_eq!(summary.info_hash, "06cb061240b24f730fbef7ead1b348d8865244af");
+    assert_eq!(summary.size, 479502);
+    assert_eq!(summary.pieces, 15);
+    assert_eq!(summary.files.len(), 2);
+    assert_eq!(summary.files.iter().map(|file| file.length).sum::<u64>(), summary.size);
+}
+
+#[test]
+fn test_from_bytes_validation() {
+    type Check = fn(&MetainfoError) -> bool;
+
+    let cases: Vec<(&str, Check)> = vec![
+        // Not bencode at all
+        ("x", |e| matches!(e, MetainfoError::Bencode(_))),
+        // Pieces isn't a multiple of 20
+        (
+            "d6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces19:aaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::InvalidPieces(19)),
+        ),
+        // Two pieces needed but only one hash given
+        (
+            "d6:lengthi20e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::PieceCountMismatch { expected: 2, actual: 1 }),
+        ),
+        // Zero piece length
+        (
+            "d6:lengthi10e4:name4:spam12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::InvalidPieceLength),
+        ),
+        // Neither length nor files
+        (
+            "d4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::InvalidLayout("neither length nor files are set")),
+        ),
+        // Both length and files
+        (
+            "d5:filesld6:lengthi10e4:pathl1:aeee6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::InvalidLayout("both length and files are set")),
+        ),
+        // Empty file path
+        (
+            "d5:filesld6:lengthi10e4:pathleee4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::EmptyPath),
+        ),
+        // Empty name
+        (
+            "d6:lengthi10e4:name0:12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::EmptyPath),
+        ),
+        // Path escaping the download folder
+        (
+            "d5:filesld6:lengthi10e4:pathl2:..4:evileee4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::UnsafePath(path) if path == ".."),
+        ),
+        // File lengths that overflow the size of the torrent
+        (
+            "d5:filesld6:lengthi9223372036854775807e4:pathl1:aeed6:lengthi9223372036854775807e4:pathl1:beed6:lengthi9223372036854775807e4:pathl1:ceee\
+             4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+            |e| matches!(e, MetainfoError::SizeOverflow),
+        ),
+    ];
+
+    for (info, check) in cases {
+        match Torrent::from_bytes(&build_test_torrent(info)) {
+            Err(e) => assert!(check(&e), "{} was rejected for the wrong reason: {:?}", info, e),
+            Ok(_) => panic!("{} should be rejected", info),
+        }
+    }
+
+    // Nested too deep to decode safely
+    let info = format!("d6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa1:x{}{}e", "l".repeat(100), "e".repeat(100));
+    assert!(matches!(Torrent::from_bytes(&build_test_torrent(&info)), Err(MetainfoError::Bencode(reason)) if reason.contains("nested")));
+
+    assert!(matches!(Torrent::from_bytes(b"d8:announce1:ae"), Err(MetainfoError::Bencode(_))));
+    assert!(matches!(Torrent::from_path("missing.torrent"), Err(MetainfoError::Io(_))));
 }
 
 
 /// Create a hash of the torrent info.
 ///
-///     This is used to create the announce that is sent to the tracker
-///     and to the peers.
-pub fn hash_torrent_info(torrent_info: &Info) -> [u8; 20] {
+/// This is used to create the announce that is sent to the tracker
+/// and to the peers.
+///
+/// The hash has to be taken over the raw bencoded info dictionary from the metainfo file.
+/// Re-serializing the Info struct would drop any keys we don't know about.
+pub fn hash_torrent_info(info_bytes: &[u8]) -> [u8; 20] {
     let _hashed_info: &mut [u8] = &mut [0; 20];
 
     let mut hasher = Sha1::new();
-    let bencoded_info = ser::to_bytes(torrent_info).unwrap();
 
-    hasher.input(&bencoded_info);
+    hasher.input(info_bytes);
     hasher.result(_hashed_info);
 
     let mut hashed_info: [u8; 20] = [0; 20];
     hashed_info.clone_from_slice(_hashed_info);
-    return hashed_info;
+    hashed_info
 }
 
 
 #[test]
 fn test_hash_torrent_info() {
-    let torrent = Torrent::new("test-tor.torrent");
-    let hashed_info = hash_torrent_info(&torrent.info);
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let hashed_info = hash_torrent_info(&torrent.info_bytes);
 
     let expected: [u8; 20] = [0x06, 0xcb, 0x06, 0x12, 0x40, 0xb2, 0x4f, 0x73, 0x0f, 0xbe, 0xf7, 0xea, 0xd1, 0xb3, 0x48, 0xd8, 0x86, 0x52, 0x44, 0xaf];
     assert_eq!(hashed_info, expected);
+    assert_eq!(torrent.info_hash.unwrap(), expected);
+}
+
+
+#[test]
+fn test_hash_torrent_info_unknown_keys() {
+    // big-buck-bunny has a url-list after the info dict which must not end up in the hash.
+    let torrent = Torrent::from_path("big-buck-bunny.torrent").unwrap();
+
+    let expected: [u8; 20] = [0xdd, 0x82, 0x55, 0xec, 0xdc, 0x7c, 0xa5, 0x5f, 0xb0, 0xbb, 0xf8, 0x13, 0x23, 0xd8, 0x70, 0x62, 0xdb, 0x1f, 0x6d, 0x1c];
+    assert_eq!(torrent.info_hash.unwrap(), expected);
+
+    // An info dict with a key the Info struct doesn't know, which a re-serialized Info would drop.
+    let torrent = Torrent::from_bytes(&build_test_torrent(
+        "d6:lengthi10e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa8:x-customd3:keyli1ei2eeee",
+    )).unwrap();
+
+    // The SHA-1 of the info dict above.
+    let expected: [u8; 20] = [0x56, 0x0e, 0xc2, 0x96, 0x2d, 0x77, 0x89, 0x07, 0x57, 0xd3, 0xca, 0xe7, 0x0f, 0x2b, 0xd8, 0x07, 0x22, 0x9c, 0x67, 0x53];
+    assert_eq!(torrent.info_hash.unwrap(), expected);
+    assert_ne!(hash_torrent_info(&serde_bencode::to_bytes(&torrent.info).unwrap()), expected);
 }
@@ -4,74 +4,143 @@ use std::time::Duration;
 use bytebuffer::ByteBuffer;
 use url::Url;
 
-use crate::{messages, PORT, utils};
-use crate::utils::torrents;
+use crate::error::{Error, Result};
+use crate::{messages, utils};
 use crate::utils::torrents::Torrent;
 
-pub fn get_torrent_peers(
-    torrent: &torrents::Torrent,
+/// How far along the download of a torrent is, in bytes, as told to the tracker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceStats {
+    pub downloaded: u64,
+    /// The bytes of the wanted pieces we don't have yet.
+    pub left: u64,
+    pub uploaded: u64,
+}
+
+/// Why we announce, the tracker is told when a download starts, completes and stops. The
+/// announces in between, every interval the tracker asks for, have no event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+/// Announce ourselves to the tracker of a torrent, peers can reach us on `port`.
+///
+/// The tracker answers with the peers it knows of and how long to wait before announcing again.
+pub fn announce(
+    torrent: &Torrent,
     peer_id: &ByteBuffer,
-) -> anyhow::Result<Vec<utils::Peer>> {
-    let tracker_url = Url::parse(&torrent.announce.as_ref().unwrap()).unwrap();
-    let base_tracker_url = format!(
-        "{}:{}",
-        tracker_url.host_str().unwrap(),
-        tracker_url.port().unwrap()
-    );
-
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", PORT)).unwrap();
-    socket.set_read_timeout(Some(Duration::new(5, 0)))?;
-
-    let conn_resp = connect_tracker(&socket, base_tracker_url);
-
-    let announce_resp = announce_tracker(&socket, &torrent, peer_id, conn_resp)
-        .expect("Not able to get peers from tracker");
-
-    if announce_resp.seeders == 0 {
-        anyhow::bail!("No peers at the moment");
-    } else {
-        return Ok(announce_resp.peers);
-    }
+    port: u16,
+    stats: AnnounceStats,
+    event: AnnounceEvent,
+    timeout: Duration,
+) -> Result<utils::AnnounceResp> {
+    let tracker = torrent_tracker(torrent)?;
+
+    announce_info_hash(tracker, &torrent.info_hash.unwrap_or_default(), peer_id, port, stats, event, timeout)
 }
 
-fn connect_tracker(socket: &UdpSocket, tracker_url: String) -> utils::ConnResp {
-    let conn_req = messages::build_conn_req();
+/// Announce an info hash to a tracker, for when there is no metainfo yet, as with magnet links.
+pub fn announce_info_hash(
+    tracker: &str,
+    info_hash: &[u8; 20],
+    peer_id: &ByteBuffer,
+    port: u16,
+    stats: AnnounceStats,
+    event: AnnounceEvent,
+    timeout: Duration,
+) -> Result<utils::AnnounceResp> {
+    let socket = tracker_socket(tracker, timeout)?;
 
-    socket
-        .connect(tracker_url)
-        .expect("couldn't connect to address");
+    let conn_resp = connect_tracker(&socket)?;
+
+    announce_tracker(&socket, info_hash, peer_id, port, stats, event, conn_resp)
+}
+
+/// Ask the tracker how many seeders and leechers a torrent has, without announcing ourselves.
+///
+/// Only UDP trackers are supported, like for announcing.
+pub fn scrape_tracker(torrent: &Torrent, timeout: Duration) -> Result<utils::ScrapeResp> {
+    let socket = tracker_socket(torrent_tracker(torrent)?, timeout)?;
+
+    let conn_resp = connect_tracker(&socket)?;
+    let scrape_req = messages::build_scrape_req(conn_resp.connection_id, &torrent.info_hash.unwrap_or_default());
+
+    socket.send(&scrape_req.to_bytes()).map_err(|e| tracker_error("Couldn't send the scrape request", e))?;
+
+    let mut recv_buf = [0; 1000];
+    let received = socket.recv(&mut recv_buf).map_err(|e| tracker_error("Couldn't receive the scrape response", e))?;
+
+    utils::parse_scrape_resp(&recv_buf, received)
+}
+
+fn torrent_tracker(torrent: &Torrent) -> Result<&str> {
+    torrent.announce.as_deref().ok_or_else(|| Error::Tracker("The torrent has no tracker".to_string()))
+}
+
+/// A socket connected to a UDP tracker.
+fn tracker_socket(announce: &str, timeout: Duration) -> Result<UdpSocket> {
+    let tracker_url = Url::parse(announce).map_err(|e| Error::Tracker(format!("Invalid tracker url {}: {}", announce, e)))?;
+
+    if tracker_url.scheme() != "udp" {
+        return Err(Error::Tracker(format!("Only UDP trackers are supported: {}", announce)));
+    }
+
+    let base_tracker_url = match (tracker_url.host_str(), tracker_url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        _ => return Err(Error::Tracker(format!("The tracker url has no host or port: {}", announce))),
+    };
+
+    // Any local port will do, so that several torrents can announce at the same time.
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| tracker_error("Couldn't open a UDP socket", e))?;
+    socket.set_read_timeout(Some(timeout)).map_err(|e| tracker_error("Couldn't set the tracker timeout", e))?;
 
     socket
-        .send(&conn_req.to_bytes())
-        .expect("couldn't send message");
+        .connect(&base_tracker_url)
+        .map_err(|e| tracker_error(&format!("Couldn't connect to the tracker {}", base_tracker_url), e))?;
+
+    Ok(socket)
+}
+
+fn connect_tracker(socket: &UdpSocket) -> Result<utils::ConnResp> {
+    let conn_req = messages::build_conn_req();
+
+    socket.send(&conn_req.to_bytes()).map_err(|e| tracker_error("Couldn't send the connect request", e))?;
 
     let mut recv_buf = [0; 16];
 
-    socket.recv(&mut recv_buf).expect("Couldn't receive tracker message");
+    let received = socket.recv(&mut recv_buf).map_err(|e| tracker_error("Couldn't receive the connect response", e))?;
 
-    return utils::parse_conn_resp(&recv_buf);
+    utils::parse_conn_resp(&recv_buf, received)
 }
 
 fn announce_tracker(
     socket: &UdpSocket,
-    torrent: &Torrent,
+    info_hash: &[u8; 20],
     peer_id: &ByteBuffer,
+    port: u16,
+    stats: AnnounceStats,
+    event: AnnounceEvent,
     conn_resp: utils::ConnResp,
-) -> anyhow::Result<utils::AnnounceResp> {
+) -> Result<utils::AnnounceResp> {
     let announce_req =
-        messages::build_announce_req(torrent, conn_resp.connection_id, &peer_id, PORT);
+        messages::build_announce_req(info_hash, conn_resp.connection_id, peer_id, port, &stats, event);
 
     socket
         .send(&announce_req.to_bytes())
-        .expect("Couldn't send annouce req");
+        .map_err(|e| tracker_error("Couldn't send the announce request", e))?;
 
     let mut recv_buf = [0; 1000];
     let recieved = socket
         .recv(&mut recv_buf)
-        .expect("Couldn't recieve announce response");
+        .map_err(|e| tracker_error("Couldn't receive the announce response", e))?;
 
-    let announce_resp =
-        utils::parse_announce_resp(&recv_buf, recieved).expect("Couldn't parse the announce resp");
+    utils::parse_announce_resp(&recv_buf, recieved)
+}
 
-    Ok(announce_resp)
+fn tracker_error(context: &str, e: std::io::Error) -> Error {
+    Error::Tracker(format!("{}: {}", context, e))
 }
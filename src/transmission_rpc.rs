@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::error::Error;
+use crate::http_client;
+use crate::magnet::MagnetLink;
+use crate::session::{info_hash_from_hex, Session, TorrentHandle, TorrentState, TorrentStatus};
+use crate::utils::decode_base64;
+use crate::utils::torrents::Torrent;
+
+/// The header clients have to send back, so that other web pages can't post to the RPC
+/// from the browser of the user (CSRF).
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+/// The RPC version of Transmission 2.80 to 3.00, which the requests below follow.
+const RPC_VERSION: u64 = 15;
+
+/// Transmission counts speeds in kB/s.
+const SPEED_BYTES: u64 = 1000;
+
+/// Enough of the Transmission RPC for its web UIs, apps and scripts to drive a session.
+///
+/// ```text
+/// torrent-add                   from "metainfo" in base64 or a "filename", a path on the
+///                               server, a plain http url or a magnet link, with
+///                               "download-dir" and "paused"
+/// torrent-get                   the "fields" of the "ids", see torrent_fields
+/// torrent-start, torrent-stop   torrent-start-now too, the queue still applies
+/// torrent-remove                with "delete-local-data"
+/// session-get, session-set      the download folder, peer port, limits and queue size
+/// ```
+///
+/// Torrents get numeric ids like in Transmission, counted up from 1 as the RPC first sees
+/// them. Requests without "ids" are about every torrent, "recently-active" is too.
+pub(crate) struct TransmissionRpc {
+    session_id: String,
+    ids: Mutex<TorrentIds>,
+}
+
+#[derive(Default)]
+struct TorrentIds {
+    last: u64,
+    by_info_hash: HashMap<String, u64>,
+}
+
+impl TorrentIds {
+    fn get(&mut self, info_hash: &str) -> u64 {
+        let last = &mut self.last;
+
+        *self.by_info_hash.entry(info_hash.to_string()).or_insert_with(|| {
+            *last += 1;
+            *last
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+type RpcResult = std::result::Result<Value, String>;
+
+impl TransmissionRpc {
+    pub fn new() -> TransmissionRpc {
+        let session_id = rand::thread_rng().sample_iter(&Alphanumeric).take(48).collect();
+
+        TransmissionRpc { session_id, ids: Mutex::new(TorrentIds::default()) }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Answer a request, errors are reported in the result field like Transmission does.
+    pub async fn handle(&self, session: &Session, body: &[u8]) -> Value {
+        let request: RpcRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return json!({ "result": format!("Invalid request: {}", e), "arguments": {} }),
+        };
+        let arguments = &request.arguments;
+
+        let result = match request.method.as_str() {
+            "torrent-add" => self.torrent_add(session, arguments).await,
+            "torrent-get" => Ok(self.torrent_get(session, arguments)),
+            "torrent-start" | "torrent-start-now" => self.start_stop(session, arguments, true).await,
+            "torrent-stop" => self.start_stop(session, arguments, false).await,
+            "torrent-remove" => self.torrent_remove(session, arguments).await,
+            "session-get" => Ok(session_get(session, arguments)),
+            "session-set" => session_set(session, arguments),
+            method => Err(format!("Method name not recognized: {}", method)),
+        };
+
+        let (result, arguments) = match result {
+            Ok(arguments) => ("success".to_string(), arguments),
+            Err(e) => (e, json!({})),
+        };
+
+        let mut response = json!({ "result": result, "arguments": arguments });
+        if let Some(tag) = request.tag {
+            response["tag"] = tag;
+        }
+
+        response
+    }
+
+    /// The torrents a request is about with their ids, in the order of the ids.
+    fn select(&self, session: &Session, arguments: &Value) -> Vec<(u64, TorrentStatus)> {
+        let mut ids = self.ids.lock().unwrap();
+        let mut torrents: Vec<(u64, TorrentStatus)> = session.torrents().into_iter().map(|status| (ids.get(&status.info_hash), status)).collect();
+        torrents.sort_by_key(|(id, _)| *id);
+
+        let matches = |wanted: &Value, id: u64, info_hash: &str| match wanted {
+            Value::Number(number) => number.as_u64() == Some(id),
+            Value::String(hash) => hash.eq_ignore_ascii_case(info_hash),
+            _ => false,
+        };
+
+        match arguments.get("ids") {
+            None => torrents,
+            Some(Value::String(ids)) if ids == "recently-active" => torrents,
+            Some(Value::Array(wanted)) => torrents.into_iter().filter(|(id, status)| wanted.iter().any(|wanted| matches(wanted, *id, &status.info_hash))).collect(),
+            Some(wanted) => torrents.into_iter().filter(|(id, status)| matches(wanted, *id, &status.info_hash)).collect(),
+        }
+    }
+
+    /// The handles of the selected torrents, with their info hashes.
+    fn handles(&self, session: &Session, arguments: &Value) -> Vec<(String, TorrentHandle)> {
+        self.select(session, arguments)
+            .into_iter()
+            .filter_map(|(_, status)| {
+                let handle = info_hash_from_hex(&status.info_hash).and_then(|info_hash| session.torrent(&info_hash))?;
+                Some((status.info_hash, handle))
+            })
+            .collect()
+    }
+
+    async fn torrent_add(&self, session: &Session, arguments: &Value) -> RpcResult {
+        let string = |key: &str| arguments.get(key).and_then(Value::as_str);
+
+        let torrent = match (string("metainfo"), string("filename")) {
+            (Some(metainfo), _) => metainfo_torrent(&decode_base64(metainfo).ok_or("The metainfo isn't valid base64")?)?,
+            (None, Some(filename)) if filename.starts_with("magnet:") => {
+                let magnet = MagnetLink::parse(filename).map_err(|e| e.to_string())?;
+
+                session.fetch_metadata(&magnet, &[]).await.map_err(|e| e.to_string())?
+            }
+            (None, Some(filename)) if filename.starts_with("http://") || filename.starts_with("https://") => {
+                let response = http_client::get(filename, None).await.map_err(|e| format!("Unable to fetch {}: {}", filename, e))?;
+                if response.status != 200 {
+                    return Err(format!("Unable to fetch {}: HTTP {}", filename, response.status));
+                }
+
+                metainfo_torrent(&response.body)?
+            }
+            (None, Some(filename)) => metainfo_torrent(&fs::read(filename).map_err(|e| format!("Unable to read {}: {}", filename, e))?)?,
+            (None, None) => return Err("Either metainfo or filename is needed".to_string()),
+        };
+        let info_hash = torrent.info_hash;
+
+        let mut options = session.settings().download_options();
+        if let Some(download_dir) = string("download-dir") {
+            options.download_folder = PathBuf::from(download_dir);
+        }
+
+        let (key, handle) = match session.add_torrent(torrent, options) {
+            Ok(handle) => ("torrent-added", handle),
+            Err(Error::DuplicateTorrent(_)) => ("torrent-duplicate", info_hash.and_then(|info_hash| session.torrent(&info_hash)).ok_or("The torrent was just removed")?),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        if key == "torrent-added" && arguments.get("paused").and_then(Value::as_bool) == Some(true) {
+            handle.pause().await.map_err(|e| e.to_string())?;
+        }
+
+        let status = handle.status().map_err(|e| e.to_string())?;
+        let id = self.ids.lock().unwrap().get(&status.info_hash);
+
+        Ok(json!({ key: { "id": id, "name": status.name, "hashString": status.info_hash } }))
+    }
+
+    fn torrent_get(&self, session: &Session, arguments: &Value) -> Value {
+        let torrents: Vec<Value> = self
+            .select(session, arguments)
+            .iter()
+            .map(|(id, status)| only_fields(torrent_fields(*id, status), arguments))
+            .collect();
+
+        json!({ "torrents": torrents })
+    }
+
+    async fn start_stop(&self, session: &Session, arguments: &Value, start: bool) -> RpcResult {
+        for (_, handle) in self.handles(session, arguments) {
+            if start {
+                handle.resume()
+            } else {
+                handle.pause().await
+            }
+            .map_err(|e| e.to_string())?;
+        }
+
+        Ok(json!({}))
+    }
+
+    async fn torrent_remove(&self, session: &Session, arguments: &Value) -> RpcResult {
+        let delete_data = arguments.get("delete-local-data").and_then(Value::as_bool).unwrap_or(false);
+
+        for (info_hash, handle) in self.handles(session, arguments) {
+            handle.remove(delete_data).await.map_err(|e| e.to_string())?;
+            self.ids.lock().unwrap().by_info_hash.remove(&info_hash);
+        }
+
+        Ok(json!({}))
+    }
+}
+
+fn metainfo_torrent(metainfo: &[u8]) -> std::result::Result<Torrent, String> {
+    Torrent::from_bytes(metainfo).map_err(|e| e.to_string())
+}
+
+/// The fields of a torrent we know of, with their Transmission names.
+fn torrent_fields(id: u64, status: &TorrentStatus) -> Map<String, Value> {
+    let eta = match (status.left, status.download_rate) {
+        (0, _) => 0,
+        (_, 0) => -1,
+        (left, rate) => (left / rate) as i64,
+    };
+
+    let fields = json!({
+        "id": id,
+        "hashString": status.info_hash,
+        "name": status.name,
+        "status": transmission_status(status.state, status.left),
+        "percentDone": status.progress as f64 / 100.0,
+        "totalSize": status.size,
+        "leftUntilDone": status.left,
+        "downloadDir": status.download_folder.display().to_string(),
+        "queuePosition": status.queue_position,
+        "downloadedEver": status.downloaded,
+        "uploadedEver": status.uploaded,
+        "rateDownload": status.download_rate,
+        "rateUpload": status.upload_rate,
+        "eta": eta,
+        "isFinished": status.state == TorrentState::Finished,
+        "error": 0,
+        "errorString": "",
+    });
+
+    match fields {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    }
+}
+
+/// Transmission's torrent status: 0 stopped, 3 queued to download, 4 downloading, 5 queued to
+/// seed and 6 seeding. Finished torrents are done seeding, so they're stopped.
+fn transmission_status(state: TorrentState, left: u64) -> u8 {
+    match state {
+        TorrentState::Paused | TorrentState::Finished => 0,
+        TorrentState::Queued if left == 0 => 5,
+        TorrentState::Queued => 3,
+        TorrentState::Downloading => 4,
+        TorrentState::Seeding => 6,
+    }
+}
+
+/// Keep the fields asked for in the "fields" argument, all of them without it.
+fn only_fields(mut fields: Map<String, Value>, arguments: &Value) -> Value {
+    if let Some(wanted) = arguments.get("fields").and_then(Value::as_array) {
+        fields.retain(|name, _| wanted.iter().any(|wanted| wanted.as_str() == Some(name.as_str())));
+    }
+
+    Value::Object(fields)
+}
+
+fn session_get(session: &Session, arguments: &Value) -> Value {
+    let settings = session.settings();
+    let speed = |limit: Option<u64>| limit.map_or(0, |limit| limit / SPEED_BYTES);
+
+    let fields = json!({
+        "version": format!("torrenter {}", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": RPC_VERSION,
+        "download-dir": settings.download_dir.display().to_string(),
+        "peer-port": settings.listen_port,
+        "speed-limit-down": speed(settings.download_limit),
+        "speed-limit-down-enabled": settings.download_limit.is_some(),
+        "speed-limit-up": speed(settings.upload_limit),
+        "speed-limit-up-enabled": settings.upload_limit.is_some(),
+        "alt-speed-down": speed(settings.alt_download_limit),
+        "alt-speed-up": speed(settings.alt_upload_limit),
+        "alt-speed-enabled": session.alt_speed(),
+        "download-queue-size": settings.max_active_downloads.unwrap_or(0),
+        "download-queue-enabled": settings.max_active_downloads.is_some(),
+        "seed-queue-size": settings.max_active_seeds.unwrap_or(0),
+        "seed-queue-enabled": settings.max_active_seeds.is_some(),
+        "units": {
+            "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+            "speed-bytes": SPEED_BYTES,
+            "size-units": ["kB", "MB", "GB", "TB"],
+            "size-bytes": 1000,
+            "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+            "memory-bytes": 1024,
+        },
+    });
+
+    match fields {
+        Value::Object(fields) => only_fields(fields, arguments),
+        fields => fields,
+    }
+}
+
+/// Change the settings of the session, keys we don't have are ignored like Transmission does.
+fn session_set(session: &Session, arguments: &Value) -> RpcResult {
+    let mut settings = session.settings();
+    let number = |key: &str| arguments.get(key).and_then(Value::as_u64);
+    let flag = |key: &str| arguments.get(key).and_then(Value::as_bool);
+    let speed = |key: &str| number(key).map(|speed| speed * SPEED_BYTES);
+
+    if let Some(download_dir) = arguments.get("download-dir").and_then(Value::as_str) {
+        settings.download_dir = PathBuf::from(download_dir);
+    }
+    if let Some(port) = number("peer-port") {
+        settings.listen_port = u16::try_from(port).map_err(|_| format!("Invalid peer port: {}", port))?;
+    }
+
+    settings.download_limit = switched(flag("speed-limit-down-enabled"), speed("speed-limit-down"), settings.download_limit);
+    settings.upload_limit = switched(flag("speed-limit-up-enabled"), speed("speed-limit-up"), settings.upload_limit);
+    settings.alt_download_limit = speed("alt-speed-down").or(settings.alt_download_limit);
+    settings.alt_upload_limit = speed("alt-speed-up").or(settings.alt_upload_limit);
+
+    let queue_size = number("download-queue-size").map(|size| size as usize);
+    settings.max_active_downloads = switched(flag("download-queue-enabled"), queue_size, settings.max_active_downloads);
+    let seed_queue_size = number("seed-queue-size").map(|size| size as usize);
+    settings.max_active_seeds = switched(flag("seed-queue-enabled"), seed_queue_size, settings.max_active_seeds);
+
+    session.set_settings(settings).map_err(|e| e.to_string())?;
+
+    if let Some(alt_speed) = flag("alt-speed-enabled") {
+        session.set_alt_speed(Some(alt_speed));
+    }
+
+    Ok(json!({}))
+}
+
+/// A setting Transmission turns on and off apart from its value, like speed-limit-down and
+/// speed-limit-down-enabled. We only have the value while it's on, so a value sent while
+/// it's off is dropped.
+fn switched<T>(enabled: Option<bool>, value: Option<T>, current: Option<T>) -> Option<T> {
+    match enabled {
+        Some(false) => None,
+        Some(true) => value.or(current),
+        None if current.is_some() => value.or(current),
+        None => None,
+    }
+}
+
+#[test]
+fn test_switched() {
+    assert_eq!(switched(Some(true), Some(5), None), Some(5));
+    assert_eq!(switched(Some(true), None, Some(3)), Some(3));
+    assert_eq!(switched(Some(false), Some(5), Some(3)), None);
+    assert_eq!(switched(None, Some(5), Some(3)), Some(5));
+    assert_eq!(switched(None, Some(5), None), None);
+    assert_eq!(switched::<u64>(None, None, Some(3)), Some(3));
+}
+
+#[test]
+fn test_torrent_fields() {
+    let status = TorrentStatus {
+        info_hash: "ab".to_string(),
+        name: "name".to_string(),
+        state: TorrentState::Queued,
+        progress: 50.0,
+        size: 2000,
+        left: 1000,
+        download_folder: PathBuf::from("downloads"),
+        download_rate: 100,
+        upload_rate: 50,
+        queue_position: 2,
+        downloaded: 1000,
+        uploaded: 0,
+    };
+
+    let fields = only_fields(torrent_fields(7, &status), &json!({ "fields": ["id", "status", "percentDone", "eta", "unknown"] }));
+    assert_eq!(fields, json!({ "id": 7, "status": 3, "percentDone": 0.5, "eta": 10 }));
+    let rates = only_fields(torrent_fields(7, &status), &json!({ "fields": ["rateDownload", "rateUpload"] }));
+    assert_eq!(rates, json!({ "rateDownload": 100, "rateUpload": 50 }));
+    assert_eq!(transmission_status(TorrentState::Queued, 0), 5);
+    assert_eq!(only_fields(torrent_fields(7, &status), &json!({})).as_object().unwrap().len(), 17);
+}
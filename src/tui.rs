@@ -0,0 +1,296 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Tabs, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use torrenter::session::info_hash_from_hex;
+use torrenter::{EventReceiver, Session};
+
+use crate::dashboard::{Dashboard, DetailView, format_bytes, format_eta, piece_map, TorrentView};
+
+/// How often the screen is redrawn, events in between are only applied to the dashboard.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the input thread waits for a key before checking whether it should stop.
+const INPUT_POLL: Duration = Duration::from_millis(100);
+
+const HELP: &str = " ↑↓ select  tab view  p pause  r resume  +- queue  x remove  d delete data  a alt speed  q quit";
+
+/// Things the user can ask for with a key.
+enum Action {
+    Pause,
+    Resume,
+    /// Move a place up or down the download queue.
+    QueueUp,
+    QueueDown,
+    Remove,
+    Delete,
+}
+
+/// Show the torrents of a session full screen until the user quits.
+///
+/// Everything shown comes from the session events, the session is only used to act on key presses.
+/// On quit every torrent is paused, so that what was downloaded is flushed to disk.
+pub async fn run(session: &Session, mut events: EventReceiver) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = run_dashboard(&mut terminal, session, &mut events).await;
+    ratatui::restore();
+
+    for torrent in session.torrents() {
+        if let Some(handle) = info_hash_from_hex(&torrent.info_hash).and_then(|info_hash| session.torrent(&info_hash)) {
+            handle.pause().await?;
+        }
+    }
+
+    result
+}
+
+async fn run_dashboard(terminal: &mut DefaultTerminal, session: &Session, events: &mut EventReceiver) -> anyhow::Result<()> {
+    let mut dashboard = Dashboard::default();
+    let mut keys = read_keys();
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+    // Set when the user asked to delete the data of a torrent, until they confirm.
+    let mut confirm_delete: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => dashboard.apply(event, Instant::now()),
+                Err(RecvError::Lagged(missed)) => dashboard.missed_events += missed,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            Some(key) = keys.recv() => {
+                if let Some(info_hash) = confirm_delete.take() {
+                    if key.code == KeyCode::Char('y') {
+                        act(session, &info_hash, Action::Delete).await;
+                    }
+                } else {
+                    let selected = dashboard.selected().map(|torrent| torrent.info_hash.clone());
+
+                    match (key.code, selected) {
+                        (KeyCode::Char('q') | KeyCode::Esc, _) => return Ok(()),
+                        (KeyCode::Up | KeyCode::Char('k'), _) => dashboard.select_previous(),
+                        (KeyCode::Down | KeyCode::Char('j'), _) => dashboard.select_next(),
+                        (KeyCode::Tab, _) => dashboard.next_view(),
+                        (KeyCode::Char('a'), _) => session.set_alt_speed(Some(!session.alt_speed())),
+                        (KeyCode::Char('p'), Some(info_hash)) => act(session, &info_hash, Action::Pause).await,
+                        (KeyCode::Char('r'), Some(info_hash)) => act(session, &info_hash, Action::Resume).await,
+                        (KeyCode::Char('+'), Some(info_hash)) => act(session, &info_hash, Action::QueueUp).await,
+                        (KeyCode::Char('-'), Some(info_hash)) => act(session, &info_hash, Action::QueueDown).await,
+                        (KeyCode::Char('x'), Some(info_hash)) => act(session, &info_hash, Action::Remove).await,
+                        (KeyCode::Char('d'), Some(info_hash)) => confirm_delete = Some(info_hash),
+                        _ => {}
+                    }
+                }
+
+                terminal.draw(|frame| draw(frame, &dashboard, confirm_delete.is_some(), session.alt_speed(), Instant::now()))?;
+            }
+            _ = redraw.tick() => {
+                terminal.draw(|frame| draw(frame, &dashboard, confirm_delete.is_some(), session.alt_speed(), Instant::now()))?;
+            }
+        }
+    }
+}
+
+/// Act on a torrent, the outcome shows up on the dashboard through the session events.
+async fn act(session: &Session, info_hash: &str, action: Action) {
+    let handle = match info_hash_from_hex(info_hash).and_then(|info_hash| session.torrent(&info_hash)) {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    let _ = match action {
+        Action::Pause => handle.pause().await,
+        Action::Resume => handle.resume(),
+        Action::QueueUp => handle.queue_position().and_then(|position| handle.set_queue_position(position.saturating_sub(1))),
+        Action::QueueDown => handle.queue_position().and_then(|position| handle.set_queue_position(position + 1)),
+        Action::Remove => handle.remove(false).await,
+        Action::Delete => handle.remove(true).await,
+    };
+}
+
+/// Read key presses on a thread of their own, reading the terminal blocks.
+fn read_keys() -> mpsc::Receiver<KeyEvent> {
+    let (sender, receiver) = mpsc::channel(16);
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            match event::poll(INPUT_POLL) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => break,
+            }
+
+            if let Ok(event::Event::Key(key)) = event::read() {
+                if key.kind == KeyEventKind::Press && sender.blocking_send(key).is_err() {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard, confirm_delete: bool, alt_speed: bool, now: Instant) {
+    let [torrents_area, details_area, footer_area] = Layout::vertical([
+        Constraint::Length(dashboard.torrents.len() as u16 + 3),
+        Constraint::Min(6),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    draw_torrents(frame, torrents_area, dashboard, now);
+
+    if let Some(torrent) = dashboard.selected() {
+        draw_details(frame, details_area, torrent, dashboard.view);
+    }
+
+    let mut footer = if confirm_delete {
+        " Delete the downloaded data of this torrent? y to confirm, any other key to cancel".to_string()
+    } else if dashboard.missed_events > 0 {
+        format!("{}  ({} events missed)", HELP, dashboard.missed_events)
+    } else {
+        HELP.to_string()
+    };
+    if alt_speed {
+        footer.push_str("  [alt speed]");
+    }
+    frame.render_widget(Paragraph::new(footer).reversed(), footer_area);
+}
+
+fn draw_torrents(frame: &mut Frame, area: Rect, dashboard: &Dashboard, now: Instant) {
+    let rows = dashboard.torrents.iter().map(|torrent| {
+        let state = match &torrent.error {
+            Some(error) => format!("error: {}", error),
+            None => format!("{:?}", torrent.state).to_lowercase(),
+        };
+
+        Row::new(vec![
+            torrent.summary.name.clone(),
+            format_bytes(torrent.summary.size as f64),
+            format!("{} {:5.1}%", progress_bar(torrent.progress(), 20), torrent.progress() * 100.0),
+            format!("{}/s", format_bytes(torrent.download_rate(now))),
+            format_eta(torrent.eta(now)),
+            torrent.peers.len().to_string(),
+            state,
+        ])
+    });
+
+    let widths = [
+        Constraint::Fill(3),
+        Constraint::Length(10),
+        Constraint::Length(28),
+        Constraint::Length(12),
+        Constraint::Length(8),
+        Constraint::Length(5),
+        Constraint::Fill(2),
+    ];
+
+    let table = Table::new(rows, widths)
+        .header(Row::new(vec!["Name", "Size", "Progress", "Down", "ETA", "Peers", "State"]).bold())
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" Torrents "));
+
+    let mut state = TableState::default().with_selected(Some(dashboard.selected));
+    frame.render_stateful_widget(table, area, &mut state);
+}
+
+fn draw_details(frame: &mut Frame, area: Rect, torrent: &TorrentView, view: DetailView) {
+    let [tabs_area, body_area] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(area);
+
+    let titles = DetailView::ALL.iter().map(|view| view.title());
+    let selected = DetailView::ALL.iter().position(|v| *v == view).unwrap_or(0);
+    frame.render_widget(Tabs::new(titles).select(selected).highlight_style(Style::new().reversed()), tabs_area);
+
+    let block = Block::bordered().title(format!(" {} ", torrent.summary.name));
+
+    match view {
+        DetailView::Files => {
+            let rows = torrent.summary.files.iter().zip(torrent.file_progress()).map(|(file, progress)| {
+                Row::new(vec![
+                    file.path.clone(),
+                    format_bytes(file.length as f64),
+                    format!("{} {:5.1}%", progress_bar(progress, 20), progress * 100.0),
+                ])
+            });
+            let widths = [Constraint::Fill(1), Constraint::Length(10), Constraint::Length(28)];
+            frame.render_widget(Table::new(rows, widths).header(Row::new(vec!["Path", "Size", "Progress"]).bold()).block(block), body_area);
+        }
+        DetailView::Pieces => {
+            let inner = block.inner(body_area);
+            let cells = piece_map(&torrent.verified, inner.width as usize * inner.height as usize);
+            let map: String = cells.iter().map(|fraction| piece_char(*fraction)).collect();
+
+            frame.render_widget(Paragraph::new(map).wrap(Wrap { trim: false }).block(block), body_area);
+        }
+        DetailView::Peers => {
+            let lines: Vec<Line> = torrent.peers.iter().map(|address| Line::from(address.to_string())).collect();
+            frame.render_widget(Paragraph::new(lines).block(block), body_area);
+        }
+        DetailView::Trackers => {
+            let rows = torrent.sources.iter().map(|source| Row::new(vec![source.url.clone(), source.kind.to_string(), source.status.clone()]));
+            let widths = [Constraint::Fill(2), Constraint::Length(9), Constraint::Fill(3)];
+            frame.render_widget(Table::new(rows, widths).header(Row::new(vec!["Url", "Kind", "Status"]).bold()).block(block), body_area);
+        }
+    }
+}
+
+fn progress_bar(fraction: f64, width: usize) -> String {
+    let filled = ((fraction.clamp(0.0, 1.0) * width as f64).round() as usize).min(width);
+    format!("{}{}", "█".repeat(filled), "░".repeat(width - filled))
+}
+
+/// The character for a cell of the piece map, darker the more of its pieces are verified.
+fn piece_char(fraction: f64) -> char {
+    match fraction {
+        f if f >= 1.0 => '█',
+        f if f >= 0.5 => '▓',
+        f if f > 0.0 => '▒',
+        _ => '░',
+    }
+}
+
+#[test]
+fn test_draw_dashboard() {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use torrenter::{Event, SessionEvent, Torrent};
+
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let mut dashboard = Dashboard::default();
+    let now = Instant::now();
+
+    dashboard.apply(SessionEvent { info_hash: torrent.info_hash_hex(), event: Event::TorrentAdded { summary: Box::new(torrent.summary()) } }, now);
+    dashboard.apply(SessionEvent { info_hash: torrent.info_hash_hex(), event: Event::PieceVerified { piece: 0 } }, now);
+
+    let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+    let mut screen = |dashboard: &Dashboard| {
+        terminal.draw(|frame| draw(frame, dashboard, false, true, now)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().iter().map(|cell| cell.symbol()).collect::<String>()
+    };
+
+    let text = screen(&dashboard);
+    assert!(text.contains("Test torrent"));
+    assert!(text.contains("468.3 KiB"));
+    assert!(text.contains("Test torrent/file1.zip"));
+    assert!(text.contains("[alt speed]"));
+
+    dashboard.next_view();
+    assert!(screen(&dashboard).contains('█'));
+
+    assert_eq!(progress_bar(0.5, 4), "██░░");
+    assert_eq!(piece_char(0.25), '▒');
+}
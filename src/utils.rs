@@ -1,12 +1,15 @@
 use core::convert::TryInto;
 
-use anyhow;
 use bytebuffer::ByteBuffer;
 use rand::Rng;
 
+use crate::error::{Error, Result};
+
 #[path = "./torrents.rs"]
 pub mod torrents;
 
+// The responses are parsed whole, even the fields we don't look at.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct ConnResp {
     action: i32,
@@ -14,6 +17,7 @@ pub struct ConnResp {
     pub connection_id: i64,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct AnnounceResp {
     action: i32,
@@ -24,6 +28,16 @@ pub struct AnnounceResp {
     pub peers: Vec<Peer>,
 }
 
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ScrapeResp {
+    action: i32,
+    transaction_id: i32,
+    pub seeders: i32,
+    pub completed: i32,
+    pub leechers: i32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     pub ip_addr: u32,
@@ -40,44 +54,192 @@ pub fn gen_peer_id() -> ByteBuffer {
     peer_id.write_i64(rng.gen::<i64>());
     peer_id.write_i32(rng.gen::<i32>());
 
-    return peer_id;
+    peer_id
 }
 
 
-pub fn parse_conn_resp(buf: &[u8; 16]) -> ConnResp {
+/// Parse the response to a connect request.
+///
+/// ```text
+/// 0       32-bit integer  action          0 // connect
+/// 4       32-bit integer  transaction_id
+/// 8       64-bit integer  connection_id
+/// ```
+pub fn parse_conn_resp(buf: &[u8], received: usize) -> Result<ConnResp> {
+    if received < 16 || buf.len() < 16 {
+        return Err(Error::Tracker(format!("The connect response is {} bytes instead of 16", received)));
+    }
+
     let conn_resp = ConnResp {
-        action: i32::from_be_bytes(buf[..4].try_into().unwrap()),
-        transaction_id: i32::from_be_bytes(buf[4..8].try_into().unwrap()),
+        action: read_i32(buf, 0),
+        transaction_id: read_i32(buf, 4),
         connection_id: i64::from_be_bytes(buf[8..16].try_into().unwrap()),
     };
 
-    return conn_resp;
+    if conn_resp.action != 0 {
+        return Err(Error::Tracker(format!("The tracker answered the connect request with action {}", conn_resp.action)));
+    }
+
+    Ok(conn_resp)
 }
 
 
-pub fn parse_announce_resp(buf: &[u8; 1000], received: usize) -> anyhow::Result<AnnounceResp> {
+/// Parse the response to an announce, followed by 6 bytes for every peer.
+///
+/// ```text
+/// 0       32-bit integer  action          1 // announce
+/// 4       32-bit integer  transaction_id
+/// 8       32-bit integer  interval
+/// 12      32-bit integer  leechers
+/// 16      32-bit integer  seeders
+/// 20 + 6 * n  32-bit integer  IP address
+/// 24 + 6 * n  16-bit integer  TCP port
+/// ```
+pub fn parse_announce_resp(buf: &[u8], received: usize) -> Result<AnnounceResp> {
+    let received = received.min(buf.len());
+
     if received < 20 {
-        anyhow::bail!("Error: Not able to announce to tracker");
-    } else {
-        let mut announce_resp = AnnounceResp {
-            action: i32::from_be_bytes(buf[..4].try_into().unwrap()),
-            transaction_id: i32::from_be_bytes(buf[4..8].try_into().unwrap()),
-            interval: i32::from_be_bytes(buf[8..12].try_into().unwrap()),
-            leechers: i32::from_be_bytes(buf[12..16].try_into().unwrap()),
-            seeders: i32::from_be_bytes(buf[16..20].try_into().unwrap()),
-            peers: Vec::new(),
+        return Err(Error::Tracker("Not able to announce to the tracker".to_string()));
+    }
+
+    let action = read_i32(buf, 0);
+    if action != 1 {
+        return Err(Error::Tracker(format!("The tracker answered the announce with action {}", action)));
+    }
+
+    // The peers are whatever follows the header, whatever the seeder count says.
+    let peers = buf[20..received]
+        .chunks_exact(6)
+        .map(|peer| Peer {
+            ip_addr: u32::from_be_bytes(peer[..4].try_into().unwrap()),
+            port: u16::from_be_bytes(peer[4..].try_into().unwrap()),
+        })
+        .collect();
+
+    Ok(AnnounceResp {
+        action,
+        transaction_id: read_i32(buf, 4),
+        interval: read_i32(buf, 8),
+        leechers: read_i32(buf, 12),
+        seeders: read_i32(buf, 16),
+        peers,
+    })
+}
+
+
+/// Parse the response to a scrape of a single torrent.
+///
+/// ```text
+/// 0       32-bit integer  action          2 // scrape
+/// 4       32-bit integer  transaction_id
+/// 8       32-bit integer  seeders
+/// 12      32-bit integer  completed
+/// 16      32-bit integer  leechers
+/// ```
+pub fn parse_scrape_resp(buf: &[u8], received: usize) -> Result<ScrapeResp> {
+    if received < 20 || buf.len() < 20 {
+        return Err(Error::Tracker("Not able to scrape the tracker".to_string()));
+    }
+
+    let action = read_i32(buf, 0);
+    if action != 2 {
+        return Err(Error::Tracker(format!("The tracker answered the scrape with action {}", action)));
+    }
+
+    let scrape_resp = ScrapeResp {
+        action,
+        transaction_id: read_i32(buf, 4),
+        seeders: read_i32(buf, 8),
+        completed: read_i32(buf, 12),
+        leechers: read_i32(buf, 16),
+    };
+
+    Ok(scrape_resp)
+}
+
+/// Read a big endian i32, the caller checks that the bytes are there.
+fn read_i32(buf: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Decode standard base64, with or without padding. Whitespace is skipped, since long
+/// values are often wrapped over lines.
+pub fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()).take_while(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
         };
 
-        let mut offset = 20;
-        for _ in 0..announce_resp.seeders {
-            announce_resp.peers.push(Peer {
-                ip_addr: u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap()),
-                port: u16::from_be_bytes(buf[offset + 4..offset + 6].try_into().unwrap()),
-            });
+        buffer = (buffer << 6 | value as u32) & 0xffff;
+        bits += 6;
 
-            offset += 4;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
         }
-
-        return Ok(announce_resp);
     }
+
+    Some(bytes)
+}
+
+
+#[test]
+fn test_decode_base64() {
+    assert_eq!(decode_base64("aGVsbG8="), Some(b"hello".to_vec()));
+    assert_eq!(decode_base64("aGVsbG8"), Some(b"hello".to_vec()));
+    assert_eq!(decode_base64("dXNlcjpz\nZWNyZXQ="), Some(b"user:secret".to_vec()));
+    assert_eq!(decode_base64("//79"), Some(vec![0xff, 0xfe, 0xfd]));
+    assert_eq!(decode_base64(""), Some(vec![]));
+    assert_eq!(decode_base64("not base64!"), None);
+}
+
+#[test]
+fn test_parse_scrape_resp() {
+    let mut buf = [0; 20];
+    buf[3] = 2;
+    buf[11] = 5;
+    buf[15] = 40;
+    buf[19] = 3;
+
+    let scrape_resp = parse_scrape_resp(&buf, 20).unwrap();
+    assert_eq!((scrape_resp.seeders, scrape_resp.completed, scrape_resp.leechers), (5, 40, 3));
+
+    assert!(parse_scrape_resp(&buf, 12).is_err());
+
+    // Trackers answer with action 3 and a message when something went wrong.
+    buf[3] = 3;
+    assert!(parse_scrape_resp(&buf, 20).is_err());
+}
+
+#[test]
+fn test_parse_announce_resp() {
+    let mut buf = [0; 1000];
+    buf[3] = 1;
+    // The tracker claims 200 seeders but only sends two peers.
+    buf[19] = 200;
+    buf[20..26].copy_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+    buf[26..32].copy_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+
+    let announce_resp = parse_announce_resp(&buf, 32).unwrap();
+    let peers: Vec<(u32, u16)> = announce_resp.peers.iter().map(|peer| (peer.ip_addr, peer.port)).collect();
+    assert_eq!(peers, vec![(0x7f000001, 6881), (0x0a000002, 6882)]);
+
+    assert!(parse_announce_resp(&buf, 12).is_err());
+    assert!(parse_announce_resp(&buf, 5000).is_ok());
+
+    let mut conn = [0; 16];
+    conn[15] = 9;
+    assert_eq!(parse_conn_resp(&conn, 16).unwrap().connection_id, 9);
+    assert!(parse_conn_resp(&conn, 8).is_err());
+
+    conn[3] = 3;
+    assert!(parse_conn_resp(&conn, 16).is_err());
 }
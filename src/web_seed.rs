@@ -0,0 +1,459 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use tokio::sync::mpsc::Sender;
+use tracing::warn;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use crate::download::PiecesManager;
+use crate::events::{Event, TorrentEvents};
+use crate::http_client;
+use crate::message_handlers::PieceChannelPayload;
+use crate::rate_limit::LimiterChain;
+use crate::storage::verify_piece_data;
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+
+/// How long to wait after a web seed fails before trying it again, doubled on every failure in a row.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(15);
+
+/// The longest we'll wait between attempts on a web seed that keeps failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait when every wanted piece is already being downloaded by someone else.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// The protocols a web seed can speak.
+///
+/// - UrlList: BEP 19, the server hosts the plain files and is asked for byte ranges of them.
+/// - HttpSeed: BEP 17, a script is asked for pieces with `?info_hash=&piece=&ranges=`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedProtocol {
+    UrlList,
+    HttpSeed,
+}
+
+/// A server hosting the data of a torrent over HTTP.
+///
+/// For url-list seeds the url either points at the file of a single file torrent, or at a folder
+/// which holds the torrent folder of a multi-file torrent (or the file itself when it ends with a slash).
+pub struct WebSeed {
+    url: String,
+    protocol: SeedProtocol,
+    failures: u32,
+    /// Set when an http seed asks us to come back later.
+    retry_after: Option<Duration>,
+}
+
+impl WebSeed {
+    pub fn url_list(url: &str) -> WebSeed {
+        WebSeed::new(url, SeedProtocol::UrlList)
+    }
+
+    pub fn http_seed(url: &str) -> WebSeed {
+        WebSeed::new(url, SeedProtocol::HttpSeed)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn new(url: &str, protocol: SeedProtocol) -> WebSeed {
+        WebSeed {
+            url: url.to_string(),
+            protocol,
+            failures: 0,
+            retry_after: None,
+        }
+    }
+
+    /// Work out the url of one of the files of the torrent.
+    pub fn file_url(&self, torrent: &Torrent, file_index: usize) -> Option<String> {
+        let components = torrent.file_path_components(file_index)?;
+        let encode = |component: &str| utf8_percent_encode(component, PATH_SEGMENT_ENCODE_SET).to_string();
+
+        // A single file torrent only needs the name when the url is a folder.
+        if torrent.info.files.is_none() && !self.url.ends_with('/') {
+            return Some(self.url.clone());
+        }
+
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.push_str(&encode(&torrent.info.name));
+
+        for component in components {
+            url.push('/');
+            url.push_str(&encode(&component));
+        }
+
+        Some(url)
+    }
+
+    /// Build the BEP 17 request url for a whole piece.
+    pub fn piece_url(&self, torrent: &Torrent, piece_index: u64) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let info_hash = encode_query_bytes(&torrent.info_hash.unwrap_or_default());
+        let piece_len = torrent.get_piece_len(piece_index);
+
+        format!("{}{}info_hash={}&piece={}&ranges=0-{}", self.url, separator, info_hash, piece_index, piece_len - 1)
+    }
+
+    /// How long to wait before using the seed again after the last failure.
+    fn backoff(&self) -> Duration {
+        if let Some(retry_after) = self.retry_after {
+            return retry_after;
+        }
+
+        let exponent = self.failures.saturating_sub(1).min(16);
+        INITIAL_BACKOFF.saturating_mul(2_u32.pow(exponent)).min(MAX_BACKOFF)
+    }
+
+    /// Count a failed fetch and get how long to wait before the next one.
+    ///
+    /// Being told to come back later isn't the seed failing, the wait is whatever it asked for.
+    fn record_failure(&mut self) -> Duration {
+        if self.retry_after.is_none() {
+            self.failures += 1;
+        }
+
+        self.backoff()
+    }
+
+    /// Download a whole piece and check its hash.
+    async fn fetch_piece(&mut self, torrent: &Torrent, piece_index: u64) -> anyhow::Result<Vec<u8>> {
+        self.retry_after = None;
+
+        let data = match self.protocol {
+            SeedProtocol::UrlList => self.fetch_file_ranges(torrent, piece_index).await?,
+            SeedProtocol::HttpSeed => self.fetch_http_seed_piece(torrent, piece_index).await?,
+        };
+
+        if !verify_piece_data(torrent, piece_index, &data) {
+            bail!("Piece {} from {} failed the hash check", piece_index, self.url);
+        }
+
+        Ok(data)
+    }
+
+    /// Ask a BEP 17 seed for a piece.
+    ///
+    /// A busy seed answers 503 with the number of seconds to wait as the body, or in a
+    /// Retry-After header.
+    async fn fetch_http_seed_piece(&mut self, torrent: &Torrent, piece_index: u64) -> anyhow::Result<Vec<u8>> {
+        let url = self.piece_url(torrent, piece_index);
+        let response = http_client::get(&url, None).await?;
+        let piece_len = torrent.get_piece_len(piece_index) as usize;
+
+        match response.status {
+            200 if response.body.len() == piece_len => Ok(response.body),
+            503 => {
+                let seconds = response
+                    .header("Retry-After")
+                    .and_then(|value| parse_retry_after(value.as_bytes()))
+                    .or_else(|| parse_retry_after(&response.body))
+                    .ok_or_else(|| anyhow!("{} is unavailable", self.url))?;
+                self.retry_after = Some(Duration::from_secs(seconds).min(MAX_BACKOFF));
+                bail!("{} asked us to retry after {} seconds", self.url, seconds)
+            }
+            status => bail!("Unexpected response from {}: {} with {} bytes", url, status, response.body.len()),
+        }
+    }
+
+    /// Download a piece with a ranged GET for every file it overlaps.
+    async fn fetch_file_ranges(&self, torrent: &Torrent, piece_index: u64) -> anyhow::Result<Vec<u8>> {
+        let offset = torrent.piece_offset(piece_index);
+        let piece_len = torrent.get_piece_len(piece_index);
+        let mut data = Vec::with_capacity(piece_len as usize);
+
+        for slice in torrent.layout.slices(offset, piece_len) {
+            let url = self.file_url(torrent, slice.file_index).ok_or_else(|| anyhow!("No such file: {}", slice.file_index))?;
+            let range = (slice.file_offset, slice.file_offset + slice.length - 1);
+            let response = http_client::get(&url, Some(range)).await?;
+
+            let start = slice.file_offset as usize;
+            let end = start + slice.length as usize;
+
+            match response.status {
+                206 if response.body.len() == slice.length as usize => data.extend_from_slice(&response.body),
+                // The server ignored the range and sent the whole file.
+                200 if response.body.len() >= end => data.extend_from_slice(&response.body[start..end]),
+                status => bail!("Unexpected response from {}: {} with {} bytes", url, status, response.body.len()),
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Percent encode raw bytes for a query string, like the info hash.
+fn encode_query_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (*b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The body of a BEP 17 retry-after response is the number of seconds to wait.
+fn parse_retry_after(body: &[u8]) -> Option<u64> {
+    std::str::from_utf8(body).ok()?.trim().parse().ok()
+}
+
+/// Download pieces from a web seed until the torrent is done.
+///
+/// The web seed is treated like a peer that has every piece: it counts towards the availability
+/// of the pieces, takes its pieces from the same picker and hands the blocks over to the disk
+/// through the same channel the peers use. A failing seed is backed off exponentially,
+/// a busy http seed is left alone for as long as it asks. Its pieces count towards the download
+/// limits of the torrent and the session.
+pub async fn run_web_seed(
+    torrent: Arc<Torrent>,
+    mut seed: WebSeed,
+    pieces: PiecesManager,
+    file_sender: Sender<PieceChannelPayload>,
+    events: TorrentEvents,
+    limiters: LimiterChain,
+) {
+    let all_pieces: Vec<u64> = (0..torrent.num_pieces()).collect();
+
+    {
+        let mut pieces = pieces.lock().unwrap();
+        all_pieces.iter().for_each(|piece_index| pieces.add_availability(*piece_index));
+    }
+
+    loop {
+        let piece_index = {
+            // Blocks that are in may still fail their hash check, and be picked again.
+            let mut pieces = pieces.lock().unwrap();
+            if pieces.bytes_left(&torrent) == 0 {
+                break;
+            }
+            pieces.pick_piece()
+        };
+
+        let piece_index = match piece_index {
+            Some(piece_index) => piece_index,
+            None => {
+                tokio::time::sleep(IDLE_WAIT).await;
+                continue;
+            }
+        };
+
+        let data = match seed.fetch_piece(&torrent, piece_index).await {
+            Ok(data) => data,
+            Err(e) => {
+                let wait = seed.record_failure();
+                warn!(piece = piece_index, error = %e, retry_in_secs = wait.as_secs(), "web seed failed");
+                events.send(Event::WebSeedFailed { url: seed.url.clone(), error: e.to_string(), retry_in_secs: wait.as_secs() });
+
+                // Only the blocks the seed was going to send, peers may have sent others meanwhile.
+                pieces.lock().unwrap().release_piece(piece_index);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+        };
+
+        seed.failures = 0;
+        limiters.download(data.len() as u64).await;
+
+        for (block_index, block) in data.chunks(BLOCK_LEN as usize).enumerate() {
+            let begin = block_index as u64 * BLOCK_LEN;
+            let payload = PieceChannelPayload { index: piece_index, begin, block: block.to_vec() };
+            if file_sender.send(payload).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    pieces.lock().unwrap().remove_availability(&all_pieces);
+}
+
+#[test]
+fn test_web_seed_file_url() {
+    let info = "d5:filesld6:lengthi10e4:pathl3:sub5:a b.ceed6:lengthi6e4:pathl1:beee4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let torrent = Torrent::from_bytes(format!("d4:info{}e", info).as_bytes()).unwrap();
+
+    let seed = WebSeed::url_list("http://mirror/files");
+    assert_eq!(seed.file_url(&torrent, 0).unwrap(), "http://mirror/files/spam/sub/a%20b.c");
+    assert_eq!(seed.file_url(&torrent, 1).unwrap(), "http://mirror/files/spam/b");
+    assert_eq!(seed.file_url(&torrent, 2), None);
+
+    let info = "d6:lengthi10e4:name8:spam.iso12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let torrent = Torrent::from_bytes(format!("d4:info{}e", info).as_bytes()).unwrap();
+
+    assert_eq!(WebSeed::url_list("http://mirror/spam.iso").file_url(&torrent, 0).unwrap(), "http://mirror/spam.iso");
+    assert_eq!(WebSeed::url_list("http://mirror/isos/").file_url(&torrent, 0).unwrap(), "http://mirror/isos/spam.iso");
+}
+
+#[test]
+fn test_web_seed_backoff() {
+    let mut seed = WebSeed::url_list("http://mirror/");
+
+    seed.failures = 1;
+    assert_eq!(seed.backoff(), INITIAL_BACKOFF);
+    seed.failures = 3;
+    assert_eq!(seed.backoff(), INITIAL_BACKOFF * 4);
+    seed.failures = 100;
+    assert_eq!(seed.backoff(), MAX_BACKOFF);
+
+    seed.retry_after = Some(Duration::from_secs(5));
+    assert_eq!(seed.backoff(), Duration::from_secs(5));
+}
+
+#[test]
+fn test_http_seed_piece_url() {
+    let torrent = Torrent::from_path("test-tor.torrent").unwrap();
+    let info_hash = encode_query_bytes(&torrent.info_hash.unwrap());
+
+    let seed = WebSeed::http_seed("http://seed/seed.php");
+    assert_eq!(seed.piece_url(&torrent, 14), format!("http://seed/seed.php?info_hash={}&piece=14&ranges=0-20749", info_hash));
+
+    let seed = WebSeed::http_seed("http://seed/seed.php?id=3");
+    assert!(seed.piece_url(&torrent, 0).starts_with("http://seed/seed.php?id=3&info_hash="));
+
+    assert_eq!(encode_query_bytes(&[0x12, b'a', b' ', 0xff]), "%12a%20%FF");
+    assert_eq!(parse_retry_after(b" 120\n"), Some(120));
+    assert_eq!(parse_retry_after(b"later"), None);
+}
+
+/// The requests made to a test server, as their path and Range header.
+#[cfg(test)]
+type SeenRequests = Arc<std::sync::Mutex<Vec<(String, Option<String>)>>>;
+
+/// Serve HTTP on a local port, answering every request with the status, headers and body
+/// the handler gives for its path and Range header. Returns the server's base url.
+#[cfg(test)]
+async fn test_server<F>(handler: F) -> (String, SeenRequests)
+where
+    F: Fn(&str, Option<&str>) -> (&'static str, Vec<(&'static str, String)>, Vec<u8>) + Send + Sync + 'static,
+{
+    use tokio::net::TcpListener;
+    use crate::http_server::{read_request, write_response};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let seen = SeenRequests::default();
+    let handler = Arc::new(handler);
+
+    let requests = seen.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let (handler, requests) = (handler.clone(), requests.clone());
+
+            tokio::spawn(async move {
+                if let Ok(Some(request)) = read_request(&mut stream, 0).await {
+                    let range = request.header("range").map(str::to_string);
+                    requests.lock().unwrap().push((request.path.clone(), range.clone()));
+
+                    let (status, mut headers, body) = handler(&request.path, range.as_deref());
+                    headers.push(("Content-Length", body.len().to_string()));
+                    let _ = write_response(&mut stream, status, &headers, &body).await;
+                }
+            });
+        }
+    });
+
+    (url, seen)
+}
+
+#[tokio::test]
+async fn test_web_seed_fetch_piece() {
+    use std::fs;
+    use std::path::Path;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::stream_server::parse_range;
+
+    // Piece 1 (16384..32768) ends a.bin and starts b.bin.
+    let folder = Path::new("test-files/test18/spam");
+    let a: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+    let b: Vec<u8> = (0..30_000).map(|i| (i % 241) as u8).collect();
+    fs::create_dir_all(folder).unwrap();
+    fs::write(folder.join("a.bin"), &a).unwrap();
+    fs::write(folder.join("b.bin"), &b).unwrap();
+    let options = CreateOptions { piece_length: Some(16 * 1024), ..Default::default() };
+    let torrent = Torrent::from_bytes(&create_torrent(folder, &options).unwrap()).unwrap();
+    let _ = fs::remove_dir_all("test-files/test18");
+    let expected = [&a[16_384..], &b[..12_768]].concat();
+
+    let (url, seen) = test_server(move |path, range| {
+        let data = match path {
+            "/spam/a.bin" => &a,
+            "/spam/b.bin" => &b,
+            // A broken mirror, which ignores the range and has the wrong data.
+            _ => return ("200 OK", vec![], vec![0; 30_000]),
+        };
+
+        match range.and_then(|range| parse_range(range, data.len() as u64)) {
+            Some(range) => ("206 Partial Content", vec![], data[range.start as usize..=range.end as usize].to_vec()),
+            None => ("200 OK", vec![], data.clone()),
+        }
+    })
+    .await;
+
+    let mut seed = WebSeed::url_list(&url);
+    assert_eq!(seed.fetch_piece(&torrent, 1).await.unwrap(), expected);
+    assert_eq!(*seen.lock().unwrap(), vec![
+        ("/spam/a.bin".to_string(), Some("bytes=16384-19999".to_string())),
+        ("/spam/b.bin".to_string(), Some("bytes=0-12767".to_string())),
+    ]);
+
+    let mut seed = WebSeed::url_list(&format!("{}broken/", url));
+    assert!(seed.fetch_piece(&torrent, 1).await.is_err());
+}
+
+#[tokio::test]
+async fn test_http_seed_retry_after() {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::pieces::Pieces;
+    use crate::rate_limit::Bandwidth;
+
+    let folder = Path::new("test-files/test19");
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    fs::create_dir_all(folder).unwrap();
+    fs::write(folder.join("data.bin"), &data).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&create_torrent(&folder.join("data.bin"), &CreateOptions::default()).unwrap()).unwrap());
+    let _ = fs::remove_dir_all(folder);
+
+    // Busy twice, asking for a second with the header and then with the body, then the piece.
+    let answered = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let requests = AtomicUsize::new(0);
+    let (url, _) = test_server({
+        let (answered, data) = (answered.clone(), data.clone());
+
+        move |_, _| {
+            answered.lock().unwrap().push(Instant::now());
+
+            match requests.fetch_add(1, Ordering::SeqCst) {
+                0 => ("503 Service Unavailable", vec![("Retry-After", "1".to_string())], vec![]),
+                1 => ("503 Service Unavailable", vec![], b"1".to_vec()),
+                _ => ("200 OK", vec![], data.clone()),
+            }
+        }
+    })
+    .await;
+
+    let mut seed = WebSeed::http_seed(&url);
+    assert!(seed.fetch_piece(&torrent, 0).await.is_err());
+    assert_eq!(seed.record_failure(), Duration::from_secs(1));
+    assert_eq!(seed.failures, 0);
+
+    let pieces = Arc::new(std::sync::Mutex::new(Pieces::new(&torrent)));
+    let (file_sender, mut file_receiver) = tokio::sync::mpsc::channel(1);
+    let limiters = LimiterChain::new(vec![Bandwidth::default()]);
+    tokio::spawn(run_web_seed(torrent.clone(), seed, pieces, file_sender, TorrentEvents::unobserved(&torrent), limiters));
+
+    let payload = file_receiver.recv().await.unwrap();
+    assert_eq!(payload.block, data);
+
+    let answered = answered.lock().unwrap();
+    assert_eq!(answered.len(), 3);
+    assert!(answered[2].duration_since(answered[1]) >= Duration::from_secs(1));
+}